@@ -0,0 +1,58 @@
+use crate::err::Error;
+use crate::stats::{DataGauge, DataStats};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+/// A handle to a server that was started with `Server::serve_in_background`
+pub struct ServerHandle {
+    pub(crate) addr: SocketAddr,
+    pub(crate) ready: Arc<AtomicBool>,
+    pub(crate) stopping: Arc<AtomicBool>,
+    pub(crate) data: DataGauge,
+    pub(crate) thread: JoinHandle<Result<(), String>>,
+}
+
+impl ServerHandle {
+    /// The address that the server is listening on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns true while the server is accepting connections
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::SeqCst)
+    }
+
+    /// A flag that is true while the server is accepting connections. The flag can
+    /// be given to a health check that runs in another thread.
+    pub fn ready_flag(&self) -> Arc<AtomicBool> {
+        self.ready.clone()
+    }
+
+    /// The number of sessions that are receiving message data and the bytes that
+    /// their handlers are holding
+    pub fn data_stats(&self) -> DataStats {
+        self.data.stats()
+    }
+
+    /// Stop accepting connections and wait for the running sessions to finish
+    pub fn shutdown(self) -> Result<(), Error> {
+        self.ready.store(false, Ordering::SeqCst);
+        self.stopping.store(true, Ordering::SeqCst);
+        // Wake up the accept loop so that it sees the stop request
+        let mut wake = self.addr;
+        if wake.ip().is_unspecified() {
+            match wake {
+                SocketAddr::V4(_) => wake.set_ip(Ipv4Addr::LOCALHOST.into()),
+                SocketAddr::V6(_) => wake.set_ip(Ipv6Addr::LOCALHOST.into()),
+            }
+        }
+        TcpStream::connect(wake).ok();
+        self.thread
+            .join()
+            .map_err(|_| Error::new("Server thread panicked"))?
+            .map_err(Error::new)
+    }
+}
@@ -37,14 +37,36 @@ cfg_if::cfg_if! {
     }
 }
 
+mod handle;
+mod pacing;
+mod proxy;
 mod running;
+mod sessions;
 mod ssl;
+mod stats;
+mod timeout;
 
 use crate::err::Error;
+pub use crate::handle::ServerHandle;
+use crate::pacing::Pacing;
 pub use crate::ssl::SslConfig;
+pub use crate::stats::DataStats;
+use crate::timeout::Timeouts;
 pub use mailin::response;
-pub use mailin::{Action, AuthMechanism, Handler, Response};
-use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+pub use mailin::{
+    Action, AuthMechanism, Envelope, Handler, Recipient, RejectAll, Response, SessionSummary,
+};
+use std::net::{IpAddr, SocketAddr, TcpListener, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// How often serve_with_shutdown checks its flag
+const SHUTDOWN_POLL: Duration = Duration::from_millis(50);
+
+// Callback for TLS handshake failures
+pub(crate) type TlsErrorHook = Arc<dyn Fn(IpAddr, &Error) + Send + Sync>;
 
 /// `Server` is used to configure and start the SMTP server
 pub struct Server<H>
@@ -54,10 +76,18 @@ where
     handler: H,
     name: String,
     ssl: Option<SslImpl>,
+    alpn_protocols: Vec<Vec<u8>>,
     num_threads: u32,
     auth: Vec<AuthMechanism>,
     tcp_listener: Option<TcpListener>,
     socket_address: Vec<SocketAddr>,
+    tls_error: Option<TlsErrorHook>,
+    pacing: Option<Pacing>,
+    proxy_protocol: bool,
+    max_sessions_per_ip: Option<usize>,
+    max_connections: Option<usize>,
+    timeouts: Timeouts,
+    reject_all: Option<RejectAll>,
 }
 
 impl<H> Server<H>
@@ -70,10 +100,18 @@ where
             handler,
             name: "localhost".to_owned(),
             ssl: None,
+            alpn_protocols: Vec::new(),
             num_threads: 4,
             auth: Vec::with_capacity(4),
             tcp_listener: None,
             socket_address: Vec::with_capacity(4),
+            tls_error: None,
+            pacing: None,
+            proxy_protocol: false,
+            max_sessions_per_ip: None,
+            max_connections: None,
+            timeouts: Timeouts::default(),
+            reject_all: None,
         }
     }
 
@@ -92,14 +130,41 @@ where
         Ok(self)
     }
 
+    /// Set the application protocols that STARTTLS accepts with ALPN, in order of
+    /// preference. By default none are set, so a client that offers ALPN, such as
+    /// an HTTP scanner, completes the handshake without a protocol. Once set, a
+    /// client that offers only other protocols fails the handshake, as required by
+    /// RFC 7301. Clients that do not offer ALPN are not affected.
+    /// ```
+    /// # use mailin_embedded::{Server, Handler};
+    /// # #[derive(Clone)]
+    /// # struct EmptyHandler {}
+    /// # impl Handler for EmptyHandler {}
+    /// # let mut server = Server::new(EmptyHandler {});
+    /// server.with_alpn_protocols(["smtp"]);
+    /// ```
+    pub fn with_alpn_protocols<I, P>(&mut self, protocols: I) -> &mut Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<Vec<u8>>,
+    {
+        self.alpn_protocols = protocols.into_iter().map(Into::into).collect();
+        self
+    }
+
     /// Set the size of the threadpool which is equal to the maximum number of
-    /// concurrent SMTP sessions.
+    /// concurrent SMTP sessions. Each session runs on its own thread, so a Handler
+    /// that blocks, e.g. on DNS lookups or disk IO, only delays its own session.
+    /// The commands of a session are always handled one at a time, in order.
     pub fn with_num_threads(&mut self, num_threads: u32) -> &mut Self {
         self.num_threads = num_threads;
         self
     }
 
-    /// Add an authentication mechanism that will supported by the server
+    /// Add an authentication mechanism that will supported by the server.
+    /// Each session runs on its own thread, so the `Handler` checks credentials
+    /// before returning. Deferring authentication with `AUTH_PENDING` is not
+    /// supported and the client is sent a 454 temporary failure.
     pub fn with_auth(&mut self, auth: AuthMechanism) -> &mut Self {
         self.auth.push(auth);
         self
@@ -133,8 +198,120 @@ where
         Ok(self)
     }
 
+    /// Set a callback that is called with the client address and the cause when a
+    /// STARTTLS handshake fails. The client has already been sent `220 Ready to start TLS`
+    /// so the connection is closed after the callback returns.
+    pub fn on_tls_error<F>(&mut self, callback: F) -> &mut Self
+    where
+        F: Fn(IpAddr, &Error) + Send + Sync + 'static,
+    {
+        self.tls_error = Some(Arc::new(callback));
+        self
+    }
+
+    /// Close sessions with a 421 response when the client sends more than `max_fast`
+    /// consecutive commands less than `min_interval` apart. This catches bots that
+    /// fire commands as soon as each response arrives.
+    ///
+    /// Commands that a client pipelines, by sending a batch of commands in one go,
+    /// arrive together and only the first command of the batch is timed. The lines
+    /// of a message sent after DATA are not timed.
+    pub fn with_command_pacing(&mut self, min_interval: Duration, max_fast: usize) -> &mut Self {
+        self.pacing = Some(Pacing {
+            min_interval,
+            max_fast,
+        });
+        self
+    }
+
+    /// Expect every connection to start with a PROXY protocol v1 or v2 header, as
+    /// sent by a load balancer such as HAProxy or an AWS NLB in front of the server.
+    /// The client address in the header is used for the session. When the proxy
+    /// terminated TLS the session is treated as a TLS session, and the server name
+    /// and application protocol it forwards are passed to `Handler::tls_started`.
+    /// Connections without a valid header are closed.
+    pub fn with_proxy_protocol(&mut self) -> &mut Self {
+        self.proxy_protocol = true;
+        self
+    }
+
+    /// Limit the number of sessions that a client address can have open at the
+    /// same time. Further connections from the address are closed with a 421
+    /// response until one of its sessions ends. When using the PROXY protocol, the
+    /// address is the client address from the PROXY header rather than the proxy.
+    pub fn with_max_sessions_per_ip(&mut self, max_sessions: usize) -> &mut Self {
+        self.max_sessions_per_ip = Some(max_sessions);
+        self
+    }
+
+    /// Limit the number of connections that can be open at the same time. Further
+    /// connections are closed with a 421 response, rather than waiting for a free
+    /// thread, until one of the open connections ends. A limit larger than the size
+    /// of the threadpool is reduced to the size of the threadpool.
+    pub fn with_max_connections(&mut self, max_connections: usize) -> &mut Self {
+        self.max_connections = Some(max_connections);
+        self
+    }
+
+    /// Set how long a client can be idle, between or during commands, and how long
+    /// a whole session can last. A session that times out is sent `421 Timeout,
+    /// closing connection` and closed. By default a client can be idle for five
+    /// minutes and a session has no time limit.
+    pub fn with_timeouts(&mut self, idle: Duration, total: Duration) -> &mut Self {
+        self.timeouts = Timeouts {
+            idle,
+            total: Some(total),
+        };
+        self
+    }
+
+    /// Refuse all mail, either with a 521 greeting that closes the connection or
+    /// by rejecting MAIL FROM, e.g. for a decommissioned MX
+    pub fn reject_all_mail(&mut self, mode: RejectAll) -> &mut Self {
+        self.reject_all = Some(mode);
+        self
+    }
+
     /// Start the SMTP server and run forever
     pub fn serve(self) -> Result<(), Error> {
         running::serve(self)
     }
+
+    /// Start the SMTP server and run until `shutdown` is set to true. The server
+    /// runs in the background, as with `serve_in_background`, and is shut down
+    /// through its `ServerHandle` shortly after the flag is set. The running
+    /// sessions are allowed to finish before returning.
+    pub fn serve_with_shutdown(self, shutdown: Arc<AtomicBool>) -> Result<(), Error>
+    where
+        H: 'static,
+    {
+        let handle = self.serve_in_background()?;
+        // Nothing wakes this thread when the flag is set, the handle wakes the
+        // accept loop once it has been seen
+        while !shutdown.load(Ordering::SeqCst) && !handle.thread.is_finished() {
+            thread::sleep(SHUTDOWN_POLL);
+        }
+        handle.shutdown()
+    }
+
+    /// Start the SMTP server in a background thread. The returned handle shows
+    /// whether the server is ready to accept connections and is used to shut it down.
+    pub fn serve_in_background(self) -> Result<ServerHandle, Error>
+    where
+        H: 'static,
+    {
+        let server_state = running::bind(self)?;
+        let addr = server_state.listener.local_addr()?;
+        let ready = server_state.ready.clone();
+        let stopping = server_state.stopping.clone();
+        let data = server_state.data.clone();
+        let thread = thread::spawn(move || running::run(&server_state).map_err(|e| e.to_string()));
+        Ok(ServerHandle {
+            addr,
+            ready,
+            stopping,
+            data,
+            thread,
+        })
+    }
 }
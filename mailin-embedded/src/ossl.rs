@@ -2,19 +2,23 @@ use crate::ssl::{SslConfig, Stream};
 use crate::Error;
 use openssl::error::ErrorStack;
 use openssl::pkey::PKey;
-use openssl::ssl::{SslAcceptor, SslAcceptorBuilder, SslMethod, SslStream};
+use openssl::ssl::{AlpnError, NameType, SslAcceptor, SslAcceptorBuilder, SslMethod, SslStream};
 use openssl::x509::X509;
 use std::fmt::Display;
 use std::fs::File;
 use std::io::Read;
 use std::net::TcpStream;
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+
+// The application protocols accepted with ALPN
+type AlpnProtocols = Arc<RwLock<Vec<Vec<u8>>>>;
 
 // Openssl wrapper
 #[derive(Clone)]
 pub struct SslImpl {
     acceptor: Arc<SslAcceptor>,
+    alpn_protocols: AlpnProtocols,
 }
 
 impl From<ErrorStack> for Error {
@@ -24,7 +28,15 @@ impl From<ErrorStack> for Error {
     }
 }
 
-impl Stream for SslStream<TcpStream> {}
+impl Stream for SslStream<TcpStream> {
+    fn server_name(&self) -> Option<String> {
+        self.ssl().servername(NameType::HOST_NAME).map(String::from)
+    }
+
+    fn alpn_protocol(&self) -> Option<Vec<u8>> {
+        self.ssl().selected_alpn_protocol().map(<[u8]>::to_vec)
+    }
+}
 
 impl SslImpl {
     pub fn setup(ssl_config: SslConfig) -> Result<Option<Self>, Error> {
@@ -51,21 +63,59 @@ impl SslImpl {
             }
             _ => None,
         };
-        let ssl = builder.map(|b| SslImpl {
-            acceptor: Arc::new(b.build()),
+        let alpn_protocols = AlpnProtocols::default();
+        let ssl = builder.map(|mut b| {
+            let protocols = alpn_protocols.clone();
+            b.set_alpn_select_callback(move |_, client| select_protocol(&protocols, client));
+            SslImpl {
+                acceptor: Arc::new(b.build()),
+                alpn_protocols,
+            }
         });
         Ok(ssl)
     }
 
+    // Accept the given application protocols with ALPN, a client that offers only
+    // other protocols fails the handshake
+    pub fn with_alpn_protocols(self, protocols: &[Vec<u8>]) -> Self {
+        if let Ok(mut alpn_protocols) = self.alpn_protocols.write() {
+            *alpn_protocols = protocols.to_vec();
+        }
+        self
+    }
+
     pub fn accept(&self, stream: TcpStream) -> Result<impl Stream, Error> {
         let ret = self
             .acceptor
             .accept(stream)
-            .map_err(|e| Error::with_source("Cannot upgrade to TLS", e))?;
+            .map_err(|e| Error::with_source(format!("TLS handshake failed: {e}"), e))?;
         Ok(ret)
     }
 }
 
+// Choose the first of our protocols that the client offers. The client's
+// protocols are in ALPN wire format, each prefixed by its length.
+fn select_protocol<'a>(ours: &AlpnProtocols, client: &'a [u8]) -> Result<&'a [u8], AlpnError> {
+    let ours = ours.read().map_err(|_| AlpnError::ALERT_FATAL)?;
+    if ours.is_empty() {
+        return Err(AlpnError::NOACK);
+    }
+    let mut offered = Vec::new();
+    let mut rest = client;
+    while let Some((len, tail)) = rest.split_first() {
+        let len = *len as usize;
+        if tail.len() < len {
+            break;
+        }
+        let (protocol, tail) = tail.split_at(len);
+        offered.push(protocol);
+        rest = tail;
+    }
+    ours.iter()
+        .find_map(|p| offered.iter().find(|o| **o == p.as_slice()).copied())
+        .ok_or(AlpnError::ALERT_FATAL)
+}
+
 fn ssl_builder(cert_path: String, key_path: String) -> Result<SslAcceptorBuilder, Error> {
     let mut builder = SslAcceptor::mozilla_modern(SslMethod::tls())?;
     let cert_pem = slurp(cert_path)?;
@@ -0,0 +1,126 @@
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+// Limits on how quickly a client can send commands
+#[derive(Clone, Copy)]
+pub(crate) struct Pacing {
+    pub min_interval: Duration,
+    pub max_fast: usize,
+}
+
+// Counts the reads from a stream that return data. Pipelined commands arrive together
+// in a single read and this is used to tell them apart from commands that are sent
+// one at a time.
+#[derive(Debug)]
+pub(crate) struct Counted<S> {
+    inner: S,
+    reads: u64,
+}
+
+impl<S> Counted<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner, reads: 0 }
+    }
+
+    pub fn reads(&self) -> u64 {
+        self.reads
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: Read> Read for Counted<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n > 0 {
+            self.reads += 1;
+        }
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for Counted<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Tracks the time between the commands of a session
+pub(crate) struct PaceTracker {
+    pacing: Pacing,
+    last_read: u64,
+    last_arrival: Option<Instant>,
+    fast: usize,
+}
+
+impl PaceTracker {
+    pub fn new(pacing: Pacing) -> Self {
+        Self {
+            pacing,
+            last_read: 0,
+            last_arrival: None,
+            fast: 0,
+        }
+    }
+
+    // Record the arrival of a command, given the number of reads done so far.
+    // Returns true if the client has sent too many commands in quick succession.
+    pub fn is_too_fast(&mut self, reads: u64) -> bool {
+        // Commands that arrived in the same read as an earlier command are pipelined
+        if reads == self.last_read {
+            return false;
+        }
+        self.last_read = reads;
+        let now = Instant::now();
+        if let Some(last) = self.last_arrival.replace(now) {
+            if now.duration_since(last) < self.pacing.min_interval {
+                self.fast += 1;
+            } else {
+                self.fast = 0;
+            }
+        }
+        self.fast > self.pacing.max_fast
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PACING: Pacing = Pacing {
+        min_interval: Duration::from_secs(10),
+        max_fast: 3,
+    };
+
+    #[test]
+    fn rapid_fire() {
+        let mut tracker = PaceTracker::new(PACING);
+        let too_fast: Vec<bool> = (1..=6).map(|reads| tracker.is_too_fast(reads)).collect();
+        assert_eq!(too_fast, vec![false, false, false, false, true, true]);
+    }
+
+    #[test]
+    fn pipelined() {
+        let mut tracker = PaceTracker::new(PACING);
+        assert!(!tracker.is_too_fast(1));
+        for _ in 0..10 {
+            assert!(!tracker.is_too_fast(2));
+        }
+    }
+
+    #[test]
+    fn counted_reads() {
+        let mut stream = Counted::new(io::Cursor::new(b"abc".to_vec()));
+        let mut buf = [0; 2];
+        assert_eq!(stream.read(&mut buf).unwrap(), 2);
+        assert_eq!(stream.read(&mut buf).unwrap(), 1);
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+        assert_eq!(stream.reads(), 2);
+    }
+}
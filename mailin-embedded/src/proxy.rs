@@ -0,0 +1,264 @@
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+// The start of a PROXY protocol v1 header
+const V1_START: &[u8] = b"PROXY ";
+// The longest v1 header, including the CRLF
+const V1_MAX_LEN: usize = 107;
+// The signature that starts a PROXY protocol v2 header
+const SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+// Commands
+const CMD_LOCAL: u8 = 0x00;
+const CMD_PROXY: u8 = 0x01;
+// Address families
+const AF_INET: u8 = 0x1;
+const AF_INET6: u8 = 0x2;
+const AF_UNIX: u8 = 0x3;
+// TLV types
+const PP2_TYPE_ALPN: u8 = 0x01;
+const PP2_TYPE_AUTHORITY: u8 = 0x02;
+const PP2_TYPE_SSL: u8 = 0x20;
+// Set in the client field of PP2_TYPE_SSL when the client connected over TLS
+const PP2_CLIENT_SSL: u8 = 0x01;
+
+// Connection details sent by a proxy in a PROXY protocol header
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct ProxyHeader {
+    // The address of the client, if the proxy forwarded one
+    pub source: Option<IpAddr>,
+    // The server name that the client asked for using SNI
+    pub authority: Option<String>,
+    // The application protocol negotiated with the client
+    pub alpn: Option<Vec<u8>>,
+    // The client connected to the proxy with TLS, which the proxy terminated
+    pub tls: bool,
+}
+
+// Read a PROXY protocol v1 or v2 header from the start of a connection
+pub(crate) fn read_header<R: Read>(reader: &mut R) -> io::Result<ProxyHeader> {
+    let mut fixed = [0; 16];
+    reader.read_exact(&mut fixed[..V1_START.len()])?;
+    if &fixed[..V1_START.len()] == V1_START {
+        return read_v1(reader);
+    }
+    reader.read_exact(&mut fixed[V1_START.len()..])?;
+    if &fixed[..12] != SIGNATURE || fixed[12] >> 4 != 2 {
+        return Err(invalid("Not a PROXY protocol v2 header"));
+    }
+    let command = fixed[12] & 0x0f;
+    if command != CMD_LOCAL && command != CMD_PROXY {
+        return Err(invalid("Invalid PROXY protocol command"));
+    }
+    let len = u16::from_be_bytes([fixed[14], fixed[15]]) as usize;
+    let mut rest = vec![0; len];
+    reader.read_exact(&mut rest)?;
+    let (source, address_len) = match fixed[13] >> 4 {
+        AF_INET if rest.len() >= 12 => {
+            let ip: [u8; 4] = rest[..4].try_into().unwrap_or_default();
+            (Some(IpAddr::V4(Ipv4Addr::from(ip))), 12)
+        }
+        AF_INET6 if rest.len() >= 36 => {
+            let ip: [u8; 16] = rest[..16].try_into().unwrap_or_default();
+            (Some(IpAddr::V6(Ipv6Addr::from(ip))), 36)
+        }
+        AF_UNIX if rest.len() >= 216 => (None, 216),
+        AF_INET | AF_INET6 | AF_UNIX => return Err(invalid("Truncated PROXY protocol address")),
+        // The block of AF_UNSPEC, or an unknown family, is skipped as it cannot be
+        // told apart from TLVs
+        _ => (None, rest.len()),
+    };
+    let mut header = ProxyHeader {
+        // The address of a LOCAL connection, such as a health check, is ignored
+        source: source.filter(|_| command == CMD_PROXY),
+        ..Default::default()
+    };
+    let mut tlvs = &rest[address_len..];
+    while !tlvs.is_empty() {
+        if tlvs.len() < 3 {
+            return Err(invalid("Truncated PROXY protocol TLV"));
+        }
+        let len = u16::from_be_bytes([tlvs[1], tlvs[2]]) as usize;
+        let value = tlvs
+            .get(3..3 + len)
+            .ok_or_else(|| invalid("Truncated PROXY protocol TLV"))?;
+        match tlvs[0] {
+            PP2_TYPE_ALPN => header.alpn = Some(value.to_vec()),
+            PP2_TYPE_AUTHORITY => {
+                header.authority = Some(String::from_utf8_lossy(value).into_owned())
+            }
+            PP2_TYPE_SSL => header.tls = value.first().is_some_and(|c| c & PP2_CLIENT_SSL != 0),
+            _ => (),
+        }
+        tlvs = &tlvs[3 + len..];
+    }
+    Ok(header)
+}
+
+// Read the rest of a v1 header, e.g "TCP4 192.0.2.1 192.0.2.2 12345 25\r\n".
+// The line is read a byte at a time so that nothing after it is consumed.
+fn read_v1<R: Read>(reader: &mut R) -> io::Result<ProxyHeader> {
+    let mut line = Vec::with_capacity(V1_MAX_LEN);
+    let mut byte = [0; 1];
+    while !line.ends_with(b"\r\n") {
+        if V1_START.len() + line.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY protocol header too long"));
+        }
+        reader.read_exact(&mut byte)?;
+        line.push(byte[0]);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("Invalid PROXY protocol header"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    let source = match fields.as_slice() {
+        ["UNKNOWN", ..] => None,
+        ["TCP4", rest @ ..] => Some(IpAddr::V4(v1_source(rest)?)),
+        ["TCP6", rest @ ..] => Some(IpAddr::V6(v1_source(rest)?)),
+        _ => return Err(invalid("Invalid PROXY protocol header")),
+    };
+    Ok(ProxyHeader {
+        source,
+        ..Default::default()
+    })
+}
+
+// Check the addresses and ports of a v1 header and return the source address
+fn v1_source<A: FromStr>(fields: &[&str]) -> io::Result<A> {
+    match fields {
+        [source, destination, source_port, destination_port]
+            if destination.parse::<A>().is_ok()
+                && source_port.parse::<u16>().is_ok()
+                && destination_port.parse::<u16>().is_ok() =>
+        {
+            source
+                .parse()
+                .map_err(|_| invalid("Invalid address in PROXY protocol header"))
+        }
+        _ => Err(invalid("Invalid PROXY protocol header")),
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Build a v2 header for a TCP over IPv4 connection with the given TLVs
+    fn v2_header(tlvs: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut body = vec![192, 0, 2, 1, 192, 0, 2, 2, 0x30, 0x39, 0, 25];
+        for (kind, value) in tlvs {
+            body.push(*kind);
+            body.extend((value.len() as u16).to_be_bytes());
+            body.extend(*value);
+        }
+        let mut header = SIGNATURE.to_vec();
+        header.extend([0x21, 0x11]);
+        header.extend((body.len() as u16).to_be_bytes());
+        header.extend(body);
+        header
+    }
+
+    #[test]
+    fn authority_and_alpn() {
+        let buf = v2_header(&[
+            (PP2_TYPE_ALPN, b"smtp"),
+            (0xEE, b"ignored"),
+            (PP2_TYPE_AUTHORITY, b"mail.example.com"),
+        ]);
+        let mut reader = io::Cursor::new(buf);
+        let header = read_header(&mut reader).unwrap();
+        assert_eq!(
+            header,
+            ProxyHeader {
+                source: Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))),
+                authority: Some("mail.example.com".to_string()),
+                alpn: Some(b"smtp".to_vec()),
+                tls: false,
+            }
+        );
+    }
+
+    #[test]
+    fn ssl_tlv() {
+        // The client field, the verify field and no sub-TLVs
+        let buf = v2_header(&[(PP2_TYPE_SSL, &[PP2_CLIENT_SSL, 0, 0, 0, 0])]);
+        let header = read_header(&mut io::Cursor::new(buf)).unwrap();
+        assert!(header.tls);
+        let buf = v2_header(&[(PP2_TYPE_SSL, &[0, 0, 0, 0, 0])]);
+        let header = read_header(&mut io::Cursor::new(buf)).unwrap();
+        assert!(!header.tls);
+    }
+
+    #[test]
+    fn v2_commands() {
+        let mut buf = v2_header(&[]);
+        // LOCAL connections keep no address
+        buf[12] = 0x20;
+        let header = read_header(&mut io::Cursor::new(buf.clone())).unwrap();
+        assert_eq!(header.source, None);
+        buf[12] = 0x22;
+        assert!(read_header(&mut io::Cursor::new(buf)).is_err());
+    }
+
+    #[test]
+    fn unspecified_family() {
+        // An address block that would be a truncated TLV if it were parsed
+        let mut buf = SIGNATURE.to_vec();
+        buf.extend([0x20, 0x00, 0, 4, 0xff, 0xff, 0xff, 0xff]);
+        let header = read_header(&mut io::Cursor::new(buf)).unwrap();
+        assert_eq!(header, ProxyHeader::default());
+    }
+
+    #[test]
+    fn v1_header() {
+        let mut reader =
+            io::Cursor::new(b"PROXY TCP4 192.0.2.1 192.0.2.2 12345 25\r\nEHLO".to_vec());
+        let header = read_header(&mut reader).unwrap();
+        assert_eq!(header.source, Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))));
+        // The command after the header is not consumed
+        assert_eq!(reader.position(), 41);
+        let mut reader =
+            io::Cursor::new(b"PROXY TCP6 2001:db8::1 2001:db8::2 12345 25\r\n".to_vec());
+        let header = read_header(&mut reader).unwrap();
+        assert_eq!(header.source, Some("2001:db8::1".parse().unwrap()));
+        let mut reader = io::Cursor::new(b"PROXY UNKNOWN\r\n".to_vec());
+        assert_eq!(read_header(&mut reader).unwrap(), ProxyHeader::default());
+    }
+
+    #[test]
+    fn invalid_v1_header() {
+        let invalid: [&[u8]; 4] = [
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 12345\r\n",
+            b"PROXY TCP4 2001:db8::1 192.0.2.2 12345 25\r\n",
+            b"PROXY TCP6 2001:db8::1 2001:db8::2 12345 port\r\n",
+            b"PROXY UDP4 192.0.2.1 192.0.2.2 12345 25\r\n",
+        ];
+        for header in invalid {
+            let mut reader = io::Cursor::new(header.to_vec());
+            assert!(read_header(&mut reader).is_err());
+        }
+        let mut long = b"PROXY UNKNOWN ".to_vec();
+        long.resize(200, b'a');
+        let mut reader = io::Cursor::new(long);
+        assert!(read_header(&mut reader).is_err());
+    }
+
+    #[test]
+    fn not_proxy_protocol() {
+        let mut reader = io::Cursor::new(b"EHLO a.domain\r\n\r\n\r\n".to_vec());
+        assert!(read_header(&mut reader).is_err());
+    }
+
+    #[test]
+    fn truncated_tlv() {
+        let mut buf = v2_header(&[(PP2_TYPE_AUTHORITY, b"mail.example.com")]);
+        // Make the TLV longer than the header
+        let len = buf.len();
+        buf[len - 17] = 0xff;
+        let mut reader = io::Cursor::new(buf);
+        assert!(read_header(&mut reader).is_err());
+    }
+}
@@ -14,7 +14,15 @@ pub struct SslImpl {
     tls_config: Arc<ServerConfig>,
 }
 
-impl Stream for StreamOwned<ServerConnection, TcpStream> {}
+impl Stream for StreamOwned<ServerConnection, TcpStream> {
+    fn server_name(&self) -> Option<String> {
+        self.conn.server_name().map(String::from)
+    }
+
+    fn alpn_protocol(&self) -> Option<Vec<u8>> {
+        self.conn.alpn_protocol().map(<[u8]>::to_vec)
+    }
+}
 
 impl From<TLSError> for Error {
     fn from(error: TLSError) -> Self {
@@ -35,11 +43,7 @@ impl SslImpl {
                 let mut chain = load_certs(&chain_path)?;
                 certs.append(&mut chain);
                 let key = load_key(&key_path)?;
-                let config = ServerConfig::builder()
-                    .with_safe_defaults()
-                    .with_no_client_auth()
-                    .with_single_cert(certs, key)?;
-                Some(config)
+                Some(server_config(certs, key)?)
             }
             SslConfig::SelfSigned {
                 cert_path,
@@ -47,11 +51,7 @@ impl SslImpl {
             } => {
                 let certs = load_certs(&cert_path)?;
                 let key = load_key(&key_path)?;
-                let config = ServerConfig::builder()
-                    .with_safe_defaults()
-                    .with_no_client_auth()
-                    .with_single_cert(certs, key)?;
-                Some(config)
+                Some(server_config(certs, key)?)
             }
             _ => None,
         };
@@ -61,13 +61,32 @@ impl SslImpl {
         Ok(ret)
     }
 
-    pub fn accept(&self, stream: TcpStream) -> Result<impl Stream, Error> {
-        let session = ServerConnection::new(self.tls_config.clone())?;
+    // Accept the given application protocols with ALPN, a client that offers only
+    // other protocols fails the handshake
+    pub fn with_alpn_protocols(mut self, protocols: &[Vec<u8>]) -> Self {
+        Arc::make_mut(&mut self.tls_config).alpn_protocols = protocols.to_vec();
+        self
+    }
+
+    pub fn accept(&self, mut stream: TcpStream) -> Result<impl Stream, Error> {
+        let mut session = ServerConnection::new(self.tls_config.clone())?;
+        // Complete the handshake now so that failures are reported by accept()
+        session
+            .complete_io(&mut stream)
+            .map_err(|e| Error::with_source(format!("TLS handshake failed: {e}"), e))?;
         let tls_stream = StreamOwned::new(session, stream);
         Ok(tls_stream)
     }
 }
 
+fn server_config(certs: Vec<Certificate>, key: PrivateKey) -> Result<ServerConfig, Error> {
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(config)
+}
+
 fn load_certs(filename: &str) -> Result<Vec<Certificate>, Error> {
     let certfile = fs::File::open(filename)?;
     let mut reader = BufReader::new(certfile);
@@ -97,3 +116,65 @@ fn load_key(filename: &str) -> Result<PrivateKey, Error> {
         .map(PrivateKey)
         .ok_or_else(|| Error::new("No RSA or PKCS8 keys found"))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerName};
+
+    const CERT_PATH: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/../mailin-server/test-certs/cert.pem"
+    );
+    const KEY_PATH: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/../mailin-server/test-certs/key.pem"
+    );
+
+    fn self_signed() -> SslImpl {
+        SslImpl::setup(SslConfig::SelfSigned {
+            cert_path: CERT_PATH.to_string(),
+            key_path: KEY_PATH.to_string(),
+        })
+        .unwrap()
+        .unwrap()
+    }
+
+    // Offer the given protocols to the server and return the protocol it chose
+    fn negotiate(ssl: SslImpl, offered: &[&[u8]]) -> Result<Option<Vec<u8>>, TLSError> {
+        let mut client_config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+        client_config.alpn_protocols = offered.iter().map(|p| p.to_vec()).collect();
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let mut client = ClientConnection::new(Arc::new(client_config), server_name).unwrap();
+        let mut server = ServerConnection::new(ssl.tls_config).unwrap();
+        // Send the ClientHello to the server, ALPN is decided while processing it
+        let mut client_hello = Vec::new();
+        client.write_tls(&mut client_hello).unwrap();
+        server.read_tls(&mut client_hello.as_slice()).unwrap();
+        server.process_new_packets()?;
+        Ok(server.alpn_protocol().map(<[u8]>::to_vec))
+    }
+
+    #[test]
+    fn no_http_alpn() {
+        let res = negotiate(self_signed(), &[b"h2", b"http/1.1"]);
+        assert_eq!(res.unwrap(), None);
+    }
+
+    #[test]
+    fn alpn_protocols() {
+        let smtp = vec![b"smtp".to_vec()];
+        let ssl = self_signed().with_alpn_protocols(&smtp);
+        let res = negotiate(ssl, &[b"http/1.1", b"smtp"]);
+        assert_eq!(res.unwrap(), Some(b"smtp".to_vec()));
+        let ssl = self_signed().with_alpn_protocols(&smtp);
+        let res = negotiate(ssl, &[b"http/1.1"]);
+        assert_eq!(res, Err(TLSError::NoApplicationProtocol));
+        // A client that does not offer ALPN is not affected
+        let ssl = self_signed().with_alpn_protocols(&smtp);
+        assert_eq!(negotiate(ssl, &[]).unwrap(), None);
+    }
+}
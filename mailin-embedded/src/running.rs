@@ -6,35 +6,80 @@ cfg_if::cfg_if! {
         use crate::rtls::SslImpl;
     }
 }
+use crate::pacing::{Counted, PaceTracker, Pacing};
+use crate::proxy;
+use crate::sessions::{ConnectionCounter, SessionCounter};
 use crate::ssl::Stream;
-use crate::Server;
+use crate::stats::{DataGauge, DataReport};
+use crate::timeout::{is_timeout, SessionTimer, Timeouts};
+use crate::{Server, TlsErrorHook};
 use bufstream_fresh::BufStream;
 use log::{debug, error, info};
-use mailin::{Action, Handler, Response, Session, SessionBuilder};
+use mailin::response::{AUTH_PENDING, TEMP_AUTH_FAILURE};
+use mailin::{Action, Handler, Phase, Response, Session, SessionBuilder};
 use scoped_threadpool::Pool;
-use std::io::{BufRead, Write};
+use std::io::{BufRead, Read, Write};
 use std::net::{IpAddr, TcpListener, TcpStream};
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
-const FIVE_MINUTES: Duration = Duration::new(5 * 60, 0);
+// Settings shared by every session
+#[derive(Clone)]
+struct SessionConfig {
+    pacing: Option<Pacing>,
+    timeouts: Timeouts,
+    proxy_protocol: bool,
+    // Counts the sessions of proxied clients, which are only known once the
+    // PROXY protocol header has been read
+    proxied_sessions: Option<SessionCounter>,
+    data: DataGauge,
+}
+
+// Checks and reports that run alongside a session
+struct SessionMonitor {
+    pacing: Option<Pacing>,
+    timer: SessionTimer,
+    data: DataReport,
+}
 
 enum SessionResult {
     Finished,
     UpgradeTls,
 }
 
-struct ServerState<H>
+pub(crate) struct ServerState<H>
 where
     H: Handler + Clone + Send,
 {
-    listener: TcpListener,
+    name: String,
+    pub listener: TcpListener,
     handler: H,
     session_builder: SessionBuilder,
     ssl: Option<SslImpl>,
     num_threads: u32,
+    tls_error: Option<TlsErrorHook>,
+    pacing: Option<Pacing>,
+    timeouts: Timeouts,
+    proxy_protocol: bool,
+    sessions: Option<SessionCounter>,
+    connections: Option<ConnectionCounter>,
+    // Messages being received across all sessions
+    pub data: DataGauge,
+    // True while connections are being accepted
+    pub ready: Arc<AtomicBool>,
+    // Set to stop accepting connections
+    pub stopping: Arc<AtomicBool>,
 }
 
 pub(crate) fn serve<H>(config: Server<H>) -> Result<(), Error>
+where
+    H: Handler + Clone + Send,
+{
+    run(&bind(config)?)
+}
+
+// Bind the listen address and prepare to accept connections
+pub(crate) fn bind<H>(config: Server<H>) -> Result<ServerState<H>, Error>
 where
     H: Handler + Clone + Send,
 {
@@ -45,6 +90,9 @@ where
     for auth in &config.auth {
         session_builder.enable_auth(auth.clone());
     }
+    if let Some(mode) = config.reject_all {
+        session_builder.reject_all_mail(mode);
+    }
     let listen = if let Some(listener) = config.tcp_listener {
         listener
     } else {
@@ -52,54 +100,165 @@ where
         TcpListener::bind(&addr[..])
             .map_err(|err| Error::with_source("Cannot open listen address", err))?
     };
-    let server_state = ServerState {
+    // The accept loop and the sessions use blocking IO, also when the listener
+    // was given to the server
+    listen
+        .set_nonblocking(false)
+        .map_err(|err| Error::with_source("Cannot use blocking IO on listen address", err))?;
+    // Connections over the size of the threadpool would wait for a thread, so they
+    // are rejected instead
+    let max_connections = config
+        .max_connections
+        .map(|max| max.min(config.num_threads as usize));
+    Ok(ServerState {
+        name: config.name,
         listener: listen,
         handler: config.handler,
         session_builder,
-        ssl: config.ssl,
+        ssl: config
+            .ssl
+            .map(|ssl| ssl.with_alpn_protocols(&config.alpn_protocols)),
         num_threads: config.num_threads,
-    };
-    run(&config.name, &server_state)
+        tls_error: config.tls_error,
+        pacing: config.pacing,
+        timeouts: config.timeouts,
+        proxy_protocol: config.proxy_protocol,
+        sessions: config.max_sessions_per_ip.map(SessionCounter::new),
+        connections: max_connections.map(ConnectionCounter::new),
+        data: DataGauge::default(),
+        ready: Arc::new(AtomicBool::new(false)),
+        stopping: Arc::new(AtomicBool::new(false)),
+    })
 }
 
-fn run<H>(name: &str, server_state: &ServerState<H>) -> Result<(), Error>
+// Accept connections until stopped, then wait for the running sessions to finish
+pub(crate) fn run<H>(server_state: &ServerState<H>) -> Result<(), Error>
 where
     H: Handler + Clone + Send,
 {
     let mut pool = Pool::new(server_state.num_threads);
     let localaddr = server_state.listener.local_addr()?;
-    info!("{} SMTP started on {}", name, localaddr);
+    info!("{} SMTP started on {}", server_state.name, localaddr);
+    server_state.ready.store(true, Ordering::SeqCst);
     pool.scoped(|scoped| {
         for conn in server_state.listener.incoming() {
+            if server_state.stopping.load(Ordering::SeqCst) {
+                break;
+            }
             match conn {
-                Ok(stream) => {
+                Ok(mut stream) => {
+                    let connection = match &server_state.connections {
+                        Some(connections) => match connections.open() {
+                            Some(connection) => Some(connection),
+                            None => {
+                                debug!("Too many connections");
+                                write_response(&mut stream, &too_many_connections()).ok();
+                                continue;
+                            }
+                        },
+                        None => None,
+                    };
+                    let slot = match (&server_state.sessions, stream.peer_addr()) {
+                        (Some(sessions), Ok(remote)) if !server_state.proxy_protocol => {
+                            match sessions.open(remote.ip()) {
+                                Some(slot) => Some(slot),
+                                None => {
+                                    debug!("({}) Too many sessions", remote.ip());
+                                    write_response(&mut stream, &too_many_sessions()).ok();
+                                    continue;
+                                }
+                            }
+                        }
+                        _ => None,
+                    };
                     let builder = server_state.session_builder.clone();
                     let acceptor = server_state.ssl.clone();
                     let handler_clone = server_state.handler.clone();
+                    let tls_error = server_state.tls_error.clone();
+                    let config = SessionConfig {
+                        pacing: server_state.pacing,
+                        timeouts: server_state.timeouts,
+                        proxy_protocol: server_state.proxy_protocol,
+                        proxied_sessions: server_state
+                            .sessions
+                            .clone()
+                            .filter(|_| server_state.proxy_protocol),
+                        data: server_state.data.clone(),
+                    };
                     scoped.execute(move || {
-                        handle_connection(stream, &builder, acceptor, handler_clone)
+                        handle_connection(
+                            stream,
+                            &builder,
+                            acceptor,
+                            tls_error,
+                            config,
+                            handler_clone,
+                        );
+                        // Let another session from the same address start
+                        drop(slot);
+                        drop(connection);
                     });
                 }
                 Err(e) => error!("Connection failed: {}", e),
             }
         }
     });
+    server_state.ready.store(false, Ordering::SeqCst);
+    info!("{} SMTP stopped on {}", server_state.name, localaddr);
     Ok(())
 }
 
-fn handle_session<H, S>(session: &mut Session<H>, stream: &mut S) -> Result<SessionResult, Error>
+fn handle_session<H, S>(
+    session: &mut Session<H>,
+    stream: &mut BufStream<Counted<S>>,
+    monitor: &mut SessionMonitor,
+) -> Result<SessionResult, Error>
 where
-    S: BufRead + Write,
+    S: Read + Write,
     H: Handler,
 {
     let mut line = Vec::with_capacity(80);
+    let mut tracker = monitor.pacing.map(PaceTracker::new);
+    let mut last_read = None;
     loop {
         line.clear();
-        let num_bytes = stream.read_until(b'\n', &mut line)?;
+        if !monitor.timer.before_read()? {
+            write_response(stream, &timed_out())?;
+            return Error::bail("Session timed out");
+        }
+        let num_bytes = match stream.read_until(b'\n', &mut line) {
+            Ok(num_bytes) => num_bytes,
+            Err(err) if is_timeout(&err) => {
+                write_response(stream, &timed_out())?;
+                return Error::bail("Client timed out");
+            }
+            Err(err) => return Err(err.into()),
+        };
         if num_bytes == 0 {
             break;
         }
-        let res = session.process(&line);
+        // A command that arrived in the same read as the previous line was pipelined
+        let reads = stream.get_ref().reads();
+        if session.phase() != Phase::Data && last_read == Some(reads) {
+            session.client_pipelined();
+        }
+        last_read = Some(reads);
+        if let Some(ref mut tracker) = tracker {
+            if session.phase() != Phase::Data && tracker.is_too_fast(stream.get_ref().reads()) {
+                write_response(stream, &too_fast())?;
+                return Error::bail("Commands sent too quickly");
+            }
+        }
+        let mut res = session.process(&line);
+        // There is nothing to complete a deferred authentication with, handlers
+        // check credentials before returning as each session has its own thread
+        if res == AUTH_PENDING {
+            error!("Authentication deferred with AUTH_PENDING, which is not supported");
+            res = session.complete_auth(TEMP_AUTH_FAILURE);
+        }
+        monitor
+            .data
+            .update(session.phase() == Phase::Data, session.buffered_bytes());
         match res.action {
             Action::Reply => {
                 write_response(stream, &res)?;
@@ -122,10 +281,32 @@ where
     Error::bail("Unexpected Eof")
 }
 
-fn write_response(mut writer: &mut dyn Write, res: &Response) -> Result<(), Error> {
-    res.write_to(&mut writer)?;
+fn too_fast() -> Response {
+    Response::custom(
+        421,
+        "Commands sent too quickly, closing connection".to_string(),
+    )
+}
+
+fn timed_out() -> Response {
+    Response::custom(421, "Timeout, closing connection".to_string())
+}
+
+fn too_many_connections() -> Response {
+    Response::custom(421, "Too many connections".to_string()).with_enhanced_code(4, 7, 0)
+}
+
+fn too_many_sessions() -> Response {
+    Response::custom(421, "Too many concurrent connections".to_string()).with_enhanced_code(4, 7, 0)
+}
+
+// Write the whole response, including every line of a multiline response,
+// with a single write and flush
+fn write_response(writer: &mut dyn Write, res: &Response) -> Result<(), Error> {
+    let buf = res.buffer()?;
     writer
-        .flush()
+        .write_all(&buf)
+        .and_then(|_| writer.flush())
         .map_err(|e| Error::with_source("Cannot write response", e))
 }
 
@@ -138,24 +319,94 @@ fn upgrade_tls(stream: TcpStream, ssl: Option<SslImpl>) -> Result<impl Stream, E
     }
 }
 
-fn start_session<H: Handler>(
+// Build a session, reading the client details from a PROXY protocol header
+// if the server is behind a proxy. Returns the session and the client address.
+fn build_session<H: Handler, R: Read>(
     session_builder: &SessionBuilder,
     remote: IpAddr,
-    mut stream: BufStream<TcpStream>,
-    ssl: Option<SslImpl>,
+    stream: &mut R,
+    proxy_protocol: bool,
     handler: H,
+) -> Result<(Session<H>, IpAddr), Error> {
+    if !proxy_protocol {
+        return Ok((session_builder.build(remote, handler), remote));
+    }
+    let header = proxy::read_header(stream)
+        .map_err(|e| Error::with_source("Cannot read PROXY protocol header", e))?;
+    let client = header.source.unwrap_or(remote);
+    debug!("({}) Proxied connection from {}", remote, client);
+    let mut session = session_builder.build(client, handler);
+    if header.tls || header.authority.is_some() || header.alpn.is_some() {
+        session.forwarded_tls(header.authority, header.alpn);
+    }
+    Ok((session, client))
+}
+
+fn start_session<H: Handler>(
+    remote: IpAddr,
+    stream: BufStream<Counted<TcpStream>>,
+    ssl: Option<SslImpl>,
+    tls_error: Option<TlsErrorHook>,
+    monitor: &mut SessionMonitor,
+    session: &mut Session<H>,
+) -> Result<(), Error> {
+    let res = serve_session(remote, stream, ssl, tls_error, monitor, session);
+    session.close();
+    res
+}
+
+// Send the greeting and, unless it refuses the connection, handle the session
+fn serve_session<H: Handler>(
+    remote: IpAddr,
+    mut stream: BufStream<Counted<TcpStream>>,
+    ssl: Option<SslImpl>,
+    tls_error: Option<TlsErrorHook>,
+    monitor: &mut SessionMonitor,
+    session: &mut Session<H>,
+) -> Result<(), Error> {
+    let greeting = session.greeting();
+    write_response(&mut stream, &greeting)?;
+    // A 521 greeting refuses the connection before any commands are read
+    if greeting.action == Action::Close {
+        return Ok(());
+    }
+    session.open();
+    let res = run_session(remote, stream, ssl, tls_error, monitor, session);
+    session.end();
+    res
+}
+
+// Handle the commands of a session, upgrading the connection if the client
+// sends STARTTLS
+fn run_session<H: Handler>(
+    remote: IpAddr,
+    mut stream: BufStream<Counted<TcpStream>>,
+    ssl: Option<SslImpl>,
+    tls_error: Option<TlsErrorHook>,
+    monitor: &mut SessionMonitor,
+    session: &mut Session<H>,
 ) -> Result<(), Error> {
-    let mut session = session_builder.build(remote, handler);
-    write_response(&mut stream, &session.greeting())?;
-    let res = handle_session(&mut session, &mut stream)?;
+    let res = handle_session(session, &mut stream, monitor)?;
     if let SessionResult::UpgradeTls = res {
         let inner_stream = stream
             .into_inner()
-            .map_err(|e| Error::with_source("Cannot flush original TcpStream", e))?;
-        let tls = upgrade_tls(inner_stream, ssl)?;
-        session.tls_active();
-        let mut buf_tls = BufStream::new(tls);
-        handle_session(&mut session, &mut buf_tls)?;
+            .map_err(|e| Error::with_source("Cannot flush original TcpStream", e))?
+            .into_inner();
+        let tls = upgrade_tls(inner_stream, ssl).inspect_err(|err| {
+            if let Some(callback) = tls_error {
+                callback(remote, err);
+            }
+        })?;
+        if let Some(protocol) = tls.alpn_protocol() {
+            debug!(
+                "({}) ALPN protocol {}",
+                remote,
+                String::from_utf8_lossy(&protocol)
+            );
+        }
+        session.tls_active_with_sni(tls.server_name());
+        let mut buf_tls = BufStream::new(Counted::new(tls));
+        handle_session(session, &mut buf_tls, monitor)?;
     }
     Ok(())
 }
@@ -164,6 +415,8 @@ fn handle_connection<H: Handler>(
     stream: TcpStream,
     session_builder: &SessionBuilder,
     ssl: Option<SslImpl>,
+    tls_error: Option<TlsErrorHook>,
+    config: SessionConfig,
     handler: H,
 ) {
     let remote = stream
@@ -171,10 +424,91 @@ fn handle_connection<H: Handler>(
         .map(|saddr| saddr.ip())
         .unwrap_or_else(|_| "0.0.0.0".parse().unwrap());
     debug!("New connection from {}", remote);
-    stream.set_read_timeout(Some(FIVE_MINUTES)).ok();
-    stream.set_write_timeout(Some(FIVE_MINUTES)).ok();
-    let bufstream = BufStream::new(stream);
-    if let Err(err) = start_session(session_builder, remote, bufstream, ssl, handler) {
+    let idle = config.timeouts.idle;
+    stream.set_read_timeout(Some(idle)).ok();
+    stream.set_write_timeout(Some(idle)).ok();
+    let timer = match stream.try_clone() {
+        Ok(socket) => SessionTimer::new(socket, config.timeouts),
+        Err(err) => {
+            error!("({}) Cannot start session: {}", remote, err);
+            return;
+        }
+    };
+    let mut monitor = SessionMonitor {
+        pacing: config.pacing,
+        timer,
+        data: config.data.report(),
+    };
+    let mut bufstream = BufStream::new(Counted::new(stream));
+    let res = build_session(
+        session_builder,
+        remote,
+        &mut bufstream,
+        config.proxy_protocol,
+        handler,
+    )
+    .and_then(|(mut session, client)| {
+        let _slot = match &config.proxied_sessions {
+            Some(sessions) => match sessions.open(client) {
+                Some(slot) => Some(slot),
+                None => {
+                    debug!("({}) Too many sessions", client);
+                    return write_response(&mut bufstream, &too_many_sessions());
+                }
+            },
+            None => None,
+        };
+        start_session(
+            remote,
+            bufstream,
+            ssl,
+            tls_error,
+            &mut monitor,
+            &mut session,
+        )
+    });
+    if let Err(err) = res {
         error!("({}) Cannot start session: {}", remote, err);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::net::Ipv4Addr;
+
+    // Counts the writes that reach the underlying stream
+    #[derive(Default)]
+    struct CountingWriter {
+        writes: usize,
+        flushes: usize,
+    }
+
+    impl Write for CountingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes += 1;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushes += 1;
+            Ok(())
+        }
+    }
+
+    #[derive(Clone)]
+    struct EmptyHandler {}
+    impl Handler for EmptyHandler {}
+
+    #[test]
+    fn multiline_greeting_single_write() {
+        let mut builder = SessionBuilder::new("some.domain");
+        builder.with_greeting_lines(vec!["Welcome".to_string(), "No spam".to_string()]);
+        let session = builder.build(IpAddr::V4(Ipv4Addr::LOCALHOST), EmptyHandler {});
+        let mut writer = CountingWriter::default();
+        write_response(&mut writer, &session.greeting()).unwrap();
+        assert_eq!(writer.writes, 1);
+        assert_eq!(writer.flushes, 1);
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+// Counts the open connections to the server
+#[derive(Clone)]
+pub(crate) struct ConnectionCounter {
+    max: usize,
+    open: Arc<AtomicUsize>,
+}
+
+// An open connection, the count goes down when this is dropped
+pub(crate) struct ConnectionSlot {
+    open: Arc<AtomicUsize>,
+}
+
+impl ConnectionCounter {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            open: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    // Count a new connection.
+    // Returns None if the maximum number of connections are already open.
+    pub fn open(&self) -> Option<ConnectionSlot> {
+        self.open
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                (count < self.max).then_some(count + 1)
+            })
+            .ok()
+            .map(|_| ConnectionSlot {
+                open: self.open.clone(),
+            })
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Counts the open sessions of each client address
+#[derive(Clone)]
+pub(crate) struct SessionCounter {
+    max: usize,
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+// An open session, the count for its address goes down when this is dropped
+pub(crate) struct SessionSlot {
+    counter: SessionCounter,
+    ip: IpAddr,
+}
+
+impl SessionCounter {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            open: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Count a new session from the given address.
+    // Returns None if the address already has the maximum number of open sessions.
+    pub fn open(&self, ip: IpAddr) -> Option<SessionSlot> {
+        let mut open = self.lock();
+        let count = open.entry(ip).or_insert(0);
+        if *count >= self.max {
+            return None;
+        }
+        *count += 1;
+        Some(SessionSlot {
+            counter: self.clone(),
+            ip,
+        })
+    }
+
+    fn close(&self, ip: IpAddr) {
+        let mut open = self.lock();
+        if let Some(count) = open.get_mut(&ip) {
+            *count = count.saturating_sub(1);
+            // Forget addresses without sessions so that the map does not grow
+            if *count == 0 {
+                open.remove(&ip);
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<IpAddr, usize>> {
+        // The counts are still valid if a thread panicked while holding the lock
+        self.open.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Drop for SessionSlot {
+    fn drop(&mut self) {
+        self.counter.close(self.ip);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn limit_per_ip() {
+        let counter = SessionCounter::new(2);
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let other = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        let first = counter.open(ip);
+        let second = counter.open(ip);
+        assert!(first.is_some() && second.is_some());
+        assert!(counter.open(ip).is_none());
+        assert!(counter.open(other).is_some());
+        drop(first);
+        assert!(counter.open(ip).is_some());
+    }
+
+    #[test]
+    fn limit_connections() {
+        let counter = ConnectionCounter::new(2);
+        let first = counter.open();
+        let second = counter.open();
+        assert!(first.is_some() && second.is_some());
+        assert!(counter.open().is_none());
+        drop(first);
+        assert!(counter.open().is_some());
+        assert_eq!(counter.open.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn cleanup() {
+        let counter = SessionCounter::new(1);
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        drop(counter.open(ip));
+        assert!(counter.lock().is_empty());
+        // A rejected session does not leave an entry behind
+        let slot = counter.open(ip);
+        assert!(counter.open(ip).is_none());
+        drop(slot);
+        assert!(counter.lock().is_empty());
+    }
+}
@@ -22,4 +22,9 @@ pub enum SslConfig {
     },
 }
 
-pub trait Stream: Read + Write {}
+pub trait Stream: Read + Write {
+    // The server name that the client asked for using SNI
+    fn server_name(&self) -> Option<String>;
+    // The application protocol negotiated with ALPN
+    fn alpn_protocol(&self) -> Option<Vec<u8>>;
+}
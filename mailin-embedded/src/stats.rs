@@ -0,0 +1,96 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// The messages that a server is receiving, to find memory pressure from large
+/// concurrent uploads
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DataStats {
+    /// The number of sessions that are receiving message data
+    pub sessions_in_data: usize,
+    /// The bytes held in memory by the handlers of those sessions, as reported by
+    /// `Handler::buffered_bytes`
+    pub buffered_bytes: usize,
+}
+
+// Totals that every session adds its own figures to
+#[derive(Clone, Default)]
+pub(crate) struct DataGauge {
+    in_data: Arc<AtomicUsize>,
+    buffered: Arc<AtomicUsize>,
+}
+
+impl DataGauge {
+    pub fn stats(&self) -> DataStats {
+        DataStats {
+            sessions_in_data: self.in_data.load(Ordering::SeqCst),
+            buffered_bytes: self.buffered.load(Ordering::SeqCst),
+        }
+    }
+
+    pub fn report(&self) -> DataReport {
+        DataReport {
+            gauge: self.clone(),
+            in_data: false,
+            buffered: 0,
+        }
+    }
+}
+
+// The figures of a single session, they are removed from the totals when this is
+// dropped
+pub(crate) struct DataReport {
+    gauge: DataGauge,
+    in_data: bool,
+    buffered: usize,
+}
+
+impl DataReport {
+    pub fn update(&mut self, in_data: bool, buffered: usize) {
+        if in_data != self.in_data {
+            if in_data {
+                self.gauge.in_data.fetch_add(1, Ordering::SeqCst);
+            } else {
+                self.gauge.in_data.fetch_sub(1, Ordering::SeqCst);
+            }
+            self.in_data = in_data;
+        }
+        if buffered != self.buffered {
+            self.gauge.buffered.fetch_add(buffered, Ordering::SeqCst);
+            self.gauge
+                .buffered
+                .fetch_sub(self.buffered, Ordering::SeqCst);
+            self.buffered = buffered;
+        }
+    }
+}
+
+impl Drop for DataReport {
+    fn drop(&mut self) {
+        self.update(false, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn totals() {
+        let gauge = DataGauge::default();
+        let mut first = gauge.report();
+        let mut second = gauge.report();
+        first.update(true, 10);
+        second.update(true, 5);
+        second.update(true, 7);
+        assert_eq!(
+            gauge.stats(),
+            DataStats {
+                sessions_in_data: 2,
+                buffered_bytes: 17,
+            }
+        );
+        first.update(false, 0);
+        drop(second);
+        assert_eq!(gauge.stats(), DataStats::default());
+    }
+}
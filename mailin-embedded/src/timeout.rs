@@ -0,0 +1,86 @@
+use std::io::{self, ErrorKind};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+pub(crate) const FIVE_MINUTES: Duration = Duration::new(5 * 60, 0);
+
+// Limits on how long a client can take
+#[derive(Clone, Copy)]
+pub(crate) struct Timeouts {
+    // The longest wait for the client to send more data
+    pub idle: Duration,
+    // The longest time that a session can last
+    pub total: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            idle: FIVE_MINUTES,
+            total: None,
+        }
+    }
+}
+
+// Sets the socket timeouts before each read so that a session ends when the client
+// is idle for too long or when the session runs past its deadline. The write
+// timeouts are set too, so a client that stops reading its responses cannot hold
+// the session open either. The socket is a
+// clone of the connection, so the timeout also applies once TLS is active.
+pub(crate) struct SessionTimer {
+    socket: TcpStream,
+    idle: Duration,
+    deadline: Option<Instant>,
+}
+
+impl SessionTimer {
+    pub fn new(socket: TcpStream, timeouts: Timeouts) -> Self {
+        Self {
+            socket,
+            idle: timeouts.idle,
+            deadline: timeouts.total.map(|total| Instant::now() + total),
+        }
+    }
+
+    // Set the timeouts of the next read and of the writes of its response.
+    // Returns false if the session has already run past its deadline.
+    pub fn before_read(&self) -> io::Result<bool> {
+        let timeout = match self.deadline {
+            Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                Some(left) if !left.is_zero() => left.min(self.idle),
+                _ => return Ok(false),
+            },
+            None => self.idle,
+        };
+        self.socket.set_read_timeout(Some(timeout))?;
+        self.socket.set_write_timeout(Some(timeout))?;
+        Ok(true)
+    }
+}
+
+// Did a read fail because the socket timeout expired?
+pub(crate) fn is_timeout(err: &io::Error) -> bool {
+    matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn deadline_limits_timeouts() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let timeouts = Timeouts {
+            idle: FIVE_MINUTES,
+            total: Some(Duration::from_secs(10)),
+        };
+        let timer = SessionTimer::new(socket.try_clone().unwrap(), timeouts);
+        assert!(timer.before_read().unwrap());
+        for timeout in [socket.read_timeout(), socket.write_timeout()] {
+            let timeout = timeout.unwrap().unwrap();
+            assert!(timeout <= Duration::from_secs(10), "{:?}", timeout);
+        }
+    }
+}
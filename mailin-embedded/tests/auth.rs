@@ -0,0 +1,38 @@
+mod common;
+
+use common::{connect, read_response, start_server};
+use mailin_embedded::response::AUTH_PENDING;
+use mailin_embedded::{AuthMechanism, Handler, Response};
+use std::io::Write;
+
+// Defers every authentication, as a handler written for a non-blocking server would
+#[derive(Clone)]
+struct PendingHandler {}
+
+impl Handler for PendingHandler {
+    fn auth_cram_md5(&mut self, _username: &str, _challenge: &str, _digest: &str) -> Response {
+        AUTH_PENDING
+    }
+}
+
+#[test]
+fn pending_auth_rejected() {
+    let addr = start_server(PendingHandler {}, |server| {
+        server.with_auth(AuthMechanism::CramMd5);
+    });
+    let (mut stream, mut reader) = connect(addr);
+    assert!(read_response(&mut reader).starts_with("220"));
+    stream.write_all(b"ehlo a.domain\r\n").unwrap();
+    assert!(read_response(&mut reader).starts_with("250"));
+    stream.write_all(b"auth cram-md5\r\n").unwrap();
+    assert!(read_response(&mut reader).starts_with("334"));
+    // base64 of "kraken 0123456789abcdef0123456789abcdef"
+    stream
+        .write_all(b"a3Jha2VuIDAxMjM0NTY3ODlhYmNkZWYwMTIzNDU2Nzg5YWJjZGVm\r\n")
+        .unwrap();
+    let res = read_response(&mut reader);
+    assert!(res.starts_with("454"), "{}", res);
+    // The session carries on without authentication
+    stream.write_all(b"noop\r\n").unwrap();
+    assert!(read_response(&mut reader).starts_with("250"));
+}
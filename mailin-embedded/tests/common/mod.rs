@@ -0,0 +1,55 @@
+// Helpers shared by the integration tests, each test uses only some of them
+#![allow(dead_code)]
+
+use mailin_embedded::{Handler, Server};
+use std::io::{BufRead, BufReader};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+
+pub const CERT_PATH: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/../mailin-server/test-certs/cert.pem"
+);
+pub const KEY_PATH: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/../mailin-server/test-certs/key.pem"
+);
+
+#[derive(Clone)]
+pub struct EmptyHandler {}
+impl Handler for EmptyHandler {}
+
+// Start a server on a free local port, after the given function has configured
+// it, and return the address that it listens on
+pub fn start_server<H, F>(handler: H, configure: F) -> SocketAddr
+where
+    H: Handler + Clone + Send + 'static,
+    F: FnOnce(&mut Server<H>),
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut server = Server::new(handler);
+    server.with_tcp_listener(listener);
+    configure(&mut server);
+    thread::spawn(move || server.serve().ok());
+    addr
+}
+
+// Connect to a server, returns the stream to write commands to and a reader for
+// the responses
+pub fn connect(addr: SocketAddr) -> (TcpStream, BufReader<TcpStream>) {
+    let stream = TcpStream::connect(addr).unwrap();
+    let reader = BufReader::new(stream.try_clone().unwrap());
+    (stream, reader)
+}
+
+// Read a, possibly multiline, response and return the last line
+pub fn read_response(reader: &mut BufReader<TcpStream>) -> String {
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        if line.len() < 4 || line.as_bytes()[3] != b'-' {
+            return line;
+        }
+    }
+}
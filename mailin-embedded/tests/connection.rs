@@ -0,0 +1,124 @@
+mod common;
+
+use common::{connect, start_server};
+use mailin_embedded::response::OK;
+use mailin_embedded::{Handler, RejectAll, Response, Server, SessionSummary};
+use std::io::{BufRead, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Event {
+    Open(IpAddr),
+    Helo,
+    SessionEnd,
+    Close,
+}
+
+// Records the callbacks in the order that they are made
+#[derive(Clone)]
+struct ConnectionHandler {
+    tx: Arc<Mutex<Sender<Event>>>,
+}
+
+impl ConnectionHandler {
+    fn record(&self, event: Event) {
+        self.tx.lock().unwrap().send(event).unwrap();
+    }
+}
+
+impl Handler for ConnectionHandler {
+    fn conn_open(&mut self, ip: IpAddr) {
+        self.record(Event::Open(ip));
+    }
+
+    fn helo(&mut self, _ip: IpAddr, _domain: &str) -> Response {
+        self.record(Event::Helo);
+        OK
+    }
+
+    fn session_end(&mut self, _summary: &SessionSummary) {
+        self.record(Event::SessionEnd);
+    }
+
+    fn conn_close(&mut self) {
+        self.record(Event::Close);
+    }
+}
+
+fn start_recording_server<F>(configure: F) -> (SocketAddr, Receiver<Event>)
+where
+    F: FnOnce(&mut Server<ConnectionHandler>),
+{
+    let (tx, rx) = mpsc::channel();
+    let handler = ConnectionHandler {
+        tx: Arc::new(Mutex::new(tx)),
+    };
+    (start_server(handler, configure), rx)
+}
+
+fn next_event(rx: &Receiver<Event>) -> Event {
+    rx.recv_timeout(Duration::from_secs(10)).unwrap()
+}
+
+const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+#[test]
+fn open_and_close_full_session() {
+    let (addr, rx) = start_recording_server(|_| ());
+    let (mut stream, mut reader) = connect(addr);
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    assert!(line.starts_with("220"), "{}", line);
+    stream.write_all(b"helo a.domain\r\n").unwrap();
+    line.clear();
+    reader.read_line(&mut line).unwrap();
+    assert!(line.starts_with("250"), "{}", line);
+    stream.write_all(b"quit\r\n").unwrap();
+    line.clear();
+    reader.read_line(&mut line).unwrap();
+    assert!(line.starts_with("221"), "{}", line);
+
+    assert_eq!(next_event(&rx), Event::Open(LOCALHOST));
+    assert_eq!(next_event(&rx), Event::Helo);
+    assert_eq!(next_event(&rx), Event::SessionEnd);
+    assert_eq!(next_event(&rx), Event::Close);
+}
+
+#[test]
+fn close_after_disconnect() {
+    let (addr, rx) = start_recording_server(|_| ());
+    let (stream, mut reader) = connect(addr);
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    assert!(line.starts_with("220"), "{}", line);
+    // Hang up without sending QUIT
+    drop(reader);
+    drop(stream);
+
+    assert_eq!(next_event(&rx), Event::Open(LOCALHOST));
+    assert_eq!(next_event(&rx), Event::SessionEnd);
+    assert_eq!(next_event(&rx), Event::Close);
+}
+
+#[test]
+fn close_after_reject_greeting() {
+    let (addr, rx) = start_recording_server(|server| {
+        server.reject_all_mail(RejectAll::Greeting);
+    });
+    let (stream, mut reader) = connect(addr);
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    assert!(line.starts_with("521 "), "{}", line);
+    // The server hangs up without waiting for a command
+    line.clear();
+    assert_eq!(reader.read_line(&mut line).unwrap(), 0);
+    // The session never opened but the connection still closes
+    assert_eq!(next_event(&rx), Event::Close);
+    assert!(rx.try_recv().is_err());
+}
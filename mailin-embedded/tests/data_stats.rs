@@ -0,0 +1,104 @@
+use mailin_embedded::response::OK;
+use mailin_embedded::{DataStats, Handler, Response, Server, ServerHandle};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const LINE: &[u8] = b"Subject: in flight\r\n";
+
+// Holds the message data of the current message
+#[derive(Clone, Default)]
+struct BufferingHandler {
+    buffer: Vec<u8>,
+}
+
+impl Handler for BufferingHandler {
+    fn data(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.buffer.extend_from_slice(buf);
+        Ok(())
+    }
+
+    fn data_end(&mut self) -> Response {
+        self.buffer.clear();
+        OK
+    }
+
+    fn buffered_bytes(&self) -> usize {
+        self.buffer.len()
+    }
+}
+
+struct Client {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl Client {
+    fn connect(handle: &ServerHandle) -> Self {
+        let stream = TcpStream::connect(handle.local_addr()).unwrap();
+        let reader = BufReader::new(stream.try_clone().unwrap());
+        let mut client = Self { stream, reader };
+        assert!(client.read_line().starts_with("220"));
+        client
+    }
+
+    fn command(&mut self, line: &[u8]) -> String {
+        self.stream.write_all(line).unwrap();
+        self.read_line()
+    }
+
+    fn read_line(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        line
+    }
+}
+
+// Wait for the server to report the expected statistics
+fn wait_for(handle: &ServerHandle, expected: DataStats) {
+    let start = Instant::now();
+    while handle.data_stats() != expected {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "{:?}",
+            handle.data_stats()
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn concurrent_data_sessions() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut server = Server::new(BufferingHandler::default());
+    server.with_tcp_listener(listener).with_num_threads(4);
+    let handle = server.serve_in_background().unwrap();
+
+    let mut clients: Vec<Client> = (0..3).map(|_| Client::connect(&handle)).collect();
+    for client in clients.iter_mut() {
+        assert!(client.command(b"helo a.domain\r\n").starts_with("250"));
+        assert!(client
+            .command(b"mail from:<ship@sea.com>\r\n")
+            .starts_with("250"));
+        assert!(client
+            .command(b"rcpt to:<fish@sea.com>\r\n")
+            .starts_with("250"));
+        assert!(client.command(b"data\r\n").starts_with("354"));
+        client.stream.write_all(LINE).unwrap();
+    }
+    wait_for(
+        &handle,
+        DataStats {
+            sessions_in_data: 3,
+            buffered_bytes: 3 * LINE.len(),
+        },
+    );
+
+    for client in clients.iter_mut() {
+        assert!(client.command(b".\r\n").starts_with("250"));
+    }
+    wait_for(&handle, DataStats::default());
+    drop(clients);
+    handle.shutdown().unwrap();
+}
@@ -0,0 +1,38 @@
+mod common;
+
+use common::{connect, read_response, start_server, EmptyHandler};
+use std::io::{BufReader, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+fn start_paced_server() -> (TcpStream, BufReader<TcpStream>) {
+    let addr = start_server(EmptyHandler {}, |server| {
+        server.with_command_pacing(Duration::from_secs(1), 3);
+    });
+    connect(addr)
+}
+
+#[test]
+fn rapid_fire_commands() {
+    let (mut stream, mut reader) = start_paced_server();
+    read_response(&mut reader);
+    let mut responses = Vec::new();
+    for _ in 0..5 {
+        stream.write_all(b"noop\r\n").unwrap();
+        responses.push(read_response(&mut reader));
+    }
+    assert!(responses[3].starts_with("250"), "{:?}", responses);
+    assert!(responses[4].starts_with("421"), "{:?}", responses);
+}
+
+#[test]
+fn pipelined_commands() {
+    let (mut stream, mut reader) = start_paced_server();
+    read_response(&mut reader);
+    stream
+        .write_all(b"helo a.domain\r\nmail from:<a@b.com>\r\nrcpt to:<c@d.com>\r\nrset\r\nnoop\r\nnoop\r\n")
+        .unwrap();
+    for _ in 0..6 {
+        assert!(read_response(&mut reader).starts_with("250"));
+    }
+}
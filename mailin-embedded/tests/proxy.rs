@@ -0,0 +1,127 @@
+mod common;
+
+use common::{connect, read_response, start_server, EmptyHandler};
+use mailin_embedded::response::OK;
+use mailin_embedded::{Handler, Response};
+use std::io::{BufRead, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Debug, PartialEq)]
+enum Seen {
+    Sni(Option<String>),
+    Helo(IpAddr),
+}
+
+#[derive(Clone)]
+struct ProxiedHandler {
+    tx: Arc<Mutex<Sender<Seen>>>,
+}
+
+impl Handler for ProxiedHandler {
+    fn tls_started(&mut self, sni: Option<&str>) {
+        let sni = sni.map(String::from);
+        self.tx.lock().unwrap().send(Seen::Sni(sni)).unwrap();
+    }
+
+    fn helo(&mut self, ip: IpAddr, _domain: &str) -> Response {
+        self.tx.lock().unwrap().send(Seen::Helo(ip)).unwrap();
+        OK
+    }
+}
+
+// A PROXY protocol v2 header for a TCP over IPv4 connection from 192.0.2.1
+// that carries an authority TLV
+fn proxy_header(authority: &str) -> Vec<u8> {
+    let mut body = vec![192, 0, 2, 1, 192, 0, 2, 2, 0x30, 0x39, 0, 25];
+    body.push(0x02);
+    body.extend((authority.len() as u16).to_be_bytes());
+    body.extend(authority.as_bytes());
+    let mut header = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+    header.extend([0x21, 0x11]);
+    header.extend((body.len() as u16).to_be_bytes());
+    header.extend(body);
+    header
+}
+
+fn start_proxied_server() -> (SocketAddr, Receiver<Seen>) {
+    let (tx, rx) = mpsc::channel();
+    let handler = ProxiedHandler {
+        tx: Arc::new(Mutex::new(tx)),
+    };
+    let addr = start_server(handler, |server| {
+        server.with_proxy_protocol();
+    });
+    (addr, rx)
+}
+
+#[test]
+fn proxied_sni() {
+    let (addr, rx) = start_proxied_server();
+    let (mut stream, mut reader) = connect(addr);
+    stream.write_all(&proxy_header("mail.example.com")).unwrap();
+    assert!(read_response(&mut reader).starts_with("220"));
+    stream.write_all(b"helo a.domain\r\n").unwrap();
+    assert!(read_response(&mut reader).starts_with("250"));
+
+    let timeout = Duration::from_secs(10);
+    assert_eq!(
+        rx.recv_timeout(timeout).unwrap(),
+        Seen::Sni(Some("mail.example.com".to_string()))
+    );
+    assert_eq!(
+        rx.recv_timeout(timeout).unwrap(),
+        Seen::Helo(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)))
+    );
+}
+
+#[test]
+fn proxy_v1_client_ip() {
+    let (addr, rx) = start_proxied_server();
+    let (mut stream, mut reader) = connect(addr);
+    stream
+        .write_all(b"PROXY TCP6 2001:db8::1 2001:db8::2 12345 25\r\n")
+        .unwrap();
+    assert!(read_response(&mut reader).starts_with("220"));
+    stream.write_all(b"helo a.domain\r\n").unwrap();
+    assert!(read_response(&mut reader).starts_with("250"));
+
+    let timeout = Duration::from_secs(10);
+    assert_eq!(
+        rx.recv_timeout(timeout).unwrap(),
+        Seen::Helo("2001:db8::1".parse().unwrap())
+    );
+}
+
+#[test]
+fn malformed_proxy_header() {
+    let (addr, _rx) = start_proxied_server();
+    let (mut stream, mut reader) = connect(addr);
+    stream.write_all(b"PROXY TCP4 a.domain\r\n").unwrap();
+    let mut line = String::new();
+    // The connection is closed without a greeting
+    assert_eq!(reader.read_line(&mut line).unwrap(), 0);
+}
+
+#[test]
+fn sessions_per_proxied_client() {
+    let addr = start_server(EmptyHandler {}, |server| {
+        server.with_proxy_protocol().with_max_sessions_per_ip(1);
+    });
+    // Clients behind the same proxy, the first one connects twice
+    let mut open = Vec::new();
+    for (client, greeting) in [
+        ("192.0.2.1", "220"),
+        ("198.51.100.1", "220"),
+        ("192.0.2.1", "421 4.7.0"),
+    ] {
+        let (mut stream, mut reader) = connect(addr);
+        let header = format!("PROXY TCP4 {} 192.0.2.2 12345 25\r\n", client);
+        stream.write_all(header.as_bytes()).unwrap();
+        let response = read_response(&mut reader);
+        assert!(response.starts_with(greeting), "{}: {:?}", client, response);
+        open.push((stream, reader));
+    }
+}
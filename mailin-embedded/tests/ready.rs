@@ -0,0 +1,25 @@
+mod common;
+
+use common::EmptyHandler;
+use mailin_embedded::Server;
+use std::net::TcpListener;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[test]
+fn ready_flag() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut server = Server::new(EmptyHandler {});
+    server.with_tcp_listener(listener);
+    let handle = server.serve_in_background().unwrap();
+    let ready = handle.ready_flag();
+    // The accept loop starts in another thread
+    let start = Instant::now();
+    while !handle.is_ready() && start.elapsed() < Duration::from_secs(10) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(handle.is_ready());
+    handle.shutdown().unwrap();
+    assert!(!ready.load(Ordering::SeqCst));
+}
@@ -0,0 +1,70 @@
+mod common;
+
+use common::{connect, read_response, start_server, EmptyHandler};
+use std::io::Write;
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn too_many_sessions() {
+    let addr = start_server(EmptyHandler {}, |server| {
+        server.with_max_sessions_per_ip(2);
+    });
+    let mut open = Vec::new();
+    for _ in 0..2 {
+        let (stream, mut reader) = connect(addr);
+        assert!(read_response(&mut reader).starts_with("220"));
+        open.push((stream, reader));
+    }
+    let (_stream, mut reader) = connect(addr);
+    let response = read_response(&mut reader);
+    assert!(response.starts_with("421 4.7.0"), "{:?}", response);
+
+    // Once a session ends another one can start
+    let (mut stream, mut reader) = open.pop().unwrap();
+    stream.write_all(b"quit\r\n").unwrap();
+    assert!(read_response(&mut reader).starts_with("221"));
+    // The slot is released just after the server closes the connection
+    let greeted = (0..50).any(|_| {
+        let (_stream, mut reader) = connect(addr);
+        let greeted = read_response(&mut reader).starts_with("220");
+        if !greeted {
+            thread::sleep(Duration::from_millis(20));
+        }
+        greeted
+    });
+    assert!(greeted);
+}
+
+#[test]
+fn too_many_connections() {
+    let addr = start_server(EmptyHandler {}, |server| {
+        server.with_max_connections(2).with_num_threads(4);
+    });
+    let mut open = Vec::new();
+    for _ in 0..2 {
+        let (stream, mut reader) = connect(addr);
+        assert!(read_response(&mut reader).starts_with("220"));
+        open.push((stream, reader));
+    }
+    let (_stream, mut reader) = connect(addr);
+    let response = read_response(&mut reader);
+    assert_eq!(response, "421 4.7.0 Too many connections\r\n");
+}
+
+#[test]
+fn connections_limited_by_threads() {
+    let addr = start_server(EmptyHandler {}, |server| {
+        server.with_max_connections(5).with_num_threads(2);
+    });
+    let mut open = Vec::new();
+    for _ in 0..2 {
+        let (stream, mut reader) = connect(addr);
+        assert!(read_response(&mut reader).starts_with("220"));
+        open.push((stream, reader));
+    }
+    // Rejected rather than left waiting for a thread
+    let (_stream, mut reader) = connect(addr);
+    let response = read_response(&mut reader);
+    assert_eq!(response, "421 4.7.0 Too many connections\r\n");
+}
@@ -0,0 +1,44 @@
+mod common;
+
+use common::EmptyHandler;
+use mailin_embedded::Server;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn serve_with_shutdown() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut server = Server::new(EmptyHandler {});
+    server.with_name("example.com").with_tcp_listener(listener);
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let flag = shutdown.clone();
+    let serving =
+        thread::spawn(move || server.serve_with_shutdown(flag).map_err(|e| e.to_string()));
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    assert!(line.starts_with("220"), "{}", line);
+
+    // The session that is in progress is allowed to finish
+    shutdown.store(true, Ordering::SeqCst);
+    stream.write_all(b"NOOP\r\n").unwrap();
+    line.clear();
+    reader.read_line(&mut line).unwrap();
+    assert!(line.starts_with("250"), "{}", line);
+    stream.write_all(b"QUIT\r\n").unwrap();
+    line.clear();
+    reader.read_line(&mut line).unwrap();
+    assert!(line.starts_with("221"), "{}", line);
+
+    serving.join().unwrap().unwrap();
+}
@@ -0,0 +1,51 @@
+mod common;
+
+use common::{connect, start_server};
+use mailin_embedded::response::OK;
+use mailin_embedded::{Handler, Response};
+use std::io::{BufRead, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SLOW: Duration = Duration::from_secs(2);
+
+// Blocks while handling HELO from slow.domain
+#[derive(Clone)]
+struct SlowHandler {}
+
+impl Handler for SlowHandler {
+    fn helo(&mut self, _ip: IpAddr, domain: &str) -> Response {
+        if domain == "slow.domain" {
+            thread::sleep(SLOW);
+        }
+        OK
+    }
+}
+
+fn helo(addr: SocketAddr, domain: &str) -> String {
+    let (mut stream, mut reader) = connect(addr);
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    stream
+        .write_all(format!("helo {}\r\n", domain).as_bytes())
+        .unwrap();
+    line.clear();
+    reader.read_line(&mut line).unwrap();
+    line
+}
+
+#[test]
+fn slow_handler_does_not_stall_other_sessions() {
+    let addr = start_server(SlowHandler {}, |server| {
+        server.with_num_threads(2);
+    });
+    let slow = thread::spawn(move || helo(addr, "slow.domain"));
+    // Let the slow session reach the handler
+    thread::sleep(Duration::from_millis(200));
+    let start = Instant::now();
+    let res = helo(addr, "fast.domain");
+    assert!(res.starts_with("250"), "{}", res);
+    assert!(start.elapsed() < SLOW / 2, "{:?}", start.elapsed());
+    assert!(slow.join().unwrap().starts_with("250"));
+}
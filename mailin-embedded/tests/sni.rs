@@ -0,0 +1,97 @@
+mod common;
+
+use common::{connect, read_response, start_server, CERT_PATH, KEY_PATH};
+use mailin_embedded::{Handler, SslConfig};
+use rustls::client::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::{
+    Certificate, ClientConfig, ClientConnection, DigitallySignedStruct, ServerName, StreamOwned,
+};
+use std::io::{Read, Write};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+#[derive(Clone)]
+struct SniHandler {
+    tx: Arc<Mutex<Sender<Option<String>>>>,
+}
+
+impl Handler for SniHandler {
+    fn tls_started(&mut self, sni: Option<&str>) {
+        let sni = sni.map(String::from);
+        self.tx.lock().unwrap().send(sni).unwrap();
+    }
+}
+
+// The test certificate is a self-signed v1 certificate that webpki cannot verify
+struct AcceptAnyCert {}
+
+impl ServerCertVerifier for AcceptAnyCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        _message: &[u8],
+        _cert: &Certificate,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        _message: &[u8],
+        _cert: &Certificate,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+}
+
+#[test]
+fn handler_sees_sni() {
+    let (tx, rx) = mpsc::channel();
+    let handler = SniHandler {
+        tx: Arc::new(Mutex::new(tx)),
+    };
+    let addr = start_server(handler, |server| {
+        server
+            .with_ssl(SslConfig::SelfSigned {
+                cert_path: CERT_PATH.to_string(),
+                key_path: KEY_PATH.to_string(),
+            })
+            .unwrap();
+    });
+
+    let (mut stream, mut reader) = connect(addr);
+    read_response(&mut reader);
+    stream.write_all(b"ehlo a.domain\r\n").unwrap();
+    read_response(&mut reader);
+    stream.write_all(b"starttls\r\n").unwrap();
+    assert!(read_response(&mut reader).starts_with("220"));
+
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(AcceptAnyCert {}))
+        .with_no_client_auth();
+    let server_name = ServerName::try_from("tenant.example.com").unwrap();
+    let client = ClientConnection::new(Arc::new(config), server_name).unwrap();
+    let mut tls = StreamOwned::new(client, stream);
+    tls.write_all(b"noop\r\n").unwrap();
+    let mut buf = [0; 64];
+    let n = tls.read(&mut buf).unwrap();
+    assert!(buf[..n].starts_with(b"250"));
+
+    let sni = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+    assert_eq!(sni.as_deref(), Some("tenant.example.com"));
+}
@@ -0,0 +1,38 @@
+mod common;
+
+use common::{connect, read_response, start_server, EmptyHandler, CERT_PATH, KEY_PATH};
+use mailin_embedded::SslConfig;
+use std::io::Write;
+use std::sync::mpsc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+#[test]
+fn tls_error_hook() {
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+    let addr = start_server(EmptyHandler {}, |server| {
+        server
+            .with_ssl(SslConfig::SelfSigned {
+                cert_path: CERT_PATH.to_string(),
+                key_path: KEY_PATH.to_string(),
+            })
+            .unwrap()
+            .on_tls_error(move |ip, err| {
+                tx.lock().unwrap().send((ip, err.to_string())).unwrap();
+            });
+    });
+
+    let (mut stream, mut reader) = connect(addr);
+    read_response(&mut reader);
+    stream.write_all(b"ehlo a.domain\r\n").unwrap();
+    read_response(&mut reader);
+    stream.write_all(b"starttls\r\n").unwrap();
+    assert!(read_response(&mut reader).starts_with("220"));
+    // Not a TLS ClientHello
+    stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+
+    let (ip, cause) = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+    assert_eq!(ip, addr.ip());
+    assert!(cause.starts_with("TLS handshake failed"), "{}", cause);
+}
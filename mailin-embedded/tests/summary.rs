@@ -0,0 +1,47 @@
+mod common;
+
+use common::{connect, read_response, start_server};
+use mailin_embedded::{Handler, SessionSummary};
+use std::io::Write;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Clone)]
+struct SummaryHandler {
+    tx: Arc<Mutex<Sender<SessionSummary>>>,
+}
+
+impl Handler for SummaryHandler {
+    fn session_end(&mut self, summary: &SessionSummary) {
+        self.tx.lock().unwrap().send(*summary).unwrap();
+    }
+}
+
+#[test]
+fn session_end_summary() {
+    let (tx, rx) = mpsc::channel();
+    let handler = SummaryHandler {
+        tx: Arc::new(Mutex::new(tx)),
+    };
+    let addr = start_server(handler, |_| ());
+
+    let (mut stream, mut reader) = connect(addr);
+    assert!(read_response(&mut reader).starts_with("220"));
+    // Send two commands without waiting for the first response
+    stream.write_all(b"helo a.domain\r\nnoop\r\n").unwrap();
+    assert!(read_response(&mut reader).starts_with("250"));
+    assert!(read_response(&mut reader).starts_with("250"));
+    stream.write_all(b"quit\r\n").unwrap();
+    assert!(read_response(&mut reader).starts_with("221"));
+
+    let summary = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+    assert_eq!(
+        summary,
+        SessionSummary {
+            used_starttls: false,
+            authenticated: false,
+            used_pipelining: true,
+        }
+    );
+}
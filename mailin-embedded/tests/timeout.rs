@@ -0,0 +1,67 @@
+mod common;
+
+use common::{connect, start_server, EmptyHandler};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+fn start_timed_server(idle: Duration, total: Duration) -> (TcpStream, BufReader<TcpStream>) {
+    let addr = start_server(EmptyHandler {}, |server| {
+        server.with_timeouts(idle, total);
+    });
+    let (stream, reader) = connect(addr);
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    (stream, reader)
+}
+
+#[test]
+fn stalled_command() {
+    let (mut stream, mut reader) =
+        start_timed_server(Duration::from_millis(200), Duration::from_secs(60));
+    assert!(read_line(&mut reader).starts_with("220"));
+    // Stall in the middle of a command
+    stream.write_all(b"mail fr").unwrap();
+    let res = read_line(&mut reader);
+    assert_eq!(res, "421 Timeout, closing connection\r\n");
+    assert_closed(&mut reader);
+}
+
+#[test]
+fn session_too_long() {
+    let (mut stream, mut reader) =
+        start_timed_server(Duration::from_secs(60), Duration::from_millis(300));
+    assert!(read_line(&mut reader).starts_with("220"));
+    // A client that is never idle is still stopped
+    let mut responses = Vec::new();
+    for _ in 0..20 {
+        stream.write_all(b"noop\r\n").ok();
+        let res = read_line(&mut reader);
+        if res.is_empty() {
+            break;
+        }
+        responses.push(res);
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert_eq!(
+        responses.last().map(String::as_str),
+        Some("421 Timeout, closing connection\r\n"),
+        "{:?}",
+        responses
+    );
+    assert!(responses.len() < 20, "{:?}", responses);
+}
+
+fn read_line(reader: &mut BufReader<TcpStream>) -> String {
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap_or(0);
+    line
+}
+
+fn assert_closed(reader: &mut BufReader<TcpStream>) {
+    let mut rest = Vec::new();
+    let n = reader.read_to_end(&mut rest).unwrap();
+    assert_eq!(n, 0);
+}
@@ -0,0 +1,243 @@
+use crate::store::{Envelope, MessageStore};
+use log::info;
+use mailin_embedded::response::OK;
+use mailin_embedded::Response;
+use mime_event::Message;
+use std::fmt::Debug;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+// Chooses the maildir, relative to the base directory, for a recipient
+type Router = Arc<dyn Fn(&str) -> PathBuf + Send + Sync>;
+
+// Stores messages in a maildir
+#[derive(Clone)]
+pub struct MailStore {
+    dir: PathBuf,
+    router: Option<Router>,
+    counter: Arc<AtomicU32>,
+}
+
+// A message that is being written to the tmp directory of one or more maildirs
+pub struct MaildirMessage {
+    paths: Vec<PathBuf>,
+    files: Tee,
+}
+
+// Writes the same data to several files
+struct Tee(Vec<BufWriter<File>>);
+
+impl MailStore {
+    pub fn new<P>(dir: P) -> Self
+    where
+        P: Into<PathBuf> + Debug,
+    {
+        Self {
+            dir: dir.into(),
+            router: None,
+            counter: Arc::new(AtomicU32::new(0)),
+        }
+    }
+
+    // Store messages in a maildir per recipient. The router returns the maildir
+    // for a recipient, relative to the base directory. Messages are copied to
+    // each maildir that their recipients are routed to.
+    pub fn new_with_router<P, F>(base: P, router: F) -> Self
+    where
+        P: Into<PathBuf> + Debug,
+        F: Fn(&str) -> PathBuf + Send + Sync + 'static,
+    {
+        Self {
+            dir: base.into(),
+            router: Some(Arc::new(router)),
+            counter: Arc::new(AtomicU32::new(0)),
+        }
+    }
+
+    // The maildirs that a message to the given recipients is stored in. Returns an
+    // error if a recipient is routed outside the base directory, e.g to "..".
+    fn maildirs(&self, to: &[String]) -> io::Result<Vec<PathBuf>> {
+        let router = match &self.router {
+            Some(router) => router,
+            None => return Ok(vec![self.dir.clone()]),
+        };
+        let mut ret: Vec<PathBuf> = Vec::with_capacity(to.len());
+        for recipient in to {
+            let route = router(recipient);
+            let mut components = route.components();
+            let is_normal = components.all(|c| matches!(c, Component::Normal(_)));
+            if !is_normal || route.as_os_str().is_empty() {
+                let msg = format!("Invalid maildir {:?} for {}", route, recipient);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+            }
+            let maildir = self.dir.join(route);
+            if !ret.contains(&maildir) {
+                ret.push(maildir);
+            }
+        }
+        Ok(ret)
+    }
+
+    fn message_file(&self) -> String {
+        let mut filename = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_millis().to_string())
+            .unwrap_or_else(|_| "0000".to_string());
+        filename.push('.');
+        filename.push_str(&process::id().to_string());
+        filename.push('.');
+        let count = self.counter.fetch_add(1, Ordering::Relaxed);
+        filename.push_str(&count.to_string());
+        filename
+    }
+}
+
+impl MessageStore for MailStore {
+    type Writer = MaildirMessage;
+
+    fn start_message(&mut self, envelope: &Envelope) -> io::Result<MaildirMessage> {
+        info!(
+            "Message from {} ({}) to {:?}, 8bit: {}",
+            envelope.from, envelope.domain, envelope.to, envelope.is8bit
+        );
+        let message_file = self.message_file();
+        let mut paths = Vec::new();
+        let mut files = Vec::new();
+        for maildir in self.maildirs(envelope.to)? {
+            let mut path = maildir.join("tmp");
+            fs::create_dir_all(&path)?;
+            path.push(&message_file);
+            info!("Writing message to {:#?}", path);
+            files.push(BufWriter::new(File::create(&path)?));
+            paths.push(path);
+        }
+        Ok(MaildirMessage {
+            paths,
+            files: Tee(files),
+        })
+    }
+
+    fn end_message(
+        &mut self,
+        mut writer: MaildirMessage,
+        message: &Message,
+    ) -> io::Result<Response> {
+        writer.flush()?;
+        info!("{:#?}", message);
+        for path in &writer.paths {
+            commit_message(path)?;
+        }
+        Ok(OK)
+    }
+
+    fn abort_message(&mut self, writer: MaildirMessage) -> io::Result<()> {
+        let MaildirMessage { paths, files } = writer;
+        drop(files);
+        for path in paths {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for MaildirMessage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.files.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.files.flush()
+    }
+}
+
+impl Write for Tee {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for file in &mut self.0 {
+            file.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for file in &mut self.0 {
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+fn commit_message(tmp_path: &Path) -> io::Result<()> {
+    let filename = tmp_path.file_name().ok_or(io::ErrorKind::InvalidInput)?;
+    let mut dest = tmp_path.to_path_buf();
+    dest.pop();
+    dest.pop();
+    dest.push("new");
+    fs::create_dir_all(&dest)?;
+    dest.push(filename);
+    fs::rename(tmp_path, dest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn route_recipients() {
+        let base = env::temp_dir().join(format!("mailin-route-{}", process::id()));
+        let mut store = MailStore::new_with_router(&base, |to: &str| {
+            PathBuf::from(to.split('@').next().unwrap_or_default())
+        });
+        let to = vec![
+            "fish@sea.com".to_string(),
+            "crab@sea.com".to_string(),
+            "fish@ocean.com".to_string(),
+        ];
+        let envelope = Envelope {
+            domain: "a.domain",
+            from: "ship@sea.com",
+            is8bit: false,
+            to: &to,
+        };
+        let mut message = store.start_message(&envelope).unwrap();
+        message
+            .write_all(b"Subject: hello\r\n\r\nHello\r\n")
+            .unwrap();
+        store.end_message(message, &Message::default()).unwrap();
+        for user in ["fish", "crab"] {
+            let new = base.join(user).join("new");
+            let files: Vec<_> = fs::read_dir(&new).unwrap().collect();
+            assert_eq!(files.len(), 1);
+            let path = files[0].as_ref().unwrap().path();
+            assert_eq!(fs::read(path).unwrap(), b"Subject: hello\r\n\r\nHello\r\n");
+        }
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn route_outside_base() {
+        let base = env::temp_dir().join(format!("mailin-escape-{}", process::id()));
+        let mut store = MailStore::new_with_router(base.join("mail"), crate::recipient_maildir);
+        for recipient in ["..", ".", ""] {
+            let to = vec![recipient.to_string()];
+            let envelope = Envelope {
+                domain: "a.domain",
+                from: "ship@sea.com",
+                is8bit: false,
+                to: &to,
+            };
+            let res = store.start_message(&envelope);
+            assert_eq!(res.err().unwrap().kind(), io::ErrorKind::InvalidInput);
+        }
+        // Nothing was written next to the mail directory
+        assert!(!base.join("tmp").exists());
+        assert!(!base.join("mail").join("tmp").exists());
+    }
+}
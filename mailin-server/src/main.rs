@@ -1,11 +1,18 @@
+mod maildir;
+mod policy;
 mod store;
 
-use crate::store::MailStore;
+use crate::maildir::MailStore;
+use crate::policy::{missing_header, parse_required_headers, too_many_parts, RequiredHeader};
+use crate::store::{Envelope, MessageStore};
 use anyhow::{anyhow, Context, Result};
 use getopts::Options;
-use log::error;
-use mailin_embedded::response::{BAD_HELLO, BLOCKED_IP, INTERNAL_ERROR, OK};
+use log::{error, info};
+use mailin_embedded::response::{
+    BAD_HELLO, BLOCKED_IP, INTERNAL_ERROR, MISSING_HEADER, OK, TOO_MANY_PARTS,
+};
 use mailin_embedded::{Response, Server, SslConfig};
+use mime_event::{HeaderFields, MessageParser};
 use mxdns::MxDns;
 use simplelog::{
     ColorChoice, CombinedLogger, Config, LevelFilter, TermLogger, TerminalMode, WriteLogger,
@@ -15,7 +22,7 @@ use std::fs::File;
 use std::io;
 use std::io::Write;
 use std::net::{IpAddr, Ipv4Addr, TcpListener};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use time::{format_description, OffsetDateTime};
 
 const DOMAIN: &str = "localhost";
@@ -30,15 +37,73 @@ const OPT_SSL_CERT: &str = "ssl-cert";
 const OPT_SSL_KEY: &str = "ssl-key";
 const OPT_SSL_CHAIN: &str = "ssl-chain";
 const OPT_BLOCKLIST: &str = "blocklist";
+const OPT_WHITELIST: &str = "whitelist";
 const OPT_MAILDIR: &str = "maildir";
+const OPT_MAILDIR_PER_RECIPIENT: &str = "maildir-per-recipient";
+const OPT_REQUIRE_HEADERS: &str = "require-headers";
+const OPT_MAX_PARTS: &str = "max-parts";
 
-#[derive(Clone)]
-struct Handler<'a> {
+struct Handler<'a, S: MessageStore> {
     mxdns: &'a MxDns,
-    mailstore: MailStore,
+    store: S,
+    required_headers: Vec<RequiredHeader>,
+    max_parts: Option<usize>,
+    message: Option<Incoming<S::Writer>>,
+}
+
+// A message that is being received, the data is parsed as it is stored
+struct Incoming<W> {
+    writer: W,
+    parser: MessageParser<io::Sink>,
+}
+
+impl<'a, S: MessageStore> Handler<'a, S> {
+    fn new(
+        mxdns: &'a MxDns,
+        store: S,
+        required_headers: Vec<RequiredHeader>,
+        max_parts: Option<usize>,
+    ) -> Self {
+        Self {
+            mxdns,
+            store,
+            required_headers,
+            max_parts,
+            message: None,
+        }
+    }
+
+    // Store the message unless it is missing a required header or has too many parts
+    fn end_message(&mut self, incoming: Incoming<S::Writer>) -> io::Result<Response> {
+        let message = incoming.parser.end();
+        let no_header = HeaderFields::default();
+        let header = message.header().unwrap_or(&no_header);
+        if let Some(missing) = missing_header(&self.required_headers, header) {
+            info!("Rejected message without a {} header", missing);
+            self.store.abort_message(incoming.writer)?;
+            return Ok(MISSING_HEADER);
+        }
+        if too_many_parts(self.max_parts, &message) {
+            info!("Rejected message with {} MIME parts", message.part_count());
+            self.store.abort_message(incoming.writer)?;
+            return Ok(TOO_MANY_PARTS);
+        }
+        self.store.end_message(incoming.writer, &message)
+    }
+}
+
+impl<'a, S: MessageStore> Clone for Handler<'a, S> {
+    fn clone(&self) -> Self {
+        Self::new(
+            self.mxdns,
+            self.store.clone(),
+            self.required_headers.clone(),
+            self.max_parts,
+        )
+    }
 }
 
-impl<'a> mailin_embedded::Handler for Handler<'a> {
+impl<'a, S: MessageStore> mailin_embedded::Handler for Handler<'a, S> {
     fn helo(&mut self, ip: IpAddr, _domain: &str) -> Response {
         if ip == Ipv4Addr::new(127, 0, 0, 1) {
             return OK;
@@ -57,15 +122,21 @@ impl<'a> mailin_embedded::Handler for Handler<'a> {
         }
     }
 
-    fn data_start(
-        &mut self,
-        _domain: &str,
-        _from: &str,
-        _is8bit: bool,
-        _to: &[String],
-    ) -> Response {
-        match self.mailstore.start_message() {
-            Ok(()) => OK,
+    fn data_start(&mut self, domain: &str, from: &str, is8bit: bool, to: &[String]) -> Response {
+        let envelope = Envelope {
+            domain,
+            from,
+            is8bit,
+            to,
+        };
+        match self.store.start_message(&envelope) {
+            Ok(writer) => {
+                self.message = Some(Incoming {
+                    writer,
+                    parser: MessageParser::new(io::sink()),
+                });
+                OK
+            }
             Err(err) => {
                 error!("Start message: {}", err);
                 INTERNAL_ERROR
@@ -74,17 +145,30 @@ impl<'a> mailin_embedded::Handler for Handler<'a> {
     }
 
     fn data(&mut self, buf: &[u8]) -> io::Result<()> {
-        self.mailstore.write_all(buf)
+        match self.message.as_mut() {
+            Some(incoming) => {
+                incoming.writer.write_all(buf)?;
+                incoming.parser.write_all(buf)
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn buffered_bytes(&self) -> usize {
+        self.message
+            .as_ref()
+            .map_or(0, |incoming| incoming.parser.buffered())
     }
 
     fn data_end(&mut self) -> Response {
-        match self.mailstore.end_message() {
-            Ok(()) => OK,
-            Err(err) => {
-                error!("End message: {}", err);
-                INTERNAL_ERROR
-            }
-        }
+        let res = match self.message.take() {
+            Some(incoming) => self.end_message(incoming),
+            None => Ok(OK),
+        };
+        res.unwrap_or_else(|err| {
+            error!("End message: {}", err);
+            INTERNAL_ERROR
+        })
     }
 }
 
@@ -116,7 +200,8 @@ fn setup_logger(log_dir: Option<String>) -> Result<()> {
 fn log_filename() -> String {
     let datetime = OffsetDateTime::now_local().unwrap_or_else(|_| OffsetDateTime::now_utc());
     let date_suffix_format =
-        format_description::parse("[year][month][day][hour][minute][second]").unwrap();
+        format_description::parse_borrowed::<2>("[year][month][day][hour][minute][second]")
+            .unwrap();
     let datetime = datetime
         .format(&date_suffix_format)
         .unwrap_or_else(|_| datetime.to_string());
@@ -136,6 +221,12 @@ fn main() -> Result<()> {
     opts.optopt("l", OPT_LOG, "the directory to write logs to", "LOG_DIR");
     opts.optopt("s", OPT_SERVER, "the name of the mailserver", "SERVER");
     opts.optmulti("", OPT_BLOCKLIST, "use blocklist", "BLOCKLIST");
+    opts.optmulti(
+        "",
+        OPT_WHITELIST,
+        "skip blocklist checks for this address or network",
+        "NETWORK",
+    );
     opts.optopt("", OPT_SSL_CERT, "ssl certificate", "PEM_FILE");
     opts.optopt("", OPT_SSL_KEY, "ssl certificate key", "PEM_FILE");
     opts.optopt(
@@ -145,6 +236,23 @@ fn main() -> Result<()> {
         "PEM_FILE",
     );
     opts.optopt("", OPT_MAILDIR, "the directory to store mail in", "MAILDIR");
+    opts.optflag(
+        "",
+        OPT_MAILDIR_PER_RECIPIENT,
+        "store mail in a maildir per recipient, under the mail directory",
+    );
+    opts.optopt(
+        "",
+        OPT_REQUIRE_HEADERS,
+        "reject messages without these headers",
+        "HEADERS",
+    );
+    opts.optopt(
+        "",
+        OPT_MAX_PARTS,
+        "reject messages with more MIME parts than this, multiparts are not counted",
+        "COUNT",
+    );
     let matches = opts
         .parse(&args[1..])
         .context("Cannot parse command line")?;
@@ -163,14 +271,43 @@ fn main() -> Result<()> {
         .opt_str(OPT_SERVER)
         .unwrap_or_else(|| DOMAIN.to_owned());
     let blocklists = matches.opt_strs(OPT_BLOCKLIST);
-    let mxdns = MxDns::new(blocklists)?;
+    let whitelist = matches.opt_strs(OPT_WHITELIST);
+    let mxdns = MxDns::new(blocklists)?
+        .with_whitelist_networks(whitelist)
+        .context("Cannot parse whitelist")?;
     let maildir = matches
         .opt_str(OPT_MAILDIR)
         .unwrap_or_else(|| "mail".to_owned());
-    let handler = Handler {
-        mxdns: &mxdns,
-        mailstore: MailStore::new(maildir),
+    let store = if matches.opt_present(OPT_MAILDIR_PER_RECIPIENT) {
+        MailStore::new_with_router(maildir, recipient_maildir)
+    } else {
+        MailStore::new(maildir)
+    };
+    serve(&matches, &mxdns, domain, ssl_config, store)
+}
+
+// The maildir for a recipient is named after the recipient address. The store
+// refuses names such as ".." that are not a directory inside the mail directory.
+fn recipient_maildir(recipient: &str) -> PathBuf {
+    PathBuf::from(recipient.replace(['/', '\\'], "_"))
+}
+
+// Run the server, storing messages in the given store
+fn serve<S: MessageStore>(
+    matches: &getopts::Matches,
+    mxdns: &MxDns,
+    domain: String,
+    ssl_config: SslConfig,
+    store: S,
+) -> Result<()> {
+    let required_headers = match matches.opt_str(OPT_REQUIRE_HEADERS) {
+        Some(list) => parse_required_headers(&list)?,
+        None => Vec::new(),
     };
+    let max_parts = matches
+        .opt_get(OPT_MAX_PARTS)
+        .context("Cannot parse maximum number of MIME parts")?;
+    let handler = Handler::new(mxdns, store, required_headers, max_parts);
     let mut server = Server::new(handler);
     server
         .with_name(domain)
@@ -190,3 +327,137 @@ fn main() -> Result<()> {
         .serve()
         .map_err(|e| anyhow!("Cannot start server: {}", e))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mailin_embedded::Handler as _;
+    use std::sync::{Arc, Mutex};
+
+    // The sender, recipients and data of a message
+    type Message = (String, Vec<String>, Vec<u8>);
+
+    // Keeps received messages in memory
+    #[derive(Clone, Default)]
+    struct MemoryStore {
+        messages: Arc<Mutex<Vec<Message>>>,
+        from: String,
+        to: Vec<String>,
+    }
+
+    impl MessageStore for MemoryStore {
+        type Writer = Vec<u8>;
+
+        fn start_message(&mut self, envelope: &Envelope) -> io::Result<Vec<u8>> {
+            self.from = envelope.from.to_string();
+            self.to = envelope.to.to_vec();
+            Ok(Vec::new())
+        }
+
+        fn end_message(
+            &mut self,
+            writer: Vec<u8>,
+            _message: &mime_event::Message,
+        ) -> io::Result<Response> {
+            let message = (self.from.clone(), self.to.clone(), writer);
+            self.messages.lock().unwrap().push(message);
+            Ok(OK)
+        }
+
+        fn abort_message(&mut self, _writer: Vec<u8>) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn memory_store() {
+        let mxdns = MxDns::with_dns(Ipv4Addr::LOCALHOST, Vec::<String>::new());
+        let store = MemoryStore::default();
+        let mut handler = Handler::new(&mxdns, store.clone(), Vec::new(), None);
+        let to = vec!["fish@sea.com".to_string()];
+        assert_eq!(
+            handler.data_start("a.domain", "ship@sea.com", false, &to),
+            OK
+        );
+        handler.data(b"Hello\r\n").unwrap();
+        assert_eq!(handler.data_end(), OK);
+        let messages = store.messages.lock().unwrap();
+        assert_eq!(
+            *messages,
+            vec![("ship@sea.com".to_string(), to, b"Hello\r\n".to_vec())]
+        );
+    }
+
+    #[test]
+    fn missing_required_header() {
+        let mxdns = MxDns::with_dns(Ipv4Addr::LOCALHOST, Vec::<String>::new());
+        let store = MemoryStore::default();
+        let required = parse_required_headers("Date,From").unwrap();
+        let mut handler = Handler::new(&mxdns, store.clone(), required, None);
+        let to = vec!["fish@sea.com".to_string()];
+        handler.data_start("a.domain", "ship@sea.com", false, &to);
+        handler.data(b"From: ship@sea.com\r\n").unwrap();
+        handler.data(b"\r\n").unwrap();
+        handler.data(b"Hello\r\n").unwrap();
+        assert_eq!(handler.data_end(), MISSING_HEADER);
+        assert!(store.messages.lock().unwrap().is_empty());
+
+        handler.data_start("a.domain", "ship@sea.com", false, &to);
+        handler
+            .data(b"Date: Sat, 24 Sep 2005 15:06:49 -0400\r\n")
+            .unwrap();
+        handler.data(b"From: ship@sea.com\r\n").unwrap();
+        handler.data(b"\r\n").unwrap();
+        handler.data(b"Hello\r\n").unwrap();
+        assert_eq!(handler.data_end(), OK);
+        assert_eq!(store.messages.lock().unwrap().len(), 1);
+
+        // The header of a multipart message, not the header of its text part
+        handler.data_start("a.domain", "ship@sea.com", false, &to);
+        handler
+            .data(b"Date: Sat, 24 Sep 2005 15:06:49 -0400\r\n")
+            .unwrap();
+        handler.data(b"From: ship@sea.com\r\n").unwrap();
+        handler
+            .data(b"Content-Type: multipart/alternative; boundary=\"b\"\r\n")
+            .unwrap();
+        handler.data(b"\r\n").unwrap();
+        for content_type in ["text/html", "text/plain"] {
+            handler.data(b"--b\r\n").unwrap();
+            let line = format!("Content-Type: {content_type}\r\n");
+            handler.data(line.as_bytes()).unwrap();
+            handler.data(b"\r\n").unwrap();
+            handler.data(b"Hello\r\n").unwrap();
+        }
+        handler.data(b"--b--\r\n").unwrap();
+        assert_eq!(handler.data_end(), OK);
+        assert_eq!(store.messages.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn too_many_mime_parts() {
+        let mxdns = MxDns::with_dns(Ipv4Addr::LOCALHOST, Vec::<String>::new());
+        let store = MemoryStore::default();
+        let mut handler = Handler::new(&mxdns, store.clone(), Vec::new(), Some(3));
+        let to = vec!["fish@sea.com".to_string()];
+        handler.data_start("a.domain", "ship@sea.com", false, &to);
+        handler
+            .data(b"Content-Type: multipart/mixed; boundary=\"b\"\r\n")
+            .unwrap();
+        handler.data(b"\r\n").unwrap();
+        for i in 0..5 {
+            handler.data(b"--b\r\n").unwrap();
+            handler.data(b"Content-Type: text/plain\r\n").unwrap();
+            handler.data(b"\r\n").unwrap();
+            handler.data(format!("Part {i}\r\n").as_bytes()).unwrap();
+        }
+        handler.data(b"--b--\r\n").unwrap();
+        assert_eq!(handler.data_end(), TOO_MANY_PARTS);
+        assert!(store.messages.lock().unwrap().is_empty());
+
+        handler.data_start("a.domain", "ship@sea.com", false, &to);
+        handler.data(b"Hello\r\n").unwrap();
+        assert_eq!(handler.data_end(), OK);
+        assert_eq!(store.messages.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,108 @@
+use anyhow::{anyhow, Result};
+use mime_event::{HeaderFields, Message};
+use std::fmt;
+use std::str::FromStr;
+
+// A header field that messages can be required to have
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RequiredHeader {
+    Date,
+    From,
+    To,
+    Subject,
+    MessageId,
+    Sender,
+    ReplyTo,
+}
+
+impl RequiredHeader {
+    fn name(self) -> &'static str {
+        match self {
+            RequiredHeader::Date => "Date",
+            RequiredHeader::From => "From",
+            RequiredHeader::To => "To",
+            RequiredHeader::Subject => "Subject",
+            RequiredHeader::MessageId => "Message-ID",
+            RequiredHeader::Sender => "Sender",
+            RequiredHeader::ReplyTo => "Reply-To",
+        }
+    }
+
+    fn is_present(self, header: &HeaderFields) -> bool {
+        let field = match self {
+            RequiredHeader::Date => &header.date,
+            RequiredHeader::From => &header.from,
+            RequiredHeader::To => &header.to,
+            RequiredHeader::Subject => &header.subject,
+            RequiredHeader::MessageId => &header.message_id,
+            RequiredHeader::Sender => &header.sender,
+            RequiredHeader::ReplyTo => &header.reply_to,
+        };
+        field.is_some()
+    }
+}
+
+impl FromStr for RequiredHeader {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let all = [
+            RequiredHeader::Date,
+            RequiredHeader::From,
+            RequiredHeader::To,
+            RequiredHeader::Subject,
+            RequiredHeader::MessageId,
+            RequiredHeader::Sender,
+            RequiredHeader::ReplyTo,
+        ];
+        all.into_iter()
+            .find(|h| h.name().eq_ignore_ascii_case(s.trim()))
+            .ok_or_else(|| anyhow!("Unsupported required header: {}", s))
+    }
+}
+
+impl fmt::Display for RequiredHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+// Parse a comma separated list of required headers e.g "Date,From"
+pub fn parse_required_headers(list: &str) -> Result<Vec<RequiredHeader>> {
+    list.split(',')
+        .filter(|h| !h.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+// Find the first required header that is missing from the header fields
+pub fn missing_header(
+    required: &[RequiredHeader],
+    header: &HeaderFields,
+) -> Option<RequiredHeader> {
+    required.iter().copied().find(|h| !h.is_present(header))
+}
+
+// Does the message have more MIME parts than the limit, if there is one
+pub fn too_many_parts(max_parts: Option<usize>, message: &Message) -> bool {
+    max_parts.is_some_and(|max| message.part_count() > max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_list() {
+        let required = parse_required_headers("Date, from,message-id").unwrap();
+        assert_eq!(
+            required,
+            vec![
+                RequiredHeader::Date,
+                RequiredHeader::From,
+                RequiredHeader::MessageId
+            ]
+        );
+        assert!(parse_required_headers("Date,X-Spam").is_err());
+    }
+}
@@ -1,14 +1,29 @@
-use crate::parser::{decode_sasl_login, decode_sasl_plain, parse, parse_auth_response};
+use crate::parser::{decode_sasl_login, decode_sasl_plain, parse, parse_auth_response, ParseError};
 use crate::response::*;
 
-use crate::smtp::Cmd;
+use crate::smtp::{Cmd, Config, Envelope, Phase, Recipient, RejectAll};
 use crate::{AuthMechanism, Handler, Response};
 use either::*;
 use log::{error, trace};
-use std::borrow::BorrowMut;
+use std::borrow::{BorrowMut, Cow};
+use std::mem;
 use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
 use ternop::ternary;
 
+// EHLO keywords of the extensions that the session implements, they cannot be
+// added as extra capabilities
+const BUILT_IN_EXTENSIONS: [&str; 8] = [
+    "8BITMIME",
+    "SIZE",
+    "PIPELINING",
+    "ENHANCEDSTATUSCODES",
+    "CHUNKING",
+    "SMTPUTF8",
+    "STARTTLS",
+    "AUTH",
+];
+
 #[cfg(test)]
 #[derive(Debug)]
 pub(crate) enum SmtpState {
@@ -17,6 +32,7 @@ pub(crate) enum SmtpState {
     Hello,
     HelloAuth,
     Auth,
+    AuthPending,
     Mail,
     Rcpt,
     Data,
@@ -26,6 +42,8 @@ pub(crate) enum SmtpState {
 enum TlsState {
     Unavailable,
     Inactive,
+    // STARTTLS accepted but the handshake has not completed
+    Pending,
     Active,
 }
 
@@ -39,6 +57,9 @@ trait State: Send + Sync {
     #[cfg(test)]
     fn id(&self) -> SmtpState;
 
+    // The phase of the SMTP session that this state belongs to
+    fn phase(&self) -> Phase;
+
     // Handle an incoming command and return the next state
     fn handle(
         self: Box<Self>,
@@ -55,8 +76,28 @@ trait State: Send + Sync {
         _handler: &mut dyn Handler,
         line: &'a [u8],
     ) -> Either<Cmd<'a>, Response> {
-        trace!("> {}", String::from_utf8_lossy(line));
-        parse(line).map(Left).unwrap_or_else(Right)
+        parse_command(line)
+    }
+
+    // Process a chunk of message data, only the Data state accepts chunks.
+    // Returns the number of bytes consumed and, when the chunk did not just continue
+    // the message, a command or response.
+    fn process_data_chunk(
+        &mut self,
+        _handler: &mut dyn Handler,
+        _buf: &[u8],
+    ) -> (usize, Option<Either<Cmd<'static>, Response>>) {
+        (0, Some(Right(BAD_SEQUENCE_COMMANDS)))
+    }
+
+    // Are input lines commands, rather than message data?
+    fn receives_commands(&self) -> bool {
+        self.phase() != Phase::Data
+    }
+
+    // The bytes of a BDAT chunk that are still to be received
+    fn chunk_remaining(&self) -> Option<usize> {
+        None
     }
 }
 
@@ -99,6 +140,9 @@ where
     }
 }
 
+// Handle commands that are valid in most states.
+// A HELO or EHLO always moves to a new Hello state, which aborts any transaction
+// in progress as if RSET had been sent (RFC 5321 4.1.4).
 fn default_handler(
     current: Box<dyn State>,
     fsm: &StateMachine,
@@ -106,14 +150,31 @@ fn default_handler(
     cmd: &Cmd,
 ) -> (Response, Option<Box<dyn State>>) {
     match *cmd {
-        Cmd::Quit => (GOODBYE, None),
+        Cmd::Quit => (fsm.config.goodbye.clone(), None),
         Cmd::Helo { domain } => handle_helo(current, fsm, handler, domain),
         Cmd::Ehlo { domain } => handle_ehlo(current, fsm, handler, domain),
         Cmd::Noop => (OK, Some(current)),
+        Cmd::Help => (fsm.help_response(), Some(current)),
         _ => unhandled(current),
     }
 }
 
+// Parse a command line, a line that cannot be parsed becomes an Invalid command
+fn parse_command(line: &[u8]) -> Either<Cmd<'_>, Response> {
+    Left(parse(line).unwrap_or_else(|error| Cmd::Invalid { error }))
+}
+
+// The response sent for a line that could not be parsed
+fn parse_error_response(err: ParseError) -> Response {
+    match err {
+        ParseError::Incomplete(_) => MISSING_PARAMETER,
+        ParseError::Syntax(_) => SYNTAX_ERROR,
+        ParseError::UnknownCommand => UNKNOWN_COMMAND,
+        ParseError::TooLong => LINE_TOO_LONG,
+        ParseError::Base64(_) => INVALID_BASE64,
+    }
+}
+
 fn unhandled(current: Box<dyn State>) -> (Response, Option<Box<dyn State>>) {
     (BAD_SEQUENCE_COMMANDS, Some(current))
 }
@@ -135,6 +196,20 @@ fn handle_rset(fsm: &StateMachine, domain: &str) -> (Response, Option<Box<dyn St
     }
 }
 
+fn handle_start_tls(
+    current: Box<dyn State>,
+    fsm: &mut StateMachine,
+) -> (Response, Option<Box<dyn State>>) {
+    match fsm.tls {
+        TlsState::Inactive => {
+            fsm.tls = TlsState::Pending;
+            (START_TLS, Some(Box::new(Idle {})))
+        }
+        TlsState::Pending | TlsState::Active => (TLS_ALREADY_ACTIVE, Some(current)),
+        TlsState::Unavailable => unhandled(current),
+    }
+}
+
 fn handle_helo(
     current: Box<dyn State>,
     fsm: &StateMachine,
@@ -189,12 +264,7 @@ fn authenticate_plain(
     password: &str,
 ) -> Response {
     let auth_res = handler.auth_plain(authorization_id, authentication_id, password);
-    fsm.auth_state = ternary!(
-        auth_res.code == 235,
-        AuthState::Authenticated,
-        AuthState::RequiresAuth
-    );
-    auth_res
+    auth_result(fsm, auth_res, Some(authentication_id))
 }
 
 fn authenticate_login(
@@ -204,14 +274,49 @@ fn authenticate_login(
     password: &str,
 ) -> Response {
     let auth_res = handler.auth_login(username, password);
-    fsm.auth_state = ternary!(
-        auth_res.code == 235,
-        AuthState::Authenticated,
-        AuthState::RequiresAuth
-    );
+    auth_result(fsm, auth_res, Some(username))
+}
+
+// Record the result of an authentication and who authenticated. A pending result
+// only records the username, which is kept if the authentication later succeeds.
+// The connection is closed once the client reaches the limit on failed attempts.
+fn auth_result(fsm: &mut StateMachine, auth_res: Response, username: Option<&str>) -> Response {
+    if auth_res == AUTH_PENDING {
+        fsm.auth_id = username.map(str::to_owned);
+    } else {
+        let is_ok = auth_res.code == 235;
+        fsm.auth_state = ternary!(is_ok, AuthState::Authenticated, AuthState::RequiresAuth);
+        if !is_ok {
+            fsm.auth_id = None;
+            fsm.auth_failures += 1;
+            if fsm.auth_failures >= fsm.config.max_auth_attempts {
+                return TOO_MANY_AUTH_FAILURES;
+            }
+        } else {
+            fsm.auth_failures = 0;
+            if let Some(username) = username {
+                fsm.auth_id = Some(username.to_owned());
+            }
+        }
+    }
     auth_res
 }
 
+// The state after an authentication attempt
+fn after_auth(domain: String, res: Response) -> (Response, Option<Box<dyn State>>) {
+    if res.action == Action::Close {
+        return (res, None);
+    }
+    let next: Box<dyn State> = if res == AUTH_PENDING {
+        Box::new(AuthPending { domain })
+    } else if res.is_error {
+        Box::new(HelloAuth { domain })
+    } else {
+        Box::new(Hello { domain })
+    };
+    (res, Some(next))
+}
+
 //------------------------------------------------------------------------------
 
 struct Idle {}
@@ -222,6 +327,10 @@ impl State for Idle {
         SmtpState::Idle
     }
 
+    fn phase(&self) -> Phase {
+        Phase::Connected
+    }
+
     fn handle(
         self: Box<Self>,
         fsm: &mut StateMachine,
@@ -233,6 +342,9 @@ impl State for Idle {
                 fsm.tls = TlsState::Active;
                 (EMPTY_RESPONSE, Some(self))
             }
+            Cmd::StartTls if matches!(fsm.tls, TlsState::Pending | TlsState::Active) => {
+                (TLS_ALREADY_ACTIVE, Some(self))
+            }
             Cmd::Rset => (OK, Some(self)),
             _ => default_handler(self, fsm, handler, &cmd),
         }
@@ -251,6 +363,10 @@ impl State for Hello {
         SmtpState::Hello
     }
 
+    fn phase(&self) -> Phase {
+        Phase::Greeted
+    }
+
     fn handle(
         self: Box<Self>,
         fsm: &mut StateMachine,
@@ -258,21 +374,39 @@ impl State for Hello {
         cmd: Cmd,
     ) -> (Response, Option<Box<dyn State>>) {
         match cmd {
+            Cmd::Mail { .. } if fsm.config.reject_all.is_some() => (NO_MAIL_ACCEPTED, Some(self)),
+            Cmd::Mail { .. } if fsm.has_max_messages() => (TOO_MANY_MESSAGES, None),
+            Cmd::Mail { size, .. } if fsm.exceeds_max_size(size) => (MESSAGE_TOO_LARGE, Some(self)),
+            Cmd::Mail {
+                reverse_path,
+                smtputf8,
+                ..
+            } if !(reverse_path.is_ascii() || smtputf8 && fsm.config.smtputf8) => {
+                (NON_ASCII_ADDRESS, Some(self))
+            }
             Cmd::Mail {
                 reverse_path,
+                raw_path,
                 is8bit,
+                smtputf8,
+                size,
+                ref params,
             } => {
-                let res = handler.mail(fsm.ip, &self.domain, reverse_path);
+                let res = handler.mail_params(fsm.ip, &self.domain, reverse_path, raw_path, params);
                 transform_state(self, res, |s| {
                     Box::new(Mail {
                         domain: s.domain,
                         reverse_path: reverse_path.to_owned(),
                         is8bit,
+                        // SMTPUTF8 is ignored when the extension is not offered
+                        smtputf8: smtputf8 && fsm.config.smtputf8,
+                        size,
+                        params: params.clone(),
                     })
                 })
             }
-            Cmd::StartTls if fsm.tls == TlsState::Inactive => (START_TLS, Some(Box::new(Idle {}))),
-            Cmd::Vrfy => (VERIFY_RESPONSE, Some(self)),
+            Cmd::StartTls => handle_start_tls(self, fsm),
+            Cmd::Vrfy => (fsm.config.vrfy_response.clone(), Some(self)),
             Cmd::Rset => handle_rset(fsm, &self.domain),
             _ => default_handler(self, fsm, handler, &cmd),
         }
@@ -291,6 +425,10 @@ impl State for HelloAuth {
         SmtpState::HelloAuth
     }
 
+    fn phase(&self) -> Phase {
+        Phase::Greeted
+    }
+
     fn handle(
         self: Box<Self>,
         fsm: &mut StateMachine,
@@ -298,17 +436,17 @@ impl State for HelloAuth {
         cmd: Cmd,
     ) -> (Response, Option<Box<dyn State>>) {
         match cmd {
-            Cmd::StartTls => (START_TLS, Some(Box::new(Idle {}))),
+            Cmd::StartTls => handle_start_tls(self, fsm),
             Cmd::AuthPlain {
                 ref authorization_id,
                 ref authentication_id,
                 ref password,
-            } if fsm.allow_auth_plain() => {
+            } if fsm.allow_auth(&AuthMechanism::Plain) => {
                 let res =
                     authenticate_plain(fsm, handler, authorization_id, authentication_id, password);
-                transform_state(self, res, |s| Box::new(Hello { domain: s.domain }))
+                after_auth(self.domain, res)
             }
-            Cmd::AuthPlainEmpty if fsm.allow_auth_plain() => {
+            Cmd::AuthPlainEmpty if fsm.allow_auth(&AuthMechanism::Plain) => {
                 let domain = self.domain.clone();
                 (
                     EMPTY_AUTH_CHALLENGE,
@@ -319,7 +457,7 @@ impl State for HelloAuth {
                     })),
                 )
             }
-            Cmd::AuthLogin { ref username } if fsm.allow_auth_login() => {
+            Cmd::AuthLogin { ref username } if fsm.allow_auth(&AuthMechanism::Login) => {
                 let domain = self.domain.clone();
                 (
                     PASSWORD_AUTH_CHALLENGE,
@@ -330,7 +468,7 @@ impl State for HelloAuth {
                     })),
                 )
             }
-            Cmd::AuthLoginEmpty if fsm.allow_auth_login() => {
+            Cmd::AuthLoginEmpty if fsm.allow_auth(&AuthMechanism::Login) => {
                 let domain = self.domain.clone();
                 (
                     USERNAME_AUTH_CHALLENGE,
@@ -341,6 +479,29 @@ impl State for HelloAuth {
                     })),
                 )
             }
+            Cmd::AuthCramMd5 if fsm.allow_auth(&AuthMechanism::CramMd5) => {
+                let challenge = cram_md5_challenge(&fsm.config.name);
+                let res = Response::custom(334, base64::encode(&challenge));
+                let domain = self.domain.clone();
+                (res, Some(Box::new(CramMd5 { domain, challenge })))
+            }
+            Cmd::AuthPlain { .. } | Cmd::AuthPlainEmpty
+                if fsm.offers_auth(&AuthMechanism::Plain) =>
+            {
+                (ENCRYPTION_REQUIRED, Some(self))
+            }
+            Cmd::AuthLogin { .. } | Cmd::AuthLoginEmpty
+                if fsm.offers_auth(&AuthMechanism::Login) =>
+            {
+                (ENCRYPTION_REQUIRED, Some(self))
+            }
+            // The remaining mechanisms are not enabled
+            Cmd::AuthPlain { .. }
+            | Cmd::AuthPlainEmpty
+            | Cmd::AuthLogin { .. }
+            | Cmd::AuthLoginEmpty
+            | Cmd::AuthCramMd5
+            | Cmd::AuthUnknown => (UNRECOGNIZED_AUTH, Some(self)),
             Cmd::Rset => handle_rset(fsm, &self.domain),
             _ => default_handler(self, fsm, handler, &cmd),
         }
@@ -361,6 +522,10 @@ impl State for Auth {
         SmtpState::Auth
     }
 
+    fn phase(&self) -> Phase {
+        Phase::Greeted
+    }
+
     fn handle(
         mut self: Box<Self>,
         fsm: &mut StateMachine,
@@ -378,38 +543,97 @@ impl State for Auth {
                         &creds.authentication_id,
                         &creds.password,
                     );
-                    if res.is_error {
-                        (
-                            res,
-                            Some(Box::new(HelloAuth {
-                                domain: self.domain,
-                            })),
-                        )
-                    } else {
-                        (
-                            res,
-                            Some(Box::new(Hello {
-                                domain: self.domain,
-                            })),
-                        )
-                    }
+                    after_auth(self.domain, res)
                 }
                 AuthMechanism::Login => {
-                    let credential = decode_sasl_login(response);
+                    let credential = match decode_sasl_login(response) {
+                        Some(credential) => credential,
+                        None => {
+                            let res = auth_result(fsm, INVALID_CREDENTIALS, None);
+                            return after_auth(self.domain, res);
+                        }
+                    };
                     if let Some(username) = self.username {
                         let res = authenticate_login(fsm, handler, &username, &credential);
-                        let domain = self.domain.clone();
-                        if res.is_error {
-                            (res, Some(Box::new(HelloAuth { domain })))
-                        } else {
-                            (res, Some(Box::new(Hello { domain })))
-                        }
+                        after_auth(self.domain, res)
                     } else {
                         self.username = Some(credential);
                         (PASSWORD_AUTH_CHALLENGE, Some(self))
                     }
                 }
+                // CRAM-MD5 is handled by the CramMd5 state
+                AuthMechanism::CramMd5 => unhandled(self),
             },
+            Cmd::AuthCancel => cancel_auth(self.domain),
+            _ => unhandled(self),
+        }
+    }
+
+    fn process_line<'a>(
+        &mut self,
+        _handler: &mut dyn Handler,
+        line: &'a [u8],
+    ) -> Either<Cmd<'a>, Response> {
+        auth_response(line)
+    }
+}
+
+// Convert a line sent during an authentication exchange into a command
+fn auth_response(line: &[u8]) -> Either<Cmd<'_>, Response> {
+    // RFC 4954, a client cancels the exchange by sending "*"
+    if line == b"*\r\n" {
+        return Left(Cmd::AuthCancel);
+    }
+    // A response that cannot be parsed is treated as empty, so that authentication
+    // fails rather than waiting for another response
+    let response = parse_auth_response(line).unwrap_or_default();
+    Left(Cmd::AuthResponse { response })
+}
+
+fn cancel_auth(domain: String) -> (Response, Option<Box<dyn State>>) {
+    (AUTH_CANCELLED, Some(Box::new(HelloAuth { domain })))
+}
+
+//------------------------------------------------------------------------------
+
+// Waiting for the response to a CRAM-MD5 challenge (RFC 2195)
+struct CramMd5 {
+    domain: String,
+    challenge: String,
+}
+
+impl State for CramMd5 {
+    #[cfg(test)]
+    fn id(&self) -> SmtpState {
+        SmtpState::Auth
+    }
+
+    fn phase(&self) -> Phase {
+        Phase::Greeted
+    }
+
+    fn handle(
+        self: Box<Self>,
+        fsm: &mut StateMachine,
+        handler: &mut dyn Handler,
+        cmd: Cmd,
+    ) -> (Response, Option<Box<dyn State>>) {
+        match cmd {
+            Cmd::AuthResponse { response } => {
+                // The response is the username and the hex digest, separated by a space
+                let decoded = base64::decode(response)
+                    .ok()
+                    .and_then(|d| String::from_utf8(d).ok());
+                let res = match decoded.as_deref().and_then(|d| d.rsplit_once(' ')) {
+                    Some((username, digest)) => {
+                        let res = handler.auth_cram_md5(username, &self.challenge, digest);
+                        auth_result(fsm, res, Some(username))
+                    }
+                    None => auth_result(fsm, INVALID_CREDENTIALS, None),
+                };
+                after_auth(self.domain, res)
+            }
+            Cmd::AuthCancel => cancel_auth(self.domain),
             _ => unhandled(self),
         }
     }
@@ -419,10 +643,50 @@ impl State for Auth {
         _handler: &mut dyn Handler,
         line: &'a [u8],
     ) -> Either<Cmd<'a>, Response> {
-        trace!("> {}", String::from_utf8_lossy(line));
-        parse_auth_response(line)
-            .map(|r| Left(Cmd::AuthResponse { response: r }))
-            .unwrap_or_else(Right)
+        auth_response(line)
+    }
+}
+
+// A unique challenge in the form given by RFC 2195 e.g <1896.697170952@example.com>
+fn cram_md5_challenge(name: &str) -> String {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    format!("<{}.{}@{}>", rand::random::<u32>(), timestamp, name)
+}
+
+//------------------------------------------------------------------------------
+
+// Waiting for the result of an authentication that the handler deferred
+struct AuthPending {
+    domain: String,
+}
+
+impl State for AuthPending {
+    #[cfg(test)]
+    fn id(&self) -> SmtpState {
+        SmtpState::AuthPending
+    }
+
+    fn phase(&self) -> Phase {
+        Phase::Greeted
+    }
+
+    fn handle(
+        self: Box<Self>,
+        fsm: &mut StateMachine,
+        handler: &mut dyn Handler,
+        cmd: Cmd,
+    ) -> (Response, Option<Box<dyn State>>) {
+        match cmd {
+            Cmd::AuthComplete { response } => {
+                let res = auth_result(fsm, response, None);
+                after_auth(self.domain, res)
+            }
+            Cmd::Quit | Cmd::Noop | Cmd::Help => default_handler(self, fsm, handler, &cmd),
+            _ => unhandled(self),
+        }
     }
 }
 
@@ -432,6 +696,12 @@ struct Mail {
     domain: String,
     reverse_path: String,
     is8bit: bool,
+    // The client sent SMTPUTF8, so the addresses may contain UTF-8
+    smtputf8: bool,
+    // The message size declared with the SIZE parameter
+    size: Option<usize>,
+    // The ESMTP parameters of MAIL FROM
+    params: Vec<(String, String)>,
 }
 
 impl State for Mail {
@@ -440,6 +710,10 @@ impl State for Mail {
         SmtpState::Mail
     }
 
+    fn phase(&self) -> Phase {
+        Phase::MailFrom
+    }
+
     fn handle(
         self: Box<Self>,
         fsm: &mut StateMachine,
@@ -447,18 +721,30 @@ impl State for Mail {
         cmd: Cmd,
     ) -> (Response, Option<Box<dyn State>>) {
         match cmd {
-            Cmd::Rcpt { forward_path } => {
-                let res = handler.rcpt(forward_path);
+            Cmd::Rcpt { forward_path, .. } if !forward_path.is_ascii() && !self.smtputf8 => {
+                (NON_ASCII_ADDRESS, Some(self))
+            }
+            Cmd::Rcpt {
+                forward_path,
+                raw_path,
+                ref params,
+            } => {
+                let res = fsm.check_rcpt(handler, forward_path, raw_path, params);
                 transform_state(self, res, |s| {
                     let fp = vec![forward_path.to_owned()];
                     Box::new(Rcpt {
                         domain: s.domain,
                         reverse_path: s.reverse_path,
                         is8bit: s.is8bit,
+                        smtputf8: s.smtputf8,
+                        size: s.size,
                         forward_path: fp,
+                        mail_params: s.params,
+                        rcpt_params: vec![params.clone()],
                     })
                 })
             }
+            Cmd::Data | Cmd::Bdat { .. } => (NO_VALID_RECIPIENTS, Some(self)),
             Cmd::Rset => handle_rset(fsm, &self.domain),
             _ => default_handler(self, fsm, handler, &cmd),
         }
@@ -471,7 +757,12 @@ struct Rcpt {
     domain: String,
     reverse_path: String,
     is8bit: bool,
+    smtputf8: bool,
+    size: Option<usize>,
     forward_path: Vec<String>,
+    mail_params: Vec<(String, String)>,
+    // The ESMTP parameters of each RCPT TO, in the same order as forward_path
+    rcpt_params: Vec<Vec<(String, String)>>,
 }
 
 impl State for Rcpt {
@@ -480,6 +771,10 @@ impl State for Rcpt {
         SmtpState::Rcpt
     }
 
+    fn phase(&self) -> Phase {
+        Phase::RcptTo
+    }
+
     fn handle(
         self: Box<Self>,
         fsm: &mut StateMachine,
@@ -487,26 +782,34 @@ impl State for Rcpt {
         cmd: Cmd,
     ) -> (Response, Option<Box<dyn State>>) {
         match cmd {
-            Cmd::Data => {
-                let res = handler.data_start(
-                    &self.domain,
-                    &self.reverse_path,
-                    self.is8bit,
-                    &self.forward_path,
-                );
-                let res = ternary!(res.is_error, res, START_DATA);
-                transform_state(self, res, |s| Box::new(Data { domain: s.domain }))
+            Cmd::Data => self.start_data(fsm, handler, None),
+            Cmd::Bdat { size, last } => self.start_data(fsm, handler, Some(Chunk::new(size, last))),
+            Cmd::Rcpt { .. } if fsm.recipients_full(self.forward_path.len()) => {
+                (fsm.config.too_many_recipients.clone(), Some(self))
+            }
+            Cmd::Rcpt { forward_path, .. } if !forward_path.is_ascii() && !self.smtputf8 => {
+                (NON_ASCII_ADDRESS, Some(self))
             }
-            Cmd::Rcpt { forward_path } => {
-                let res = handler.rcpt(forward_path);
+            Cmd::Rcpt {
+                forward_path,
+                raw_path,
+                ref params,
+            } => {
+                let res = fsm.check_rcpt(handler, forward_path, raw_path, params);
                 transform_state(self, res, |s| {
                     let mut fp = s.forward_path;
                     fp.push(forward_path.to_owned());
+                    let mut rcpt_params = s.rcpt_params;
+                    rcpt_params.push(params.clone());
                     Box::new(Rcpt {
                         domain: s.domain,
                         reverse_path: s.reverse_path,
                         is8bit: s.is8bit,
+                        smtputf8: s.smtputf8,
+                        size: s.size,
                         forward_path: fp,
+                        mail_params: s.mail_params,
+                        rcpt_params,
                     })
                 })
             }
@@ -516,10 +819,116 @@ impl State for Rcpt {
     }
 }
 
+impl Rcpt {
+    // Start a message with DATA, or with the first chunk of BDAT
+    fn start_data(
+        self: Box<Self>,
+        fsm: &mut StateMachine,
+        handler: &mut dyn Handler,
+        chunk: Option<Chunk>,
+    ) -> (Response, Option<Box<dyn State>>) {
+        let res = handler.data_start(
+            &self.domain,
+            &self.reverse_path,
+            self.is8bit,
+            &self.forward_path,
+        );
+        // BDAT is answered once its chunk has been received
+        let res = match chunk {
+            _ if res.is_error => res,
+            Some(_) => EMPTY_RESPONSE,
+            None => START_DATA,
+        };
+        let tls = fsm.tls == TlsState::Active;
+        let auth_id = fsm.auth_id.clone();
+        let (res, next) = transform_state(self, res, |s| {
+            let recipients = s
+                .forward_path
+                .into_iter()
+                .zip(s.rcpt_params)
+                .map(|(forward_path, params)| Recipient {
+                    forward_path,
+                    params,
+                })
+                .collect();
+            let envelope = Envelope {
+                helo_domain: s.domain.clone(),
+                reverse_path: s.reverse_path,
+                mail_params: s.mail_params,
+                recipients,
+                size: s.size,
+                is8bit: s.is8bit,
+                smtputf8: s.smtputf8,
+                auth_id,
+                tls,
+            };
+            Box::new(Data {
+                domain: s.domain,
+                envelope,
+                partial: Vec::new(),
+                declared_size: s.size,
+                max_size: fsm.config.max_message_size,
+                received: 0,
+                // BDAT data is binary and is not changed
+                normalize_crlf: fsm.config.normalize_data_crlf && chunk.is_none(),
+                chunk,
+                failed: None,
+            })
+        });
+        // An empty chunk has been received already
+        match (next, chunk.filter(|c| c.size == 0)) {
+            (Some(data), Some(empty)) if !res.is_error => {
+                data.handle(fsm, handler, chunk_end(empty.last))
+            }
+            (next, _) => (res, next),
+        }
+    }
+}
+
 //------------------------------------------------------------------------------
 
+// A chunk of message data sent with BDAT (RFC 3030)
+#[derive(Clone, Copy)]
+struct Chunk {
+    size: usize,
+    remaining: usize,
+    // Is this the last chunk of the message?
+    last: bool,
+}
+
+impl Chunk {
+    fn new(size: usize, last: bool) -> Self {
+        Self {
+            size,
+            remaining: size,
+            last,
+        }
+    }
+}
+
+// The dummy command sent when all of a chunk has been received
+fn chunk_end(last: bool) -> Cmd<'static> {
+    ternary!(last, Cmd::DataEnd, Cmd::ChunkEnd)
+}
+
 struct Data {
     domain: String,
+    envelope: Envelope,
+    // An incomplete line that has not yet been terminated by LF
+    partial: Vec<u8>,
+    // The message size declared with the SIZE parameter
+    declared_size: Option<usize>,
+    // The configured maximum message size
+    max_size: Option<usize>,
+    // The number of message bytes received
+    received: usize,
+    // Convert bare CR and LF in the message to CRLF
+    normalize_crlf: bool,
+    // The current chunk when the message is sent with BDAT
+    chunk: Option<Chunk>,
+    // The error from writing the message, sent when the message or the current
+    // chunk has been received
+    failed: Option<Response>,
 }
 
 impl State for Data {
@@ -528,17 +937,61 @@ impl State for Data {
         SmtpState::Data
     }
 
+    fn phase(&self) -> Phase {
+        Phase::Data
+    }
+
     fn handle(
         self: Box<Self>,
-        _fsm: &mut StateMachine,
+        fsm: &mut StateMachine,
         handler: &mut dyn Handler,
         cmd: Cmd,
     ) -> (Response, Option<Box<dyn State>>) {
         match cmd {
+            Cmd::DataEnd | Cmd::ChunkEnd if self.failed.is_some() => (
+                self.failed.unwrap_or(TRANSACTION_FAILED),
+                Some(Box::new(Hello {
+                    domain: self.domain,
+                })),
+            ),
+            Cmd::DataEnd if self.received == 0 && fsm.config.reject_empty_messages => {
+                self.abort(handler, EMPTY_MESSAGE)
+            }
+            // Oversized messages are discarded without calling data_end
+            Cmd::DataEnd if self.exceeds_max_size() => self.abort(handler, MESSAGE_TOO_LARGE),
+            Cmd::DataEnd if self.exceeds_declared_size() => {
+                self.abort(handler, DECLARED_SIZE_EXCEEDED)
+            }
             Cmd::DataEnd => {
-                let res = handler.data_end();
+                let res = handler.data_end_envelope(&self.envelope);
                 transform_state(self, res, |s| Box::new(Hello { domain: s.domain }))
             }
+            Cmd::ChunkEnd => {
+                let size = self.chunk.map(|c| c.size).unwrap_or_default();
+                let res = Response::custom(250, format!("{} octets received", size));
+                (res.with_enhanced_code(2, 0, 0), Some(self))
+            }
+            Cmd::Bdat { size, last } if self.chunk.is_some() => {
+                let mut data = self;
+                data.chunk = Some(Chunk::new(size, last));
+                if size == 0 {
+                    data.handle(fsm, handler, chunk_end(last))
+                } else {
+                    (EMPTY_RESPONSE, Some(data))
+                }
+            }
+            // Commands can be sent between chunks, those that end the transaction
+            // abandon the message
+            _ if self.chunk.is_some() => {
+                let (res, next) = match cmd {
+                    Cmd::Rset => handle_rset(fsm, &self.domain),
+                    _ => default_handler(self, fsm, handler, &cmd),
+                };
+                if next.as_ref().map(|s| s.phase()) != Some(Phase::Data) {
+                    handler.data_abort();
+                }
+                (res, next)
+            }
             _ => unhandled(self),
         }
     }
@@ -546,108 +999,592 @@ impl State for Data {
     fn process_line<'a>(
         &mut self,
         handler: &mut dyn Handler,
-        mut line: &'a [u8],
+        line: &'a [u8],
     ) -> Either<Cmd<'a>, Response> {
-        if line == b".\r\n" {
-            trace!("> _data_");
-            Left(Cmd::DataEnd)
+        // Chunks are received by the state machine, only the commands between
+        // them are lines
+        if self.chunk.is_some() {
+            return parse_command(line);
+        }
+        // Lines can be delivered in pieces, wait for the end of the line
+        if !line.ends_with(b"\n") {
+            self.partial.extend_from_slice(line);
+            return Right(EMPTY_RESPONSE);
+        }
+        if self.partial.is_empty() {
+            self.data_line(handler, line)
         } else {
-            if line.starts_with(b".") {
-                line = &line[1..];
-            }
-            match handler.data(line) {
-                Ok(_) => Right(EMPTY_RESPONSE),
-                Err(e) => {
-                    error!("Error saving message: {}", e);
-                    Right(TRANSACTION_FAILED)
+            let mut complete = mem::take(&mut self.partial);
+            complete.extend_from_slice(line);
+            self.data_line(handler, &complete)
+        }
+    }
+
+    fn process_data_chunk(
+        &mut self,
+        handler: &mut dyn Handler,
+        buf: &[u8],
+    ) -> (usize, Option<Either<Cmd<'static>, Response>>) {
+        if self.chunk.is_some() {
+            return self.receive_chunk(handler, buf);
+        }
+        let mut pos = 0;
+        // Complete a line left over from the previous chunk
+        if !self.partial.is_empty() {
+            let end = match buf.iter().position(|c| *c == b'\n') {
+                Some(i) => i + 1,
+                None => {
+                    self.partial.extend_from_slice(buf);
+                    return (buf.len(), None);
                 }
+            };
+            let mut line = mem::take(&mut self.partial);
+            line.extend_from_slice(&buf[..end]);
+            if self.write_line(handler, &line) {
+                return (end, Some(Left(Cmd::DataEnd)));
             }
+            pos = end;
+        }
+        // Lines are written in runs, only lines that start with a dot are handled
+        // individually
+        let mut start = pos;
+        while let Some(i) = buf[pos..].iter().position(|c| *c == b'\n') {
+            let end = pos + i + 1;
+            if buf[pos] == b'.' {
+                self.write_data(handler, &buf[start..pos]);
+                if self.write_line(handler, &buf[pos..end]) {
+                    return (end, Some(Left(Cmd::DataEnd)));
+                }
+                start = end;
+            }
+            pos = end;
+        }
+        self.write_data(handler, &buf[start..pos]);
+        // Keep an incomplete line until the rest of it arrives
+        self.partial.extend_from_slice(&buf[pos..]);
+        (buf.len(), None)
+    }
+
+    fn receives_commands(&self) -> bool {
+        matches!(self.chunk, Some(Chunk { remaining: 0, .. }))
+    }
+
+    fn chunk_remaining(&self) -> Option<usize> {
+        self.chunk.map(|c| c.remaining).filter(|n| *n > 0)
+    }
+}
+
+impl Data {
+    // Receive the data of a BDAT chunk, which is passed on without removing dot
+    // stuffing. Only the bytes up to the end of the chunk are consumed.
+    fn receive_chunk(
+        &mut self,
+        handler: &mut dyn Handler,
+        buf: &[u8],
+    ) -> (usize, Option<Either<Cmd<'static>, Response>>) {
+        let chunk = match self.chunk.as_mut() {
+            Some(chunk) if chunk.remaining > 0 => chunk,
+            _ => return (0, Some(Right(BAD_SEQUENCE_COMMANDS))),
+        };
+        let len = buf.len().min(chunk.remaining);
+        chunk.remaining -= len;
+        let end = ternary!(chunk.remaining == 0, Some(chunk_end(chunk.last)), None);
+        self.write_data(handler, &buf[..len]);
+        (len, end.map(Left))
+    }
+
+    // Handle a complete line of data
+    fn data_line<'a>(
+        &mut self,
+        handler: &mut dyn Handler,
+        line: &[u8],
+    ) -> Either<Cmd<'a>, Response> {
+        if self.write_line(handler, line) {
+            Left(Cmd::DataEnd)
+        } else {
+            Right(EMPTY_RESPONSE)
+        }
+    }
+
+    // Write a complete line of data, removing any dot stuffing.
+    // Returns true if the line ends the message. Only CRLF.CRLF ends a message,
+    // a dot followed by a bare LF is data (see SMTP smuggling).
+    fn write_line(&mut self, handler: &mut dyn Handler, line: &[u8]) -> bool {
+        if line == b".\r\n" {
+            trace!("> _data_");
+            true
+        } else {
+            let line = line.strip_prefix(b".").unwrap_or(line);
+            self.write_data(handler, line);
+            false
+        }
+    }
+
+    // Pass message data to the handler. After an error the message is aborted and
+    // the rest of it is read and discarded.
+    fn write_data(&mut self, handler: &mut dyn Handler, buf: &[u8]) {
+        self.received += buf.len();
+        // Data after the declared or maximum size is discarded
+        if buf.is_empty()
+            || self.failed.is_some()
+            || self.exceeds_declared_size()
+            || self.exceeds_max_size()
+        {
+            return;
+        }
+        let buf = ternary!(self.normalize_crlf, normalize_crlf(buf), Cow::Borrowed(buf));
+        if let Err(e) = handler.data(&buf) {
+            error!("Error saving message: {}", e);
+            handler.data_abort();
+            self.failed = Some(TRANSACTION_FAILED);
+        }
+    }
+
+    // Abandon the message without calling data_end
+    fn abort(
+        self: Box<Self>,
+        handler: &mut dyn Handler,
+        res: Response,
+    ) -> (Response, Option<Box<dyn State>>) {
+        handler.data_abort();
+        (
+            res,
+            Some(Box::new(Hello {
+                domain: self.domain,
+            })),
+        )
+    }
+
+    fn exceeds_declared_size(&self) -> bool {
+        matches!(self.declared_size, Some(size) if self.received > size)
+    }
+
+    fn exceeds_max_size(&self) -> bool {
+        matches!(self.max_size, Some(max) if self.received > max)
+    }
+}
+
+// Replace bare CR and bare LF with CRLF
+fn normalize_crlf(buf: &[u8]) -> Cow<'_, [u8]> {
+    let is_bare = |i: usize| match buf[i] {
+        b'\r' => buf.get(i + 1) != Some(&b'\n'),
+        b'\n' => i == 0 || buf[i - 1] != b'\r',
+        _ => false,
+    };
+    if !(0..buf.len()).any(is_bare) {
+        return Cow::Borrowed(buf);
+    }
+    let mut normalized = Vec::with_capacity(buf.len() + 16);
+    for (i, c) in buf.iter().enumerate() {
+        if is_bare(i) {
+            normalized.extend_from_slice(b"\r\n");
+        } else {
+            normalized.push(*c);
+        }
+    }
+    Cow::Owned(normalized)
+}
+
+//------------------------------------------------------------------------------
+
+// How a command contributes to the progress of a session
+#[derive(Clone, Copy)]
+enum Progress {
+    Stalled,
+    Auth,
+    TransactionEnd,
+    Other,
+}
+
+impl Progress {
+    fn from_cmd(cmd: &Cmd) -> Self {
+        match cmd {
+            Cmd::Noop | Cmd::Rset | Cmd::Help => Progress::Stalled,
+            Cmd::AuthPlain { .. }
+            | Cmd::AuthResponse { .. }
+            | Cmd::AuthCancel
+            | Cmd::AuthComplete { .. } => Progress::Auth,
+            Cmd::DataEnd => Progress::TransactionEnd,
+            // An empty last chunk ends the message straight away
+            Cmd::Bdat {
+                size: 0,
+                last: true,
+            } => Progress::TransactionEnd,
+            _ => Progress::Other,
         }
     }
 }
+
 //------------------------------------------------------------------------------
 
 pub(crate) struct StateMachine {
     ip: IpAddr,
-    auth_mechanisms: Vec<AuthMechanism>,
+    config: Config,
     auth_state: AuthState,
+    // The user that authenticated
+    auth_id: Option<String>,
     tls: TlsState,
     smtp: Option<Box<dyn State>>,
-    auth_plain: bool,
-    auth_login: bool,
+    no_progress: usize,
+    // Failed authentications since the last success
+    auth_failures: usize,
+    // The bytes of a rejected BDAT chunk that are still to be discarded
+    discard: usize,
+    // Messages delivered in the session
+    messages: usize,
 }
 
 impl StateMachine {
-    pub fn new(ip: IpAddr, auth_mechanisms: Vec<AuthMechanism>, allow_start_tls: bool) -> Self {
+    pub fn new(ip: IpAddr, config: Config) -> Self {
         let auth_state = ternary!(
-            auth_mechanisms.is_empty(),
+            config.auth_mechanisms.is_empty(),
             AuthState::Unavailable,
             AuthState::RequiresAuth
         );
-        let tls = ternary!(allow_start_tls, TlsState::Inactive, TlsState::Unavailable);
-        let auth_plain = auth_mechanisms.contains(&AuthMechanism::Plain);
-        let auth_login = auth_mechanisms.contains(&AuthMechanism::Login);
+        let tls = ternary!(
+            config.start_tls_extension,
+            TlsState::Inactive,
+            TlsState::Unavailable
+        );
         Self {
             ip,
-            auth_mechanisms,
+            config,
             auth_state,
+            auth_id: None,
             tls,
             smtp: Some(Box::new(Idle {})),
-            auth_plain,
-            auth_login,
+            no_progress: 0,
+            auth_failures: 0,
+            discard: 0,
+            messages: 0,
         }
     }
 
     // Respond and change state with the given command
     pub fn command(&mut self, handler: &mut dyn Handler, cmd: Cmd) -> Response {
+        let bdat_size = match cmd {
+            Cmd::Bdat { .. } if !self.config.chunking => return UNKNOWN_COMMAND,
+            Cmd::Bdat { size, .. } => Some(size),
+            _ => None,
+        };
+        let progress = Progress::from_cmd(&cmd);
         let (response, next_state) = match self.smtp.take() {
             Some(last_state) => last_state.handle(self, handler, cmd),
             None => (INVALID_STATE, None),
         };
         self.smtp = next_state;
+        // The chunk of a rejected BDAT is still sent by the client
+        if let Some(size) = bdat_size.filter(|_| response.is_error) {
+            self.discard = size;
+        }
+        if matches!(progress, Progress::TransactionEnd) && !response.is_error {
+            self.messages += 1;
+        }
+        if self.is_stalled(progress, &response) {
+            self.smtp = None;
+            return TOO_MANY_NO_PROGRESS;
+        }
         response
     }
 
+    // Count commands that do not progress the session and check if the limit is exceeded.
+    // The count is reset when a transaction completes.
+    fn is_stalled(&mut self, progress: Progress, res: &Response) -> bool {
+        match progress {
+            Progress::TransactionEnd if !res.is_error => self.no_progress = 0,
+            Progress::Stalled => self.no_progress += 1,
+            Progress::Auth if res.is_error => self.no_progress += 1,
+            _ => (),
+        }
+        self.config
+            .max_no_progress
+            .map(|max| self.no_progress > max)
+            .unwrap_or(false)
+    }
+
+    // Process a line, `raw` is the line as the client sent it, before a bare LF
+    // line ending was replaced with CRLF
     pub fn process_line<'a>(
         &mut self,
         handler: &mut dyn Handler,
+        raw: &[u8],
         line: &'a [u8],
     ) -> Either<Cmd<'a>, Response> {
+        // BDAT chunks are binary and are not split into lines. The bytes after the
+        // end of a chunk cannot be handed back, so the session is closed rather
+        // than losing them.
+        if let Some(remaining) = self.chunk_remaining() {
+            if line.len() > remaining {
+                error!(
+                    "{} bytes passed after the end of a BDAT chunk",
+                    line.len() - remaining
+                );
+                if self.discard == 0 {
+                    handler.data_abort();
+                }
+                self.smtp = None;
+                return Right(INVALID_STATE);
+            }
+            let (_, next) = self.process_data_chunk(handler, line);
+            return next.unwrap_or(Right(EMPTY_RESPONSE));
+        }
         match self.smtp {
             Some(ref mut s) => {
                 let s: &mut dyn State = s.borrow_mut();
-                s.process_line(handler, line)
+                // Message contents are not logged or checked
+                if s.receives_commands() {
+                    trace!("> {}", log_line(raw, self.config.log_line_limit));
+                    handler.command_line(raw);
+                    if self.config.reject_8bit_commands
+                        && !is_7bit_or_utf8(line, self.config.smtputf8)
+                    {
+                        return Right(SYNTAX_ERROR);
+                    }
+                }
+                match s.process_line(handler, line) {
+                    Left(Cmd::Invalid { error }) => Right(self.parse_error(line, error)),
+                    next => next,
+                }
             }
             None => Right(INVALID_STATE),
         }
     }
 
+    // Report a line that could not be parsed and return the response to it
+    fn parse_error(&self, line: &[u8], error: ParseError) -> Response {
+        trace!("Parse error: {}", error);
+        if let Some(ref hook) = self.config.parse_error_hook {
+            hook(line);
+        }
+        parse_error_response(error)
+    }
+
+    pub fn process_data_chunk(
+        &mut self,
+        handler: &mut dyn Handler,
+        buf: &[u8],
+    ) -> (usize, Option<Either<Cmd<'static>, Response>>) {
+        if self.discard > 0 {
+            let len = buf.len().min(self.discard);
+            self.discard -= len;
+            return (len, None);
+        }
+        match self.smtp {
+            Some(ref mut s) => s.process_data_chunk(handler, buf),
+            None => (0, Some(Right(INVALID_STATE))),
+        }
+    }
+
+    pub fn phase(&self) -> Phase {
+        match self.smtp.as_ref().map(|s| s.phase()) {
+            None => Phase::Closed,
+            Some(Phase::Greeted) if matches!(self.auth_state, AuthState::Authenticated) => {
+                Phase::Authenticated
+            }
+            Some(phase) => phase,
+        }
+    }
+
+    // Remove the enhanced status code of a response unless the extension is enabled
+    pub fn status_codes(&self, mut res: Response) -> Response {
+        if !self.config.enhanced_status_codes {
+            res.enhanced = None;
+        }
+        res
+    }
+
+    // The session arrived through a proxy that terminated TLS
+    pub fn forwarded_tls(&mut self) {
+        self.tls = TlsState::Active;
+    }
+
+    // Are command lines that end with a bare LF accepted?
+    pub fn accepts_bare_lf(&self) -> bool {
+        self.config.bare_lf_commands && self.receives_commands()
+    }
+
+    // Are input lines commands, rather than message data?
+    pub fn receives_commands(&self) -> bool {
+        let receives_commands = self.smtp.as_ref().map(|s| s.receives_commands());
+        self.discard == 0 && receives_commands.unwrap_or(true)
+    }
+
+    // The bytes of a BDAT chunk that are still to be received
+    pub fn chunk_remaining(&self) -> Option<usize> {
+        if self.discard > 0 {
+            return Some(self.discard);
+        }
+        self.smtp.as_ref().and_then(|s| s.chunk_remaining())
+    }
+
+    pub fn message_count(&self) -> usize {
+        self.messages
+    }
+
+    // The address of the client
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+
+    fn has_max_messages(&self) -> bool {
+        self.config
+            .max_messages
+            .map(|max| self.messages >= max)
+            .unwrap_or(false)
+    }
+
+    pub fn reject_all(&self) -> Option<RejectAll> {
+        self.config.reject_all
+    }
+
+    // Has the client authenticated?
+    pub fn is_authenticated(&self) -> bool {
+        matches!(self.auth_state, AuthState::Authenticated)
+    }
+
     #[cfg(test)]
     pub fn current_state(&self) -> SmtpState {
         let id = self.smtp.as_ref().map(|s| s.id());
         id.unwrap_or(SmtpState::Invalid)
     }
 
+    // A summary of the commands that the server accepts
+    fn help_response(&self) -> Response {
+        let mut commands = vec![
+            "HELO", "EHLO", "MAIL", "RCPT", "DATA", "RSET", "NOOP", "QUIT", "VRFY", "HELP",
+        ];
+        if self.tls == TlsState::Inactive {
+            commands.push("STARTTLS");
+        }
+        if !self.config.auth_mechanisms.is_empty() {
+            commands.push("AUTH");
+        }
+        if self.config.chunking {
+            commands.push("BDAT");
+        }
+        let summary = format!("Commands supported: {}", commands.join(" "));
+        Response::dynamic(214, summary, Vec::new()).with_enhanced_code(2, 0, 0)
+    }
+
     fn ehlo_response(&self) -> Response {
-        let mut extensions = vec!["8BITMIME".to_string()];
+        let size = match self.config.max_message_size {
+            Some(max) => format!("SIZE {}", max),
+            None => "SIZE".to_string(),
+        };
+        let mut extensions = vec!["8BITMIME".to_string(), size];
+        if self.config.pipelining {
+            extensions.push("PIPELINING".to_string());
+        }
+        if self.config.enhanced_status_codes {
+            extensions.push("ENHANCEDSTATUSCODES".to_string());
+        }
+        if self.config.chunking {
+            extensions.push("CHUNKING".to_string());
+        }
+        if self.config.smtputf8 {
+            extensions.push("SMTPUTF8".to_string());
+        }
         if self.tls == TlsState::Inactive {
             extensions.push("STARTTLS".to_string());
-        } else if !self.auth_mechanisms.is_empty() {
-            let mut auth_available = "AUTH".to_string();
-            for auth in &self.auth_mechanisms {
-                auth_available += " ";
-                auth_available += auth.extension();
-            }
-            extensions.push(auth_available);
+        }
+        // Until TLS is active, only offer mechanisms that do not send the password
+        let mechanisms: Vec<&str> = self
+            .config
+            .auth_mechanisms
+            .iter()
+            .filter(|m| self.tls != TlsState::Inactive || !m.requires_tls())
+            .map(|m| m.extension())
+            .collect();
+        if !mechanisms.is_empty() {
+            extensions.push(format!("AUTH {}", mechanisms.join(" ")));
+        }
+        let extra = self.config.extra_capabilities.iter().filter(|capability| {
+            let keyword = capability.split(' ').next().unwrap_or_default();
+            !BUILT_IN_EXTENSIONS
+                .iter()
+                .any(|built_in| built_in.eq_ignore_ascii_case(keyword))
+        });
+        for capability in extra {
+            extensions.push(capability.to_string());
         }
         Response::dynamic(250, "server offers extensions:".to_string(), extensions)
     }
 
-    fn allow_auth_plain(&self) -> bool {
-        self.auth_plain && self.tls == TlsState::Active
+    // Is a declared message size larger than the maximum message size?
+    fn exceeds_max_size(&self, size: Option<usize>) -> bool {
+        matches!((size, self.config.max_message_size), (Some(size), Some(max)) if size > max)
+    }
+
+    // Check a recipient with the handler, unless its domain is a catch-all domain
+    fn check_rcpt(
+        &self,
+        handler: &mut dyn Handler,
+        forward_path: &str,
+        raw_path: &str,
+        params: &[(String, String)],
+    ) -> Response {
+        let domain = forward_path.rsplit_once('@').map(|(_, domain)| domain);
+        let catch_all = domain.map(|domain| {
+            self.config
+                .catch_all_domains
+                .iter()
+                .any(|d| d.eq_ignore_ascii_case(domain))
+        });
+        if catch_all.unwrap_or(false) {
+            OK
+        } else {
+            handler.rcpt_params(forward_path, raw_path, params)
+        }
+    }
+
+    fn recipients_full(&self, count: usize) -> bool {
+        matches!(self.config.max_recipients, Some(max) if count >= max)
+    }
+
+    fn offers_auth(&self, mechanism: &AuthMechanism) -> bool {
+        self.config.auth_mechanisms.contains(mechanism)
+    }
+
+    // Mechanisms that send the password are only allowed once TLS is active
+    fn allow_auth(&self, mechanism: &AuthMechanism) -> bool {
+        self.offers_auth(mechanism) && (!mechanism.requires_tls() || self.tls == TlsState::Active)
+    }
+}
+
+// Is a command line 7-bit, or UTF-8 when SMTPUTF8 is enabled?
+fn is_7bit_or_utf8(line: &[u8], smtputf8: bool) -> bool {
+    ternary!(smtputf8, std::str::from_utf8(line).is_ok(), line.is_ascii())
+}
+
+// Convert a line to text for logging, truncated to at most max characters
+fn log_line(line: &[u8], max: usize) -> Cow<'_, str> {
+    let text = String::from_utf8_lossy(line);
+    match text.char_indices().nth(max) {
+        Some((end, _)) => Cow::Owned(format!("{}...", &text[..end])),
+        None => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crlf_normalized() {
+        assert_eq!(&*normalize_crlf(b"a\r\nb\r\n"), b"a\r\nb\r\n");
+        assert!(matches!(normalize_crlf(b"a\r\n"), Cow::Borrowed(_)));
+        assert_eq!(&*normalize_crlf(b"a\rb\r\n"), b"a\r\nb\r\n");
+        assert_eq!(&*normalize_crlf(b"a\nb\r\r\n"), b"a\r\nb\r\n\r\n");
+        assert_eq!(&*normalize_crlf(b"\n"), b"\r\n");
     }
 
-    fn allow_auth_login(&self) -> bool {
-        self.auth_login && self.tls == TlsState::Active
+    #[test]
+    fn log_line_truncated() {
+        let line = "é".repeat(10 * 1024);
+        let logged = log_line(line.as_bytes(), 200);
+        assert_eq!(logged.chars().count(), 203);
+        assert!(logged.ends_with("é..."));
+        let logged = log_line(b"helo a.domain\r\n", 200);
+        assert_eq!(logged, "helo a.domain\r\n");
     }
 }
@@ -47,8 +47,10 @@ pub mod response;
 mod smtp;
 
 pub use crate::{
-    response::{Action, Response},
-    smtp::{Session, SessionBuilder},
+    response::{Action, Response, ResponseClass},
+    smtp::{
+        DataOutcome, Envelope, Phase, Recipient, RejectAll, Session, SessionBuilder, SessionSummary,
+    },
 };
 
 /// A `Handler` makes decisions about incoming mail commands.
@@ -94,11 +96,45 @@ pub trait Handler {
         response::OK
     }
 
+    /// Called when a mail message is started, with the reverse path exactly as the
+    /// client sent it, including the angle brackets and any source route.
+    /// Calls `mail` by default.
+    fn mail_raw(&mut self, ip: IpAddr, domain: &str, from: &str, _raw_from: &str) -> Response {
+        self.mail(ip, domain, from)
+    }
+
+    /// Called when a mail message is started, with the ESMTP parameters that
+    /// followed the reverse path, e.g `("ENVID", "1234")`. Keywords are uppercase.
+    /// Calls `mail_raw` by default.
+    fn mail_params(
+        &mut self,
+        ip: IpAddr,
+        domain: &str,
+        from: &str,
+        raw_from: &str,
+        _params: &[(String, String)],
+    ) -> Response {
+        self.mail_raw(ip, domain, from, raw_from)
+    }
+
     /// Called when a mail recipient is set
     fn rcpt(&mut self, _to: &str) -> Response {
         response::OK
     }
 
+    /// Called when a mail recipient is set, with the forward path exactly as the
+    /// client sent it. Calls `rcpt` by default.
+    fn rcpt_raw(&mut self, to: &str, _raw_to: &str) -> Response {
+        self.rcpt(to)
+    }
+
+    /// Called when a mail recipient is set, with the ESMTP parameters that followed
+    /// the forward path, e.g `("NOTIFY", "SUCCESS,FAILURE")`. Keywords are
+    /// uppercase. Calls `rcpt_raw` by default.
+    fn rcpt_params(&mut self, to: &str, raw_to: &str, _params: &[(String, String)]) -> Response {
+        self.rcpt_raw(to, raw_to)
+    }
+
     /// Called when a data command is received
     fn data_start(
         &mut self,
@@ -120,7 +156,19 @@ pub trait Handler {
         response::OK
     }
 
-    /// Called when a plain authentication request is received
+    /// Called at the end of receiving data with the envelope of the transaction,
+    /// e.g to relay the message. Calls `data_end` by default.
+    fn data_end_envelope(&mut self, _envelope: &Envelope) -> Response {
+        self.data_end()
+    }
+
+    /// Called instead of `data_end` when a message is abandoned after `data_start`,
+    /// e.g when it is rejected once received, a call to `data` fails or the client
+    /// resets the transaction between BDAT chunks
+    fn data_abort(&mut self) {}
+
+    /// Called when a plain authentication request is received.
+    /// Return `AUTH_PENDING` to finish authentication later with `Session::complete_auth`.
     fn auth_plain(
         &mut self,
         _authorization_id: &str,
@@ -130,10 +178,50 @@ pub trait Handler {
         response::INVALID_CREDENTIALS
     }
 
-    /// Called when a login authentication request is received
+    /// Called when a login authentication request is received.
+    /// Return `AUTH_PENDING` to finish authentication later with `Session::complete_auth`.
     fn auth_login(&mut self, _username: &str, _password: &str) -> Response {
         response::INVALID_CREDENTIALS
     }
+
+    /// Called with the exact bytes of each line that the client sends, other than
+    /// message data, before the line is parsed. Nothing is removed or replaced, so
+    /// the line can contain invalid UTF-8 and control characters. `escape_ascii()`
+    /// renders the line for display.
+    fn command_line(&mut self, _line: &[u8]) {}
+
+    /// Called when a CRAM-MD5 authentication response is received. The handler
+    /// computes the HMAC-MD5 of the challenge, keyed with the shared secret of the
+    /// user, and returns `AUTH_OK` if it matches the hex digest sent by the client.
+    fn auth_cram_md5(&mut self, _username: &str, _challenge: &str, _digest: &str) -> Response {
+        response::INVALID_CREDENTIALS
+    }
+
+    /// Called when STARTTLS has completed, or when a proxy that terminated TLS
+    /// forwarded the connection, with the server name that the client asked for
+    /// using SNI
+    fn tls_started(&mut self, _sni: Option<&str>) {}
+
+    /// The number of bytes of message data that the handler is holding in memory,
+    /// such as a partly parsed header. Servers report the total across sessions.
+    fn buffered_bytes(&self) -> usize {
+        0
+    }
+
+    /// Called by `Session::open` when a client connects, once the greeting has been
+    /// sent, e.g to set up per-connection state such as rate counters
+    fn conn_open(&mut self, _ip: IpAddr) {}
+
+    /// Called by `Session::end` when the connection of a session that was opened
+    /// has closed, whether or not the client sent QUIT, with the extensions that
+    /// the client used during the session
+    fn session_end(&mut self, _summary: &SessionSummary) {}
+
+    /// Called by `Session::close` once the connection has closed, whether or not the
+    /// client sent QUIT, e.g to clean up the state that was set up in
+    /// `conn_open`. It is also called when the greeting refused the connection, in
+    /// which case `conn_open` was not called.
+    fn conn_close(&mut self) {}
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -144,6 +232,10 @@ pub enum AuthMechanism {
 
     /// Sequential mechanism over TLS
     Login,
+
+    /// Challenge-response mechanism that does not send the password and is
+    /// allowed without TLS
+    CramMd5,
 }
 
 impl AuthMechanism {
@@ -152,8 +244,14 @@ impl AuthMechanism {
         match self {
             AuthMechanism::Plain => "PLAIN",
             AuthMechanism::Login => "LOGIN",
+            AuthMechanism::CramMd5 => "CRAM-MD5",
         }
     }
+
+    // Does the mechanism send the password, so that it is only allowed over TLS?
+    fn requires_tls(&self) -> bool {
+        !matches!(self, AuthMechanism::CramMd5)
+    }
 }
 
 #[cfg(test)]
@@ -180,7 +278,7 @@ mod tests {
         data_end_called: bool,
     }
 
-    impl<'a> Handler for &'a mut TestHandler {
+    impl Handler for &mut TestHandler {
         fn helo(&mut self, ip: IpAddr, domain: &str) -> Response {
             assert_eq!(self.ip, ip);
             assert_eq!(self.domain, domain);
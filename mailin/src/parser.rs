@@ -1,35 +1,108 @@
 use nom::branch::alt;
-use nom::bytes::complete::{is_not, tag, tag_no_case, take_while1};
-use nom::character::is_alphanumeric;
-use nom::combinator::{map, map_res, value};
-use nom::sequence::{pair, preceded, separated_pair, terminated};
+use nom::bytes::complete::{is_not, tag, tag_no_case, take_while, take_while1};
+use nom::character::{is_alphanumeric, is_digit};
+use nom::combinator::{consumed, cut, map, map_opt, map_res, opt, value};
+use nom::multi::fold_many0;
+use nom::sequence::{delimited, pair, preceded, terminated};
 use nom::IResult;
 
-use crate::response::*;
 use crate::smtp::{Cmd, Credentials};
+use std::fmt;
 use std::str;
-use log::info;
 
 //----- Parser -----------------------------------------------------------------
 
+// Command lines longer than this are rejected. This is more generous than the
+// 512 octets of RFC 5321 to allow for extension parameters.
+pub(crate) const MAX_COMMAND_LINE: usize = 2048;
+
+// Commands that are recognized and whether they need a parameter
+const COMMANDS: [(&str, bool); 13] = [
+    ("helo", true),
+    ("ehlo", true),
+    ("mail", true),
+    ("rcpt", true),
+    ("data", false),
+    ("rset", false),
+    ("quit", false),
+    ("vrfy", true),
+    ("noop", false),
+    ("starttls", false),
+    ("auth", true),
+    ("bdat", true),
+    ("help", false),
+];
+
+/// The reasons that a line from the client cannot be parsed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// A known command, given in lowercase, without its required parameter
+    Incomplete(&'static str),
+    /// A known command, given in lowercase, with invalid syntax
+    Syntax(&'static str),
+    /// The command is not recognized
+    UnknownCommand,
+    /// The line is longer than the command line limit
+    TooLong,
+    /// A known command, given in lowercase, with a parameter that is not valid base64
+    Base64(&'static str),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Incomplete(command) => {
+                write!(f, "{} without a parameter", command.to_uppercase())
+            }
+            ParseError::Syntax(command) => write!(f, "invalid {} syntax", command.to_uppercase()),
+            ParseError::UnknownCommand => write!(f, "unknown command"),
+            ParseError::TooLong => write!(f, "command line too long"),
+            ParseError::Base64(command) => {
+                write!(f, "invalid base64 in {}", command.to_uppercase())
+            }
+        }
+    }
+}
+
 // Parse a line from the client
-pub fn parse(line: &[u8]) -> Result<Cmd, Response> {
-    command(line).map(|r| r.1).map_err(|e| match e {
-        nom::Err::Incomplete(_) => MISSING_PARAMETER,
-        nom::Err::Error(_) => SYNTAX_ERROR,
-        nom::Err::Failure(_) => SYNTAX_ERROR,
-    })
+pub fn parse(line: &[u8]) -> Result<Cmd<'_>, ParseError> {
+    if line.len() > MAX_COMMAND_LINE {
+        return Err(ParseError::TooLong);
+    }
+    match command(line) {
+        Ok((_, cmd)) => Ok(cmd),
+        // Only a badly encoded AUTH initial response stops the parser with a failure
+        Err(nom::Err::Failure(_)) => Err(ParseError::Base64("auth")),
+        Err(_) => Err(classify_error(line)),
+    }
 }
 
 // Parse an authentication response from the client
-pub fn parse_auth_response(line: &[u8]) -> Result<&[u8], Response> {
-    auth_response(line).map(|r| r.1).map_err(|_| SYNTAX_ERROR)
+pub fn parse_auth_response(line: &[u8]) -> Result<&[u8], ParseError> {
+    auth_response(line)
+        .map(|r| r.1)
+        .map_err(|_| ParseError::Syntax("auth"))
+}
+
+// Find out why a line that is not a valid command failed to parse
+fn classify_error(line: &[u8]) -> ParseError {
+    let line = line.strip_suffix(b"\r\n").unwrap_or(line);
+    let verb_end = line.iter().position(|b| *b == b' ').unwrap_or(line.len());
+    let (verb, args) = line.split_at(verb_end);
+    let command = COMMANDS
+        .iter()
+        .find(|(name, _)| verb.eq_ignore_ascii_case(name.as_bytes()));
+    match command {
+        None => ParseError::UnknownCommand,
+        Some((name, true)) if args.iter().all(|b| *b == b' ') => ParseError::Incomplete(name),
+        Some((name, _)) => ParseError::Syntax(name),
+    }
 }
 
-fn command(buf: &[u8]) -> IResult<&[u8], Cmd> {
+fn command(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
     terminated(
         alt((
-            helo, ehlo, mail, rcpt, data, rset, quit, vrfy, noop, starttls, auth,
+            helo, ehlo, mail, rcpt, data, rset, quit, vrfy, noop, starttls, auth, bdat, help,
         )),
         tag(b"\r\n"),
     )(buf)
@@ -39,12 +112,12 @@ fn hello_domain(buf: &[u8]) -> IResult<&[u8], &str> {
     map_res(is_not(b" \t\r\n" as &[u8]), str::from_utf8)(buf)
 }
 
-fn helo(buf: &[u8]) -> IResult<&[u8], Cmd> {
+fn helo(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
     let parse_domain = preceded(cmd(b"helo"), hello_domain);
     map(parse_domain, |domain| Cmd::Helo { domain })(buf)
 }
 
-fn ehlo(buf: &[u8]) -> IResult<&[u8], Cmd> {
+fn ehlo(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
     let parse_domain = preceded(cmd(b"ehlo"), hello_domain);
     map(parse_domain, |domain| Cmd::Ehlo { domain })(buf)
 }
@@ -57,91 +130,181 @@ fn take_all(buf: &[u8]) -> IResult<&[u8], &str> {
     map_res(is_not(b"\r\n" as &[u8]), str::from_utf8)(buf)
 }
 
-fn body_eq_8bit(buf: &[u8]) -> IResult<&[u8], bool> {
-    let preamble = pair(space, tag_no_case(b"body="));
-    let is8bit = alt((
-        value(true, tag_no_case(b"8bitmime")),
-        value(false, tag_no_case(b"7bit")),
-    ));
-    preceded(preamble, is8bit)(buf)
-}
-
-fn is8bitmime(buf: &[u8]) -> IResult<&[u8], bool> {
-    body_eq_8bit(buf).or(Ok((buf, false)))
-}
-
-fn mail(buf: &[u8]) -> IResult<&[u8], Cmd> {
-    let r1 = {
-        let preamble = pair(cmd(b"mail"), tag_no_case(b"from:<"));
-        let mail_path_parser = preceded(preamble, mail_path);
-        let parser = separated_pair(mail_path_parser, tag(b">"), is8bitmime);
-        map(parser, |r| Cmd::Mail {
-            reverse_path: r.0,
-            is8bit: r.1,
-        })(buf)
-    };
-
-    if r1.is_err(){
-        let r2 = {
-            // info!("fuck qq");
-            let preamble = pair(cmd(b"mail"), tag_no_case(b"from: <"));
-            let mail_path_parser = preceded(preamble, mail_path);
-            let parser = separated_pair(mail_path_parser, tag(b">"), is8bitmime);
-            map(parser, |r| Cmd::Mail {
-                reverse_path: r.0,
-                is8bit: r.1,
-            })(buf)
-        };
-        return r2;
+// ESMTP parameters as keyword and value pairs
+type Params = Vec<(String, String)>;
+
+// An ESMTP parameter after a path, e.g SIZE=1000. Keywords are returned in
+// uppercase and a parameter without a value has an empty value.
+fn esmtp_param(buf: &[u8]) -> IResult<&[u8], (String, String)> {
+    let keyword = map_res(
+        take_while1(|c: u8| c.is_ascii_alphanumeric() || c == b'-'),
+        str::from_utf8,
+    );
+    let value = preceded(tag(b"="), alt((quoted_value, esmtp_value)));
+    let param = pair(keyword, opt(value));
+    map(preceded(space, param), |(keyword, value)| {
+        (
+            keyword.to_ascii_uppercase(),
+            value.unwrap_or_default().to_string(),
+        )
+    })(buf)
+}
+
+fn esmtp_value(buf: &[u8]) -> IResult<&[u8], &str> {
+    map_res(
+        take_while1(|c: u8| c > b' ' && c < 127 && c != b'='),
+        str::from_utf8,
+    )(buf)
+}
+
+fn quoted_value(buf: &[u8]) -> IResult<&[u8], &str> {
+    let inner = map_res(
+        take_while(|c| c != b'"' && c != b'\r' && c != b'\n'),
+        str::from_utf8,
+    );
+    delimited(tag(b"\""), inner, tag(b"\""))(buf)
+}
+
+fn esmtp_params(buf: &[u8]) -> IResult<&[u8], Params> {
+    fold_many0(esmtp_param, Vec::new, |mut acc, param| {
+        acc.push(param);
+        acc
+    })(buf)
+}
+
+// The MAIL FROM parameters that the server understands
+#[derive(Default)]
+struct MailOptions {
+    is8bit: bool,
+    smtputf8: bool,
+    // The declared message size
+    size: Option<usize>,
+}
+
+// Check the MAIL FROM parameters that the server understands
+fn mail_options(params: &Params) -> Result<MailOptions, ()> {
+    let mut options = MailOptions::default();
+    for (keyword, value) in params {
+        match keyword.as_str() {
+            "BODY" if value.eq_ignore_ascii_case("8bitmime") => options.is8bit = true,
+            "BODY" if value.eq_ignore_ascii_case("7bit") => options.is8bit = false,
+            "BODY" => return Err(()),
+            "SIZE" => options.size = Some(value.parse().map_err(|_| ())?),
+            // SMTPUTF8 has no value (RFC 6531)
+            "SMTPUTF8" if value.is_empty() => options.smtputf8 = true,
+            "SMTPUTF8" => return Err(()),
+            _ => (),
+        }
     }
+    Ok(options)
+}
 
-    return r1;
+// The parameters of MAIL FROM, with the options that the server understands
+fn mail_params(buf: &[u8]) -> IResult<&[u8], (Params, MailOptions)> {
+    map_res(esmtp_params, |params| {
+        mail_options(&params).map(|options| (params, options))
+    })(buf)
 }
 
-fn rcpt(buf: &[u8]) -> IResult<&[u8], Cmd> {
-    let r1 = {
-    let preamble = pair(cmd(b"rcpt"), tag_no_case(b"to:<"));
-    let mail_path_parser = preceded(preamble, mail_path);
-    let parser = terminated(mail_path_parser, tag(b">"));
-    map(parser, |path| Cmd::Rcpt { forward_path: path })(buf)
-    };
+// A path in angle brackets. Returns the mailbox, without any source route,
+// and the raw path as sent by the client.
+fn path(buf: &[u8]) -> IResult<&[u8], (&str, &str)> {
+    let bracketed = delimited(tag(b"<"), mail_path, tag(b">"));
+    map_res(consumed(bracketed), |(raw, mailbox)| {
+        str::from_utf8(raw).map(|raw| (strip_source_route(mailbox), raw))
+    })(buf)
+}
 
-    if r1.is_err(){
-        let r2 = {
-            let preamble = pair(cmd(b"rcpt"), tag_no_case(b"to: <"));
-            let mail_path_parser = preceded(preamble, mail_path);
-            let parser = terminated(mail_path_parser, tag(b">"));
-            map(parser, |path| Cmd::Rcpt { forward_path: path })(buf)
-        };
-        return r2;
+// The reverse path of MAIL FROM, which is empty for bounces, e.g MAIL FROM:<>
+fn reverse_path(buf: &[u8]) -> IResult<&[u8], (&str, &str)> {
+    let null_path = value(("", "<>"), tag(b"<>"));
+    alt((path, null_path))(buf)
+}
+
+// Source routes, e.g <@relay:user@domain>, must be accepted but are ignored (RFC 5321 4.1.1.3)
+fn strip_source_route(mailbox: &str) -> &str {
+    match mailbox.split_once(':') {
+        Some((_, rest)) if mailbox.starts_with('@') => rest,
+        _ => mailbox,
     }
+}
 
-    return r1;
+fn mail(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
+    // Some clients, e.g qq.com, send a space before the path
+    let from = pair(tag_no_case(b"from:"), opt(tag(b" ")));
+    let preamble = pair(cmd(b"mail"), from);
+    let parser = pair(preceded(preamble, reverse_path), mail_params);
+    let parser = terminated(parser, trailing_comment);
+    map(parser, |((reverse_path, raw_path), (params, options))| {
+        Cmd::Mail {
+            reverse_path,
+            raw_path,
+            is8bit: options.is8bit,
+            smtputf8: options.smtputf8,
+            size: options.size,
+            params,
+        }
+    })(buf)
+}
+
+fn rcpt(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
+    let to = pair(tag_no_case(b"to:"), opt(tag(b" ")));
+    let preamble = pair(cmd(b"rcpt"), to);
+    let parser = pair(preceded(preamble, path), esmtp_params);
+    let parser = terminated(parser, trailing_comment);
+    map(parser, |((forward_path, raw_path), params)| Cmd::Rcpt {
+        forward_path,
+        raw_path,
+        params,
+    })(buf)
 }
 
-fn data(buf: &[u8]) -> IResult<&[u8], Cmd> {
-    value(Cmd::Data, tag_no_case(b"data"))(buf)
+// Ignore an optional comment in parentheses at the end of a command
+fn trailing_comment(buf: &[u8]) -> IResult<&[u8], Option<&[u8]>> {
+    let comment = delimited(tag(b"("), take_while(|b| b != b')'), tag(b")"));
+    opt(preceded(space, comment))(buf)
 }
 
-fn rset(buf: &[u8]) -> IResult<&[u8], Cmd> {
-    value(Cmd::Rset, tag_no_case(b"rset"))(buf)
+fn data(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
+    value(Cmd::Data, bare_cmd(b"data"))(buf)
 }
 
-fn quit(buf: &[u8]) -> IResult<&[u8], Cmd> {
-    value(Cmd::Quit, tag_no_case(b"quit"))(buf)
+fn rset(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
+    value(Cmd::Rset, bare_cmd(b"rset"))(buf)
 }
 
-fn vrfy(buf: &[u8]) -> IResult<&[u8], Cmd> {
+fn quit(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
+    value(Cmd::Quit, bare_cmd(b"quit"))(buf)
+}
+
+fn vrfy(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
     let preamble = preceded(cmd(b"vrfy"), take_all);
     value(Cmd::Vrfy, preamble)(buf)
 }
 
-fn noop(buf: &[u8]) -> IResult<&[u8], Cmd> {
-    value(Cmd::Noop, tag_no_case(b"noop"))(buf)
+fn noop(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
+    value(Cmd::Noop, bare_cmd(b"noop"))(buf)
+}
+
+fn starttls(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
+    value(Cmd::StartTls, bare_cmd(b"starttls"))(buf)
 }
 
-fn starttls(buf: &[u8]) -> IResult<&[u8], Cmd> {
-    value(Cmd::StartTls, tag_no_case(b"starttls"))(buf)
+// HELP with an optional topic, which is ignored
+fn help(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
+    let topic = opt(preceded(space, opt(take_all)));
+    value(Cmd::Help, pair(tag_no_case(b"help"), topic))(buf)
+}
+
+// BDAT <size> [LAST] (RFC 3030)
+fn bdat(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
+    let size = map_res(map_res(take_while1(is_digit), str::from_utf8), str::parse);
+    let last = opt(preceded(space, bare_cmd(b"last")));
+    let parser = preceded(cmd(b"bdat"), pair(size, last));
+    map(parser, |(size, last)| Cmd::Bdat {
+        size,
+        last: last.is_some(),
+    })(buf)
 }
 
 fn is_base64(chr: u8) -> bool {
@@ -160,33 +323,64 @@ fn empty(buf: &[u8]) -> IResult<&[u8], &[u8]> {
     Ok((buf, b"" as &[u8]))
 }
 
-fn auth_plain(buf: &[u8]) -> IResult<&[u8], Cmd> {
+fn auth_plain(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
     let parser = preceded(tag_no_case(b"plain"), alt((auth_initial, empty)));
     map(parser, sasl_plain_cmd)(buf)
 }
 
-fn auth_login(buf: &[u8]) -> IResult<&[u8], Cmd> {
-    let parser = preceded(tag_no_case(b"login"), alt((auth_initial, empty)));
-    map(parser, sasl_login_cmd)(buf)
+fn auth_login(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
+    // An initial response that is not a valid username is a syntax error
+    let initial = map_opt(alt((auth_initial, empty)), sasl_login_cmd);
+    preceded(tag_no_case(b"login"), cut(initial))(buf)
+}
+
+fn auth_cram_md5(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
+    value(Cmd::AuthCramMd5, tag_no_case(b"cram-md5"))(buf)
 }
 
-fn auth(buf: &[u8]) -> IResult<&[u8], Cmd> {
-    preceded(cmd(b"auth"), alt((auth_plain, auth_login)))(buf)
+fn is_sasl_mech(chr: u8) -> bool {
+    is_alphanumeric(chr) || chr == b'-' || chr == b'_'
+}
+
+// Any other SASL mechanism, with an optional initial response (RFC 4954)
+fn auth_unknown(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
+    let parser = pair(take_while1(is_sasl_mech), alt((auth_initial, empty)));
+    value(Cmd::AuthUnknown, parser)(buf)
+}
+
+fn auth(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
+    preceded(
+        cmd(b"auth"),
+        alt((auth_plain, auth_login, auth_cram_md5, auth_unknown)),
+    )(buf)
 }
 
 //---- Helper functions ---------------------------------------------------------
 
+// A command verb and the space that follows it
+type Verb<'a> = (&'a [u8], &'a [u8]);
+
 // Return a parser to match the given command
-fn cmd(cmd_tag: &[u8]) -> impl Fn(&[u8]) -> IResult<&[u8], (&[u8], &[u8])> + '_ {
+fn cmd(cmd_tag: &[u8]) -> impl Fn(&[u8]) -> IResult<&[u8], Verb<'_>> + '_ {
     move |buf: &[u8]| pair(tag_no_case(cmd_tag), space)(buf)
 }
 
+// Match a command without parameters. Some clients send trailing whitespace.
+fn bare_cmd(cmd_tag: &[u8]) -> impl Fn(&[u8]) -> IResult<&[u8], &[u8]> + '_ {
+    move |buf: &[u8]| {
+        terminated(
+            tag_no_case(cmd_tag),
+            take_while(|b| b == b' ' || b == b'\t'),
+        )(buf)
+    }
+}
+
 // Match one or more spaces
 fn space(buf: &[u8]) -> IResult<&[u8], &[u8]> {
     take_while1(|b| b == b' ')(buf)
 }
 
-fn sasl_plain_cmd(param: &[u8]) -> Cmd {
+fn sasl_plain_cmd(param: &[u8]) -> Cmd<'_> {
     if param.is_empty() {
         Cmd::AuthPlainEmpty
     } else {
@@ -199,13 +393,11 @@ fn sasl_plain_cmd(param: &[u8]) -> Cmd {
     }
 }
 
-fn sasl_login_cmd(param: &[u8]) -> Cmd {
+fn sasl_login_cmd(param: &[u8]) -> Option<Cmd<'_>> {
     if param.is_empty() {
-        Cmd::AuthLoginEmpty
+        Some(Cmd::AuthLoginEmpty)
     } else {
-        Cmd::AuthLogin {
-            username: decode_sasl_login(param),
-        }
+        decode_sasl_login(param).map(|username| Cmd::AuthLogin { username })
     }
 }
 
@@ -232,10 +424,10 @@ pub(crate) fn decode_sasl_plain(param: &[u8]) -> Credentials {
 }
 
 // Decodes base64 encoded login authentication parameters (in login auth, username and password are
-// sent in separate lines)
-pub(crate) fn decode_sasl_login(param: &[u8]) -> String {
-    let decoded = base64::decode(param).unwrap_or_default();
-    String::from_utf8(decoded).unwrap_or_default()
+// sent in separate lines). Returns None if the parameter is empty or not valid base64.
+pub(crate) fn decode_sasl_login(param: &[u8]) -> Option<String> {
+    let decoded = base64::decode(param).ok()?;
+    String::from_utf8(decoded).ok().filter(|s| !s.is_empty())
 }
 
 fn next_string(it: &mut dyn Iterator<Item = &[u8]>) -> String {
@@ -279,6 +471,18 @@ mod tests {
         };
     }
 
+    #[test]
+    fn auth_invalid_initial_login() {
+        assert_eq!(
+            parse(b"auth login ZHVtb\r\n").err(),
+            Some(ParseError::Base64("auth"))
+        );
+        assert_eq!(
+            parse(b"auth login =\r\n").err(),
+            Some(ParseError::Base64("auth"))
+        );
+    }
+
     #[test]
     fn auth_empty_plain() {
         let res = parse(b"auth plain\r\n");
@@ -296,6 +500,274 @@ mod tests {
             _ => panic!("Auth login without initial response incorrectly parsed"),
         };
     }
+
+    #[test]
+    fn auth_cram_md5() {
+        let res = parse(b"AUTH CRAM-MD5\r\n");
+        match res {
+            Ok(Cmd::AuthCramMd5) => {}
+            _ => panic!("Auth cram-md5 incorrectly parsed"),
+        };
+    }
+
+    #[test]
+    fn auth_unknown() {
+        let res = parse(b"auth gssapi\r\n");
+        assert!(matches!(res, Ok(Cmd::AuthUnknown)), "{:?}", res);
+        let res = parse(b"AUTH SCRAM-SHA-256 biwsbj11c2Vy\r\n");
+        assert!(matches!(res, Ok(Cmd::AuthUnknown)), "{:?}", res);
+    }
+    #[test]
+    fn rcpt_comment() {
+        let res = parse(b"RCPT TO:<a@b> (internal note)\r\n");
+        match res {
+            Ok(Cmd::Rcpt { forward_path, .. }) => assert_eq!(forward_path, "a@b"),
+            _ => panic!("Rcpt with comment incorrectly parsed"),
+        };
+    }
+
+    #[test]
+    fn mail_comment() {
+        let res = parse(b"MAIL FROM:<a@b> BODY=8BITMIME (sent by c)\r\n");
+        match res {
+            Ok(Cmd::Mail {
+                reverse_path,
+                is8bit,
+                ..
+            }) => {
+                assert_eq!(reverse_path, "a@b");
+                assert!(is8bit);
+            }
+            _ => panic!("Mail with comment incorrectly parsed"),
+        };
+    }
+
+    #[test]
+    fn mail_size() {
+        let res = parse(b"MAIL FROM:<a@b> SIZE=1000 BODY=8BITMIME\r\n");
+        match res {
+            Ok(Cmd::Mail { is8bit, size, .. }) => {
+                assert!(is8bit);
+                assert_eq!(size, Some(1000));
+            }
+            _ => panic!("Mail with size incorrectly parsed"),
+        };
+    }
+
+    #[test]
+    fn mail_smtputf8() {
+        let res = parse("MAIL FROM:<船@海.com> SMTPUTF8\r\n".as_bytes());
+        match res {
+            Ok(Cmd::Mail {
+                reverse_path,
+                smtputf8,
+                ..
+            }) => {
+                assert_eq!(reverse_path, "船@海.com");
+                assert!(smtputf8);
+            }
+            _ => panic!("Mail with SMTPUTF8 incorrectly parsed"),
+        };
+        assert!(parse(b"MAIL FROM:<a@b> SMTPUTF8=yes\r\n").is_err());
+    }
+
+    #[test]
+    fn null_reverse_path() {
+        let res = parse(b"MAIL FROM:<>\r\n");
+        match res {
+            Ok(Cmd::Mail {
+                reverse_path,
+                raw_path,
+                is8bit,
+                ..
+            }) => {
+                assert_eq!(reverse_path, "");
+                assert_eq!(raw_path, "<>");
+                assert!(!is8bit);
+            }
+            _ => panic!("Mail with null path incorrectly parsed"),
+        };
+        let res = parse(b"MAIL FROM:<> BODY=8BITMIME\r\n");
+        match res {
+            Ok(Cmd::Mail {
+                reverse_path,
+                is8bit,
+                ..
+            }) => {
+                assert_eq!(reverse_path, "");
+                assert!(is8bit);
+            }
+            _ => panic!("Mail with null path and body incorrectly parsed"),
+        };
+        let res = parse(b"MAIL FROM:<a@b>\r\n");
+        match res {
+            Ok(Cmd::Mail { reverse_path, .. }) => assert_eq!(reverse_path, "a@b"),
+            _ => panic!("Mail incorrectly parsed"),
+        };
+        // Only the reverse path can be empty
+        assert_eq!(
+            parse(b"RCPT TO:<>\r\n").err(),
+            Some(ParseError::Syntax("rcpt"))
+        );
+    }
+
+    #[test]
+    fn source_route() {
+        let res = parse(b"MAIL FROM:<@relay:user@domain>\r\n");
+        match res {
+            Ok(Cmd::Mail {
+                reverse_path,
+                raw_path,
+                ..
+            }) => {
+                assert_eq!(reverse_path, "user@domain");
+                assert_eq!(raw_path, "<@relay:user@domain>");
+            }
+            _ => panic!("Mail with source route incorrectly parsed"),
+        };
+        let res = parse(b"RCPT TO:<@a,@b:user@domain>\r\n");
+        match res {
+            Ok(Cmd::Rcpt {
+                forward_path,
+                raw_path,
+                ..
+            }) => {
+                assert_eq!(forward_path, "user@domain");
+                assert_eq!(raw_path, "<@a,@b:user@domain>");
+            }
+            _ => panic!("Rcpt with source route incorrectly parsed"),
+        };
+    }
+
+    #[test]
+    fn esmtp_parameters() {
+        let res =
+            parse(b"MAIL FROM:<a@b> size=1000 ret=HDRS ENVID=\"QQ 123\" SMTPUTF8 X-CUSTOM=1\r\n");
+        match res {
+            Ok(Cmd::Mail { size, params, .. }) => {
+                assert_eq!(size, Some(1000));
+                let expected = vec![
+                    ("SIZE", "1000"),
+                    ("RET", "HDRS"),
+                    ("ENVID", "QQ 123"),
+                    ("SMTPUTF8", ""),
+                    ("X-CUSTOM", "1"),
+                ];
+                let params: Vec<(&str, &str)> = params
+                    .iter()
+                    .map(|(k, v)| (k.as_str(), v.as_str()))
+                    .collect();
+                assert_eq!(params, expected);
+            }
+            _ => panic!("Mail with parameters incorrectly parsed"),
+        };
+        let res = parse(b"RCPT TO:<c@d> NOTIFY=SUCCESS,FAILURE ORCPT=rfc822;c@d\r\n");
+        match res {
+            Ok(Cmd::Rcpt { params, .. }) => {
+                assert_eq!(
+                    params,
+                    vec![
+                        ("NOTIFY".to_string(), "SUCCESS,FAILURE".to_string()),
+                        ("ORCPT".to_string(), "rfc822;c@d".to_string()),
+                    ]
+                );
+            }
+            _ => panic!("Rcpt with parameters incorrectly parsed"),
+        };
+        assert!(parse(b"MAIL FROM:<a@b> BODY=BINARYMIME\r\n").is_err());
+        assert!(parse(b"MAIL FROM:<a@b> SIZE=big\r\n").is_err());
+    }
+
+    #[test]
+    fn trailing_whitespace() {
+        assert!(matches!(parse(b"RSET \r\n"), Ok(Cmd::Rset)));
+        assert!(matches!(parse(b"QUIT  \r\n"), Ok(Cmd::Quit)));
+        assert!(matches!(parse(b"NOOP\t\r\n"), Ok(Cmd::Noop)));
+        assert!(matches!(parse(b"data \r\n"), Ok(Cmd::Data)));
+        assert_eq!(parse(b"rset x\r\n").err(), Some(ParseError::Syntax("rset")));
+    }
+
+    #[test]
+    fn bdat_command() {
+        assert!(matches!(
+            parse(b"BDAT 1000\r\n"),
+            Ok(Cmd::Bdat {
+                size: 1000,
+                last: false
+            })
+        ));
+        assert!(matches!(
+            parse(b"bdat 0 last\r\n"),
+            Ok(Cmd::Bdat {
+                size: 0,
+                last: true
+            })
+        ));
+        assert!(matches!(
+            parse(b"BDAT 12 LAST \r\n"),
+            Ok(Cmd::Bdat {
+                size: 12,
+                last: true
+            })
+        ));
+        assert_eq!(
+            parse(b"bdat\r\n").err(),
+            Some(ParseError::Incomplete("bdat"))
+        );
+        assert_eq!(
+            parse(b"bdat big\r\n").err(),
+            Some(ParseError::Syntax("bdat"))
+        );
+        assert_eq!(
+            parse(b"bdat 10 first\r\n").err(),
+            Some(ParseError::Syntax("bdat"))
+        );
+    }
+
+    #[test]
+    fn help_command() {
+        assert!(matches!(parse(b"HELP\r\n"), Ok(Cmd::Help)));
+        assert!(matches!(parse(b"help mail\r\n"), Ok(Cmd::Help)));
+        assert!(matches!(parse(b"help \r\n"), Ok(Cmd::Help)));
+        assert_eq!(parse(b"helpme\r\n").err(), Some(ParseError::UnknownCommand));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(
+            parse(b"mail\r\n").err(),
+            Some(ParseError::Incomplete("mail"))
+        );
+        assert_eq!(
+            parse(b"HELO \r\n").err(),
+            Some(ParseError::Incomplete("helo"))
+        );
+        assert_eq!(
+            parse(b"mail to:<a@b>\r\n").err(),
+            Some(ParseError::Syntax("mail"))
+        );
+        assert_eq!(
+            parse(b"data now\r\n").err(),
+            Some(ParseError::Syntax("data"))
+        );
+        assert_eq!(
+            parse(b"helo a.domain").err(),
+            Some(ParseError::Syntax("helo"))
+        );
+        assert_eq!(
+            parse(b"etrn a.domain\r\n").err(),
+            Some(ParseError::UnknownCommand)
+        );
+        let mut long = b"helo ".to_vec();
+        long.resize(MAX_COMMAND_LINE, b'a');
+        long.extend_from_slice(b"\r\n");
+        assert_eq!(parse(&long).err(), Some(ParseError::TooLong));
+        assert_eq!(
+            ParseError::Incomplete("mail").to_string(),
+            "MAIL without a parameter"
+        );
+    }
+
     #[test]
     fn test_mail_from() {
         let res = parse(b"MAIL FROM:<823143047@qq.com>\r\n");
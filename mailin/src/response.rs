@@ -1,19 +1,27 @@
 use log::trace;
+use std::borrow::Cow;
 use std::io;
 
 // Empty response that sends nothing back to the client
 pub(crate) const EMPTY_RESPONSE: Response = Response::empty();
 // Start TLS handshake
-pub(crate) const START_TLS: Response =
-    Response::fixed_action(220, "Ready to start TLS", Action::UpgradeTls);
+pub(crate) const START_TLS: Response = Response::fixed_action(
+    220,
+    Some((2, 0, 0)),
+    "Ready to start TLS",
+    Action::UpgradeTls,
+);
 /// Response to indicate that the SMTP session finished
-pub const GOODBYE: Response = Response::fixed(221, "Goodbye");
+pub const GOODBYE: Response = Response::fixed_enhanced(221, (2, 0, 0), "Goodbye");
+/// Returned by `Handler::auth_plain` or `Handler::auth_login` to defer authentication
+/// until the result is passed to `Session::complete_auth`. Nothing is sent to the client.
+pub const AUTH_PENDING: Response = Response::no_reply("Authentication pending");
 /// Authentication succeeded
-pub const AUTH_OK: Response = Response::fixed(235, "Authentication succeeded");
+pub const AUTH_OK: Response = Response::fixed_enhanced(235, (2, 7, 0), "Authentication succeeded");
 /// OK response
-pub const OK: Response = Response::fixed(250, "OK");
+pub const OK: Response = Response::fixed_enhanced(250, (2, 0, 0), "OK");
 // Non-commital response to VERIFY command
-pub(crate) const VERIFY_RESPONSE: Response = Response::fixed(252, "Maybe");
+pub(crate) const VERIFY_RESPONSE: Response = Response::fixed_enhanced(252, (2, 0, 0), "Maybe");
 // Empty response sent as an auth challenge.
 pub(crate) const EMPTY_AUTH_CHALLENGE: Response = Response::fixed(334, "");
 // Username response sent as an auth challenge for the login mechanism.
@@ -26,37 +34,112 @@ pub(crate) const PASSWORD_AUTH_CHALLENGE: Response = Response::fixed(334, "UGFzc
 pub const START_DATA: Response = Response::fixed(354, "Start mail input; end with <CRLF>.<CRLF>");
 // State machine is not accepting commands
 pub(crate) const INVALID_STATE: Response =
-    Response::fixed(421, "Internal service error, closing connection");
+    Response::fixed_enhanced(421, (4, 3, 0), "Internal service error, closing connection");
+// Client sent too many commands that did not progress the session
+pub(crate) const TOO_MANY_NO_PROGRESS: Response =
+    Response::fixed_enhanced(421, (4, 7, 0), "Too many no-op commands");
+// The client reached the limit on failed authentication attempts
+pub(crate) const TOO_MANY_AUTH_FAILURES: Response =
+    Response::fixed_enhanced(421, (4, 7, 0), "Too many authentication failures");
+// The client reached the limit on messages per session
+pub(crate) const TOO_MANY_MESSAGES: Response =
+    Response::fixed_enhanced(421, (4, 7, 0), "Too many messages this session");
 /// Service not available
-pub const NO_SERVICE: Response = Response::fixed(421, "Service not available, closing connection");
+pub const NO_SERVICE: Response =
+    Response::fixed_enhanced(421, (4, 3, 2), "Service not available, closing connection");
 /// Internal server error
-pub const INTERNAL_ERROR: Response = Response::fixed(451, "Aborted: local error in processing");
+pub const INTERNAL_ERROR: Response =
+    Response::fixed_enhanced(451, (4, 3, 0), "Aborted: local error in processing");
 /// Insufficient system storage
-pub const OUT_OF_SPACE: Response = Response::fixed(452, "Insufficient system storage");
+pub const OUT_OF_SPACE: Response =
+    Response::fixed_enhanced(452, (4, 3, 1), "Insufficient system storage");
+/// Too many recipients in the transaction
+pub const TOO_MANY_RECIPIENTS: Response =
+    Response::fixed_enhanced(452, (4, 5, 3), "Too many recipients");
 /// Authentication system is not working
-pub const TEMP_AUTH_FAILURE: Response = Response::fixed(454, "Temporary authentication failure");
+pub const TEMP_AUTH_FAILURE: Response =
+    Response::fixed_enhanced(454, (4, 7, 0), "Temporary authentication failure");
 // Parser error
-pub(crate) const SYNTAX_ERROR: Response = Response::fixed(500, "Syntax error");
+pub(crate) const SYNTAX_ERROR: Response = Response::fixed_enhanced(500, (5, 5, 2), "Syntax error");
+// Parser found a parameter that is not valid base64
+pub(crate) const INVALID_BASE64: Response =
+    Response::fixed_enhanced(501, (5, 5, 2), "Invalid base64 encoding");
 // Parser found missing parameter
-pub(crate) const MISSING_PARAMETER: Response = Response::fixed(502, "Missing parameter");
+pub(crate) const MISSING_PARAMETER: Response =
+    Response::fixed_enhanced(502, (5, 5, 4), "Missing parameter");
+/// VRFY is turned off with `SessionBuilder::disable_vrfy`
+pub const VRFY_DISABLED: Response =
+    Response::fixed_enhanced(502, (5, 5, 1), "VRFY command disabled");
+// Parser did not recognize the command
+pub(crate) const UNKNOWN_COMMAND: Response =
+    Response::fixed_enhanced(500, (5, 5, 1), "Command not recognized");
+// Command line longer than the parser accepts
+pub(crate) const LINE_TOO_LONG: Response =
+    Response::fixed_enhanced(500, (5, 5, 0), "Line too long");
 // Command is unexpected for the current state
-pub(crate) const BAD_SEQUENCE_COMMANDS: Response = Response::fixed(503, "Bad sequence of commands");
+pub(crate) const BAD_SEQUENCE_COMMANDS: Response =
+    Response::fixed_enhanced(503, (5, 5, 1), "Bad sequence of commands");
+// AUTH with a mechanism that is not enabled
+pub(crate) const UNRECOGNIZED_AUTH: Response =
+    Response::fixed_enhanced(504, (5, 5, 4), "Unrecognized authentication type");
+// STARTTLS sent when TLS is already active or being negotiated
+pub(crate) const TLS_ALREADY_ACTIVE: Response =
+    Response::fixed_enhanced(503, (5, 5, 1), "TLS already active");
 /// User storage quota exceeded
-pub const NO_STORAGE: Response = Response::fixed(552, "Exceeded storage allocation");
+pub const NO_STORAGE: Response =
+    Response::fixed_enhanced(552, (5, 2, 2), "Exceeded storage allocation");
+// The message was larger than the size declared in MAIL FROM
+pub(crate) const DECLARED_SIZE_EXCEEDED: Response =
+    Response::fixed_enhanced(552, (5, 3, 4), "Message exceeds declared size");
+/// The message is larger than the maximum message size
+pub const MESSAGE_TOO_LARGE: Response =
+    Response::fixed_enhanced(552, (5, 3, 4), "Message size exceeds fixed maximum");
 /// Authentication required
-pub const AUTHENTICATION_REQUIRED: Response = Response::fixed(530, "Authentication required");
+pub const AUTHENTICATION_REQUIRED: Response =
+    Response::fixed_enhanced(530, (5, 7, 0), "Authentication required");
 /// Bad authentication attempt
-pub const INVALID_CREDENTIALS: Response = Response::fixed(535, "Invalid credentials");
+pub const INVALID_CREDENTIALS: Response =
+    Response::fixed_enhanced(535, (5, 7, 8), "Invalid credentials");
+// The client cancelled an authentication exchange
+pub(crate) const AUTH_CANCELLED: Response =
+    Response::fixed_enhanced(501, (5, 7, 0), "Authentication cancelled");
+/// Authentication is not allowed before TLS is active
+pub const ENCRYPTION_REQUIRED: Response = Response::fixed_enhanced(
+    538,
+    (5, 7, 11),
+    "Encryption required for requested authentication mechanism",
+);
 /// Unknown user
-pub const NO_MAILBOX: Response = Response::fixed(550, "Mailbox unavailable");
+pub const NO_MAILBOX: Response = Response::fixed_enhanced(550, (5, 1, 1), "Mailbox unavailable");
 /// Error with HELO
-pub const BAD_HELLO: Response = Response::fixed(550, "Bad HELO");
+pub const BAD_HELLO: Response = Response::fixed_enhanced(550, (5, 1, 0), "Bad HELO");
 /// IP address on blocklists
-pub const BLOCKED_IP: Response = Response::fixed(550, "IP address on blocklists");
+pub const BLOCKED_IP: Response =
+    Response::fixed_enhanced(550, (5, 7, 1), "IP address on blocklists");
+/// The server does not accept any mail
+pub const NO_MAIL_ACCEPTED: Response =
+    Response::fixed_enhanced(550, (5, 7, 1), "This server does not accept mail");
+/// Message is missing a header that the server requires
+pub const MISSING_HEADER: Response =
+    Response::fixed_enhanced(550, (5, 6, 0), "Missing required header");
 /// Invalid mailbox name
-pub const BAD_MAILBOX: Response = Response::fixed(553, "Mailbox name not allowed");
+pub const BAD_MAILBOX: Response =
+    Response::fixed_enhanced(553, (5, 1, 3), "Mailbox name not allowed");
+// Non-ASCII address in a transaction that did not use SMTPUTF8
+pub(crate) const NON_ASCII_ADDRESS: Response =
+    Response::fixed_enhanced(553, (5, 6, 7), "Non-ASCII addresses require SMTPUTF8");
+/// Empty message rejected
+pub const EMPTY_MESSAGE: Response =
+    Response::fixed_enhanced(554, (5, 6, 0), "Empty message rejected");
+/// Message has more MIME parts than the server accepts
+pub const TOO_MANY_PARTS: Response =
+    Response::fixed_enhanced(554, (5, 6, 0), "Too many MIME parts");
+// DATA sent before any recipient was accepted
+pub(crate) const NO_VALID_RECIPIENTS: Response =
+    Response::fixed_enhanced(554, (5, 5, 1), "No valid recipients");
 /// Error handling incoming message
-pub const TRANSACTION_FAILED: Response = Response::fixed(554, "Transaction failed");
+pub const TRANSACTION_FAILED: Response =
+    Response::fixed_enhanced(554, (5, 0, 0), "Transaction failed");
 
 /// Response contains a code and message to be sent back to the client
 #[derive(Clone, Debug, PartialEq)]
@@ -65,6 +148,9 @@ pub struct Response {
     pub code: u16,
     /// The text message
     message: Message,
+    /// The RFC 3463 enhanced status code, class.subject.detail, that is sent before
+    /// the message when the session enables ENHANCEDSTATUSCODES
+    pub enhanced: Option<(u8, u16, u16)>,
     /// Is the response an error response?
     pub is_error: bool,
     /// The action to take after sending the response to the client
@@ -92,44 +178,87 @@ pub enum Action {
     Reply,
 }
 
+/// ResponseClass is the category of a response, given by the first digit of the code
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum ResponseClass {
+    /// 2xx, the command was accepted
+    Positive,
+    /// 3xx, the command was accepted and more information is needed
+    Intermediate,
+    /// 4xx, the command failed but may succeed if retried later
+    TransientNegative,
+    /// 5xx, the command failed and should not be retried
+    PermanentNegative,
+}
+
 impl Response {
     // A response that uses a fixed static string
     pub(crate) const fn fixed(code: u16, message: &'static str) -> Self {
-        Self::fixed_action(code, message, Response::action_from_code(code))
+        Self::fixed_action(code, None, message, Response::action_from_code(code))
     }
 
     const fn action_from_code(code: u16) -> Action {
         match code {
-            221 | 421 => Action::Close,
+            221 | 421 | 521 => Action::Close,
             _ => Action::Reply,
         }
     }
 
     // A response that uses a fixed static string and a given action
-    pub(crate) const fn fixed_action(code: u16, message: &'static str, action: Action) -> Self {
+    pub(crate) const fn fixed_action(
+        code: u16,
+        enhanced: Option<(u8, u16, u16)>,
+        message: &'static str,
+        action: Action,
+    ) -> Self {
         Self {
             code,
             message: Message::Fixed(message),
+            enhanced,
             is_error: (code < 200 || code >= 400),
             action,
         }
     }
 
+    // A response that uses a fixed static string and has an enhanced status code
+    pub(crate) const fn fixed_enhanced(
+        code: u16,
+        enhanced: (u8, u16, u16),
+        message: &'static str,
+    ) -> Self {
+        Self::fixed_action(
+            code,
+            Some(enhanced),
+            message,
+            Response::action_from_code(code),
+        )
+    }
+
     /// Create an application defined response.
+    /// Line breaks in the message are replaced with spaces when the response is
+    /// written, so a message built from client input cannot add lines to the reply.
     pub const fn custom(code: u16, message: String) -> Self {
         Self {
             code,
             message: Message::Custom(message),
+            enhanced: None,
             is_error: (code < 200 || code >= 400),
             action: Response::action_from_code(code),
         }
     }
 
+    /// Add an RFC 3463 enhanced status code, e.g `(5, 1, 1)` for a bad mailbox
+    pub fn with_enhanced_code(mut self, class: u8, subject: u16, detail: u16) -> Self {
+        self.enhanced = Some((class, subject, detail));
+        self
+    }
+
     // A response that is built dynamically and can be a multiline response
     pub(crate) fn dynamic(code: u16, head: String, tail: Vec<String>) -> Self {
         Self {
             code,
             message: Message::Dynamic(head, tail),
+            enhanced: None,
             is_error: false,
             action: Action::Reply,
         }
@@ -140,30 +269,67 @@ impl Response {
         Self {
             code: 0,
             message: Message::Empty,
+            enhanced: None,
             is_error: false,
             action: Action::NoReply,
         }
     }
 
+    // A response that is not sent to the client but is distinguished by its message
+    const fn no_reply(message: &'static str) -> Self {
+        Self {
+            code: 0,
+            message: Message::Fixed(message),
+            enhanced: None,
+            is_error: false,
+            action: Action::NoReply,
+        }
+    }
+
+    /// Get the class of the response from its code
+    pub fn class(&self) -> ResponseClass {
+        match self.code {
+            300..=399 => ResponseClass::Intermediate,
+            400..=499 => ResponseClass::TransientNegative,
+            500..=599 => ResponseClass::PermanentNegative,
+            _ => ResponseClass::Positive,
+        }
+    }
+
+    /// Is the response a temporary failure (4xx) that can be retried?
+    pub fn is_temporary(&self) -> bool {
+        self.class() == ResponseClass::TransientNegative
+    }
+
+    /// Is the response a permanent failure (5xx)?
+    pub fn is_permanent(&self) -> bool {
+        self.class() == ResponseClass::PermanentNegative
+    }
+
     /// Write the response to the given writer
     pub fn write_to(&self, out: &mut dyn io::Write) -> io::Result<()> {
+        let status = match self.enhanced {
+            Some((class, subject, detail)) => format!("{}.{}.{} ", class, subject, detail),
+            None => String::new(),
+        };
         match &self.message {
             Message::Dynamic(ref head, ref tail) => {
                 if tail.is_empty() {
-                    write!(out, "{} {}\r\n", self.code, head)?;
+                    write!(out, "{} {}{}\r\n", self.code, status, one_line(head))?;
                 } else {
-                    write!(out, "{}-{}\r\n", self.code, head)?;
+                    write!(out, "{}-{}{}\r\n", self.code, status, one_line(head))?;
                     for i in 0..tail.len() {
+                        let line = one_line(&tail[i]);
                         if tail.len() > 1 && i < tail.len() - 1 {
-                            write!(out, "{}-{}\r\n", self.code, tail[i])?;
+                            write!(out, "{}-{}{}\r\n", self.code, status, line)?;
                         } else {
-                            write!(out, "{} {}\r\n", self.code, tail[i])?;
+                            write!(out, "{} {}{}\r\n", self.code, status, line)?;
                         }
                     }
                 }
             }
-            Message::Fixed(s) => write!(out, "{} {}\r\n", self.code, s)?,
-            Message::Custom(s) => write!(out, "{} {}\r\n", self.code, s)?,
+            Message::Fixed(s) => write!(out, "{} {}{}\r\n", self.code, status, s)?,
+            Message::Custom(s) => write!(out, "{} {}{}\r\n", self.code, status, one_line(s))?,
             Message::Empty => (),
         };
         Ok(())
@@ -187,3 +353,52 @@ impl Response {
         }
     }
 }
+
+// Replace line breaks so that the text cannot inject extra lines into a response
+fn one_line(text: &str) -> Cow<'_, str> {
+    if text.contains(['\r', '\n']) {
+        Cow::Owned(text.replace(['\r', '\n'], " "))
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn response_class() {
+        assert_eq!(OK.class(), ResponseClass::Positive);
+        assert_eq!(START_DATA.class(), ResponseClass::Intermediate);
+        let busy = Response::custom(450, "Mailbox busy".to_string());
+        assert_eq!(busy.class(), ResponseClass::TransientNegative);
+        assert!(busy.is_temporary());
+        assert!(!busy.is_permanent());
+        assert_eq!(NO_MAILBOX.class(), ResponseClass::PermanentNegative);
+        assert!(NO_MAILBOX.is_permanent());
+        assert!(!NO_MAILBOX.is_temporary());
+        assert!(!OK.is_temporary() && !OK.is_permanent());
+    }
+
+    #[test]
+    fn no_line_injection() {
+        let res = Response::custom(550, "No such user <a@b.com>\r\n250 OK".to_string());
+        assert_eq!(
+            res.buffer().unwrap(),
+            b"550 No such user <a@b.com>  250 OK\r\n"
+        );
+        let res = Response::dynamic(250, "head\n".to_string(), vec!["a\rb".to_string()]);
+        assert_eq!(res.buffer().unwrap(), b"250-head \r\n250 a b\r\n");
+    }
+
+    #[test]
+    fn enhanced_status_code() {
+        assert_eq!(OK.buffer().unwrap(), b"250 2.0.0 OK\r\n");
+        let mut ok = OK;
+        ok.enhanced = None;
+        assert_eq!(ok.buffer().unwrap(), b"250 OK\r\n");
+        let res = Response::custom(550, "No such user".to_string()).with_enhanced_code(5, 1, 1);
+        assert_eq!(res.buffer().unwrap(), b"550 5.1.1 No such user\r\n");
+    }
+}
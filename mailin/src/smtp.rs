@@ -1,7 +1,10 @@
+use std::mem;
 use std::net::IpAddr;
 use std::str;
+use std::sync::Arc;
 
 use crate::fsm::StateMachine;
+use crate::parser::{ParseError, MAX_COMMAND_LINE};
 use crate::response::*;
 use crate::{AuthMechanism, Handler};
 use either::{Left, Right};
@@ -19,17 +22,28 @@ pub enum Cmd<'a> {
     },
     Mail {
         reverse_path: &'a str,
+        raw_path: &'a str,
         is8bit: bool,
+        smtputf8: bool,
+        size: Option<usize>,
+        params: Vec<(String, String)>,
     },
     Rcpt {
         forward_path: &'a str,
+        raw_path: &'a str,
+        params: Vec<(String, String)>,
     },
     Data,
+    Bdat {
+        size: usize,
+        last: bool,
+    },
     Rset,
     Noop,
     StartTls,
     Quit,
     Vrfy,
+    Help,
     AuthLogin {
         username: String,
     },
@@ -40,14 +54,70 @@ pub enum Cmd<'a> {
     },
     AuthLoginEmpty,
     AuthPlainEmpty,
+    AuthCramMd5,
+    // AUTH with a mechanism that the server does not implement
+    AuthUnknown,
     // Dummy command containing client authentication
     AuthResponse {
         response: &'a [u8],
     },
+    // Dummy command sent when the client cancels authentication with "*"
+    AuthCancel,
     // Dummy command to signify end of data
     DataEnd,
+    // Dummy command sent when a BDAT chunk, other than the last, has been received
+    ChunkEnd,
     // Dummy command sent when STARTTLS was successful
     StartedTls,
+    // Dummy command carrying the result of a deferred authentication
+    AuthComplete {
+        response: Response,
+    },
+    // Dummy command for a line that could not be parsed
+    Invalid {
+        error: ParseError,
+    },
+}
+
+/// How a server that accepts no mail, such as a decommissioned MX, turns clients away
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RejectAll {
+    /// Send a 521 greeting and close the connection (RFC 7504)
+    Greeting,
+    /// Greet clients normally and reject MAIL FROM with a 550 response. Some
+    /// senders retry forever after a 521 greeting but give up after a 550.
+    Mail,
+}
+
+/// The phase of an SMTP session, for monitoring
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// The client has connected but not sent HELO or EHLO
+    Connected,
+    /// The client has sent HELO or EHLO
+    Greeted,
+    /// The client has successfully authenticated
+    Authenticated,
+    /// The client has started a mail transaction
+    MailFrom,
+    /// The client has given at least one recipient
+    RcptTo,
+    /// The client is sending the message
+    Data,
+    /// The session has finished
+    Closed,
+}
+
+/// The result of passing a chunk of message data to `Session::process_data_chunk`
+#[derive(Clone, Debug, PartialEq)]
+pub struct DataOutcome {
+    /// The number of bytes used from the chunk. Bytes after the end of the message
+    /// are not used and should be processed as commands.
+    pub consumed: usize,
+    /// True if the chunk contained the end of the message
+    pub ended: bool,
+    /// A response to write back to the client, if any
+    pub response: Option<Response>,
 }
 
 pub(crate) struct Credentials {
@@ -59,8 +129,60 @@ pub(crate) struct Credentials {
 /// A single smtp session connected to a single client
 pub struct Session<H: Handler> {
     name: String,
+    greeting: Option<String>,
+    greeting_lines: Vec<String>,
+    sni: Option<String>,
+    alpn: Option<Vec<u8>>,
     handler: H,
     fsm: StateMachine,
+    summary: SessionSummary,
+    // An incomplete command line that has not yet been terminated by LF
+    partial: Vec<u8>,
+}
+
+/// The extensions that a client used during a session, e.g to find clients that
+/// never use STARTTLS even though it is offered
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SessionSummary {
+    /// The client upgraded the connection with STARTTLS
+    pub used_starttls: bool,
+    /// The client authenticated successfully
+    pub authenticated: bool,
+    /// The client sent commands without waiting for the responses
+    pub used_pipelining: bool,
+}
+
+/// The envelope of a mail transaction, as passed to `Handler::data_end_envelope`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Envelope {
+    /// The domain given with HELO or EHLO
+    pub helo_domain: String,
+    /// The reverse path from MAIL FROM, which is empty for bounces
+    pub reverse_path: String,
+    /// The ESMTP parameters of MAIL FROM, such as RET and ENVID for DSN
+    pub mail_params: Vec<(String, String)>,
+    /// The accepted recipients, in the order they were given
+    pub recipients: Vec<Recipient>,
+    /// The message size declared with the SIZE parameter
+    pub size: Option<usize>,
+    /// The client sent BODY=8BITMIME
+    pub is8bit: bool,
+    /// The client sent the SMTPUTF8 parameter, so the addresses and headers may
+    /// contain UTF-8
+    pub smtputf8: bool,
+    /// The user that authenticated, if any
+    pub auth_id: Option<String>,
+    /// TLS was active, after STARTTLS
+    pub tls: bool,
+}
+
+/// A recipient accepted with RCPT TO
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Recipient {
+    /// The forward path of the recipient
+    pub forward_path: String,
+    /// The ESMTP parameters of RCPT TO, such as NOTIFY and ORCPT for DSN
+    pub params: Vec<(String, String)>,
 }
 
 #[derive(Clone)]
@@ -84,29 +206,275 @@ pub struct Session<H: Handler> {
 ///
 pub struct SessionBuilder {
     name: String,
-    start_tls_extension: bool,
-    auth_mechanisms: Vec<AuthMechanism>,
+    greeting: Option<String>,
+    greeting_lines: Vec<String>,
+    config: Config,
+}
+
+// Callback for command lines that cannot be parsed
+pub(crate) type ParseErrorHook = Arc<dyn Fn(&[u8]) + Send + Sync>;
+
+// Session configuration passed to the state machine
+#[derive(Clone)]
+pub(crate) struct Config {
+    // The name of the server
+    pub name: String,
+    pub start_tls_extension: bool,
+    pub auth_mechanisms: Vec<AuthMechanism>,
+    pub goodbye: Response,
+    pub max_no_progress: Option<usize>,
+    pub max_auth_attempts: usize,
+    pub log_line_limit: usize,
+    pub reject_8bit_commands: bool,
+    pub max_recipients: Option<usize>,
+    // Domains that accept any recipient
+    pub catch_all_domains: Vec<String>,
+    pub too_many_recipients: Response,
+    pub reject_empty_messages: bool,
+    pub max_message_size: Option<usize>,
+    pub pipelining: bool,
+    pub enhanced_status_codes: bool,
+    pub chunking: bool,
+    pub smtputf8: bool,
+    pub extra_capabilities: Vec<&'static str>,
+    pub vrfy_response: Response,
+    pub reject_all: Option<RejectAll>,
+    pub max_messages: Option<usize>,
+    pub bare_lf_commands: bool,
+    pub normalize_data_crlf: bool,
+    pub parse_error_hook: Option<ParseErrorHook>,
 }
 
 impl SessionBuilder {
     /// Create a new session for the given mailserver name
     pub fn new<S: Into<String>>(name: S) -> Self {
+        let name = name.into();
         Self {
-            name: name.into(),
-            start_tls_extension: false,
-            auth_mechanisms: Vec::with_capacity(4),
+            name: name.clone(),
+            greeting: None,
+            greeting_lines: Vec::new(),
+            config: Config {
+                name,
+                start_tls_extension: false,
+                auth_mechanisms: Vec::with_capacity(4),
+                goodbye: GOODBYE,
+                max_no_progress: None,
+                max_auth_attempts: 3,
+                log_line_limit: 200,
+                reject_8bit_commands: false,
+                max_recipients: Some(100),
+                catch_all_domains: Vec::new(),
+                too_many_recipients: TOO_MANY_RECIPIENTS,
+                reject_empty_messages: false,
+                max_message_size: None,
+                pipelining: false,
+                enhanced_status_codes: false,
+                chunking: false,
+                smtputf8: false,
+                extra_capabilities: Vec::new(),
+                vrfy_response: VERIFY_RESPONSE,
+                reject_all: None,
+                max_messages: None,
+                bare_lf_commands: false,
+                normalize_data_crlf: false,
+                parse_error_hook: None,
+            },
         }
     }
 
     /// Enable support for StartTls
     pub fn enable_start_tls(&mut self) -> &mut Self {
-        self.start_tls_extension = true;
+        self.config.start_tls_extension = true;
         self
     }
 
     /// Enable support for authentication
     pub fn enable_auth(&mut self, auth: AuthMechanism) -> &mut Self {
-        self.auth_mechanisms.push(auth);
+        self.config.auth_mechanisms.push(auth);
+        self
+    }
+
+    /// Set the message sent in the 221 response to QUIT
+    pub fn with_quit_message<S: Into<String>>(&mut self, message: S) -> &mut Self {
+        self.config.goodbye = Response::custom(221, message.into());
+        self
+    }
+
+    /// Close the session after more than `max` commands that do not progress it, such
+    /// as NOOP, RSET or failed AUTH. The count is reset when a message is delivered.
+    pub fn with_no_progress_limit(&mut self, max: usize) -> &mut Self {
+        self.config.max_no_progress = Some(max);
+        self
+    }
+
+    /// Close the session with a 421 response after `max` failed AUTH attempts.
+    /// The count is reset when an authentication succeeds. Defaults to 3.
+    pub fn with_max_auth_attempts(&mut self, max: usize) -> &mut Self {
+        self.config.max_auth_attempts = max;
+        self
+    }
+
+    /// Truncate lines from the client to `max` characters when they are logged.
+    /// Defaults to 200.
+    pub fn with_log_line_limit(&mut self, max: usize) -> &mut Self {
+        self.config.log_line_limit = max;
+        self
+    }
+
+    /// Call `callback` with each command line that cannot be parsed, e.g to debug
+    /// problems with a client. The client is sent the usual error response.
+    pub fn on_parse_error<F>(&mut self, callback: F) -> &mut Self
+    where
+        F: Fn(&[u8]) + Send + Sync + 'static,
+    {
+        self.config.parse_error_hook = Some(Arc::new(callback));
+        self
+    }
+
+    /// Reject command lines that contain bytes outside of 7-bit ASCII with a 500
+    /// response. By default 8-bit bytes are passed on to the command parser. When
+    /// SMTPUTF8 is enabled, command lines that are valid UTF-8 are accepted.
+    /// Non-ASCII addresses are still rejected in transactions that did not use it.
+    pub fn reject_8bit_commands(&mut self) -> &mut Self {
+        self.config.reject_8bit_commands = true;
+        self
+    }
+
+    /// Limit the number of recipients in a transaction to `max`. Recipients after the
+    /// limit are rejected with a 452 response, the accepted recipients remain valid.
+    /// The count starts again with each transaction. Defaults to 100, the minimum
+    /// that RFC 5321 requires servers to accept.
+    pub fn with_max_recipients(&mut self, max: usize) -> &mut Self {
+        self.config.max_recipients = Some(max);
+        self
+    }
+
+    /// Set the response sent to recipients after the recipient limit, e.g a 550
+    /// response to hard-fail them.
+    pub fn with_too_many_recipients_response(&mut self, response: Response) -> &mut Self {
+        self.config.too_many_recipients = response;
+        self
+    }
+
+    /// Accept any recipient at the given domains, e.g for a catch-all address.
+    /// RCPT TO for these domains is accepted without calling the handler, the
+    /// recipients at other domains are checked by the handler as usual.
+    pub fn with_catch_all_domains(&mut self, domains: Vec<String>) -> &mut Self {
+        self.config.catch_all_domains = domains;
+        self
+    }
+
+    /// Reject messages with an empty body with a 554 response, the handler does not
+    /// see the end of the message. By default empty messages are accepted.
+    pub fn reject_empty_messages(&mut self) -> &mut Self {
+        self.config.reject_empty_messages = true;
+        self
+    }
+
+    /// Limit the size of messages to `max` bytes and advertise the limit with the
+    /// SIZE extension. Messages that declare a larger size in MAIL FROM are rejected
+    /// straight away, larger messages without a declared size are rejected with a 552
+    /// response once the data ends. The handler does not see data beyond the limit.
+    pub fn with_max_message_size(&mut self, max: usize) -> &mut Self {
+        self.config.max_message_size = Some(max);
+        self
+    }
+
+    /// Advertise the PIPELINING extension, which allows clients to send several
+    /// commands without waiting for each response. See `Session::process_many`.
+    pub fn enable_pipelining(&mut self) -> &mut Self {
+        self.config.pipelining = true;
+        self
+    }
+
+    /// Advertise the ENHANCEDSTATUSCODES extension and send the RFC 3463 status
+    /// code of each response, e.g `250 2.0.0 OK`. Without it, the enhanced codes of
+    /// responses are removed before they are returned by the session.
+    pub fn enable_enhanced_status_codes(&mut self) -> &mut Self {
+        self.config.enhanced_status_codes = true;
+        self
+    }
+
+    /// Advertise the CHUNKING extension and accept messages sent with BDAT (RFC 3030).
+    /// The message data of BDAT is binary, a server must pass exactly the number of
+    /// bytes given by `Session::chunk_remaining` to the session.
+    pub fn enable_chunking(&mut self) -> &mut Self {
+        self.config.chunking = true;
+        self
+    }
+
+    /// Advertise the SMTPUTF8 extension and accept non-ASCII addresses in
+    /// transactions started with `MAIL FROM:<...> SMTPUTF8` (RFC 6531). Without it,
+    /// non-ASCII addresses are rejected with a 553 response.
+    pub fn enable_smtputf8(&mut self) -> &mut Self {
+        self.config.smtputf8 = true;
+        self
+    }
+
+    /// Refuse VRFY with a 502 response, so that clients cannot use it to probe for
+    /// addresses. By default VRFY is answered with a non-committal 252 response.
+    pub fn disable_vrfy(&mut self) -> &mut Self {
+        self.config.vrfy_response = VRFY_DISABLED;
+        self
+    }
+
+    /// Set the response sent to VRFY, e.g a 550 response to deny every address.
+    pub fn with_vrfy_response(&mut self, response: Response) -> &mut Self {
+        self.config.vrfy_response = response;
+        self
+    }
+
+    /// Accept command lines that end with a bare LF instead of CRLF, as sent by some
+    /// lenient clients. By default such lines are rejected with a syntax error.
+    /// The lines of a message sent after DATA are not changed.
+    pub fn accept_bare_lf_commands(&mut self) -> &mut Self {
+        self.config.bare_lf_commands = true;
+        self
+    }
+
+    /// Replace bare CR and bare LF in messages with CRLF before they are passed to
+    /// `Handler::data`, so that stored messages have consistent line endings. By
+    /// default the message is passed on as it was sent.
+    pub fn normalize_data_crlf(&mut self) -> &mut Self {
+        self.config.normalize_data_crlf = true;
+        self
+    }
+
+    /// Close the session with a 421 response when the client starts another
+    /// transaction after delivering `max` messages, so that busy clients cycle
+    /// their connections.
+    pub fn with_max_messages_per_session(&mut self, max: usize) -> &mut Self {
+        self.config.max_messages = Some(max);
+        self
+    }
+
+    /// Refuse all mail, either with a 521 greeting or by rejecting MAIL FROM
+    pub fn reject_all_mail(&mut self, mode: RejectAll) -> &mut Self {
+        self.config.reject_all = Some(mode);
+        self
+    }
+
+    /// Send the given lines, such as a legal notice, before the server name in a
+    /// multiline 220 greeting.
+    pub fn with_greeting_lines(&mut self, lines: Vec<String>) -> &mut Self {
+        self.greeting_lines = lines;
+        self
+    }
+
+    /// Replace the `<name> ESMTP` text of the 220 greeting. RFC 5321 expects the
+    /// greeting to start with the server name.
+    pub fn with_greeting<S: Into<String>>(&mut self, greeting: S) -> &mut Self {
+        self.greeting = Some(greeting.into());
+        self
+    }
+
+    /// Advertise extra capabilities, such as `DSN` or `DELIVERBY`, in the EHLO
+    /// response. They are listed in the given order after the built-in ones. The
+    /// handler is responsible for supporting them. Extensions that the session
+    /// implements itself, such as `CHUNKING` or `STARTTLS`, are turned on with their
+    /// own builder methods and are left out if given here.
+    pub fn with_extra_capabilities(&mut self, capabilities: Vec<&'static str>) -> &mut Self {
+        self.config.extra_capabilities = capabilities;
         self
     }
 
@@ -114,12 +482,14 @@ impl SessionBuilder {
     pub fn build<H: Handler>(&self, remote: IpAddr, handler: H) -> Session<H> {
         Session {
             name: self.name.clone(),
+            greeting: self.greeting.clone(),
+            greeting_lines: self.greeting_lines.clone(),
+            sni: None,
+            alpn: None,
             handler,
-            fsm: StateMachine::new(
-                remote,
-                self.auth_mechanisms.clone(),
-                self.start_tls_extension,
-            ),
+            fsm: StateMachine::new(remote, self.config.clone()),
+            summary: SessionSummary::default(),
+            partial: Vec::new(),
         }
     }
 }
@@ -127,17 +497,141 @@ impl SessionBuilder {
 impl<H: Handler> Session<H> {
     /// Get a greeting to send to the client
     pub fn greeting(&self) -> Response {
-        Response::dynamic(220, format!("{} ESMTP", self.name), Vec::new())
+        if self.fsm.reject_all() == Some(RejectAll::Greeting) {
+            let res = Response::custom(521, format!("{} does not accept mail", self.name));
+            return self.fsm.status_codes(res.with_enhanced_code(5, 3, 2));
+        }
+        let last = match &self.greeting {
+            Some(greeting) => greeting.clone(),
+            None => format!("{} ESMTP", self.name),
+        };
+        match self.greeting_lines.split_first() {
+            Some((head, rest)) => {
+                let mut tail = rest.to_vec();
+                tail.push(last);
+                Response::dynamic(220, head.clone(), tail)
+            }
+            None => Response::dynamic(220, last, Vec::new()),
+        }
+    }
+
+    /// Get the current phase of the session
+    pub fn phase(&self) -> Phase {
+        self.fsm.phase()
+    }
+
+    /// Get the number of bytes of a BDAT chunk that are still to be received, or
+    /// `None` when the session expects a command line.
+    ///
+    /// A chunk is binary and does not have to end on a line boundary, so a server
+    /// should read exactly this many bytes and pass them to `process_data_chunk` or
+    /// `process`. `process` cannot return bytes after the end of a chunk, so it
+    /// closes the session with a 421 response if it is passed any.
+    /// `process_data_chunk` and `process_many` stop at the end of the chunk.
+    pub fn chunk_remaining(&self) -> Option<usize> {
+        self.fsm.chunk_remaining()
     }
 
     /// STARTTLS active
     pub fn tls_active(&mut self) {
+        self.tls_active_with_sni(None);
+    }
+
+    /// STARTTLS active, the client asked for the given server name using SNI
+    pub fn tls_active_with_sni(&mut self, sni: Option<String>) {
+        self.handler.tls_started(sni.as_deref());
+        self.sni = sni;
+        self.summary.used_starttls = true;
+        // Commands sent before the handshake are not carried over
+        self.partial.clear();
         self.command(Cmd::StartedTls);
     }
 
+    /// The connection arrived through a proxy that terminated TLS. The proxy
+    /// forwarded the server name and application protocol that the client asked
+    /// for, e.g in a PROXY protocol header. The session is treated as though
+    /// STARTTLS had completed, so STARTTLS is not offered, authentication
+    /// mechanisms that need TLS are allowed and `Envelope::tls` is set.
+    pub fn forwarded_tls(&mut self, sni: Option<String>, alpn: Option<Vec<u8>>) {
+        self.fsm.forwarded_tls();
+        self.handler.tls_started(sni.as_deref());
+        self.sni = sni;
+        self.alpn = alpn;
+    }
+
+    /// Complete an authentication that the handler deferred by returning
+    /// `AUTH_PENDING`, e.g once an external credential check has finished.
+    ///
+    /// Returns the response to write back to the client, which is the given
+    /// response unless the session was not waiting for authentication.
+    pub fn complete_auth(&mut self, response: Response) -> Response {
+        let response = self.command(Cmd::AuthComplete { response });
+        let response = self.fsm.status_codes(response);
+        response.log();
+        response
+    }
+
+    /// Get the server name that the client asked for using SNI
+    pub fn sni(&self) -> Option<&str> {
+        self.sni.as_deref()
+    }
+
+    /// Get the application protocol that a proxy negotiated with the client
+    pub fn alpn(&self) -> Option<&[u8]> {
+        self.alpn.as_deref()
+    }
+
+    /// Get the number of bytes of message data that the handler is holding, see
+    /// `Handler::buffered_bytes`
+    pub fn buffered_bytes(&self) -> usize {
+        self.handler.buffered_bytes()
+    }
+
+    /// Get the number of messages delivered in the session
+    pub fn message_count(&self) -> usize {
+        self.fsm.message_count()
+    }
+
+    /// Record that the client sent a command without waiting for the response to
+    /// the previous one. `process_many` does this for batches of commands.
+    pub fn client_pipelined(&mut self) {
+        self.summary.used_pipelining = true;
+    }
+
+    /// Get the extensions that the client has used so far
+    pub fn summary(&self) -> SessionSummary {
+        SessionSummary {
+            authenticated: self.fsm.is_authenticated(),
+            ..self.summary
+        }
+    }
+
+    /// Start the session once the greeting has been sent. Passes the address of
+    /// the client to `Handler::conn_open`.
+    pub fn open(&mut self) {
+        self.handler.conn_open(self.fsm.ip());
+    }
+
+    /// End a session that was opened once the connection has closed, whether or
+    /// not the client sent QUIT. Passes the session summary to
+    /// `Handler::session_end`.
+    pub fn end(&mut self) {
+        let summary = self.summary();
+        self.handler.session_end(&summary);
+    }
+
+    /// Tell the handler that the connection has closed, after `Session::end` if
+    /// the session was opened. Calls `Handler::conn_close`.
+    pub fn close(&mut self) {
+        self.handler.conn_close();
+    }
+
     /// Process a line sent by the client.
     ///
-    /// Returns a response that should be written back to the client.
+    /// Returns a response that should be written back to the client. A command line
+    /// can be passed in pieces, e.g as it arrives from a streaming reader. Until the
+    /// LF that ends the line is passed, the response is `Action::NoReply` and
+    /// `has_partial_line` is true.
     ///
     /// # Examples
     /// ```
@@ -161,15 +655,152 @@ impl<H: Handler> Session<H> {
     /// assert_eq!(&msg, b"250 OK\r\n");
     /// ```
     pub fn process(&mut self, line: &[u8]) -> Response {
+        let complete_line;
+        let line = match self.partial_line(line) {
+            Some(partial) => {
+                complete_line = partial;
+                &complete_line
+            }
+            None if self.has_partial_line() => return EMPTY_RESPONSE,
+            None => line,
+        };
+        let raw = line;
+        let crlf_line;
+        let line = match line.strip_suffix(b"\n") {
+            Some(rest) if !rest.ends_with(b"\r") && self.fsm.accepts_bare_lf() => {
+                crlf_line = [rest, b"\r\n"].concat();
+                &crlf_line
+            }
+            _ => line,
+        };
         // TODO: process within fsm
-        let response = match self.fsm.process_line(&mut self.handler, line) {
+        let response = match self.fsm.process_line(&mut self.handler, raw, line) {
             Left(cmd) => self.command(cmd),
             Right(res) => res,
         };
+        let response = self.fsm.status_codes(response);
         response.log();
         response
     }
 
+    /// Process several lines sent together by the client, as a pipelining client
+    /// does.
+    ///
+    /// The lines are processed in order and one response is returned for each
+    /// line, so each command sees the state left by the command before it.
+    /// Responses with `Action::NoReply`, such as those for message data, should
+    /// not be written back to the client. Processing stops after a response that
+    /// closes the session or upgrades to TLS, the lines after it are not processed.
+    ///
+    /// # Examples
+    /// ```
+    /// use mailin::{Action, Session, SessionBuilder, Handler};
+    ///
+    /// # use std::net::{IpAddr, Ipv4Addr};
+    /// # struct EmptyHandler{};
+    /// # impl Handler for EmptyHandler{};
+    /// # let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+    /// # let handler = EmptyHandler{};
+    /// # let mut session = SessionBuilder::new("name").build(addr, handler);
+    /// # session.process(b"HELO example.com\r\n");
+    /// let responses =
+    ///     session.process_many(b"MAIL FROM:<ship@sea.com>\r\nRCPT TO:<fish@sea.com>\r\nDATA\r\n");
+    /// let codes: Vec<u16> = responses.iter().map(|r| r.code).collect();
+    /// assert_eq!(codes, vec![250, 250, 354]);
+    /// ```
+    pub fn process_many(&mut self, buf: &[u8]) -> Vec<Response> {
+        let mut responses = Vec::new();
+        let mut rest = buf;
+        while !rest.is_empty() {
+            // A BDAT chunk is not split into lines and ends where its size says
+            let len = match self.chunk_remaining() {
+                Some(remaining) => remaining.min(rest.len()),
+                None => rest
+                    .iter()
+                    .position(|c| *c == b'\n')
+                    .map_or(rest.len(), |i| i + 1),
+            };
+            let (line, tail) = rest.split_at(len);
+            rest = tail;
+            if !responses.is_empty() && self.phase() != Phase::Data {
+                self.client_pipelined();
+            }
+            let response = self.process(line);
+            let stop = matches!(response.action, Action::Close | Action::UpgradeTls);
+            responses.push(response);
+            if stop {
+                break;
+            }
+        }
+        responses
+    }
+
+    /// Process a chunk of message data sent by the client while in the DATA phase.
+    ///
+    /// This is an alternative to `process` for servers that read large chunks from
+    /// the client. The chunk does not have to end on a line boundary. Dot-stuffing is
+    /// removed before data is passed to the handler. The data of a BDAT chunk is
+    /// passed on unchanged and only bytes up to the end of the chunk are consumed.
+    ///
+    /// # Examples
+    /// ```
+    /// use mailin::{Session, SessionBuilder, Handler};
+    ///
+    /// # use std::net::{IpAddr, Ipv4Addr};
+    /// # struct EmptyHandler{};
+    /// # impl Handler for EmptyHandler{};
+    /// # let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+    /// # let handler = EmptyHandler{};
+    /// # let mut session = SessionBuilder::new("name").build(addr, handler);
+    /// # session.process(b"HELO example.com\r\n");
+    /// # session.process(b"MAIL FROM:<ship@sea.com>\r\n");
+    /// # session.process(b"RCPT TO:<fish@sea.com>\r\n");
+    /// # session.process(b"DATA\r\n");
+    /// let outcome = session.process_data_chunk(b"Hello\r\n.\r\nQUIT\r\n");
+    /// assert!(outcome.ended);
+    /// assert_eq!(outcome.consumed, 10);
+    /// assert_eq!(outcome.response.map(|r| r.code), Some(250));
+    /// ```
+    pub fn process_data_chunk(&mut self, buf: &[u8]) -> DataOutcome {
+        let (consumed, next) = self.fsm.process_data_chunk(&mut self.handler, buf);
+        let ended = matches!(next, Some(Left(Cmd::DataEnd)));
+        let response = next.map(|next| match next {
+            Left(cmd) => self.command(cmd),
+            Right(res) => res,
+        });
+        let response = response.map(|res| self.fsm.status_codes(res));
+        if let Some(ref res) = response {
+            res.log();
+        }
+        DataOutcome {
+            consumed,
+            ended,
+            response,
+        }
+    }
+
+    /// Is part of a command line waiting for the rest of the line?
+    pub fn has_partial_line(&self) -> bool {
+        !self.partial.is_empty()
+    }
+
+    // Keep the pieces of a command line until it is complete, and then return the
+    // whole line if it was passed in more than one piece
+    fn partial_line(&mut self, line: &[u8]) -> Option<Vec<u8>> {
+        if !self.fsm.receives_commands() || (self.partial.is_empty() && line.ends_with(b"\n")) {
+            return None;
+        }
+        // Enough of a long line is kept to find that it is too long
+        let room = (MAX_COMMAND_LINE + 1).saturating_sub(self.partial.len());
+        self.partial
+            .extend_from_slice(&line[..line.len().min(room)]);
+        if line.ends_with(b"\n") {
+            Some(mem::take(&mut self.partial))
+        } else {
+            None
+        }
+    }
+
     fn command(&mut self, cmd: Cmd) -> Response {
         self.fsm.command(&mut self.handler, cmd)
     }
@@ -186,12 +817,17 @@ mod tests {
 
     struct EmptyHandler {}
     impl Handler for EmptyHandler {}
-    struct DataHandler(Vec<u8>);
+    // The message data and the number of aborted messages
+    struct DataHandler(Vec<u8>, usize);
     impl Handler for DataHandler {
         fn data(&mut self, buf: &[u8]) -> std::io::Result<()> {
             self.0.extend(buf);
             Ok(())
         }
+
+        fn data_abort(&mut self) {
+            self.1 += 1;
+        }
     }
 
     // Check that the state machine matches the given state pattern
@@ -216,7 +852,25 @@ mod tests {
 
     fn new_data_session() -> Session<DataHandler> {
         let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
-        SessionBuilder::new("some.name").build(addr, DataHandler(vec![]))
+        SessionBuilder::new("some.name").build(addr, DataHandler(vec![], 0))
+    }
+
+    #[test]
+    fn multiline_greeting() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let session = SessionBuilder::new("some.name")
+            .with_greeting_lines(vec![
+                "Unauthorized use prohibited".to_string(),
+                "Contact postmaster@some.name".to_string(),
+            ])
+            .build(addr, EmptyHandler {});
+        let greeting = session.greeting().buffer().unwrap();
+        assert_eq!(
+            greeting,
+            b"220-Unauthorized use prohibited\r\n\
+              220-Contact postmaster@some.name\r\n\
+              220 some.name ESMTP\r\n"
+        );
     }
 
     #[test]
@@ -230,6 +884,54 @@ mod tests {
         assert_state!(session.fsm.current_state(), SmtpState::Hello);
     }
 
+    #[test]
+    fn reject_8bit_commands() {
+        let mut session = new_session();
+        let res = session.process("helo dé.domain\r\n".as_bytes());
+        assert_eq!(res.code, 250);
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .reject_8bit_commands()
+            .build(addr, EmptyHandler {});
+        let res = session.process("helo dé.domain\r\n".as_bytes());
+        assert_eq!(res.code, 500);
+        assert_state!(session.fsm.current_state(), SmtpState::Idle);
+    }
+
+    #[test]
+    fn reject_8bit_commands_smtputf8() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .reject_8bit_commands()
+            .enable_smtputf8()
+            .build(addr, EmptyHandler {});
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process("mail from:<船@海.com> smtputf8\r\n".as_bytes());
+        assert_eq!(res.code, 250);
+        // Bytes that are not UTF-8 are still rejected
+        let res = session.process(b"rcpt to:<\xff@sea.com>\r\n");
+        assert_eq!(res.code, 500);
+    }
+
+    #[test]
+    fn parse_error_callback() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let lines = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = lines.clone();
+        let mut session = SessionBuilder::new("some.name")
+            .on_parse_error(move |line| seen.lock().unwrap().push(line.to_vec()))
+            .build(addr, EmptyHandler {});
+        session.process(b"helo a.domain\r\n");
+        let res = session.process(b"MAIL FRMO:<x>\r\n");
+        assert_eq!(res.buffer().unwrap(), b"500 Syntax error\r\n");
+        assert_eq!(session.process(b"mail\r\n").code, 502);
+        assert_eq!(session.process(b"mail from:<ship@sea.com>\r\n").code, 250);
+        assert_eq!(
+            *lines.lock().unwrap(),
+            vec![b"MAIL FRMO:<x>\r\n".to_vec(), b"mail\r\n".to_vec()]
+        );
+    }
+
     #[test]
     fn mail_from() {
         let mut session = new_session();
@@ -259,43 +961,288 @@ mod tests {
         assert_state!(session.fsm.current_state(), SmtpState::Rcpt);
     }
 
+    // Accepts only postmaster
+    struct PostmasterHandler {}
+    impl Handler for PostmasterHandler {
+        fn rcpt(&mut self, to: &str) -> Response {
+            ternary!(to.starts_with("postmaster@"), OK, NO_MAILBOX)
+        }
+    }
+
     #[test]
-    fn helo_noop() {
+    fn catch_all_domains() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .with_catch_all_domains(vec!["sea.com".to_string()])
+            .build(addr, PostmasterHandler {});
+        session.process(b"helo a.domain\r\n");
+        session.process(b"mail from:<ship@land.com>\r\n");
+        let res = session.process(b"rcpt to:<anything@SEA.com>\r\n");
+        assert_eq!(res.code, 250);
+        let res = session.process(b"rcpt to:<fish@ocean.com>\r\n");
+        assert_eq!(res.code, 550);
+        let res = session.process(b"rcpt to:<postmaster@ocean.com>\r\n");
+        assert_eq!(res.code, 250);
+        // A subdomain is not a catch-all domain
+        let res = session.process(b"rcpt to:<fish@deep.sea.com>\r\n");
+        assert_eq!(res.code, 550);
+    }
+
+    #[test]
+    fn smtputf8() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .enable_smtputf8()
+            .build(addr, EmptyHandler {});
+        let ehlo = session.process(b"ehlo a.domain\r\n").buffer().unwrap();
+        assert!(String::from_utf8(ehlo)
+            .unwrap()
+            .ends_with("250 SMTPUTF8\r\n"));
+        let res = session.process("mail from:<船@海.com> smtputf8\r\n".as_bytes());
+        assert_eq!(res.code, 250);
+        let res = session.process("rcpt to:<魚@海.com>\r\n".as_bytes());
+        assert_eq!(res.code, 250);
+    }
+
+    #[test]
+    fn smtputf8_not_negotiated() {
         let mut session = new_session();
-        let res1 = session.process(b"helo a.domain\r\n");
-        assert_eq!(res1.code, 250);
-        let res2 = session.process(b"noop\r\n");
-        assert_eq!(res2.code, 250);
-        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process("mail from:<船@海.com>\r\n".as_bytes());
+        assert_eq!(res.code, 553);
+        // The parameter is ignored when the extension is not offered
+        let res = session.process("mail from:<船@海.com> smtputf8\r\n".as_bytes());
+        assert_eq!(res.code, 553);
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        let res = session.process("rcpt to:<魚@海.com>\r\n".as_bytes());
+        assert_eq!(res.code, 553);
+        let res = session.process(b"rcpt to:<fish@sea.com>\r\n");
+        assert_eq!(res.code, 250);
+    }
+
+    #[test]
+    fn too_many_recipients() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .with_max_recipients(2)
+            .build(addr, EmptyHandler {});
+        session.process(b"helo a.domain\r\n");
         session.process(b"mail from:<ship@sea.com>\r\n");
-        let res3 = session.process(b"noop\r\n");
-        assert_eq!(res3.code, 250);
-        assert_state!(session.fsm.current_state(), SmtpState::Mail);
         session.process(b"rcpt to:<fish@sea.com>\r\n");
-        let res4 = session.process(b"noop\r\n");
-        assert_eq!(res4.code, 250);
+        let res = session.process(b"rcpt to:<kraken@sea.com>\r\n");
+        assert_eq!(res.code, 250);
+        let res = session.process(b"rcpt to:<whale@sea.com>\r\n");
+        assert_eq!(res.code, 452);
         assert_state!(session.fsm.current_state(), SmtpState::Rcpt);
+        let res = session.process(b"data\r\n");
+        assert_eq!(res.code, 354);
+        assert_state!(session.fsm.current_state(), SmtpState::Data);
     }
 
     #[test]
-    fn data() {
-        let mut session = new_data_session();
+    fn recipient_limit_reset() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .with_max_recipients(2)
+            .build(addr, EmptyHandler {});
         session.process(b"helo a.domain\r\n");
         session.process(b"mail from:<ship@sea.com>\r\n");
         session.process(b"rcpt to:<fish@sea.com>\r\n");
-        let res1 = session.process(b"data\r\n");
-        assert_eq!(res1.code, 354);
-        let res2 = session.process(b"Hello World\r\n");
-        assert_eq!(res2.action, Action::NoReply);
-        let res3 = session.process(b".\r\n");
-        assert_eq!(res3.code, 250);
-        assert_state!(session.fsm.current_state(), SmtpState::Hello);
-        assert_eq!(&session.handler.0, b"Hello World\r\n");
+        session.process(b"rcpt to:<kraken@sea.com>\r\n");
+        let res = session.process(b"rset\r\n");
+        assert_eq!(res.code, 250);
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        for to in ["fish", "kraken"] {
+            let res = session.process(format!("rcpt to:<{}@sea.com>\r\n", to).as_bytes());
+            assert_eq!(res.code, 250);
+        }
+        session.process(b"data\r\n");
+        let res = session.process(b".\r\n");
+        assert_eq!(res.code, 250);
+        // The next message has its own limit
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        let res = session.process(b"rcpt to:<kraken@sea.com>\r\n");
+        assert_eq!(res.code, 250);
+        let res = session.process(b"rcpt to:<whale@sea.com>\r\n");
+        assert_eq!(res.code, 452);
     }
 
     #[test]
-    fn dot_stuffed_data() {
-        let mut session = new_data_session();
+    fn default_recipient_limit() {
+        let mut session = new_session();
+        session.process(b"helo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        for i in 0..100 {
+            let res = session.process(format!("rcpt to:<fish{}@sea.com>\r\n", i).as_bytes());
+            assert_eq!(res.code, 250);
+        }
+        let res = session.process(b"rcpt to:<kraken@sea.com>\r\n");
+        assert_eq!(res.code, 452);
+    }
+
+    #[test]
+    fn too_many_recipients_response() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .with_max_recipients(1)
+            .with_too_many_recipients_response(NO_MAILBOX)
+            .build(addr, EmptyHandler {});
+        session.process(b"helo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        let res = session.process(b"rcpt to:<kraken@sea.com>\r\n");
+        assert_eq!(res.code, 550);
+    }
+
+    #[test]
+    fn data_without_recipients() {
+        let mut session = new_session();
+        session.process(b"helo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        let res = session.process(b"data\r\n");
+        assert_eq!(res.buffer().unwrap(), b"554 No valid recipients\r\n");
+        assert_state!(session.fsm.current_state(), SmtpState::Mail);
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        let res = session.process(b"data\r\n");
+        assert_eq!(res.code, 354);
+    }
+
+    #[test]
+    fn ehlo_resets_transaction() {
+        let mut session = new_session();
+        session.process(b"helo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        let res = session.process(b"ehlo b.domain\r\n");
+        assert_eq!(res.code, 250);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        // The aborted transaction has no recipients to send data to
+        let res = session.process(b"data\r\n");
+        assert_eq!(res.code, 503);
+        let res = session.process(b"rcpt to:<fish@sea.com>\r\n");
+        assert_eq!(res.code, 503);
+        let res = session.process(b"mail from:<boat@sea.com>\r\n");
+        assert_eq!(res.code, 250);
+        assert_state!(session.fsm.current_state(), SmtpState::Mail);
+    }
+
+    #[test]
+    fn partial_command_line() {
+        let mut session = new_session();
+        session.process(b"helo a.domain\r\n");
+        let res = session.process(b"MAIL FROM:<sh");
+        assert_eq!(res.action, Action::NoReply);
+        assert!(session.has_partial_line());
+        assert_eq!(session.process(b"ip@sea.com>").action, Action::NoReply);
+        let res = session.process(b"\r\n");
+        assert_eq!(res.code, 250);
+        assert!(!session.has_partial_line());
+        assert_state!(session.fsm.current_state(), SmtpState::Mail);
+        // The parameter check applies to complete lines
+        session.process(b"RCPT");
+        assert_eq!(session.process(b"\r\n").code, 502);
+        // A line that never ends is not kept in full
+        let long = vec![b'a'; 3000];
+        assert_eq!(session.process(&long).action, Action::NoReply);
+        assert_eq!(session.process(&long).action, Action::NoReply);
+        assert_eq!(session.partial.len(), MAX_COMMAND_LINE + 1);
+        assert_eq!(session.process(b"\r\n").code, 500);
+    }
+
+    #[test]
+    fn helo_noop() {
+        let mut session = new_session();
+        let res0 = session.process(b"noop\r\n");
+        assert_eq!(res0.code, 250);
+        assert_state!(session.fsm.current_state(), SmtpState::Idle);
+        let res1 = session.process(b"helo a.domain\r\n");
+        assert_eq!(res1.code, 250);
+        let res2 = session.process(b"noop\r\n");
+        assert_eq!(res2.code, 250);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        let res3 = session.process(b"noop\r\n");
+        assert_eq!(res3.code, 250);
+        assert_state!(session.fsm.current_state(), SmtpState::Mail);
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        let res4 = session.process(b"noop\r\n");
+        assert_eq!(res4.code, 250);
+        assert_state!(session.fsm.current_state(), SmtpState::Rcpt);
+    }
+
+    #[test]
+    fn help() {
+        let mut session = new_session();
+        let res = session.process(b"help\r\n");
+        assert_eq!(
+            res.buffer().unwrap(),
+            b"214 Commands supported: HELO EHLO MAIL RCPT DATA RSET NOOP QUIT VRFY HELP\r\n"
+        );
+        assert_state!(session.fsm.current_state(), SmtpState::Idle);
+        session.process(b"helo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        assert_eq!(session.process(b"HELP MAIL\r\n").code, 214);
+        assert_state!(session.fsm.current_state(), SmtpState::Mail);
+        // Extensions that are offered are listed
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .enable_start_tls()
+            .enable_chunking()
+            .build(addr, EmptyHandler {});
+        let help = session.process(b"help\r\n").buffer().unwrap();
+        assert!(String::from_utf8(help)
+            .unwrap()
+            .ends_with("HELP STARTTLS BDAT\r\n"));
+    }
+
+    #[test]
+    fn data() {
+        let mut session = new_data_session();
+        session.process(b"helo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        let res1 = session.process(b"data\r\n");
+        assert_eq!(res1.code, 354);
+        let res2 = session.process(b"Hello World\r\n");
+        assert_eq!(res2.action, Action::NoReply);
+        let res3 = session.process(b".\r\n");
+        assert_eq!(res3.code, 250);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        assert_eq!(&session.handler.0, b"Hello World\r\n");
+    }
+
+    // Send a message with a bare CR and a bare LF, returns the data seen by the handler
+    fn send_bare_line_endings(builder: &SessionBuilder) -> Vec<u8> {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = builder.build(addr, DataHandler(vec![], 0));
+        session.process(b"helo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        session.process(b"data\r\n");
+        session.process(b"Hello\rWorld\r\n");
+        session.process(b"Bare\n");
+        assert_eq!(session.process(b".\r\n").code, 250);
+        session.handler.0
+    }
+
+    #[test]
+    fn normalize_data_crlf() {
+        let mut builder = SessionBuilder::new("some.name");
+        builder.normalize_data_crlf();
+        let data = send_bare_line_endings(&builder);
+        assert_eq!(data, b"Hello\r\nWorld\r\nBare\r\n");
+    }
+
+    #[test]
+    fn raw_data_line_endings() {
+        let data = send_bare_line_endings(&SessionBuilder::new("some.name"));
+        assert_eq!(data, b"Hello\rWorld\r\nBare\n");
+    }
+
+    #[test]
+    fn dot_stuffed_data() {
+        let mut session = new_data_session();
         session.process(b"helo a.domain\r\n");
         session.process(b"mail from:<ship@sea.com>\r\n");
         session.process(b"rcpt to:<fish@sea.com>\r\n");
@@ -311,6 +1258,43 @@ mod tests {
         assert_eq!(&session.handler.0, b"Hello World\r\n.\r\n");
     }
 
+    #[test]
+    fn data_split_terminator() {
+        let mut session = new_data_session();
+        session.process(b"helo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        session.process(b"data\r\n");
+        session.process(b"Hello World\r\n");
+        for b in b".\r" {
+            let res = session.process(&[*b]);
+            assert_eq!(res.action, Action::NoReply);
+            assert_state!(session.fsm.current_state(), SmtpState::Data);
+        }
+        let res = session.process(b"\n");
+        assert_eq!(res.code, 250);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        assert_eq!(&session.handler.0, b"Hello World\r\n");
+    }
+
+    #[test]
+    fn data_bare_lf_dot() {
+        let mut session = new_data_session();
+        session.process(b"helo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        session.process(b"data\r\n");
+        for line in [&b"Hello\n"[..], b".\n", b"mail from:<evil@sea.com>\r\n"] {
+            let res = session.process(line);
+            assert_eq!(res.action, Action::NoReply);
+            assert_state!(session.fsm.current_state(), SmtpState::Data);
+        }
+        let res = session.process(b".\r\n");
+        assert_eq!(res.code, 250);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        assert_eq!(&session.handler.0, b"Hello\n\nmail from:<evil@sea.com>\r\n");
+    }
+
     #[test]
     fn data_8bit() {
         let mut session = new_session();
@@ -328,89 +1312,1113 @@ mod tests {
     }
 
     #[test]
-    fn rset_hello() {
-        let mut session = new_session();
-        session.process(b"helo some.domain\r\n");
-        session.process(b"mail from:<ship@sea.com>\r\n");
-        let res = session.process(b"rset\r\n");
-        assert_eq!(res.code, 250);
-        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+    fn rset_hello() {
+        let mut session = new_session();
+        session.process(b"helo some.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        let res = session.process(b"rset\r\n");
+        assert_eq!(res.code, 250);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+    }
+
+    #[test]
+    fn rset_idle() {
+        let mut session = new_session();
+        let res = session.process(b"rset\r\n");
+        assert_eq!(res.code, 250);
+        assert_state!(session.fsm.current_state(), SmtpState::Idle);
+    }
+
+    #[test]
+    fn quit() {
+        let mut session = new_session();
+        session.process(b"helo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        let res = session.process(b"quit\r\n");
+        assert_eq!(res.code, 221);
+        assert_eq!(res.action, Action::Close);
+        assert_state!(session.fsm.current_state(), SmtpState::Invalid);
+    }
+
+    #[test]
+    fn quit_message() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .with_quit_message("some.name closing connection")
+            .build(addr, EmptyHandler {});
+        session.process(b"helo a.domain\r\n");
+        let res = session.process(b"quit\r\n");
+        assert_eq!(res.code, 221);
+        assert_eq!(res.action, Action::Close);
+        assert_eq!(
+            res.buffer().unwrap(),
+            b"221 some.name closing connection\r\n"
+        );
+        assert_state!(session.fsm.current_state(), SmtpState::Invalid);
+    }
+
+    #[test]
+    fn no_progress_limit() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .with_no_progress_limit(3)
+            .build(addr, EmptyHandler {});
+        session.process(b"helo a.domain\r\n");
+        for _ in 0..3 {
+            let res = session.process(b"noop\r\n");
+            assert_eq!(res.code, 250);
+        }
+        let res = session.process(b"rset\r\n");
+        assert_eq!(res.code, 421);
+        assert_eq!(res.action, Action::Close);
+        assert_state!(session.fsm.current_state(), SmtpState::Invalid);
+    }
+
+    #[test]
+    fn no_progress_reset() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .with_no_progress_limit(2)
+            .build(addr, EmptyHandler {});
+        session.process(b"helo a.domain\r\n");
+        session.process(b"noop\r\n");
+        session.process(b"noop\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        session.process(b"data\r\n");
+        let res = session.process(b".\r\n");
+        assert_eq!(res.code, 250);
+        for _ in 0..2 {
+            let res = session.process(b"noop\r\n");
+            assert_eq!(res.code, 250);
+        }
+    }
+
+    #[test]
+    fn vrfy() {
+        let mut session = new_session();
+        session.process(b"helo a.domain\r\n");
+        let res1 = session.process(b"vrfy kraken\r\n");
+        assert_eq!(res1.code, 252);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        let res2 = session.process(b"vrfy boat\r\n");
+        assert_eq!(res2.code, 503);
+        assert_state!(session.fsm.current_state(), SmtpState::Mail);
+    }
+
+    fn send_message<H: Handler>(session: &mut Session<H>) -> Response {
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        session.process(b"data\r\n");
+        session.process(b"Hello\r\n");
+        session.process(b".\r\n")
+    }
+
+    #[test]
+    fn message_count() {
+        let mut session = new_session();
+        session.process(b"helo a.domain\r\n");
+        assert_eq!(session.message_count(), 0);
+        assert_eq!(send_message(&mut session).code, 250);
+        assert_eq!(session.message_count(), 1);
+        // An aborted transaction is not counted
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rset\r\n");
+        assert_eq!(send_message(&mut session).code, 250);
+        assert_eq!(session.message_count(), 2);
+    }
+
+    #[test]
+    fn max_messages_per_session() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .with_max_messages_per_session(2)
+            .build(addr, EmptyHandler {});
+        session.process(b"helo a.domain\r\n");
+        for _ in 0..2 {
+            assert_eq!(send_message(&mut session).code, 250);
+        }
+        let res = session.process(b"mail from:<ship@sea.com>\r\n");
+        assert_eq!(res.code, 421);
+        assert_eq!(res.action, Action::Close);
+        assert_eq!(
+            res.buffer().unwrap(),
+            b"421 Too many messages this session\r\n"
+        );
+        assert_eq!(session.message_count(), 2);
+    }
+
+    #[test]
+    fn bare_lf_data() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .accept_bare_lf_commands()
+            .build(addr, EmptyHandler {});
+        assert_eq!(session.process(b"helo a.domain\n").code, 250);
+        assert_eq!(session.process(b"mail from:<ship@sea.com>\n").code, 250);
+        assert_eq!(session.process(b"rcpt to:<fish@sea.com>\n").code, 250);
+        let res = session.process(b"DATA\n");
+        assert_eq!(res.code, 354);
+        assert_state!(session.fsm.current_state(), SmtpState::Data);
+        assert_eq!(session.phase(), Phase::Data);
+        session.process(b"Hello\r\n");
+        assert_eq!(session.process(b".\r\n").code, 250);
+    }
+
+    #[test]
+    fn bare_lf_rejected() {
+        let mut session = new_session();
+        assert_eq!(session.process(b"helo a.domain\r\n").code, 250);
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        let res = session.process(b"DATA\n");
+        assert_eq!(res.code, 500);
+        assert_state!(session.fsm.current_state(), SmtpState::Rcpt);
+    }
+
+    #[test]
+    fn reject_all_at_greeting() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .reject_all_mail(RejectAll::Greeting)
+            .build(addr, EmptyHandler {});
+        let res = session.greeting();
+        assert_eq!(res.code, 521);
+        assert_eq!(res.action, Action::Close);
+        assert_eq!(
+            res.buffer().unwrap(),
+            b"521 some.name does not accept mail\r\n"
+        );
+        // Clients that carry on regardless cannot send mail
+        session.process(b"helo a.domain\r\n");
+        let res = session.process(b"mail from:<ship@sea.com>\r\n");
+        assert_eq!(res.code, 550);
+    }
+
+    #[test]
+    fn reject_all_enhanced_status_codes() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .reject_all_mail(RejectAll::Greeting)
+            .enable_enhanced_status_codes()
+            .build(addr, EmptyHandler {});
+        assert_eq!(
+            session.greeting().buffer().unwrap(),
+            b"521 5.3.2 some.name does not accept mail\r\n"
+        );
+        session.process(b"helo a.domain\r\n");
+        let res = session.process(b"mail from:<ship@sea.com>\r\n");
+        assert_eq!(
+            res.buffer().unwrap(),
+            b"550 5.7.1 This server does not accept mail\r\n"
+        );
+    }
+
+    #[test]
+    fn reject_all_at_mail() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .reject_all_mail(RejectAll::Mail)
+            .build(addr, EmptyHandler {});
+        assert_eq!(session.greeting().code, 220);
+        let res = session.process(b"helo a.domain\r\n");
+        assert_eq!(res.code, 250);
+        let res = session.process(b"mail from:<ship@sea.com>\r\n");
+        assert_eq!(
+            res.buffer().unwrap(),
+            b"550 This server does not accept mail\r\n"
+        );
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        let res = session.process(b"rcpt to:<fish@sea.com>\r\n");
+        assert_eq!(res.code, 503);
+    }
+
+    #[test]
+    fn vrfy_disabled() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .disable_vrfy()
+            .build(addr, EmptyHandler {});
+        session.process(b"helo a.domain\r\n");
+        let res = session.process(b"vrfy kraken\r\n");
+        assert_eq!(res.code, 502);
+        assert_eq!(res.buffer().unwrap(), b"502 VRFY command disabled\r\n");
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+    }
+
+    #[test]
+    fn vrfy_response() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .with_vrfy_response(NO_MAILBOX)
+            .build(addr, EmptyHandler {});
+        session.process(b"helo a.domain\r\n");
+        let res = session.process(b"vrfy kraken\r\n");
+        assert_eq!(res.code, 550);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+    }
+
+    struct AuthHandler {}
+    impl Handler for AuthHandler {
+        fn auth_plain(
+            &mut self,
+            authorization_id: &str,
+            authentication_id: &str,
+            password: &str,
+        ) -> Response {
+            ternary!(
+                authorization_id == "test" && authentication_id == "test" && password == "1234",
+                AUTH_OK,
+                INVALID_CREDENTIALS
+            )
+        }
+
+        fn auth_login(&mut self, username: &str, password: &str) -> Response {
+            ternary!(
+                username == "test" && password == "1234",
+                AUTH_OK,
+                INVALID_CREDENTIALS
+            )
+        }
+    }
+
+    fn new_auth_session(with_start_tls: bool) -> Session<AuthHandler> {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut builder = SessionBuilder::new("some.domain");
+        builder.enable_auth(AuthMechanism::Plain);
+        builder.enable_auth(AuthMechanism::Login);
+        if with_start_tls {
+            builder.enable_start_tls();
+        }
+        builder.build(addr, AuthHandler {})
+    }
+
+    fn start_tls(session: &mut Session<AuthHandler>) {
+        let res = session.process(b"ehlo a.domain\r\n");
+        assert_eq!(res.code, 250);
+        assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
+        let res = session.process(b"starttls\r\n");
+        assert_eq!(res.code, 220);
+        session.tls_active();
+    }
+
+    #[test]
+    fn summary_starttls() {
+        let mut session = new_auth_session(true);
+        session.process(b"ehlo a.domain\r\n");
+        assert!(!session.summary().used_starttls);
+        start_tls(&mut session);
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"auth plain dGVzdAB0ZXN0ADEyMzQ=\r\n");
+        let summary = session.summary();
+        assert!(summary.used_starttls);
+        assert!(summary.authenticated);
+        assert!(!summary.used_pipelining);
+    }
+
+    #[test]
+    fn summary_without_starttls() {
+        let mut session = new_auth_session(true);
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"quit\r\n");
+        assert_eq!(session.summary(), SessionSummary::default());
+    }
+
+    #[test]
+    fn summary_pipelining() {
+        let mut session = new_session();
+        session.process_many(b"helo a.domain\r\n");
+        assert!(!session.summary().used_pipelining);
+        session.process_many(b"mail from:<ship@sea.com>\r\nrcpt to:<fish@sea.com>\r\n");
+        assert!(session.summary().used_pipelining);
+    }
+
+    #[test]
+    fn start_tls_over_tls() {
+        let mut session = new_auth_session(true);
+        start_tls(&mut session);
+        let res = session.process(b"ehlo a.domain\r\n");
+        assert_eq!(res.code, 250);
+        let res = session.process(b"starttls\r\n");
+        assert_eq!(res.code, 503);
+        assert_eq!(res.action, Action::Reply);
+        assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
+    }
+
+    #[test]
+    fn double_start_tls() {
+        let mut session = new_auth_session(true);
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process(b"starttls\r\n");
+        assert_eq!(res.action, Action::UpgradeTls);
+        let res = session.process(b"starttls\r\n");
+        assert_eq!(res.code, 503);
+        assert_eq!(res.action, Action::Reply);
+        session.tls_active();
+        assert_state!(session.fsm.current_state(), SmtpState::Idle);
+    }
+
+    #[test]
+    fn pipelining_ehlo() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .enable_pipelining()
+            .build(addr, EmptyHandler {});
+        let res = session.process(b"ehlo a.domain\r\n");
+        let ehlo = String::from_utf8(res.buffer().unwrap()).unwrap();
+        assert_eq!(
+            ehlo,
+            "250-server offers extensions:\r\n250-8BITMIME\r\n250-SIZE\r\n250 PIPELINING\r\n"
+        );
+    }
+
+    #[test]
+    fn custom_greeting() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let session = SessionBuilder::new("some.name")
+            .with_greeting("some.name ESMTP Mail service ready")
+            .build(addr, EmptyHandler {});
+        let greeting = session.greeting().buffer().unwrap();
+        assert_eq!(greeting, b"220 some.name ESMTP Mail service ready\r\n");
+    }
+
+    #[test]
+    fn extra_capabilities() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .enable_start_tls()
+            .with_max_message_size(1000)
+            .with_extra_capabilities(vec!["DSN", "chunking", "DELIVERBY 60"])
+            .build(addr, EmptyHandler {});
+        let res = session.process(b"ehlo a.domain\r\n");
+        let ehlo = String::from_utf8(res.buffer().unwrap()).unwrap();
+        assert_eq!(
+            ehlo,
+            "250-server offers extensions:\r\n250-8BITMIME\r\n250-SIZE 1000\r\n\
+             250-STARTTLS\r\n250-DSN\r\n250 DELIVERBY 60\r\n"
+        );
+    }
+
+    #[test]
+    fn enhanced_status_codes() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .enable_enhanced_status_codes()
+            .build(addr, EmptyHandler {});
+        let res = session.process(b"ehlo a.domain\r\n");
+        assert_eq!(
+            res.buffer().unwrap(),
+            b"250-server offers extensions:\r\n250-8BITMIME\r\n250-SIZE\r\n250 ENHANCEDSTATUSCODES\r\n"
+        );
+        let res = session.process(b"mail from:<ship@sea.com>\r\n");
+        assert_eq!(res.buffer().unwrap(), b"250 2.0.0 OK\r\n");
+        let res = session.process(b"data\r\n");
+        assert_eq!(res.buffer().unwrap(), b"554 5.5.1 No valid recipients\r\n");
+        let res = session.process(b"quit\r\n");
+        assert_eq!(res.buffer().unwrap(), b"221 2.0.0 Goodbye\r\n");
+    }
+
+    #[test]
+    fn no_enhanced_status_codes() {
+        let mut session = new_session();
+        session.process(b"helo a.domain\r\n");
+        let res = session.process(b"mail from:<ship@sea.com>\r\n");
+        assert_eq!(res.buffer().unwrap(), b"250 OK\r\n");
+        let res = session.process(b"quit\r\n");
+        assert_eq!(res.buffer().unwrap(), b"221 Goodbye\r\n");
+    }
+
+    #[test]
+    fn process_many() {
+        let mut session = new_data_session();
+        let responses = session.process_many(
+            b"helo a.domain\r\nmail from:<ship@sea.com>\r\nrcpt to:<fish@sea.com>\r\ndata\r\n",
+        );
+        let codes: Vec<u16> = responses.iter().map(|r| r.code).collect();
+        assert_eq!(codes, vec![250, 250, 250, 354]);
+        assert_state!(session.fsm.current_state(), SmtpState::Data);
+        let responses = session.process_many(b"Subject: many\r\n\r\nbody\r\n.\r\nquit\r\nnoop\r\n");
+        let actions: Vec<Action> = responses.iter().map(|r| r.action.clone()).collect();
+        assert_eq!(
+            actions,
+            vec![
+                Action::NoReply,
+                Action::NoReply,
+                Action::NoReply,
+                Action::Reply,
+                Action::Close
+            ]
+        );
+        assert_eq!(responses[3].code, 250);
+        assert_eq!(session.handler.0, b"Subject: many\r\n\r\nbody\r\n");
+    }
+
+    const CHUNKED_DATA: &[u8] = b"Subject: chunks\r\n\r\n..dot stuffed\r\nline\r\n.\r\nquit\r\n";
+    const CHUNKED_BODY: &[u8] = b"Subject: chunks\r\n\r\n.dot stuffed\r\nline\r\n";
+
+    fn start_chunked_data() -> Session<DataHandler> {
+        let mut session = new_data_session();
+        session.process(b"helo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        session.process(b"data\r\n");
+        session
+    }
+
+    #[test]
+    fn declared_size_exceeded() {
+        let mut session = new_data_session();
+        session.process(b"helo a.domain\r\n");
+        let res = session.process(b"mail from:<ship@sea.com> size=10\r\n");
+        assert_eq!(res.code, 250);
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        session.process(b"data\r\n");
+        session.process(b"This message is longer\r\n");
+        session.process(b"than it claimed to be\r\n");
+        let res = session.process(b".\r\n");
+        assert_eq!(res.code, 552);
+        assert_eq!(session.handler.1, 1);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        // The session can still send a message
+        session.handler.0.clear();
+        session.process(b"mail from:<ship@sea.com> size=7\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        session.process(b"data\r\n");
+        session.process(b"Hello\r\n");
+        let res = session.process(b".\r\n");
+        assert_eq!(res.code, 250);
+        assert_eq!(session.handler.0, b"Hello\r\n");
+    }
+
+    #[test]
+    fn empty_message() {
+        let mut session = new_data_session();
+        session.process(b"helo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        session.process(b"data\r\n");
+        let res = session.process(b".\r\n");
+        assert_eq!(res.code, 250);
+        assert!(session.handler.0.is_empty());
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+    }
+
+    #[test]
+    fn reject_empty_message() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .reject_empty_messages()
+            .build(addr, DataHandler(vec![], 0));
+        session.process(b"helo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        session.process(b"data\r\n");
+        let res = session.process(b".\r\n");
+        assert_eq!(res.code, 554);
+        assert_eq!(session.handler.1, 1);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        // A message with a body is still accepted
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        session.process(b"data\r\n");
+        session.process(b"\r\n");
+        let res = session.process(b".\r\n");
+        assert_eq!(res.code, 250);
+    }
+
+    #[test]
+    fn rset_in_data() {
+        let mut session = new_data_session();
+        session.process(b"helo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        session.process(b"data\r\n");
+        let res = session.process(b"RSET\r\n");
+        assert_eq!(res.action, Action::NoReply);
+        session.process(b"..RSET\r\n");
+        let outcome = session.process_data_chunk(b"RSET\r\nQUIT\r\n");
+        assert!(!outcome.ended);
+        assert_state!(session.fsm.current_state(), SmtpState::Data);
+        let res = session.process(b".\r\n");
+        assert_eq!(res.code, 250);
+        assert_eq!(session.handler.0, b"RSET\r\n.RSET\r\nRSET\r\nQUIT\r\n");
+    }
+
+    // Fails to save any message, counting the aborted messages
+    struct FailingHandler(usize);
+    impl Handler for FailingHandler {
+        fn data(&mut self, _buf: &[u8]) -> std::io::Result<()> {
+            Err(std::io::Error::other("disk full"))
+        }
+
+        fn data_end(&mut self) -> Response {
+            panic!("data_end called after a failed write");
+        }
+
+        fn data_abort(&mut self) {
+            self.0 += 1;
+        }
+    }
+
+    #[test]
+    fn data_write_error() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .enable_chunking()
+            .build(addr, FailingHandler(0));
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        session.process(b"data\r\n");
+        // The rest of the message is read before the failure is reported
+        assert_eq!(session.process(b"Hello\r\n").action, Action::NoReply);
+        assert_eq!(session.process(b"World\r\n").action, Action::NoReply);
+        assert_eq!(session.process(b".\r\n").code, 554);
+        assert_eq!(session.handler.0, 1);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        // A failed chunk is reported once it has been received
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        assert_eq!(session.process(b"bdat 5\r\n").action, Action::NoReply);
+        assert_eq!(session.process(b"Hello").code, 554);
+        assert_eq!(session.handler.0, 2);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+    }
+
+    // The envelope and body of each message received
+    #[derive(Default)]
+    struct EnvelopeHandler(Vec<(String, bool, Vec<String>, Vec<u8>)>);
+    impl Handler for EnvelopeHandler {
+        fn data_start(
+            &mut self,
+            _domain: &str,
+            from: &str,
+            is8bit: bool,
+            to: &[String],
+        ) -> Response {
+            self.0
+                .push((from.to_string(), is8bit, to.to_vec(), Vec::new()));
+            OK
+        }
+
+        fn data(&mut self, buf: &[u8]) -> std::io::Result<()> {
+            if let Some(message) = self.0.last_mut() {
+                message.3.extend(buf);
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn sequential_messages() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name").build(addr, EnvelopeHandler::default());
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com> body=8bitmime size=20\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        session.process(b"rcpt to:<crab@sea.com>\r\n");
+        session.process(b"data\r\n");
+        session.process(b"First\r\n");
+        assert_eq!(session.process(b".\r\n").code, 250);
+        // An abandoned transaction does not leak into the next message
+        session.process(b"mail from:<boat@sea.com> body=8bitmime\r\n");
+        session.process(b"rcpt to:<eel@sea.com>\r\n");
+        session.process(b"rset\r\n");
+        session.process(b"mail from:<sub@sea.com>\r\n");
+        session.process(b"rcpt to:<whale@sea.com>\r\n");
+        session.process(b"data\r\n");
+        session.process(b"Second message, longer than the first declared size\r\n");
+        assert_eq!(session.process(b".\r\n").code, 250);
+        let messages = &session.handler.0;
+        assert_eq!(messages.len(), 2);
+        let (from, is8bit, to, body) = &messages[0];
+        assert_eq!(from, "ship@sea.com");
+        assert!(is8bit);
+        assert_eq!(to, &["fish@sea.com", "crab@sea.com"]);
+        assert_eq!(body, b"First\r\n");
+        let (from, is8bit, to, body) = &messages[1];
+        assert_eq!(from, "sub@sea.com");
+        assert!(!is8bit);
+        assert_eq!(to, &["whale@sea.com"]);
+        assert_eq!(
+            body,
+            b"Second message, longer than the first declared size\r\n"
+        );
+    }
+
+    // Records the raw command lines
+    #[derive(Default)]
+    struct TranscriptHandler(Vec<Vec<u8>>);
+    impl Handler for TranscriptHandler {
+        fn command_line(&mut self, line: &[u8]) {
+            self.0.push(line.to_vec());
+        }
+    }
+
+    #[test]
+    fn raw_command_lines() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session =
+            SessionBuilder::new("some.name").build(addr, TranscriptHandler::default());
+        let smuggled = b"noop\x00\x1b[31m\xff\r\n";
+        session.process(b"helo a.domain\r\n");
+        session.process(smuggled);
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        session.process(b"data\r\n");
+        // Message data is not part of the transcript
+        session.process(b"Hello\r\n");
+        session.process(b".\r\n");
+        let lines = &session.handler.0;
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[1], smuggled);
+        assert_eq!(
+            lines[1].escape_ascii().to_string(),
+            "noop\\x00\\x1b[31m\\xff\\r\\n"
+        );
+    }
+
+    #[test]
+    fn raw_bare_lf_command_lines() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .accept_bare_lf_commands()
+            .build(addr, TranscriptHandler::default());
+        assert_eq!(session.process(b"helo a.domain\n").code, 250);
+        assert_eq!(session.process(b"noop\r\n").code, 250);
+        // The line ending is seen as it was sent
+        assert_eq!(
+            session.handler.0,
+            vec![b"helo a.domain\n".to_vec(), b"noop\r\n".to_vec()]
+        );
+    }
+
+    #[test]
+    fn bounce_message() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name").build(addr, EnvelopeHandler::default());
+        session.process(b"ehlo a.domain\r\n");
+        assert_eq!(session.process(b"mail from:<>\r\n").code, 250);
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        session.process(b"data\r\n");
+        session.process(b"Undeliverable\r\n");
+        assert_eq!(session.process(b".\r\n").code, 250);
+        let (from, _, to, _) = &session.handler.0[0];
+        assert_eq!(from, "");
+        assert_eq!(to, &["fish@sea.com"]);
+    }
+
+    #[test]
+    fn max_message_size() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .with_max_message_size(10)
+            .build(addr, DataHandler(vec![], 0));
+        let res = session.process(b"ehlo a.domain\r\n");
+        let ehlo = String::from_utf8(res.buffer().unwrap()).unwrap();
+        assert!(ehlo.contains("250 SIZE 10\r\n"), "{}", ehlo);
+        let res = session.process(b"mail from:<ship@sea.com> size=11\r\n");
+        assert_eq!(res.code, 552);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        let res = session.process(b"mail from:<ship@sea.com> size=10\r\n");
+        assert_eq!(res.code, 250);
+    }
+
+    #[test]
+    fn max_message_size_streamed() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .with_max_message_size(10)
+            .build(addr, DataHandler(vec![], 0));
+        session.process(b"helo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        session.process(b"data\r\n");
+        session.process(b"Hello\r\n");
+        session.process(b"This is too much\r\n");
+        let res = session.process(b".\r\n");
+        assert_eq!(res.code, 552);
+        assert_eq!(session.handler.1, 1);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        assert_eq!(session.handler.0, b"Hello\r\n");
+    }
+
+    #[test]
+    fn data_chunk() {
+        let mut session = start_chunked_data();
+        let outcome = session.process_data_chunk(CHUNKED_DATA);
+        assert!(outcome.ended);
+        assert_eq!(outcome.consumed, CHUNKED_DATA.len() - 6);
+        assert_eq!(outcome.response.map(|r| r.code), Some(250));
+        assert_eq!(session.handler.0, CHUNKED_BODY);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+    }
+
+    #[test]
+    fn data_chunk_splits() {
+        for split in 1..CHUNKED_DATA.len() {
+            let mut session = start_chunked_data();
+            let first = session.process_data_chunk(&CHUNKED_DATA[..split]);
+            let consumed = if first.ended {
+                first.consumed
+            } else {
+                assert_eq!(first.consumed, split);
+                assert_eq!(first.response, None);
+                let second = session.process_data_chunk(&CHUNKED_DATA[split..]);
+                assert!(second.ended, "no end with split at {}", split);
+                split + second.consumed
+            };
+            assert_eq!(consumed, CHUNKED_DATA.len() - 6);
+            assert_eq!(session.handler.0, CHUNKED_BODY, "split at {}", split);
+        }
+    }
+
+    #[test]
+    fn data_chunk_outside_data() {
+        let mut session = new_session();
+        let outcome = session.process_data_chunk(b"hello\r\n");
+        assert_eq!(outcome.consumed, 0);
+        assert_eq!(outcome.response.map(|r| r.code), Some(503));
+    }
+
+    fn new_bdat_session() -> Session<DataHandler> {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .enable_chunking()
+            .build(addr, DataHandler(vec![], 0));
+        let ehlo = session.process(b"ehlo a.domain\r\n").buffer().unwrap();
+        assert!(String::from_utf8(ehlo)
+            .unwrap()
+            .ends_with("250 CHUNKING\r\n"));
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        session
+    }
+
+    #[test]
+    fn bdat_last() {
+        let mut session = new_bdat_session();
+        let res = session.process(b"BDAT 13 LAST\r\n");
+        assert_eq!(res.action, Action::NoReply);
+        assert_eq!(session.chunk_remaining(), Some(13));
+        // The chunk is binary, dots and bare line endings are not changed
+        let outcome = session.process_data_chunk(b".Hello\rWorld\nQUIT\r\n");
+        assert!(outcome.ended);
+        assert_eq!(outcome.consumed, 13);
+        assert_eq!(outcome.response.map(|r| r.code), Some(250));
+        assert_eq!(session.handler.0, b".Hello\rWorld\n");
+        assert_eq!(session.chunk_remaining(), None);
+        assert_eq!(session.message_count(), 1);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+    }
+
+    #[test]
+    fn bdat_chunks() {
+        let mut session = new_bdat_session();
+        assert_eq!(session.process(b"bdat 10\r\n").action, Action::NoReply);
+        // Chunks can be passed to process in pieces
+        assert_eq!(session.process(b"Hello ").action, Action::NoReply);
+        assert_eq!(session.chunk_remaining(), Some(4));
+        let res = session.process(b"Wo\r\n");
+        assert_eq!(res.buffer().unwrap(), b"250 10 octets received\r\n");
+        assert_state!(session.fsm.current_state(), SmtpState::Data);
+        assert_eq!(session.process(b"noop\r\n").code, 250);
+        assert_eq!(session.process(b"data\r\n").code, 503);
+        session.process(b"bdat 5\r\n");
+        let outcome = session.process_data_chunk(b"rld\r\n");
+        assert_eq!(outcome.response.map(|r| r.code), Some(250));
+        assert!(!outcome.ended);
+        // An empty last chunk ends the message
+        assert_eq!(session.process(b"bdat 0 last\r\n").code, 250);
+        assert_eq!(session.handler.0, b"Hello Wo\r\nrld\r\n");
+        assert_eq!(session.message_count(), 1);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+    }
+
+    #[test]
+    fn bdat_abandoned() {
+        let mut session = new_bdat_session();
+        session.process(b"bdat 5\r\n");
+        session.process(b"Hello");
+        assert_eq!(session.handler.1, 0);
+        assert_eq!(session.process(b"rset\r\n").code, 250);
+        assert_eq!(session.handler.1, 1);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        // A new greeting also ends the transaction
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        session.process(b"bdat 5\r\n");
+        session.process(b"Hello");
+        assert_eq!(session.process(b"ehlo a.domain\r\n").code, 250);
+        assert_eq!(session.handler.1, 2);
+        // Other commands between chunks keep the message
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        session.process(b"bdat 5\r\n");
+        session.process(b"Hello");
+        assert_eq!(session.process(b"noop\r\n").code, 250);
+        assert_eq!(session.handler.1, 2);
+        assert_state!(session.fsm.current_state(), SmtpState::Data);
+    }
+
+    #[test]
+    fn bdat_past_chunk_end() {
+        // The commands after a chunk are split from it
+        let mut session = new_bdat_session();
+        let responses = session.process_many(b"bdat 5 last\r\nHelloQUIT\r\n");
+        let codes: Vec<u16> = responses.iter().map(|r| r.code).collect();
+        assert_eq!(codes, vec![0, 250, 221]);
+        assert_eq!(session.handler.0, b"Hello");
+        // process cannot hand back the bytes after the chunk
+        let mut session = new_bdat_session();
+        session.process(b"bdat 5 last\r\n");
+        let res = session.process(b"HelloQUIT\r\n");
+        assert_eq!(res.code, 421);
+        assert_eq!(res.action, Action::Close);
+        assert_eq!(session.handler.1, 1);
+    }
+
+    #[test]
+    fn bdat_rejected() {
+        let mut session = new_bdat_session();
+        session.process(b"rset\r\n");
+        // The chunk of a rejected BDAT is discarded
+        assert_eq!(session.process(b"bdat 13 last\r\n").code, 503);
+        assert_eq!(session.chunk_remaining(), Some(13));
+        assert_eq!(session.process(b"mail fro").action, Action::NoReply);
+        assert_eq!(session.process(b"m:<\r\n").action, Action::NoReply);
+        assert_eq!(session.chunk_remaining(), None);
+        assert_eq!(session.process(b"mail from:<ship@sea.com>\r\n").code, 250);
+        assert!(session.handler.0.is_empty());
+        // Without the extension, BDAT is an unknown command
+        let mut session = new_data_session();
+        session.process(b"helo a.domain\r\n");
+        assert_eq!(session.process(b"bdat 8 last\r\n").code, 500);
+        assert_eq!(session.chunk_remaining(), None);
+    }
+
+    struct SniHandler(Option<String>);
+    impl Handler for SniHandler {
+        fn tls_started(&mut self, sni: Option<&str>) {
+            self.0 = sni.map(String::from);
+        }
+    }
+
+    #[test]
+    fn tls_sni() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .enable_start_tls()
+            .build(addr, SniHandler(None));
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"starttls\r\n");
+        assert_eq!(session.sni(), None);
+        session.tls_active_with_sni(Some("tenant.example.com".to_string()));
+        assert_eq!(session.sni(), Some("tenant.example.com"));
+        assert_eq!(session.handler.0.as_deref(), Some("tenant.example.com"));
     }
 
     #[test]
-    fn rset_idle() {
-        let mut session = new_session();
-        let res = session.process(b"rset\r\n");
-        assert_eq!(res.code, 250);
+    fn forwarded_tls() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name").build(addr, SniHandler(None));
+        session.forwarded_tls(
+            Some("tenant.example.com".to_string()),
+            Some(b"smtp".to_vec()),
+        );
+        assert_eq!(session.sni(), Some("tenant.example.com"));
+        assert_eq!(session.alpn(), Some(&b"smtp"[..]));
+        assert_eq!(session.handler.0.as_deref(), Some("tenant.example.com"));
         assert_state!(session.fsm.current_state(), SmtpState::Idle);
     }
 
     #[test]
-    fn quit() {
-        let mut session = new_session();
-        session.process(b"helo a.domain\r\n");
-        session.process(b"mail from:<ship@sea.com>\r\n");
-        let res = session.process(b"quit\r\n");
-        assert_eq!(res.code, 221);
-        assert_eq!(res.action, Action::Close);
-        assert_state!(session.fsm.current_state(), SmtpState::Invalid);
+    fn forwarded_tls_active() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .enable_start_tls()
+            .enable_auth(AuthMechanism::Plain)
+            .build(addr, SniHandler(None));
+        session.forwarded_tls(None, None);
+        let res = session.process(b"ehlo a.domain\r\n");
+        let ehlo = String::from_utf8(res.buffer().unwrap()).unwrap();
+        assert!(!ehlo.contains("STARTTLS"), "{}", ehlo);
+        assert!(ehlo.contains("AUTH PLAIN"), "{}", ehlo);
+        assert_eq!(session.process(b"starttls\r\n").code, 503);
+        // The password is not refused for want of TLS
+        let res = session.process(b"auth plain dGVzdAB0ZXN0AHRlc3Q=\r\n");
+        assert_eq!(res.code, 535);
+    }
+
+    #[derive(Default)]
+    struct RawPathHandler {
+        from: (String, String),
+        to: (String, String),
+    }
+    impl Handler for RawPathHandler {
+        fn mail_raw(&mut self, _ip: IpAddr, _domain: &str, from: &str, raw: &str) -> Response {
+            self.from = (from.to_string(), raw.to_string());
+            OK
+        }
+
+        fn rcpt_raw(&mut self, to: &str, raw: &str) -> Response {
+            self.to = (to.to_string(), raw.to_string());
+            OK
+        }
     }
 
     #[test]
-    fn vrfy() {
-        let mut session = new_session();
+    fn raw_paths() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name").build(addr, RawPathHandler::default());
         session.process(b"helo a.domain\r\n");
-        let res1 = session.process(b"vrfy kraken\r\n");
-        assert_eq!(res1.code, 252);
-        assert_state!(session.fsm.current_state(), SmtpState::Hello);
-        session.process(b"mail from:<ship@sea.com>\r\n");
-        let res2 = session.process(b"vrfy boat\r\n");
-        assert_eq!(res2.code, 503);
-        assert_state!(session.fsm.current_state(), SmtpState::Mail);
+        session.process(b"mail from:<@relay:user@domain>\r\n");
+        session.process(b"rcpt to: <fish@sea.com>\r\n");
+        let handler = &session.handler;
+        assert_eq!(handler.from.0, "user@domain");
+        assert_eq!(handler.from.1, "<@relay:user@domain>");
+        assert_eq!(handler.to.0, "fish@sea.com");
+        assert_eq!(handler.to.1, "<fish@sea.com>");
     }
 
-    struct AuthHandler {}
-    impl Handler for AuthHandler {
-        fn auth_plain(
+    #[derive(Default)]
+    struct ParamsHandler {
+        mail: Vec<(String, String)>,
+        rcpt: Vec<(String, String)>,
+    }
+    impl Handler for ParamsHandler {
+        fn mail_params(
             &mut self,
-            authorization_id: &str,
-            authentication_id: &str,
-            password: &str,
+            _ip: IpAddr,
+            _domain: &str,
+            _from: &str,
+            _raw_from: &str,
+            params: &[(String, String)],
         ) -> Response {
-            ternary!(
-                authorization_id == "test" && authentication_id == "test" && password == "1234",
-                AUTH_OK,
-                INVALID_CREDENTIALS
-            )
+            self.mail = params.to_vec();
+            OK
         }
 
-        fn auth_login(&mut self, username: &str, password: &str) -> Response {
+        fn rcpt_params(
+            &mut self,
+            _to: &str,
+            _raw_to: &str,
+            params: &[(String, String)],
+        ) -> Response {
+            self.rcpt = params.to_vec();
+            OK
+        }
+    }
+
+    #[test]
+    fn esmtp_params() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name").build(addr, ParamsHandler::default());
+        session.process(b"helo a.domain\r\n");
+        let res = session.process(b"mail from:<ship@sea.com> AUTH=<> ENVID=abc\r\n");
+        assert_eq!(res.code, 250);
+        let res = session.process(b"rcpt to:<fish@sea.com> NOTIFY=NEVER\r\n");
+        assert_eq!(res.code, 250);
+        let handler = &session.handler;
+        assert_eq!(
+            handler.mail,
+            vec![
+                ("AUTH".to_string(), "<>".to_string()),
+                ("ENVID".to_string(), "abc".to_string())
+            ]
+        );
+        assert_eq!(
+            handler.rcpt,
+            vec![("NOTIFY".to_string(), "NEVER".to_string())]
+        );
+    }
+
+    #[derive(Default)]
+    struct RelayHandler {
+        envelope: Option<Envelope>,
+    }
+    impl Handler for RelayHandler {
+        fn auth_plain(&mut self, _: &str, authentication_id: &str, password: &str) -> Response {
             ternary!(
-                username == "test" && password == "1234",
+                authentication_id == "test" && password == "1234",
                 AUTH_OK,
                 INVALID_CREDENTIALS
             )
         }
-    }
 
-    fn new_auth_session(with_start_tls: bool) -> Session<AuthHandler> {
-        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
-        let mut builder = SessionBuilder::new("some.domain");
-        builder.enable_auth(AuthMechanism::Plain);
-        builder.enable_auth(AuthMechanism::Login);
-        if with_start_tls {
-            builder.enable_start_tls();
+        fn data_end_envelope(&mut self, envelope: &Envelope) -> Response {
+            self.envelope = Some(envelope.clone());
+            OK
         }
-        builder.build(addr, AuthHandler {})
     }
 
-    fn start_tls(session: &mut Session<AuthHandler>) {
-        let res = session.process(b"ehlo a.domain\r\n");
-        assert_eq!(res.code, 250);
-        assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
-        let res = session.process(b"starttls\r\n");
-        assert_eq!(res.code, 220);
+    #[test]
+    fn envelope() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut builder = SessionBuilder::new("some.name");
+        builder.enable_auth(AuthMechanism::Plain).enable_start_tls();
+        let mut session = builder.build(addr, RelayHandler::default());
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"starttls\r\n");
         session.tls_active();
+        session.process(b"ehlo b.domain\r\n");
+        let res = session.process(b"auth plain dGVzdAB0ZXN0ADEyMzQ=\r\n");
+        assert_eq!(res.code, 235);
+        session.process(b"mail from:<ship@sea.com> BODY=8BITMIME SIZE=100 ENVID=abc\r\n");
+        session.process(b"rcpt to:<fish@sea.com> NOTIFY=NEVER\r\n");
+        session.process(b"rcpt to:<kraken@sea.com>\r\n");
+        session.process(b"data\r\n");
+        session.process(b"Hello\r\n");
+        let res = session.process(b".\r\n");
+        assert_eq!(res.code, 250);
+        let param = |k: &str, v: &str| (k.to_string(), v.to_string());
+        let expected = Envelope {
+            helo_domain: "b.domain".to_string(),
+            reverse_path: "ship@sea.com".to_string(),
+            mail_params: vec![
+                param("BODY", "8BITMIME"),
+                param("SIZE", "100"),
+                param("ENVID", "abc"),
+            ],
+            recipients: vec![
+                Recipient {
+                    forward_path: "fish@sea.com".to_string(),
+                    params: vec![param("NOTIFY", "NEVER")],
+                },
+                Recipient {
+                    forward_path: "kraken@sea.com".to_string(),
+                    params: vec![],
+                },
+            ],
+            size: Some(100),
+            is8bit: true,
+            smtputf8: false,
+            auth_id: Some("test".to_string()),
+            tls: true,
+        };
+        assert_eq!(session.handler.envelope, Some(expected));
+    }
+
+    #[test]
+    fn phases() {
+        let mut session = new_auth_session(true);
+        assert_eq!(session.phase(), Phase::Connected);
+        start_tls(&mut session);
+        session.process(b"ehlo a.domain\r\n");
+        assert_eq!(session.phase(), Phase::Greeted);
+        session.process(b"auth plain dGVzdAB0ZXN0ADEyMzQ=\r\n");
+        assert_eq!(session.phase(), Phase::Authenticated);
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        assert_eq!(session.phase(), Phase::MailFrom);
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        assert_eq!(session.phase(), Phase::RcptTo);
+        session.process(b"data\r\n");
+        assert_eq!(session.phase(), Phase::Data);
+        session.process(b".\r\n");
+        assert_eq!(session.phase(), Phase::Authenticated);
+        session.process(b"quit\r\n");
+        assert_eq!(session.phase(), Phase::Closed);
     }
 
     #[test]
@@ -422,6 +2430,22 @@ mod tests {
         assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
     }
 
+    #[test]
+    fn auth_requires_tls() {
+        let mut session = new_auth_session(true);
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process(b"auth plain dGVzdAB0ZXN0ADEyMzQ=\r\n");
+        assert_eq!(res.code, 538);
+        assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
+        let res = session.process(b"starttls\r\n");
+        assert_eq!(res.code, 220);
+        session.tls_active();
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process(b"auth plain dGVzdAB0ZXN0ADEyMzQ=\r\n");
+        assert_eq!(res.code, 235);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+    }
+
     #[test]
     fn auth_ehlo() {
         let mut session = new_auth_session(true);
@@ -432,7 +2456,8 @@ mod tests {
         let greeting = String::from_utf8(res.buffer().unwrap()).unwrap();
         assert_eq!(
             greeting,
-            "250-server offers extensions:\r\n250-8BITMIME\r\n250 AUTH PLAIN LOGIN\r\n".to_string()
+            "250-server offers extensions:\r\n250-8BITMIME\r\n250-SIZE\r\n250 AUTH PLAIN LOGIN\r\n"
+                .to_string()
         )
     }
 
@@ -448,6 +2473,113 @@ mod tests {
         assert_state!(session.fsm.current_state(), SmtpState::Hello);
     }
 
+    #[test]
+    fn max_auth_attempts() {
+        let mut session = new_auth_session(true);
+        start_tls(&mut session);
+        session.process(b"ehlo a.domain\r\n");
+        for _ in 0..2 {
+            let res = session.process(b"auth plain dGVzdAB0ZXN0AHdyb25n\r\n");
+            assert_eq!(res.code, 535);
+            assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
+        }
+        let res = session.process(b"auth plain dGVzdAB0ZXN0AHdyb25n\r\n");
+        assert_eq!(res.code, 421);
+        assert_eq!(res.action, Action::Close);
+        assert_state!(session.fsm.current_state(), SmtpState::Invalid);
+    }
+
+    #[test]
+    fn max_auth_attempts_malformed() {
+        let mut session = new_auth_session(true);
+        start_tls(&mut session);
+        session.process(b"ehlo a.domain\r\n");
+        // Responses that cannot be decoded count as failed attempts
+        for _ in 0..2 {
+            session.process(b"auth login\r\n");
+            let res = session.process(b"ZHVtb\r\n");
+            assert_eq!(res.code, 535);
+            assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
+        }
+        session.process(b"auth login\r\n");
+        let res = session.process(b"ZHVtb\r\n");
+        assert_eq!(res.code, 421);
+        assert_eq!(res.action, Action::Close);
+        assert_state!(session.fsm.current_state(), SmtpState::Invalid);
+    }
+
+    #[test]
+    fn auth_success_within_attempts() {
+        let mut session = new_auth_session(true);
+        start_tls(&mut session);
+        session.process(b"ehlo a.domain\r\n");
+        for _ in 0..2 {
+            let res = session.process(b"auth plain dGVzdAB0ZXN0AHdyb25n\r\n");
+            assert_eq!(res.code, 535);
+        }
+        let res = session.process(b"auth plain dGVzdAB0ZXN0ADEyMzQ=\r\n");
+        assert_eq!(res.code, 235);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+    }
+
+    // Defers every authentication to an external check
+    struct DeferredAuthHandler {}
+    impl Handler for DeferredAuthHandler {
+        fn auth_plain(
+            &mut self,
+            _authorization_id: &str,
+            _authentication_id: &str,
+            _password: &str,
+        ) -> Response {
+            AUTH_PENDING
+        }
+    }
+
+    #[test]
+    fn deferred_auth() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut builder = SessionBuilder::new("some.domain");
+        builder.enable_auth(AuthMechanism::Plain).enable_start_tls();
+        let mut session = builder.build(addr, DeferredAuthHandler {});
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"starttls\r\n");
+        session.tls_active();
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process(b"auth plain dGVzdAB0ZXN0ADEyMzQ=\r\n");
+        assert_eq!(res.action, Action::NoReply);
+        assert_state!(session.fsm.current_state(), SmtpState::AuthPending);
+        // Commands wait until the external check finishes
+        let res = session.process(b"mail from:<ship@sea.com>\r\n");
+        assert_eq!(res.code, 503);
+        assert_state!(session.fsm.current_state(), SmtpState::AuthPending);
+        let res = session.complete_auth(AUTH_OK);
+        assert_eq!(res.code, 235);
+        assert_eq!(session.phase(), Phase::Authenticated);
+        let res = session.process(b"mail from:<ship@sea.com>\r\n");
+        assert_eq!(res.code, 250);
+        // Only a pending authentication can be completed
+        let res = session.complete_auth(AUTH_OK);
+        assert_eq!(res.code, 503);
+    }
+
+    #[test]
+    fn deferred_auth_failure() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut builder = SessionBuilder::new("some.domain");
+        builder.enable_auth(AuthMechanism::Plain).enable_start_tls();
+        let mut session = builder.build(addr, DeferredAuthHandler {});
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"starttls\r\n");
+        session.tls_active();
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"auth plain\r\n");
+        session.process(b"dGVzdAB0ZXN0ADEyMzQ=\r\n");
+        assert_state!(session.fsm.current_state(), SmtpState::AuthPending);
+        let res = session.complete_auth(INVALID_CREDENTIALS);
+        assert_eq!(res.code, 535);
+        assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
+    }
+
     #[test]
     fn auth_login_param() {
         let mut session = new_auth_session(true);
@@ -490,6 +2622,17 @@ mod tests {
         assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
     }
 
+    #[test]
+    fn invalid_auth_login_param() {
+        let mut session = new_auth_session(true);
+        start_tls(&mut session);
+        let mut res = session.process(b"ehlo a.domain\r\n");
+        assert_eq!(res.code, 250);
+        res = session.process(b"auth login dGVzd\r\n");
+        assert_eq!(res.code, 501);
+        assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
+    }
+
     #[test]
     fn auth_plain_challenge() {
         let mut session = new_auth_session(true);
@@ -533,7 +2676,136 @@ mod tests {
         assert_eq!(res.code, 250);
         assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
         res = session.process(b"auth plain dGVzdAB0ZXN0ADEyMzQ=\r\n");
-        assert_eq!(res.code, 503);
+        assert_eq!(res.code, 538);
+        res = session.process(b"auth login\r\n");
+        assert_eq!(res.code, 538);
+    }
+
+    #[test]
+    fn auth_unrecognized() {
+        let mut session = new_auth_session(true);
+        start_tls(&mut session);
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process(b"auth gssapi\r\n");
+        assert_eq!(
+            res.buffer().unwrap(),
+            b"504 Unrecognized authentication type\r\n"
+        );
+        assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
+        // CRAM-MD5 is implemented but not enabled
+        let res = session.process(b"auth cram-md5\r\n");
+        assert_eq!(res.code, 504);
+        // An enabled mechanism still works
+        let res = session.process(b"auth plain dGVzdAB0ZXN0ADEyMzQ=\r\n");
+        assert_eq!(res.code, 235);
+    }
+
+    // Checks the CRAM-MD5 response from RFC 2195 and records the challenge
+    #[derive(Default)]
+    struct CramMd5Handler {
+        challenge: Option<String>,
+    }
+    impl Handler for CramMd5Handler {
+        fn auth_cram_md5(&mut self, username: &str, challenge: &str, digest: &str) -> Response {
+            self.challenge = Some(challenge.to_string());
+            ternary!(
+                username == "tim" && digest == "b913a602c7eda7a495b4e6e7334d3890",
+                AUTH_OK,
+                INVALID_CREDENTIALS
+            )
+        }
+    }
+
+    fn new_cram_md5_session() -> Session<CramMd5Handler> {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut builder = SessionBuilder::new("some.domain");
+        builder
+            .enable_auth(AuthMechanism::Plain)
+            .enable_auth(AuthMechanism::CramMd5)
+            .enable_start_tls();
+        builder.build(addr, CramMd5Handler::default())
+    }
+
+    // Send AUTH CRAM-MD5 and return the decoded challenge
+    fn cram_md5_challenge(session: &mut Session<CramMd5Handler>) -> String {
+        let res = session.process(b"auth cram-md5\r\n");
+        assert_eq!(res.code, 334);
+        assert_state!(session.fsm.current_state(), SmtpState::Auth);
+        let buf = res.buffer().unwrap();
+        let encoded = std::str::from_utf8(&buf[4..]).unwrap().trim_end();
+        String::from_utf8(base64::decode(encoded).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn auth_cram_md5() {
+        let mut session = new_cram_md5_session();
+        // CRAM-MD5 does not send the password so it is offered without TLS
+        let res = session.process(b"ehlo a.domain\r\n");
+        let greeting = String::from_utf8(res.buffer().unwrap()).unwrap();
+        assert!(greeting.contains("250-STARTTLS\r\n250 AUTH CRAM-MD5\r\n"));
+        let challenge = cram_md5_challenge(&mut session);
+        assert!(challenge.starts_with('<'));
+        assert!(challenge.ends_with("@some.domain>"));
+        // tim b913a602c7eda7a495b4e6e7334d3890
+        let res = session.process(b"dGltIGI5MTNhNjAyYzdlZGE3YTQ5NWI0ZTZlNzMzNGQzODkw\r\n");
+        assert_eq!(res.code, 235);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        assert_eq!(session.handler.challenge, Some(challenge));
+    }
+
+    #[test]
+    fn auth_cram_md5_after_tls() {
+        let mut session = new_cram_md5_session();
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"starttls\r\n");
+        session.tls_active();
+        let res = session.process(b"ehlo a.domain\r\n");
+        let greeting = String::from_utf8(res.buffer().unwrap()).unwrap();
+        assert!(greeting.ends_with("250 AUTH PLAIN CRAM-MD5\r\n"));
+        cram_md5_challenge(&mut session);
+    }
+
+    #[test]
+    fn bad_auth_cram_md5() {
+        let mut session = new_cram_md5_session();
+        session.process(b"ehlo a.domain\r\n");
+        cram_md5_challenge(&mut session);
+        // The wrong digest
+        let res = session.process(b"dGltIDAwMDA=\r\n");
+        assert_eq!(res.code, 535);
+        assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
+        // No digest
+        cram_md5_challenge(&mut session);
+        let res = session.process(b"dGlt\r\n");
+        assert_eq!(res.code, 535);
+        assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
+        // Bad base64, the third failure closes the connection
+        cram_md5_challenge(&mut session);
+        let res = session.process(b"ZHVtb\r\n");
+        assert_eq!(res.code, 421);
+        assert_eq!(res.action, Action::Close);
+    }
+
+    #[test]
+    fn cancel_auth_cram_md5() {
+        let mut session = new_cram_md5_session();
+        session.process(b"ehlo a.domain\r\n");
+        cram_md5_challenge(&mut session);
+        let res = session.process(b"*\r\n");
+        assert_eq!(res.code, 501);
+        assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
+        assert_eq!(session.handler.challenge, None);
+    }
+
+    #[test]
+    fn cancel_auth_login() {
+        let mut session = new_auth_session(true);
+        start_tls(&mut session);
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"auth login\r\n");
+        let res = session.process(b"*\r\n");
+        assert_eq!(res.code, 501);
+        assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
     }
 
     #[test]
@@ -579,6 +2851,27 @@ mod tests {
         assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
     }
 
+    #[test]
+    fn malformed_auth_login_challenge() {
+        for (username, password) in [
+            (&b"\r\n"[..], &b"MTIzNA==\r\n"[..]),
+            (b"!!!\r\n", b"MTIzNA==\r\n"),
+            (b"dGVzdA==\r\n", b"\r\n"),
+            (b"dGVzdA==\r\n", b"MTI\r\n"),
+        ] {
+            let mut session = new_auth_session(true);
+            start_tls(&mut session);
+            session.process(b"ehlo a.domain\r\n");
+            session.process(b"auth login\r\n");
+            let mut res = session.process(username);
+            if res == PASSWORD_AUTH_CHALLENGE {
+                res = session.process(password);
+            }
+            assert_eq!(res.code, 535);
+            assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
+        }
+    }
+
     #[test]
     fn rset_with_auth() {
         let mut session = new_auth_session(true);
@@ -0,0 +1,113 @@
+// Measures parsing a message with many nested and sibling parts.
+// Run with `cargo bench -p mime-event`.
+use mime_event::{Event, EventParser, Handler};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+// Counts allocations so that changes to cloning in the parser can be seen
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const PARTS: usize = 500;
+const ITERATIONS: usize = 20;
+
+struct CountingHandler(usize);
+
+impl Handler for CountingHandler {
+    fn event(&mut self, _ev: Event) {
+        self.0 += 1;
+    }
+}
+
+// A multipart/mixed message with many attachments
+fn flat_message() -> Vec<Vec<u8>> {
+    let mut lines: Vec<Vec<u8>> = vec![
+        b"Subject: many parts\r\n".to_vec(),
+        b"Content-Type: multipart/mixed; boundary=\"outer-boundary\"\r\n".to_vec(),
+        b"\r\n".to_vec(),
+    ];
+    for i in 0..PARTS {
+        lines.push(b"--outer-boundary\r\n".to_vec());
+        lines.push(b"Content-Type: text/plain; charset=utf-8; format=flowed\r\n".to_vec());
+        lines.push(
+            format!("Content-Disposition: attachment; filename=\"{i}.txt\"\r\n").into_bytes(),
+        );
+        lines.push(b"\r\n".to_vec());
+        lines.push(format!("Attachment {i}\r\n").into_bytes());
+    }
+    lines.push(b"--outer-boundary--\r\n".to_vec());
+    lines
+}
+
+// A multipart/mixed message where each part is a multipart/alternative
+fn nested_message() -> Vec<Vec<u8>> {
+    let mut lines: Vec<Vec<u8>> = vec![
+        b"Subject: many parts\r\n".to_vec(),
+        b"Content-Type: multipart/mixed; boundary=\"outer-boundary\"\r\n".to_vec(),
+        b"\r\n".to_vec(),
+    ];
+    for i in 0..PARTS {
+        let boundary = format!("inner-boundary-{i}");
+        lines.push(b"--outer-boundary\r\n".to_vec());
+        lines.push(
+            format!("Content-Type: multipart/alternative; boundary=\"{boundary}\"\r\n")
+                .into_bytes(),
+        );
+        lines.push(b"\r\n".to_vec());
+        for mime in ["text/plain", "text/html"] {
+            lines.push(format!("--{boundary}\r\n").into_bytes());
+            lines.push(format!("Content-Type: {mime}; charset=utf-8\r\n").into_bytes());
+            lines.push(b"\r\n".to_vec());
+            lines.push(format!("Part {i} as {mime}\r\n").into_bytes());
+        }
+        lines.push(format!("--{boundary}--\r\n").into_bytes());
+        lines.push(b"\r\n".to_vec());
+    }
+    lines.push(b"--outer-boundary--\r\n".to_vec());
+    lines
+}
+
+fn bench(name: &str, lines: &[Vec<u8>]) -> io::Result<()> {
+    let start = Instant::now();
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let mut events = 0;
+    for _ in 0..ITERATIONS {
+        let mut parser = EventParser::new(io::sink(), CountingHandler(0));
+        for line in lines {
+            parser.write_all(line)?;
+        }
+        events += parser.end().0;
+    }
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    println!(
+        "{} with {} parts: {:?} per message, {} allocations per message, {} events",
+        name,
+        PARTS,
+        start.elapsed() / ITERATIONS as u32,
+        allocations / ITERATIONS,
+        events / ITERATIONS,
+    );
+    Ok(())
+}
+
+fn main() -> io::Result<()> {
+    bench("flat", &flat_message())?;
+    bench("nested", &nested_message())
+}
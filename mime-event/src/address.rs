@@ -0,0 +1,289 @@
+use crate::encoded_word;
+
+/// An email address from a header such as From or To
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Address {
+    /// The display name e.g "Sender Name"
+    pub display_name: Option<String>,
+    /// The part of the address before the @
+    pub local: String,
+    /// The part of the address after the @
+    pub domain: String,
+}
+
+/// Parse a list of comma separated addresses from a header value.
+///
+/// Display names can be quoted or RFC 2047 encoded. Entries that do not contain
+/// an address with a domain are skipped.
+/// # Example
+/// ```
+/// use mime_event::parse_addresses;
+///
+/// let addresses = parse_addresses(br#""Name, Sender" <sender@example.com>, other@example.com"#);
+/// assert_eq!(addresses.len(), 2);
+/// assert_eq!(addresses[0].display_name.as_deref(), Some("Name, Sender"));
+/// assert_eq!(addresses[1].local, "other");
+/// ```
+pub fn parse_addresses(raw: &[u8]) -> Vec<Address> {
+    split_addresses(raw)
+        .into_iter()
+        .filter_map(parse_address)
+        .collect()
+}
+
+// Decode the RFC 2047 encoded display names in a list of addresses. The list is
+// split before decoding so that a decoded name, e.g one containing a comma, cannot
+// add an address. Decoded names are quoted when they contain special characters.
+pub(crate) fn decode_display_names(raw: &[u8]) -> Vec<u8> {
+    let entries: Vec<Vec<u8>> = split_addresses(raw)
+        .into_iter()
+        .map(decode_display_name)
+        .collect();
+    entries.join(&b","[..])
+}
+
+fn decode_display_name(entry: &[u8]) -> Vec<u8> {
+    let open = match entry.iter().rposition(|c| *c == b'<') {
+        Some(open) if entry[..open].windows(2).any(|w| w == b"=?") => open,
+        _ => return entry.to_vec(),
+    };
+    let indent = entry.len() - entry.trim_ascii_start().len();
+    let name = encoded_word::decode_header(&unquote(trim(&entry[..open])));
+    let mut ret = entry[..indent].to_vec();
+    if name.bytes().any(|c| SPECIALS.contains(&c)) {
+        ret.push(b'"');
+        for c in name.bytes() {
+            if c == b'"' || c == b'\\' {
+                ret.push(b'\\');
+            }
+            ret.push(c);
+        }
+        ret.push(b'"');
+    } else {
+        ret.extend_from_slice(name.as_bytes());
+    }
+    ret.push(b' ');
+    ret.extend_from_slice(&entry[open..]);
+    ret
+}
+
+// Characters that must be quoted in a display name (RFC 5322)
+const SPECIALS: &[u8] = b"()<>[]:;@\\,.\"";
+
+// Split on commas that are not inside quotes, comments or angle brackets
+fn split_addresses(raw: &[u8]) -> Vec<&[u8]> {
+    let mut ret = Vec::new();
+    let mut start = 0;
+    let mut depth = 0;
+    let mut in_quotes = false;
+    let mut in_angle = false;
+    let mut escaped = false;
+    for (i, &c) in raw.iter().enumerate() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match c {
+            b'\\' => escaped = true,
+            b'"' if depth == 0 => in_quotes = !in_quotes,
+            b'(' if !in_quotes => depth += 1,
+            b')' if !in_quotes && depth > 0 => depth -= 1,
+            b'<' if !in_quotes && depth == 0 => in_angle = true,
+            b'>' if !in_quotes && depth == 0 => in_angle = false,
+            b',' if !in_quotes && depth == 0 && !in_angle => {
+                ret.push(&raw[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    ret.push(&raw[start..]);
+    ret
+}
+
+fn parse_address(raw: &[u8]) -> Option<Address> {
+    let item = strip_comments(raw);
+    let (name, addr) = match item.iter().rposition(|c| *c == b'<') {
+        Some(open) => {
+            let close = item[open..].iter().position(|c| *c == b'>')? + open;
+            (trim(&item[..open]), &item[open + 1..close])
+        }
+        None => (&[] as &[u8], trim(&item)),
+    };
+    let at = addr.iter().rposition(|c| *c == b'@')?;
+    let local = unquote(&addr[..at]);
+    let domain = &addr[at + 1..];
+    if local.is_empty() || domain.is_empty() {
+        return None;
+    }
+    let display_name = if name.is_empty() {
+        None
+    } else {
+        Some(encoded_word::decode_header(&unquote(name)))
+    };
+    Some(Address {
+        display_name,
+        local: String::from_utf8_lossy(&local).into_owned(),
+        domain: String::from_utf8_lossy(domain).into_owned(),
+    })
+}
+
+// Remove surrounding quotes and escapes from a quoted string
+fn unquote(value: &[u8]) -> Vec<u8> {
+    match value
+        .strip_prefix(b"\"")
+        .and_then(|v| v.strip_suffix(b"\""))
+    {
+        Some(inner) => {
+            let mut ret = Vec::with_capacity(inner.len());
+            let mut escaped = false;
+            for &c in inner {
+                if c == b'\\' && !escaped {
+                    escaped = true;
+                } else {
+                    ret.push(c);
+                    escaped = false;
+                }
+            }
+            ret
+        }
+        None => value.to_vec(),
+    }
+}
+
+/// Remove comments in parentheses from a header value such as an email address.
+///
+/// Comments can be nested and parentheses inside quoted strings are not treated
+/// as comments.
+/// # Example
+/// ```
+/// use mime_event::strip_comments;
+///
+/// let from = strip_comments(b"sender@example.com (Sender Name)");
+/// assert_eq!(from, b"sender@example.com");
+/// ```
+pub fn strip_comments(value: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(value.len());
+    let mut depth = 0;
+    let mut in_quotes = false;
+    let mut escaped = false;
+    for &c in value {
+        if escaped {
+            escaped = false;
+        } else if c == b'\\' {
+            escaped = true;
+        } else if c == b'"' && depth == 0 {
+            in_quotes = !in_quotes;
+        } else if c == b'(' && !in_quotes {
+            depth += 1;
+            continue;
+        } else if c == b')' && depth > 0 {
+            depth -= 1;
+            continue;
+        }
+        if depth == 0 {
+            ret.push(c);
+        }
+    }
+    trim(&ret).to_vec()
+}
+
+// Remove leading and trailing whitespace
+fn trim(value: &[u8]) -> &[u8] {
+    let start = value
+        .iter()
+        .position(|c| !c.is_ascii_whitespace())
+        .unwrap_or(value.len());
+    let end = value
+        .iter()
+        .rposition(|c| !c.is_ascii_whitespace())
+        .map(|i| i + 1)
+        .unwrap_or(start);
+    &value[start..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header::Header;
+    use crate::line_parser::header;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn from_comment() {
+        let tok = header(b"From: sender@example.com (Sender (the) Name)\r\n").unwrap();
+        match tok {
+            Header::From(from) => assert_eq!(strip_comments(from), b"sender@example.com"),
+            _ => panic!("From header not parsed"),
+        }
+    }
+
+    fn address(display_name: Option<&str>, local: &str, domain: &str) -> Address {
+        Address {
+            display_name: display_name.map(|n| n.to_string()),
+            local: local.to_string(),
+            domain: domain.to_string(),
+        }
+    }
+
+    #[test]
+    fn single_address() {
+        let addresses = parse_addresses(b"sender@example.com");
+        assert_eq!(addresses, vec![address(None, "sender", "example.com")]);
+    }
+
+    #[test]
+    fn multiple_addresses() {
+        let addresses = parse_addresses(b"Sender <sender@example.com>, other@example.com (Other)");
+        assert_eq!(
+            addresses,
+            vec![
+                address(Some("Sender"), "sender", "example.com"),
+                address(None, "other", "example.com"),
+            ]
+        );
+    }
+
+    #[test]
+    fn quoted_name() {
+        let addresses = parse_addresses(
+            br#""Name, \"The\" Sender" <sender@example.com>, "x@y" <z@example.com>"#,
+        );
+        assert_eq!(
+            addresses,
+            vec![
+                address(Some(r#"Name, "The" Sender"#), "sender", "example.com"),
+                address(Some("x@y"), "z", "example.com"),
+            ]
+        );
+    }
+
+    #[test]
+    fn encoded_name() {
+        let addresses = parse_addresses(b"=?utf-8?q?Caf=C3=A9?= <cafe@example.com>");
+        assert_eq!(
+            addresses,
+            vec![address(Some("Café"), "cafe", "example.com")]
+        );
+    }
+
+    #[test]
+    fn decoded_display_names() {
+        let decoded = decode_display_names(
+            b"=?utf-8?q?Caf=C3=A9?= <cafe@example.com>, =?utf-8?q?a=22b=2C?= <ab@example.com>",
+        );
+        assert_eq!(
+            decoded,
+            r#"Café <cafe@example.com>, "a\"b," <ab@example.com>"#.as_bytes()
+        );
+        // Names without encoded-words are left as they are
+        let raw = br#""Name, Sender" <sender@example.com>, other@example.com"#;
+        assert_eq!(decode_display_names(raw), raw);
+    }
+
+    #[test]
+    fn quoted_parentheses() {
+        let from = strip_comments(br#""Name (not a comment)" <a@b.com> (comment)"#);
+        assert_eq!(from, br#""Name (not a comment)" <a@b.com>"#);
+    }
+}
@@ -0,0 +1,109 @@
+use crate::event::Event;
+use crate::header::Header;
+use crate::parser::Handler;
+use crate::transfer_encoding::decode_quoted_printable;
+
+/// Event handler that decodes quoted-printable bodies before passing the
+/// events on to another handler.
+///
+/// `Event::Body` lines of parts with `Content-Transfer-Encoding: quoted-printable`
+/// are decoded, other events are passed on unchanged. The offsets in
+/// `Event::BodyStart` and `Event::PartEnd` refer to the raw message.
+/// # Example
+/// ```
+/// use mime_event::{DecodingHandler, EventParser, Handler, Event};
+/// # use std::io;
+/// # use std::io::Write;
+///
+/// #[derive(Default)]
+/// struct BodyHandler{
+///   body: Vec<u8>,
+/// }
+///
+/// impl Handler for BodyHandler {
+///     fn event<'a>(&mut self, ev: Event<'a>) {
+///         if let Event::Body(b) = ev {
+///             self.body.extend_from_slice(b);
+///         }
+///     }
+/// }
+///
+/// let handler = DecodingHandler::new(BodyHandler::default());
+/// let mut parser = EventParser::new(io::sink(), handler);
+/// parser.write_all(b"Content-Transfer-Encoding: quoted-printable\r\n");
+/// parser.write_all(b"\r\n");
+/// parser.write_all(b"caf=C3=A9 au =\r\n");
+/// parser.write_all(b"lait\r\n");
+/// let handler = parser.end().into_inner();
+/// assert_eq!(handler.body, "café au lait\r\n".as_bytes());
+/// ```
+pub struct DecodingHandler<H: Handler> {
+    handler: H,
+    // Is the body of the current part quoted-printable?
+    quoted_printable: bool,
+    // An encoded character split between two body events
+    pending: Vec<u8>,
+}
+
+impl<H: Handler> DecodingHandler<H> {
+    /// Create a DecodingHandler that sends decoded events to the given handler
+    pub fn new(handler: H) -> Self {
+        Self {
+            handler,
+            quoted_printable: false,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Get the wrapped handler
+    pub fn into_inner(self) -> H {
+        self.handler
+    }
+}
+
+impl<H: Handler> Handler for DecodingHandler<H> {
+    fn event(&mut self, ev: Event) {
+        match ev {
+            Event::Header(Header::ContentTransferEncoding(encoding)) => {
+                self.quoted_printable = encoding
+                    .trim_ascii()
+                    .eq_ignore_ascii_case(b"quoted-printable");
+            }
+            // Each part has its own encoding
+            Event::PartStart { .. } => self.quoted_printable = false,
+            Event::Body(line) if self.quoted_printable => {
+                self.body(line);
+                return;
+            }
+            _ => (),
+        }
+        self.flush();
+        self.handler.event(ev);
+    }
+}
+
+impl<H: Handler> DecodingHandler<H> {
+    // Encoded characters never span lines, but a long line can be split
+    // into several events, so hold back an encoded character that is cut off
+    fn body(&mut self, chunk: &[u8]) {
+        let mut line = std::mem::take(&mut self.pending);
+        line.extend_from_slice(chunk);
+        if !line.ends_with(b"\n") {
+            let start = line.len().saturating_sub(2);
+            if let Some(i) = line[start..].iter().position(|&b| b == b'=') {
+                self.pending = line.split_off(start + i);
+            }
+        }
+        let decoded = decode_quoted_printable(&line);
+        self.handler.event(Event::Body(&decoded));
+    }
+
+    // Pass on anything left at the end of a body
+    fn flush(&mut self) {
+        if !self.pending.is_empty() {
+            let pending = std::mem::take(&mut self.pending);
+            self.handler
+                .event(Event::Body(&decode_quoted_printable(&pending)));
+        }
+    }
+}
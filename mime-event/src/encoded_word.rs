@@ -0,0 +1,171 @@
+// Decoding of RFC 2047 encoded-words e.g =?utf-8?q?caf=C3=A9?=
+
+/// Decode the RFC 2047 encoded-words, e.g `=?utf-8?q?caf=C3=A9?=`, in a header
+/// value and return the text. Both the B and Q encodings are supported and
+/// whitespace between adjacent encoded-words is removed. Text in charsets other
+/// than UTF-8 and ISO-8859-1 is converted lossily as UTF-8.
+/// ```
+/// use mime_event::decode_header;
+///
+/// let subject = decode_header(b"Re: =?UTF-8?B?Y2Fmw6k=?= time");
+/// assert_eq!(subject, "Re: café time");
+/// ```
+pub fn decode_header(value: &[u8]) -> String {
+    let mut ret = String::with_capacity(value.len());
+    let mut rest = value;
+    // Whitespace between two encoded-words is not displayed
+    let mut pending_space: &[u8] = b"";
+    let mut last_encoded = false;
+    while !rest.is_empty() {
+        if let Some((text, remaining)) = encoded_word(rest) {
+            if !last_encoded {
+                ret.push_str(&String::from_utf8_lossy(pending_space));
+            }
+            ret.push_str(&text);
+            pending_space = b"";
+            last_encoded = true;
+            rest = remaining;
+        } else {
+            let end = next_token(rest);
+            let (token, remaining) = rest.split_at(end);
+            if token.iter().all(|c| c.is_ascii_whitespace()) {
+                pending_space = token;
+            } else {
+                ret.push_str(&String::from_utf8_lossy(pending_space));
+                ret.push_str(&String::from_utf8_lossy(token));
+                pending_space = b"";
+                last_encoded = false;
+            }
+            rest = remaining;
+        }
+    }
+    ret.push_str(&String::from_utf8_lossy(pending_space));
+    ret
+}
+
+// Find the end of the next run of whitespace or non-whitespace
+fn next_token(buf: &[u8]) -> usize {
+    let is_space = buf[0].is_ascii_whitespace();
+    let mut end = 1;
+    while end < buf.len() && buf[end].is_ascii_whitespace() == is_space {
+        // Stop before a possible encoded-word
+        if !is_space && buf[end..].starts_with(b"=?") {
+            break;
+        }
+        end += 1;
+    }
+    end
+}
+
+// Decode an encoded-word at the start of the buffer.
+// Returns the decoded text and the remaining buffer.
+fn encoded_word(buf: &[u8]) -> Option<(String, &[u8])> {
+    let inner = buf.strip_prefix(b"=?")?;
+    let mut fields = inner.splitn(3, |c| *c == b'?');
+    let charset = fields.next()?;
+    let encoding = fields.next()?;
+    let rest = fields.next()?;
+    let end = rest.windows(2).position(|w| w == b"?=")?;
+    let text = &rest[..end];
+    let bytes = match encoding {
+        b"B" | b"b" => base64::decode(text).ok()?,
+        b"Q" | b"q" => decode_q(text),
+        _ => return None,
+    };
+    Some((to_string(charset, bytes), &rest[end + 2..]))
+}
+
+// Decode the Q encoding, which is similar to quoted-printable
+fn decode_q(text: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(text.len());
+    let mut i = 0;
+    while i < text.len() {
+        match text[i] {
+            b'_' => ret.push(b' '),
+            b'=' if i + 2 < text.len() => match hex_byte(&text[i + 1..i + 3]) {
+                Some(b) => {
+                    ret.push(b);
+                    i += 2;
+                }
+                None => ret.push(b'='),
+            },
+            c => ret.push(c),
+        }
+        i += 1;
+    }
+    ret
+}
+
+// Convert two hex digits to a byte, from_str_radix alone would accept a sign
+pub(crate) fn hex_byte(digits: &[u8]) -> Option<u8> {
+    if !digits.iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+    let s = std::str::from_utf8(digits).ok()?;
+    u8::from_str_radix(s, 16).ok()
+}
+
+// Convert text in the given charset to a String
+fn to_string(charset: &[u8], bytes: Vec<u8>) -> String {
+    // Remove any RFC 2231 language suffix e.g utf-8*en
+    let charset = charset.split(|c| *c == b'*').next().unwrap_or(charset);
+    if charset.eq_ignore_ascii_case(b"iso-8859-1") || charset.eq_ignore_ascii_case(b"latin1") {
+        bytes.into_iter().map(char::from).collect()
+    } else {
+        String::from_utf8(bytes).unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn plain_text() {
+        assert_eq!(decode_header(b"Hello  World"), "Hello  World");
+    }
+
+    #[test]
+    fn q_encoded() {
+        assert_eq!(
+            decode_header(b"=?utf-8?Q?caf=C3=A9_au_lait?="),
+            "café au lait"
+        );
+    }
+
+    #[test]
+    fn b_encoded() {
+        assert_eq!(
+            decode_header(b"Re: =?UTF-8?B?Y2Fmw6k=?= time"),
+            "Re: café time"
+        );
+    }
+
+    #[test]
+    fn adjacent_words() {
+        assert_eq!(
+            decode_header(b"=?iso-8859-1?q?caf?= =?iso-8859-1?q?=E9?="),
+            "café"
+        );
+        // Folded whitespace between encoded-words is removed too
+        assert_eq!(
+            decode_header(b"=?utf-8?q?caf?=\r\n  =?utf-8?q?=C3=A9?= au lait"),
+            "café au lait"
+        );
+    }
+
+    #[test]
+    fn mixed_encodings() {
+        assert_eq!(
+            decode_header(b"=?UTF-8?B?Y2Fmw6k=?= =?utf-8?Q?_au_lait?= and =?utf-8?q?th=C3=A9?="),
+            "café au lait and thé"
+        );
+    }
+
+    #[test]
+    fn invalid_hex() {
+        // Not hex digits, so left as they are
+        assert_eq!(decode_header(b"=?utf-8?q?a=+Fb=zz?="), "a=+Fb=zz");
+    }
+}
@@ -22,8 +22,12 @@ pub enum Event<'a> {
         /// Byte offset of the Body in the mail message
         offset: usize,
     },
-    /// A line of an email body
+    /// A line of an email body, long lines are split into several events
     Body(&'a [u8]),
+    /// A line of the preamble, the text of a multipart before its first part
+    Preamble(&'a [u8]),
+    /// A line of the epilogue, the text after the close boundary of a multipart
+    Epilogue(&'a [u8]),
     /// End of a MIME mulitpart part
     PartEnd {
         /// Byte offset of the end of the Part in the mail message
@@ -53,6 +57,10 @@ pub enum Multipart {
     Mixed,
     /// MIME multipart message digest
     Digest,
+    /// MIME multipart related e.g html with embedded images
+    Related,
+    /// MIME multipart report e.g a delivery status notification
+    Report,
 }
 
 pub(crate) fn mime_type(v: &[u8]) -> Mime {
@@ -62,6 +70,8 @@ pub(crate) fn mime_type(v: &[u8]) -> Mime {
             "multipart/alternative" => Mime::Multipart(Multipart::Alternative),
             "multipart/mixed" => Mime::Multipart(Multipart::Mixed),
             "multipart/digest" => Mime::Multipart(Multipart::Digest),
+            "multipart/related" => Mime::Multipart(Multipart::Related),
+            "multipart/report" => Mime::Multipart(Multipart::Report),
             _ => Mime::Type(v.to_vec()),
         }
     } else {
@@ -78,6 +88,8 @@ impl<'a> fmt::Debug for Event<'a> {
             Event::PartStart { offset } => write!(f, "PartStart({offset:?})"),
             Event::BodyStart { offset } => write!(f, "BodyStart({offset:?})"),
             Event::Body(block) => write!(f, "Body({})", display_bytes(block)),
+            Event::Preamble(line) => write!(f, "Preamble({})", display_bytes(line)),
+            Event::Epilogue(line) => write!(f, "Epilogue({})", display_bytes(line)),
             Event::PartEnd { offset } => write!(f, "PartEnd({offset:?})"),
             Event::MultipartEnd => write!(f, "MultipartEnd"),
             Event::End => write!(f, "End"),
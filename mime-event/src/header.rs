@@ -30,6 +30,10 @@ pub enum Header<'a> {
     },
     /// Description of a MIME part
     ContentDescription(&'a [u8]),
+    /// How the body of a MIME part is encoded e.g "base64"
+    ContentTransferEncoding(&'a [u8]),
+    /// The Content-ID of a MIME part, as referenced by "cid:" URLs
+    ContentId(&'a [u8]),
     /// Subject header
     Subject(&'a [u8]),
     /// The SMTP sender header
@@ -59,6 +63,10 @@ impl<'a> fmt::Debug for Header<'a> {
             Header::MessageId(message_id) => dbg_single(f, "MessageId", message_id),
             Header::Date(date) => dbg_single(f, "Date", date),
             Header::ContentDescription(desc) => dbg_single(f, "ContentDescription", desc),
+            Header::ContentTransferEncoding(encoding) => {
+                dbg_single(f, "ContentTransferEncoding", encoding)
+            }
+            Header::ContentId(content_id) => dbg_single(f, "ContentId", content_id),
             Header::ContentDisposition {
                 disposition_type,
                 parameters,
@@ -30,6 +30,11 @@ impl HeaderBuffer {
         }
     }
 
+    // The number of bytes held until the header line is complete
+    pub(crate) fn len(&self) -> usize {
+        self.line.len()
+    }
+
     // Get the remaining contents of the buffer and clear the buffer
     pub(crate) fn take(&mut self) -> Option<(Vec<u8>, usize)> {
         if self.has_value {
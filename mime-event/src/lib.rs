@@ -72,7 +72,10 @@
 #![forbid(unsafe_code)]
 #![forbid(missing_docs)]
 
+mod address;
 mod debug;
+mod decoding_handler;
+mod encoded_word;
 mod event;
 mod header;
 mod header_buffer;
@@ -81,10 +84,14 @@ mod message;
 mod message_handler;
 mod message_parser;
 mod parser;
+mod transfer_encoding;
 
+pub use address::{parse_addresses, strip_comments, Address};
+pub use decoding_handler::DecodingHandler;
+pub use encoded_word::decode_header;
 pub use event::{Event, Mime, Multipart};
 pub use header::Header;
-pub use message::{HeaderFields, Message, Part};
+pub use message::{DuplicateHeaders, HeaderFields, Message, ParseWarning, Part};
 pub use message_handler::MessageHandler;
 pub use message_parser::MessageParser;
-pub use parser::{EventParser, Handler};
+pub use parser::{EventParser, Handler, MAX_BODY_EVENT};
@@ -8,9 +8,12 @@ use nom::IResult;
 use std::collections::HashMap;
 use std::io;
 
+// Header parameter names and their values
+type Parameters<'a> = HashMap<&'a [u8], Vec<u8>>;
+
 // Parse a header line.
 // The result type must be io::Result to be compatible with io::Write()
-pub(crate) fn header(line: &[u8]) -> io::Result<Header> {
+pub(crate) fn header(line: &[u8]) -> io::Result<Header<'_>> {
     let res = alt((
         header_end,
         content,
@@ -23,6 +26,8 @@ pub(crate) fn header(line: &[u8]) -> io::Result<Header> {
         date,
         content_disposition,
         content_description,
+        content_transfer_encoding,
+        content_id,
         unstructured,
     ))(line);
     match res {
@@ -34,7 +39,7 @@ pub(crate) fn header(line: &[u8]) -> io::Result<Header> {
     }
 }
 
-fn content(buf: &[u8]) -> IResult<&[u8], Header> {
+fn content(buf: &[u8]) -> IResult<&[u8], Header<'_>> {
     map(header_with_params(b"Content-Type"), |v| {
         Header::ContentType {
             mime_type: v.0,
@@ -43,7 +48,7 @@ fn content(buf: &[u8]) -> IResult<&[u8], Header> {
     })(buf)
 }
 
-fn content_disposition(buf: &[u8]) -> IResult<&[u8], Header> {
+fn content_disposition(buf: &[u8]) -> IResult<&[u8], Header<'_>> {
     map(header_with_params(b"Content-Disposition"), |v| {
         Header::ContentDisposition {
             disposition_type: v.0,
@@ -55,7 +60,7 @@ fn content_disposition(buf: &[u8]) -> IResult<&[u8], Header> {
 // Parse a header field followed by parameters
 fn header_with_params(
     header: &[u8],
-) -> impl Fn(&[u8]) -> IResult<&[u8], (&[u8], HashMap<&[u8], Vec<u8>>)> + '_ {
+) -> impl Fn(&[u8]) -> IResult<&[u8], (&[u8], Parameters<'_>)> + '_ {
     move |buf: &[u8]| {
         let preamble = match_header_key(header);
         let (i, value) = preceded(preamble, header_value_with_parameters)(buf)?;
@@ -81,7 +86,7 @@ fn header_value_with_parameters(buf: &[u8]) -> IResult<&[u8], &[u8]> {
     is_not(";\r\n")(buf)
 }
 
-fn parameters(buf: &[u8]) -> IResult<&[u8], HashMap<&[u8], Vec<u8>>> {
+fn parameters(buf: &[u8]) -> IResult<&[u8], Parameters<'_>> {
     fold_many0(parameter, HashMap::new, |mut acc: HashMap<_, _>, item| {
         acc.insert(item.0, item.1);
         acc
@@ -151,41 +156,52 @@ fn match_unstructured(header: &[u8]) -> impl Fn(&[u8]) -> IResult<&[u8], &[u8]>
     }
 }
 
-fn from(buf: &[u8]) -> IResult<&[u8], Header> {
+fn from(buf: &[u8]) -> IResult<&[u8], Header<'_>> {
     map(match_unstructured(b"From"), Header::From)(buf)
 }
 
-fn to(buf: &[u8]) -> IResult<&[u8], Header> {
+fn to(buf: &[u8]) -> IResult<&[u8], Header<'_>> {
     map(match_unstructured(b"To"), Header::To)(buf)
 }
 
-fn subject(buf: &[u8]) -> IResult<&[u8], Header> {
+fn subject(buf: &[u8]) -> IResult<&[u8], Header<'_>> {
     map(match_unstructured(b"Subject"), Header::Subject)(buf)
 }
 
-fn sender(buf: &[u8]) -> IResult<&[u8], Header> {
+fn sender(buf: &[u8]) -> IResult<&[u8], Header<'_>> {
     map(match_unstructured(b"Sender"), Header::Sender)(buf)
 }
 
-fn reply_to(buf: &[u8]) -> IResult<&[u8], Header> {
+fn reply_to(buf: &[u8]) -> IResult<&[u8], Header<'_>> {
     map(match_unstructured(b"Reply-To"), Header::ReplyTo)(buf)
 }
 
-fn message_id(buf: &[u8]) -> IResult<&[u8], Header> {
+fn message_id(buf: &[u8]) -> IResult<&[u8], Header<'_>> {
     map(match_unstructured(b"Message-ID"), Header::MessageId)(buf)
 }
 
-fn date(buf: &[u8]) -> IResult<&[u8], Header> {
+fn date(buf: &[u8]) -> IResult<&[u8], Header<'_>> {
     map(match_unstructured(b"Date"), Header::Date)(buf)
 }
 
-fn content_description(buf: &[u8]) -> IResult<&[u8], Header> {
+fn content_description(buf: &[u8]) -> IResult<&[u8], Header<'_>> {
     map(match_unstructured(b"Content-Description"), |v| {
         Header::ContentDescription(v)
     })(buf)
 }
 
-fn unstructured(buf: &[u8]) -> IResult<&[u8], Header> {
+fn content_transfer_encoding(buf: &[u8]) -> IResult<&[u8], Header<'_>> {
+    map(
+        match_unstructured(b"Content-Transfer-Encoding"),
+        Header::ContentTransferEncoding,
+    )(buf)
+}
+
+fn content_id(buf: &[u8]) -> IResult<&[u8], Header<'_>> {
+    map(match_unstructured(b"Content-ID"), Header::ContentId)(buf)
+}
+
+fn unstructured(buf: &[u8]) -> IResult<&[u8], Header<'_>> {
     let (i, key) = terminated(header_key, colon_space)(buf)?;
     let (i, value) = terminated(unstructured_value, tag(b"\r\n"))(i)?;
     Ok((i, Header::Unstructured(key, value)))
@@ -199,7 +215,7 @@ fn unstructured_value(buf: &[u8]) -> IResult<&[u8], &[u8]> {
     is_not("\r\n")(buf)
 }
 
-fn header_end(buf: &[u8]) -> IResult<&[u8], Header> {
+fn header_end(buf: &[u8]) -> IResult<&[u8], Header<'_>> {
     map(tag(b"\r\n"), |_| Header::End)(buf)
 }
 
@@ -233,6 +249,12 @@ mod tests {
         )
     }
 
+    #[test]
+    fn content_id() {
+        let tok = header(b"Content-ID: <logo@example.com>\r\n").unwrap();
+        assert_eq!(tok, Header::ContentId(b"<logo@example.com>"))
+    }
+
     #[test]
     fn content_type() {
         let tok = header(b"Content-Type: multipart/mixed; boundary=--boundary--\r\n").unwrap();
@@ -266,6 +288,16 @@ mod tests {
         )
     }
 
+    #[test]
+    fn content_transfer_encoding() {
+        let tok = header(b"content-transfer-encoding: base64\r\n").unwrap();
+        assert_eq!(tok, Header::ContentTransferEncoding(b"base64"));
+        let tok = header(b"Content-Transfer-Encoding: quoted-printable\r\n").unwrap();
+        assert_eq!(tok, Header::ContentTransferEncoding(b"quoted-printable"));
+        let tok = header(b"CONTENT-TRANSFER-ENCODING: 7bit\r\n").unwrap();
+        assert_eq!(tok, Header::ContentTransferEncoding(b"7bit"));
+    }
+
     #[test]
     fn quoted_boundary() {
         let tok =
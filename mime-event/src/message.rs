@@ -1,7 +1,10 @@
+use crate::address::{parse_addresses, Address};
 use crate::debug::OptionDbg;
-use crate::event::Mime;
+use crate::event::{Mime, Multipart};
+use crate::transfer_encoding;
 use std::collections::HashMap;
 use std::fmt;
+use std::fmt::Write;
 
 /// A simplified Email Message overview.
 ///
@@ -19,6 +22,44 @@ pub struct Message {
     pub(crate) inlines: Vec<usize>,
     pub(crate) other: Vec<usize>,
     pub(crate) parts: Vec<Part>,
+    // The multiparts and parts of the message in order
+    pub(crate) structure: Vec<Node>,
+    pub(crate) warnings: Vec<ParseWarning>,
+    // The number of bytes in the message and in its header
+    pub(crate) length: usize,
+    pub(crate) header_length: usize,
+    // The start and end of each part of the outermost multipart, a part that
+    // contains a nested multipart is a single section
+    pub(crate) sections: Vec<(usize, usize)>,
+    pub(crate) preamble: Vec<u8>,
+    pub(crate) epilogue: Vec<u8>,
+}
+
+/// A problem with a message that did not stop it from being parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseWarning {
+    /// A header field that should only appear once was repeated, which can hide a
+    /// spoofed From or Subject
+    DuplicateHeader(&'static str),
+}
+
+/// What to do with repeated header fields that should only appear once
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicateHeaders {
+    /// Keep the first value
+    KeepFirst,
+    /// Keep the last value
+    #[default]
+    KeepLast,
+    /// Keep the first value and record the later values, see `Part::duplicate_headers`
+    RecordAll,
+}
+
+// An entry in the structure of a message
+#[derive(Debug)]
+pub(crate) enum Node {
+    Multipart { depth: usize, multipart: Multipart },
+    Part { depth: usize, index: usize },
 }
 
 /// A part of an email message.
@@ -33,6 +74,12 @@ pub struct Part {
     pub content_type: Option<ContentType>,
     /// MIME content disposition
     pub content_disposition: Option<ContentDisposition>,
+    /// MIME content transfer encoding in lowercase e.g "base64"
+    pub content_transfer_encoding: Option<Vec<u8>>,
+    /// MIME content ID without the angle brackets, as used in "cid:" URLs
+    pub content_id: Option<Vec<u8>>,
+    // Repeated header fields, kept with DuplicateHeaders::RecordAll
+    pub(crate) duplicates: Vec<(&'static str, Vec<u8>)>,
     pub(crate) start: usize,
     pub(crate) body_start: usize,
     pub(crate) end: usize,
@@ -57,6 +104,24 @@ pub struct HeaderFields {
     pub reply_to: Option<Vec<u8>>,
 }
 
+impl HeaderFields {
+    /// Parse the addresses in the From field
+    pub fn from_addresses(&self) -> Vec<Address> {
+        self.from
+            .as_ref()
+            .map(|v| parse_addresses(v))
+            .unwrap_or_default()
+    }
+
+    /// Parse the addresses in the To field
+    pub fn to_addresses(&self) -> Vec<Address> {
+        self.to
+            .as_ref()
+            .map(|v| parse_addresses(v))
+            .unwrap_or_default()
+    }
+}
+
 impl fmt::Debug for HeaderFields {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut d = f.debug_struct("HeaderFields");
@@ -71,10 +136,10 @@ impl fmt::Debug for HeaderFields {
     }
 }
 
+/// The MIME content type of a part and its parameters
 #[derive(Clone, Debug)]
 pub struct ContentType {
     pub(crate) mime_type: Mime,
-    #[allow(dead_code)]
     pub(crate) parameters: HashMap<Vec<u8>, Vec<u8>>,
 }
 
@@ -85,7 +150,55 @@ pub struct ContentDisposition {
     pub parameters: HashMap<Vec<u8>, Vec<u8>>,
 }
 
+impl ContentType {
+    /// The MIME type e.g "text/plain"
+    pub fn mime_type(&self) -> &[u8] {
+        match &self.mime_type {
+            Mime::Type(t) => t,
+            Mime::Multipart(m) => multipart_name(*m).as_bytes(),
+        }
+    }
+
+    /// The parameters of the content type e.g charset
+    pub fn parameters(&self) -> &HashMap<Vec<u8>, Vec<u8>> {
+        &self.parameters
+    }
+}
+
+impl ContentDisposition {
+    /// The parameters of the content disposition e.g filename
+    pub fn parameters(&self) -> &HashMap<Vec<u8>, Vec<u8>> {
+        &self.parameters
+    }
+}
+
 impl Part {
+    /// Get the filename of the part from the filename parameter of the content
+    /// disposition or, failing that, the name parameter of the content type
+    pub fn filename(&self) -> Option<Vec<u8>> {
+        let from_disposition = self
+            .content_disposition
+            .as_ref()
+            .and_then(|d| parameter(&d.parameters, b"filename"));
+        let from_content_type = || {
+            self.content_type
+                .as_ref()
+                .and_then(|c| parameter(&c.parameters, b"name"))
+        };
+        from_disposition.or_else(from_content_type).cloned()
+    }
+
+    /// Get the MIME type of the part, as sent, e.g "image/jpeg"
+    pub fn mime_type_str(&self) -> Option<&[u8]> {
+        self.content_type.as_ref().map(ContentType::mime_type)
+    }
+
+    /// Get the repeated header fields, as name and value, that were not kept in
+    /// `header`. Only recorded when parsing with `DuplicateHeaders::RecordAll`.
+    pub fn duplicate_headers(&self) -> &[(&'static str, Vec<u8>)] {
+        &self.duplicates
+    }
+
     /// Get start and length of the part
     pub fn position(&self) -> (usize, usize) {
         (self.start, self.end - self.start + 1)
@@ -95,9 +208,30 @@ impl Part {
     pub fn body(&self) -> (usize, usize) {
         (self.body_start, self.end - self.body_start + 1)
     }
+
+    /// Get the body of the part from the raw message and undo its content transfer
+    /// encoding. Returns None if the encoding is not supported or the body cannot be
+    /// decoded.
+    pub fn decoded_body(&self, raw: &[u8]) -> Option<Vec<u8>> {
+        // A part in a multipart ends where its closing boundary starts, otherwise the
+        // part runs to the end of the message
+        let end = if self.end + 1 >= raw.len() {
+            raw.len()
+        } else {
+            self.end
+        };
+        let body = raw.get(self.body_start..end)?;
+        transfer_encoding::decode(self.content_transfer_encoding.as_deref(), body)
+    }
 }
 
 impl Message {
+    /// The header fields of the message itself. The header of a multipart message
+    /// is parsed along with its first part, so `top()` may not have it.
+    pub fn header(&self) -> Option<&HeaderFields> {
+        self.parts.first().map(|part| &part.header)
+    }
+
     /// The first part of the message
     pub fn top(&self) -> Option<&Part> {
         self.parts.get(self.top)
@@ -108,6 +242,26 @@ impl Message {
         self.text.and_then(|i| self.parts.get(i))
     }
 
+    /// Decode the first text part of the message, given the raw bytes written to the
+    /// parser's writer.
+    /// # Example
+    /// ```
+    /// use mime_event::MessageParser;
+    /// # use std::io::Write;
+    ///
+    /// let mut raw = Vec::new();
+    /// let mut parser = MessageParser::new(&mut raw);
+    /// parser.write_all(b"Content-Transfer-Encoding: base64\r\n").unwrap();
+    /// parser.write_all(b"\r\n").unwrap();
+    /// parser.write_all(b"SGVsbG8=\r\n").unwrap();
+    /// let message = parser.end();
+    /// assert_eq!(message.extract_text(&raw).as_deref(), Some("Hello"));
+    /// ```
+    pub fn extract_text(&self, raw: &[u8]) -> Option<String> {
+        let decoded = self.text()?.decoded_body(raw)?;
+        Some(String::from_utf8_lossy(&decoded).into_owned())
+    }
+
     /// The first HTML part of the message
     pub fn html(&self) -> Option<&Part> {
         self.html.and_then(|i| self.parts.get(i))
@@ -119,4 +273,205 @@ impl Message {
             .iter()
             .flat_map(move |i| self.parts.get(*i))
     }
+
+    /// The number of parts in the message, not counting the multiparts that
+    /// contain them
+    pub fn part_count(&self) -> usize {
+        self.parts.len()
+    }
+
+    /// Problems found while parsing the message
+    pub fn warnings(&self) -> &[ParseWarning] {
+        &self.warnings
+    }
+
+    /// The preamble of a multipart message, the text before its first boundary.
+    /// Empty if the message is not multipart or has no preamble.
+    pub fn preamble(&self) -> &[u8] {
+        &self.preamble
+    }
+
+    /// The epilogue of a multipart message, the text after its close boundary.
+    /// Empty if the message is not multipart or has no epilogue.
+    pub fn epilogue(&self) -> &[u8] {
+        &self.epilogue
+    }
+
+    /// Rebuild the original message, given the raw bytes written to the parser's
+    /// writer, e.g to re-sign or re-send it.
+    ///
+    /// The header, the boundaries and the parts are taken from `raw` at the
+    /// positions found by the parser, the preamble and epilogue come from the
+    /// message. A multipart nested in a part is copied along with that part.
+    /// # Example
+    /// ```
+    /// use mime_event::MessageParser;
+    /// # use std::io::Write;
+    ///
+    /// let msg: &[&[u8]] = &[
+    ///     b"Content-Type: multipart/mixed; boundary=b\r\n",
+    ///     b"\r\n",
+    ///     b"Preamble\r\n",
+    ///     b"--b\r\n",
+    ///     b"\r\n",
+    ///     b"Hello\r\n",
+    ///     b"--b--\r\n",
+    ///     b"Epilogue\r\n",
+    /// ];
+    /// let mut raw = Vec::new();
+    /// let mut parser = MessageParser::new(&mut raw);
+    /// for line in msg {
+    ///     parser.write_all(line).unwrap();
+    /// }
+    /// let message = parser.end();
+    /// assert_eq!(message.preamble(), b"Preamble\r\n");
+    /// assert_eq!(message.reconstruct(&raw), msg.concat());
+    /// ```
+    pub fn reconstruct(&self, raw: &[u8]) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(self.length);
+        // A message that is not multipart is a single part
+        if self.sections.is_empty() && self.preamble.is_empty() {
+            copy(&mut ret, raw, 0, self.length);
+            return ret;
+        }
+        copy(&mut ret, raw, 0, self.header_length);
+        ret.extend_from_slice(&self.preamble);
+        let mut pos = self.header_length + self.preamble.len();
+        for (start, end) in &self.sections {
+            // The boundary before the part
+            copy(&mut ret, raw, pos, *start);
+            copy(&mut ret, raw, *start, *end);
+            pos = *end;
+        }
+        // The close boundary
+        let epilogue_start = self.length.saturating_sub(self.epilogue.len());
+        copy(&mut ret, raw, pos, epilogue_start);
+        ret.extend_from_slice(&self.epilogue);
+        ret
+    }
+
+    /// Inline image parts, such as the images in a multipart/related HTML message,
+    /// paired with their content ID
+    pub fn inline_images(&self) -> Vec<(Option<Vec<u8>>, &Part)> {
+        self.inlines
+            .iter()
+            .flat_map(|i| self.parts.get(*i))
+            .filter(|p| p.mime_type_str().is_some_and(|t| t.starts_with(b"image/")))
+            .map(|p| (p.content_id.clone(), p))
+            .collect()
+    }
+
+    /// Render the structure of the message as a tree, for debugging.
+    ///
+    /// Each line is a multipart or a part, indented by its depth. Parts show their
+    /// content type, how the message classified them, their disposition and filename
+    /// and the start and length of their body.
+    /// # Example
+    /// ```
+    /// use mime_event::MessageParser;
+    /// use std::io::{self, Write};
+    ///
+    /// let mut parser = MessageParser::new(io::sink());
+    /// for line in [&b"Content-Type: text/plain\r\n"[..], b"\r\n", b"Hello\r\n"] {
+    ///     parser.write_all(line).unwrap();
+    /// }
+    /// let message = parser.end();
+    /// assert_eq!(message.tree_string(), "text/plain [top, text] body 28+7\n");
+    /// ```
+    pub fn tree_string(&self) -> String {
+        let mut ret = String::new();
+        for node in &self.structure {
+            match node {
+                Node::Multipart { depth, multipart } => {
+                    let _ = writeln!(ret, "{}{}", indent(*depth), multipart_name(*multipart));
+                }
+                Node::Part { depth, index } => {
+                    if let Some(part) = self.parts.get(*index) {
+                        ret.push_str(&indent(*depth));
+                        self.write_part(&mut ret, *index, part);
+                    }
+                }
+            }
+        }
+        ret
+    }
+
+    // Write a line describing a part
+    fn write_part(&self, out: &mut String, index: usize, part: &Part) {
+        // A part without a content type of its own is text
+        let mime_type = match part.content_type.as_ref().map(|c| &c.mime_type) {
+            Some(Mime::Type(t)) => String::from_utf8_lossy(t).into_owned(),
+            _ => "text/plain".to_string(),
+        };
+        let _ = write!(out, "{}", mime_type);
+        let labels = self.labels(index);
+        if !labels.is_empty() {
+            let _ = write!(out, " [{}]", labels.join(", "));
+        }
+        if let Some(disposition) = &part.content_disposition {
+            let _ = write!(
+                out,
+                " {}",
+                String::from_utf8_lossy(&disposition.disposition_type)
+            );
+            if let Some(filename) = disposition.parameters.get(&b"filename"[..]) {
+                let _ = write!(out, " filename={}", String::from_utf8_lossy(filename));
+            }
+        }
+        let (body_start, body_length) = part.body();
+        let _ = writeln!(out, " body {}+{}", body_start, body_length);
+    }
+
+    // How the message classified a part
+    fn labels(&self, index: usize) -> Vec<&'static str> {
+        let mut ret = Vec::new();
+        if self.top == index {
+            ret.push("top");
+        }
+        if self.text == Some(index) {
+            ret.push("text");
+        }
+        if self.html == Some(index) {
+            ret.push("html");
+        }
+        let lists = [
+            (&self.attachments, "attachment"),
+            (&self.inlines, "inline"),
+            (&self.other, "other"),
+        ];
+        for (list, label) in lists {
+            if list.contains(&index) {
+                ret.push(label);
+            }
+        }
+        ret
+    }
+}
+
+// Find a parameter, parameter names are case insensitive
+fn parameter<'a>(parameters: &'a HashMap<Vec<u8>, Vec<u8>>, name: &[u8]) -> Option<&'a Vec<u8>> {
+    parameters
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v)
+}
+
+// Append the bytes from start to end of the raw message, as far as they are available
+fn copy(out: &mut Vec<u8>, raw: &[u8], start: usize, end: usize) {
+    let end = end.min(raw.len());
+    out.extend_from_slice(raw.get(start..end).unwrap_or_default());
+}
+
+fn indent(depth: usize) -> String {
+    "  ".repeat(depth)
+}
+
+fn multipart_name(multipart: Multipart) -> &'static str {
+    match multipart {
+        Multipart::Alternative => "multipart/alternative",
+        Multipart::Mixed => "multipart/mixed",
+        Multipart::Digest => "multipart/digest",
+        Multipart::Related => "multipart/related",
+        Multipart::Report => "multipart/report",
+    }
 }
@@ -1,20 +1,36 @@
+use crate::address;
+use crate::encoded_word;
 use crate::event::{mime_type, Event, Mime, Multipart};
 use crate::header::Header;
-use crate::message::{ContentType, Message, Part};
+use crate::message::{
+    ContentDisposition, ContentType, DuplicateHeaders, Message, Node, ParseWarning, Part,
+};
 use crate::parser::Handler;
+use crate::transfer_encoding;
 use std::collections::HashMap;
 use std::mem;
 
 /// Event handler that builds Messages, used by the MessageParser
 #[derive(Default)]
 pub struct MessageHandler {
+    // Decode RFC 2047 encoded-words in the Subject, From and To fields
+    pub(crate) decode_headers: bool,
+    pub(crate) duplicate_headers: DuplicateHeaders,
     is_multipart: bool,
+    // The number of open multiparts
+    depth: usize,
+    // The depth of the parts of a multipart/related that has not yet seen its first part
+    related_depth: Option<usize>,
+    // The start of the current part of the outermost multipart
+    outer_part_start: usize,
+    // A nested multipart has ended, so the part that ends next only contains it
+    nested_end: bool,
     target: Target,
     current_part: Part,
     message: Message,
 }
 
-#[derive(Debug, PartialEq, Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
 enum Target {
     #[default]
     Top,
@@ -36,7 +52,19 @@ impl Handler for MessageHandler {
             Event::PartEnd { offset } => self.part_end(offset),
             Event::BodyStart { offset } => self.body_start(offset),
             Event::Body(_) => (),
-            Event::MultipartEnd => (),
+            // Only the preamble and epilogue of the outermost multipart are kept,
+            // the others are inside a part
+            Event::Preamble(line) if self.depth == 0 => {
+                self.message.preamble.extend_from_slice(line)
+            }
+            Event::Epilogue(line) if self.depth == 0 => {
+                self.message.epilogue.extend_from_slice(line)
+            }
+            Event::Preamble(_) | Event::Epilogue(_) => (),
+            Event::MultipartEnd => {
+                self.depth = self.depth.saturating_sub(1);
+                self.nested_end = true;
+            }
             Event::End => self.end(),
         }
     }
@@ -49,22 +77,71 @@ impl MessageHandler {
     }
 
     fn handle_header(&mut self, header: Header) {
-        let target = &mut self.current_part.header;
+        let decode_headers = self.decode_headers;
+        let text = |value: &[u8]| {
+            if decode_headers {
+                encoded_word::decode_header(value).into_bytes()
+            } else {
+                value.to_vec()
+            }
+        };
+        // Only the display names in address lists are decoded
+        let addresses = |value: &[u8]| {
+            if decode_headers {
+                address::decode_display_names(value)
+            } else {
+                value.to_vec()
+            }
+        };
+        let policy = self.duplicate_headers;
+        let part = &mut self.current_part;
+        let fields = &mut part.header;
+        let (name, field, value) = match header {
+            Header::From(from) => ("From", &mut fields.from, addresses(from)),
+            Header::To(to) => ("To", &mut fields.to, addresses(to)),
+            Header::Date(date) => ("Date", &mut fields.date, date.to_vec()),
+            Header::Subject(subject) => ("Subject", &mut fields.subject, text(subject)),
+            Header::Sender(sender) => ("Sender", &mut fields.sender, sender.to_vec()),
+            Header::ReplyTo(reply_to) => ("Reply-To", &mut fields.reply_to, reply_to.to_vec()),
+            Header::MessageId(msg_id) => ("Message-ID", &mut fields.message_id, msg_id.to_vec()),
+            _ => return self.mime_header(header),
+        };
+        // These fields should only appear once
+        if field.is_none() {
+            *field = Some(value);
+            return;
+        }
+        match policy {
+            DuplicateHeaders::KeepFirst => (),
+            DuplicateHeaders::KeepLast => *field = Some(value),
+            DuplicateHeaders::RecordAll => part.duplicates.push((name, value)),
+        }
+        let warning = ParseWarning::DuplicateHeader(name);
+        self.message.warnings.push(warning);
+    }
+
+    fn mime_header(&mut self, header: Header) {
         match header {
-            Header::From(from) => target.from = Some(from.to_vec()),
-            Header::To(to) => target.to = Some(to.to_vec()),
-            Header::Date(date) => target.date = Some(date.to_vec()),
-            Header::Subject(subject) => target.subject = Some(subject.to_vec()),
-            Header::Sender(sender) => target.sender = Some(sender.to_vec()),
-            Header::ReplyTo(reply_to) => target.reply_to = Some(reply_to.to_vec()),
-            Header::MessageId(msg_id) => target.message_id = Some(msg_id.to_vec()),
             Header::ContentType {
                 mime_type,
                 parameters,
             } => self.content_type(mime_type, parameters),
             Header::ContentDisposition {
-                disposition_type, ..
-            } => self.content_disposition(disposition_type),
+                disposition_type,
+                parameters,
+            } => self.content_disposition(disposition_type, parameters),
+            Header::ContentTransferEncoding(encoding) => {
+                let encoding = encoding.trim_ascii().to_ascii_lowercase();
+                self.current_part.content_transfer_encoding = Some(encoding);
+            }
+            Header::ContentId(content_id) => {
+                let content_id = content_id.trim_ascii();
+                let content_id = content_id
+                    .strip_prefix(b"<")
+                    .and_then(|id| id.strip_suffix(b">"))
+                    .unwrap_or(content_id);
+                self.current_part.content_id = Some(content_id.to_vec());
+            }
             _ => (),
         }
     }
@@ -81,7 +158,21 @@ impl MessageHandler {
         });
     }
 
-    fn content_disposition(&mut self, disposition_type: &[u8]) {
+    fn content_disposition(
+        &mut self,
+        disposition_type: &[u8],
+        parameter_refs: HashMap<&[u8], Vec<u8>>,
+    ) {
+        let parameters = parameter_refs
+            .into_iter()
+            .map(|(k, v)| (k.to_vec(), v))
+            .collect();
+        self.current_part
+            .content_disposition
+            .replace(ContentDisposition {
+                disposition_type: disposition_type.to_vec(),
+                parameters,
+            });
         // Use the content disposition to set a more accurate target for this part
         if self.target != Target::Top && self.target != Target::TopAlternative {
             self.target = match disposition_type {
@@ -93,6 +184,11 @@ impl MessageHandler {
     }
 
     fn multipart_start(&mut self, multipart: Multipart) {
+        self.message.structure.push(Node::Multipart {
+            depth: self.depth,
+            multipart,
+        });
+        self.depth += 1;
         // Set the default target for all parts in this multipart
         self.target = match multipart {
             Multipart::Alternative if self.target == Target::Top => Target::TopAlternative,
@@ -100,26 +196,50 @@ impl MessageHandler {
             Multipart::Mixed if self.target == Target::Top => Target::FirstMixed,
             Multipart::Mixed => Target::Attachments,
             Multipart::Digest => Target::Attachments,
+            // The first part is the root of a related multipart and is handled like
+            // a part of the enclosing multipart
+            Multipart::Related => {
+                self.related_depth = Some(self.depth);
+                self.target
+            }
+            Multipart::Report => Target::Other,
         }
     }
 
     fn part_start(&mut self, offset: usize) {
         self.is_multipart = true;
         self.current_part.start = offset;
+        self.nested_end = false;
+        if self.depth == 1 {
+            self.outer_part_start = offset;
+        }
     }
 
     fn part_end(&mut self, offset: usize) {
+        if self.depth == 1 {
+            let section = (self.outer_part_start, offset);
+            self.message.sections.push(section);
+        }
+        // The parts of the nested multipart have already been added
+        if mem::take(&mut self.nested_end) {
+            return;
+        }
         self.current_part.end = offset;
+        let is_text = is_content_text(&self.current_part);
         let content_type = self.current_part.content_type.clone();
         let part_index = self.add_part();
+        self.message.structure.push(Node::Part {
+            depth: self.depth,
+            index: part_index,
+        });
         match self.target {
             Target::Top => {
                 self.message.top = part_index;
-                if is_content_text(&content_type) {
+                if is_text {
                     self.message.text = Some(part_index);
                 }
             }
-            Target::TopAlternative if is_content_text(&content_type) => {
+            Target::TopAlternative if is_text => {
                 self.message.top = part_index;
                 self.message.text = Some(part_index);
             }
@@ -136,6 +256,12 @@ impl MessageHandler {
             Target::Inlines => self.message.inlines.push(part_index),
             Target::Other => self.message.other.push(part_index),
         }
+        // The parts after the root of a related multipart are referenced by the root
+        // e.g images embedded in html
+        if self.related_depth == Some(self.depth) {
+            self.related_depth = None;
+            self.target = Target::Inlines;
+        }
     }
 
     fn body_start(&mut self, offset: usize) {
@@ -147,11 +273,15 @@ impl MessageHandler {
     }
 
     fn end(&mut self) {
-        let content_type = self.current_part.content_type.clone();
         if !self.is_multipart {
+            let is_text = is_content_text(&self.current_part);
             let part_index = self.add_part();
+            self.message.structure.push(Node::Part {
+                depth: 0,
+                index: part_index,
+            });
             self.message.top = part_index;
-            if is_content_text(&content_type) {
+            if is_text {
                 self.message.text = Some(part_index);
             }
         }
@@ -165,8 +295,11 @@ impl MessageHandler {
     }
 }
 
-fn is_content_text(content_type: &Option<ContentType>) -> bool {
-    content_type.is_none() || is_content(content_type, b"text/plain")
+// Is the part plain text, in an encoding that can be decoded?
+fn is_content_text(part: &Part) -> bool {
+    let content_type = &part.content_type;
+    (content_type.is_none() || is_content(content_type, b"text/plain"))
+        && transfer_encoding::is_supported(part.content_transfer_encoding.as_deref())
 }
 
 fn is_content(content_type: &Option<ContentType>, check_is: &[u8]) -> bool {
@@ -1,4 +1,4 @@
-use crate::message::Message;
+use crate::message::{DuplicateHeaders, Message};
 use crate::message_handler::MessageHandler;
 use crate::parser::EventParser;
 use std::io;
@@ -28,6 +28,7 @@ use std::io::Write;
 /// ```
 pub struct MessageParser<W: Write> {
     event_parser: EventParser<W, MessageHandler>,
+    length: usize,
 }
 
 impl<W: Write> MessageParser<W> {
@@ -36,19 +37,59 @@ impl<W: Write> MessageParser<W> {
     pub fn new(writer: W) -> Self {
         Self {
             event_parser: EventParser::new(writer, MessageHandler::default()),
+            length: 0,
         }
     }
 
+    /// Create a MessageParser that decodes RFC 2047 encoded-words in the
+    /// `subject`, `from` and `to` header fields, see `decode_header`.
+    /// The decoded fields are UTF-8. In `from` and `to` only the display names are
+    /// decoded, and quoted if needed, so that decoding cannot change the addresses.
+    pub fn with_decoded_headers(writer: W) -> Self {
+        let mut handler = MessageHandler::default();
+        handler.decode_headers = true;
+        Self {
+            event_parser: EventParser::new(writer, handler),
+            length: 0,
+        }
+    }
+
+    /// Set how header fields that should only appear once, such as From or Subject,
+    /// are handled when they are repeated. Repeats are always reported in
+    /// `Message::warnings`.
+    pub fn with_duplicate_headers(mut self, policy: DuplicateHeaders) -> Self {
+        self.event_parser.handler_mut().duplicate_headers = policy;
+        self
+    }
+
+    /// The number of bytes that the parser is holding until it has the rest of a
+    /// header field
+    pub fn buffered(&self) -> usize {
+        self.event_parser.buffered()
+    }
+
     /// Call this method to signal the end of a message. Will return the parsed message.
     pub fn end(self) -> Message {
-        self.event_parser.end().get_message()
+        let header_length = self.event_parser.header_length();
+        let mut message = self.event_parser.end().get_message();
+        message.length = self.length;
+        message.header_length = header_length.unwrap_or(self.length);
+        // The body of a message without MIME parts runs to the end of the message
+        if let [part] = message.parts.as_mut_slice() {
+            if part.end == 0 {
+                part.end = self.length.saturating_sub(1);
+            }
+        }
+        message
     }
 }
 
 /// Write data to the MessageParser to parse a Message
 impl<W: Write> Write for MessageParser<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.event_parser.write(buf)
+        let written = self.event_parser.write(buf)?;
+        self.length += written;
+        Ok(written)
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -6,6 +6,10 @@ use std::collections::HashMap;
 use std::io;
 use std::io::Write;
 
+/// The default maximum size of an `Event::Body`.
+/// Longer body lines are sent as several events.
+pub const MAX_BODY_EVENT: usize = 64 * 1024;
+
 /// A Handler receives parser events
 pub trait Handler {
     /// Method that receives parser events
@@ -20,8 +24,10 @@ enum State {
     MultipartPreamble,
     PartStart,
     Body,
+    Epilogue,
 }
 
+// A multipart that has not yet seen its close boundary
 struct MultipartState {
     content_type: Multipart,
     boundary: Vec<u8>,
@@ -67,9 +73,12 @@ pub struct EventParser<W: Write, H: Handler> {
     offset: usize,
     handler: H,
     content_type: Mime,
-    boundary: Option<Vec<u8>>,
+    // Open multiparts, the innermost multipart is last
     multipart_stack: Vec<MultipartState>,
     header_buffer: HeaderBuffer,
+    max_body_event: usize,
+    // The length of the header of the message, including the blank line after it
+    header_length: Option<usize>,
 }
 
 impl<W: Write, H: Handler> EventParser<W, H> {
@@ -83,12 +92,36 @@ impl<W: Write, H: Handler> EventParser<W, H> {
             offset: 0,
             handler,
             content_type: Mime::Type(b"text/plain".to_vec()),
-            boundary: None,
             multipart_stack: Vec::default(),
             header_buffer: HeaderBuffer::default(),
+            max_body_event: MAX_BODY_EVENT,
+            header_length: None,
         }
     }
 
+    /// Set the maximum size of an `Event::Body`, the default is `MAX_BODY_EVENT`.
+    /// Body lines longer than this are split into several `Event::Body` events.
+    pub fn with_max_body_event(mut self, max: usize) -> Self {
+        self.max_body_event = max.max(1);
+        self
+    }
+
+    // Configure the handler before any events are sent
+    pub(crate) fn handler_mut(&mut self) -> &mut H {
+        &mut self.handler
+    }
+
+    /// The number of bytes that the parser is holding until it has the rest of a
+    /// header field, which can be folded over several lines
+    pub fn buffered(&self) -> usize {
+        self.header_buffer.len()
+    }
+
+    // The length of the header of the message, once the blank line after it is seen
+    pub(crate) fn header_length(&self) -> Option<usize> {
+        self.header_length
+    }
+
     /// Call when message has finished and there is no more input.
     /// Returns the handler.
     pub fn end(mut self) -> H {
@@ -96,16 +129,17 @@ impl<W: Write, H: Handler> EventParser<W, H> {
         self.handler
     }
 
+    // The boundary of the innermost open multipart
+    fn boundary(&self) -> Option<&[u8]> {
+        self.multipart_stack.last().map(|m| m.boundary.as_slice())
+    }
+
     fn is_open_boundary(&self, buf: &[u8]) -> bool {
-        self.boundary
-            .as_ref()
-            .filter(|b| buf.starts_with(b))
-            .is_some()
+        self.boundary().filter(|b| buf.starts_with(b)).is_some()
     }
 
     fn is_close_boundary(&self, buf: &[u8]) -> bool {
-        self.boundary
-            .as_ref()
+        self.boundary()
             .filter(|b| {
                 let end = b.len();
                 buf.starts_with(b) && buf.len() > end + 2 && buf.ends_with(b"--\r\n")
@@ -115,6 +149,9 @@ impl<W: Write, H: Handler> EventParser<W, H> {
 
     fn header_field(&mut self, buf: &[u8], state: State) -> io::Result<State> {
         if buf.starts_with(b"\r\n") {
+            if self.header_length.is_none() {
+                self.header_length = Some(self.offset + 2);
+            }
             self.state = match state {
                 State::MultipartHeader => State::MultipartPreamble,
                 _ => {
@@ -130,7 +167,7 @@ impl<W: Write, H: Handler> EventParser<W, H> {
             if let Header::ContentType {
                 mime_type: mtype,
                 parameters: params,
-            } = token.clone()
+            } = &token
             {
                 self.content_type(mtype, params);
             }
@@ -144,20 +181,18 @@ impl<W: Write, H: Handler> EventParser<W, H> {
     }
 
     // Handle Content-Type headers
-    fn content_type(&mut self, mtype: &[u8], params: HashMap<&[u8], Vec<u8>>) {
-        if let (Mime::Multipart(m), Some(b)) = (&self.content_type, &self.boundary) {
-            self.multipart_stack.push(MultipartState {
-                content_type: *m,
-                boundary: b.clone(),
-            })
-        }
+    fn content_type(&mut self, mtype: &[u8], params: &HashMap<&[u8], Vec<u8>>) {
         self.content_type = mime_type(mtype);
-        if let Mime::Multipart(_) = &self.content_type {
-            self.boundary = params.get(&(b"boundary")[..]).map(|boundary| {
-                let mut full = b"--".to_vec();
+        if let Mime::Multipart(m) = self.content_type {
+            if let Some(boundary) = params.get(&(b"boundary")[..]) {
+                let mut full = Vec::with_capacity(boundary.len() + 2);
+                full.extend_from_slice(b"--");
                 full.extend_from_slice(boundary);
-                full
-            });
+                self.multipart_stack.push(MultipartState {
+                    content_type: m,
+                    boundary: full,
+                });
+            }
         }
     }
 
@@ -207,28 +242,35 @@ impl<W: Write, H: Handler> EventParser<W, H> {
                     }
                     State::PartStart
                 } else {
+                    self.handler.event(Event::Preamble(buf));
                     State::MultipartPreamble
                 }
             }
-            State::Body => {
+            // The epilogue of a nested multipart is in a part of the enclosing multipart
+            State::Body | State::Epilogue => {
                 if self.is_close_boundary(buf) {
                     self.handler.event(Event::PartEnd {
                         offset: self.offset,
                     });
                     self.handler.event(Event::MultipartEnd);
-                    // Use last multipart if available
-                    if let Some(last) = self.multipart_stack.pop() {
-                        self.content_type = Mime::Multipart(last.content_type);
-                        self.boundary = Some(last.boundary);
+                    // Continue with the enclosing multipart if available
+                    self.multipart_stack.pop();
+                    if let Some(outer) = self.multipart_stack.last() {
+                        self.content_type = Mime::Multipart(outer.content_type);
                     }
-                    State::Header
+                    State::Epilogue
                 } else if self.is_open_boundary(buf) {
                     self.handler.event(Event::PartEnd {
                         offset: self.offset,
                     });
                     State::PartStart
+                } else if let State::Epilogue = self.state {
+                    self.handler.event(Event::Epilogue(buf));
+                    State::Epilogue
                 } else {
-                    self.handler.event(Event::Body(buf));
+                    for chunk in buf.chunks(self.max_body_event) {
+                        self.handler.event(Event::Body(chunk));
+                    }
                     State::Body
                 }
             }
@@ -0,0 +1,79 @@
+// Decoding of MIME Content-Transfer-Encodings e.g base64
+
+use crate::encoded_word::hex_byte;
+
+// Can a body with the given encoding be decoded?
+// The encoding is lowercase, a missing encoding means 7bit.
+pub(crate) fn is_supported(encoding: Option<&[u8]>) -> bool {
+    matches!(
+        encoding,
+        None | Some(b"7bit" | b"8bit" | b"binary" | b"quoted-printable" | b"base64")
+    )
+}
+
+// Decode a body with the given encoding.
+// Returns None if the encoding is not supported or the body is not valid.
+pub(crate) fn decode(encoding: Option<&[u8]>, body: &[u8]) -> Option<Vec<u8>> {
+    match encoding {
+        None | Some(b"7bit" | b"8bit" | b"binary") => Some(body.to_vec()),
+        Some(b"quoted-printable") => Some(decode_quoted_printable(body)),
+        Some(b"base64") => {
+            let text: Vec<u8> = body
+                .iter()
+                .copied()
+                .filter(|c| !c.is_ascii_whitespace())
+                .collect();
+            base64::decode(&text).ok()
+        }
+        _ => None,
+    }
+}
+
+pub(crate) fn decode_quoted_printable(body: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(body.len());
+    let mut i = 0;
+    while i < body.len() {
+        let rest = &body[i..];
+        if rest.starts_with(b"=\r\n") {
+            // Soft line break
+            i += 3;
+        } else if rest.starts_with(b"=\n") {
+            i += 2;
+        } else if let Some(b) = rest
+            .strip_prefix(b"=")
+            .filter(|r| r.len() >= 2)
+            .and_then(|r| hex_byte(&r[..2]))
+        {
+            ret.push(b);
+            i += 3;
+        } else {
+            ret.push(body[i]);
+            i += 1;
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn base64_lines() {
+        let decoded = decode(Some(b"base64"), b"SGVsbG8s\r\nIHdvcmxk\r\n");
+        assert_eq!(decoded.as_deref(), Some(&b"Hello, world"[..]));
+    }
+
+    #[test]
+    fn quoted_printable() {
+        let decoded = decode(Some(b"quoted-printable"), b"caf=C3=A9 au =\r\nlait=3D\r\n");
+        assert_eq!(decoded.as_deref(), Some("café au lait=\r\n".as_bytes()));
+    }
+
+    #[test]
+    fn unsupported() {
+        assert!(!is_supported(Some(b"x-uuencode")));
+        assert_eq!(decode(Some(b"x-uuencode"), b"begin 644 file\r\n"), None);
+    }
+}
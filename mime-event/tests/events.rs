@@ -1,4 +1,4 @@
-use mime_event::{Event, EventParser, Handler, Header, Multipart};
+use mime_event::{DecodingHandler, Event, EventParser, Handler, Header, Multipart, MAX_BODY_EVENT};
 use pretty_assertions::assert_eq;
 use std::collections::HashMap;
 use std::io;
@@ -28,6 +28,133 @@ fn swaks() {
     handler.final_check()
 }
 
+#[test]
+fn quoted_printable_decoding() {
+    let msg = include_bytes!("multipart_alternative.msg");
+    let mut expected = multipart_alternative_events();
+    let soft_break = body("  <div style=\"FONT-SIZE: 10pt; FONT-FAMILY: Arial\">Sample HTML =\r\n");
+    let index = expected.iter().position(|ev| *ev == soft_break).unwrap();
+    // The soft line break is removed, the offsets are unchanged
+    expected[index] = body("  <div style=\"FONT-SIZE: 10pt; FONT-FAMILY: Arial\">Sample HTML ");
+    let handler = DecodingHandler::new(TestHandler::new(expected));
+    let handler = parse_message(&msg[..], handler).unwrap();
+    handler.into_inner().final_check()
+}
+
+#[test]
+fn nested_multipart() {
+    let msg = b"Content-Type: multipart/mixed; boundary=outer
+
+--outer
+Content-Type: multipart/alternative; boundary=inner
+
+--inner
+Content-Type: text/plain; charset=us-ascii
+
+Plain
+--inner--
+
+--outer
+
+Attachment
+--outer--";
+    let handler = TestHandler::new(nested_multipart_events());
+    let handler = parse_message(&msg[..], handler).unwrap();
+    handler.final_check()
+}
+
+#[test]
+fn preamble_and_epilogue() {
+    let msg = b"Content-Type: multipart/mixed; boundary=b
+
+Preamble
+--b
+
+Hello
+--b--
+Epilogue";
+    let handler = TestHandler::new(vec![
+        Event::Start,
+        content_type("multipart/mixed", "boundary", "b"),
+        Event::Preamble(b"Preamble\r\n"),
+        Event::MultipartStart(Multipart::Mixed),
+        Event::PartStart { offset: 60 },
+        Event::BodyStart { offset: 62 },
+        body("Hello\r\n"),
+        Event::PartEnd { offset: 69 },
+        Event::MultipartEnd,
+        Event::Epilogue(b"Epilogue\r\n"),
+        Event::End,
+    ]);
+    let handler = parse_message(&msg[..], handler).unwrap();
+    handler.final_check()
+}
+
+#[test]
+fn long_body_line() {
+    let line = vec![b'A'; 5 * 1024 * 1024];
+    let mut parser = EventParser::new(io::sink(), BodyHandler::default());
+    let header: &[&[u8]] = &[
+        b"Content-Type: multipart/mixed; boundary=b\r\n",
+        b"\r\n",
+        b"--b\r\n",
+        b"\r\n",
+    ];
+    for header_line in header {
+        parser.write_all(header_line).unwrap();
+    }
+    parser.write_all(&line).unwrap();
+    parser.write_all(b"\r\n").unwrap();
+    parser.write_all(b"--b--\r\n").unwrap();
+    let handler = parser.end();
+    assert!(handler.chunks > 1);
+    assert!(handler.largest <= MAX_BODY_EVENT);
+    assert_eq!(handler.body.len(), line.len() + 2);
+    assert!(handler.body.starts_with(&line));
+    // The offset of the end of the part counts the whole line
+    let header_len: usize = header.iter().map(|l| l.len()).sum();
+    assert_eq!(handler.body_start, Some(header_len));
+    assert_eq!(handler.part_end, Some(header_len + line.len() + 2));
+}
+
+#[test]
+fn quoted_printable_split_line() {
+    let handler = DecodingHandler::new(BodyHandler::default());
+    let mut parser = EventParser::new(io::sink(), handler).with_max_body_event(4);
+    parser
+        .write_all(b"Content-Transfer-Encoding: quoted-printable\r\n")
+        .unwrap();
+    parser.write_all(b"\r\n").unwrap();
+    parser.write_all(b"caf=C3=A9 au lait=\r\n").unwrap();
+    parser.write_all(b"!=\r\n").unwrap();
+    let handler = parser.end().into_inner();
+    assert_eq!(handler.body, "café au lait!".as_bytes());
+}
+
+#[derive(Default)]
+struct BodyHandler {
+    body: Vec<u8>,
+    chunks: usize,
+    largest: usize,
+    body_start: Option<usize>,
+    part_end: Option<usize>,
+}
+
+impl Handler for BodyHandler {
+    fn event(&mut self, ev: Event) {
+        match ev {
+            Event::Body(chunk) => {
+                self.body.extend_from_slice(chunk);
+                self.chunks += 1;
+                self.largest = self.largest.max(chunk.len());
+            }
+            Event::BodyStart { offset } => self.body_start = Some(offset),
+            Event::PartEnd { offset } => self.part_end = Some(offset),
+            _ => (),
+        }
+    }
+}
+
 struct TestHandler<'a> {
     current: usize,
     expected_events: Vec<Event<'a>>,
@@ -55,7 +182,7 @@ impl<'a> Handler for TestHandler<'a> {
     }
 }
 
-fn parse_message<'a>(message: &[u8], handler: TestHandler<'a>) -> io::Result<TestHandler<'a>> {
+fn parse_message<H: Handler>(message: &[u8], handler: H) -> io::Result<H> {
     let writer = io::sink();
     let mut parser = EventParser::new(writer, handler);
     for line in message.split(|ch| *ch == b'\n') {
@@ -85,13 +212,13 @@ fn multipart_alternative_events() -> Vec<Event<'static>> {
         Event::MultipartStart(Multipart::Alternative),
         Event::PartStart { offset: 507 },
         content_type("text/plain", "charset", "iso-8859-1"),
-        unstructured_header("Content-Transfer-Encoding", "quoted-printable"),
+        content_transfer_encoding("quoted-printable"),
         Event::BodyStart { offset: 600 },
         body("Sample Text Content\r\n"),
         Event::PartEnd { offset: 621 },
         Event::PartStart { offset: 676 },
         content_type("text/html", "charset", "iso-8859-1"),
-        unstructured_header("Content-Transfer-Encoding", "quoted-printable"),
+        content_transfer_encoding("quoted-printable"),
         Event::BodyStart { offset: 768 },
         body("<html>\r\n"),
         body("<head>\r\n"),
@@ -145,6 +272,8 @@ fn multipart_mixed_events() -> Vec<Event<'static>> {
         body("\r\n"),
         Event::PartEnd { offset: 683 },
         Event::MultipartEnd,
+        Event::PartEnd { offset: 711 },
+        Event::MultipartEnd,
         Event::End,
     ]
 }
@@ -167,6 +296,31 @@ fn swaks_events() -> Vec<Event<'static>> {
     ]
 }
 
+fn nested_multipart_events() -> Vec<Event<'static>> {
+    vec![
+        Event::Start,
+        content_type("multipart/mixed", "boundary", "outer"),
+        Event::MultipartStart(Multipart::Mixed),
+        Event::PartStart { offset: 58 },
+        content_type("multipart/alternative", "boundary", "inner"),
+        Event::MultipartStart(Multipart::Alternative),
+        Event::PartStart { offset: 122 },
+        content_type("text/plain", "charset", "us-ascii"),
+        Event::BodyStart { offset: 168 },
+        body("Plain\r\n"),
+        Event::PartEnd { offset: 175 },
+        Event::MultipartEnd,
+        Event::Epilogue(b"\r\n"),
+        Event::PartEnd { offset: 188 },
+        Event::PartStart { offset: 197 },
+        Event::BodyStart { offset: 199 },
+        body("Attachment\r\n"),
+        Event::PartEnd { offset: 211 },
+        Event::MultipartEnd,
+        Event::End,
+    ]
+}
+
 //--- Helper functions to create events ---
 
 fn unstructured_header<'a>(key: &'a str, value: &'a str) -> Event<'a> {
@@ -177,23 +331,23 @@ fn header(header: Header) -> Event {
     Event::Header(header)
 }
 
-fn from(from: &str) -> Event {
+fn from(from: &str) -> Event<'_> {
     header(Header::From(from.as_bytes()))
 }
 
-fn to(to: &str) -> Event {
+fn to(to: &str) -> Event<'_> {
     header(Header::To(to.as_bytes()))
 }
 
-fn message_id(message_id: &str) -> Event {
+fn message_id(message_id: &str) -> Event<'_> {
     header(Header::MessageId(message_id.as_bytes()))
 }
 
-fn subject(subject: &str) -> Event {
+fn subject(subject: &str) -> Event<'_> {
     header(Header::Subject(subject.as_bytes()))
 }
 
-fn date(date: &str) -> Event {
+fn date(date: &str) -> Event<'_> {
     header(Header::Date(date.as_bytes()))
 }
 
@@ -204,7 +358,11 @@ fn content_type<'a>(mime: &'a str, param_key: &'a str, param_value: &str) -> Eve
     })
 }
 
-fn body(block: &str) -> Event {
+fn content_transfer_encoding(encoding: &str) -> Event<'_> {
+    header(Header::ContentTransferEncoding(encoding.as_bytes()))
+}
+
+fn body(block: &str) -> Event<'_> {
     Event::Body(block.as_bytes())
 }
 
@@ -1,4 +1,4 @@
-use mime_event::{HeaderFields, Message, MessageParser};
+use mime_event::{DuplicateHeaders, HeaderFields, Message, MessageParser, ParseWarning, Part};
 use pretty_assertions::assert_eq;
 use std::io;
 use std::io::Write;
@@ -16,10 +16,17 @@ fn multipart_alternative() {
         sender: None,
         subject: field(b"Sample Multi-Part"),
     };
+    assert_eq!(message.header(), Some(&expected_header));
+    assert_eq!(message.html().unwrap().header, HeaderFields::default());
     let header = &message.text().unwrap().header;
     assert_eq!(header, &expected_header);
     let header = &message.top().unwrap().header;
     assert_eq!(header, &expected_header);
+    let from = header.from_addresses();
+    assert_eq!(from.len(), 1);
+    assert_eq!(from[0].display_name.as_deref(), Some("Senders Name"));
+    assert_eq!(from[0].local, "sender");
+    assert_eq!(from[0].domain, "sendersdomain.com");
     assert_eq!(message.top().unwrap().body(), (600, 22));
     assert_eq!(message.html().unwrap().body(), (768, 137));
 }
@@ -49,6 +56,20 @@ fn multipart_mixed() {
     }
 }
 
+#[test]
+fn multipart_mixed_tree() {
+    let msg = include_bytes!("multipart_mixed.msg");
+    let message = parse_message(&msg[..]).unwrap();
+    let expected = "multipart/mixed
+  text/plain [top] body 241+49
+  multipart/digest
+    text/plain [attachment] body 416+108
+    text/plain [attachment] body 551+133
+";
+    assert_eq!(message.tree_string(), expected);
+    assert_eq!(message.part_count(), 3);
+}
+
 #[test]
 fn swaks() {
     let msg = include_bytes!("swaks.msg");
@@ -68,13 +89,330 @@ fn swaks() {
     assert_eq!(header, &expected_header);
 }
 
+#[test]
+fn base64_text() {
+    let msg = b"Content-Type: multipart/alternative; boundary=b
+\n--b
+Content-Type: text/plain; charset=utf-8
+Content-Transfer-Encoding: x-uuencode
+
+begin 644 text.txt
+--b
+Content-Type: text/plain; charset=utf-8
+Content-Transfer-Encoding: BASE64
+
+Q2Fmw6kgYXUgbGFpdCwK
+c2FucyBzdWNyZS4=
+--b--";
+    let mut raw = Vec::new();
+    let mut parser = MessageParser::new(&mut raw);
+    for line in msg.split(|ch| *ch == b'\n') {
+        let mut buf = line.to_vec();
+        buf.extend_from_slice(b"\r\n");
+        parser.write_all(&buf).unwrap();
+    }
+    let message = parser.end();
+    let text = message.text().unwrap();
+    assert_eq!(
+        text.content_transfer_encoding.as_deref(),
+        Some(&b"base64"[..])
+    );
+    assert_eq!(
+        message.extract_text(&raw).as_deref(),
+        Some("Café au lait,\nsans sucre.")
+    );
+}
+
+#[test]
+fn transfer_encodings() {
+    let msg = b"Content-Type: multipart/mixed; boundary=b
+
+--b
+Content-Type: text/plain
+Content-Transfer-Encoding: 7bit
+
+Plain
+--b
+Content-Type: text/html
+content-transfer-encoding: Quoted-Printable
+
+<p>caf=C3=A9</p>
+--b
+Content-Type: image/png
+Content-Disposition: attachment; filename=dot.png
+Content-Transfer-Encoding: BASE64
+
+iVBORw0KGgo=
+--b--";
+    let message = parse_message(&msg[..]).unwrap();
+    let encoding = |part: &Part| part.content_transfer_encoding.clone();
+    assert_eq!(encoding(message.top().unwrap()), field(b"7bit"));
+    // Encodings are stored in lowercase
+    let rest: Vec<_> = message.attachments().map(encoding).collect();
+    assert_eq!(rest, vec![field(b"quoted-printable"), field(b"base64")]);
+}
+
+#[test]
+fn decoded_headers() {
+    let msg = b"From: =?utf-8?q?Andr=C3=A9?= <andre@example.com>
+To: =?iso-8859-1?B?Sm/rbA==?= <joel@example.com>
+Subject: =?UTF-8?B?Q2Fmw6k=?=
+ =?utf-8?q?_au_lait?=
+
+Body";
+    let parser = MessageParser::with_decoded_headers(io::sink());
+    let message = write_lines(parser, msg).unwrap();
+    let header = &message.top().unwrap().header;
+    assert_eq!(header.subject, field("Café au lait".as_bytes()));
+    assert_eq!(header.from, field("André <andre@example.com>".as_bytes()));
+    assert_eq!(header.to, field("Joël <joel@example.com>".as_bytes()));
+    assert_eq!(
+        header.from_addresses()[0].display_name.as_deref(),
+        Some("André")
+    );
+    // Without the option the fields are left as sent
+    let message = parse_message(msg).unwrap();
+    let header = &message.top().unwrap().header;
+    assert_eq!(
+        header.to,
+        field(b"=?iso-8859-1?B?Sm/rbA==?= <joel@example.com>")
+    );
+}
+
+#[test]
+fn encoded_address_injection() {
+    let msg = b"From: =?utf-8?q?boss=40corp.example=2C?= <attacker@evil.example>
+
+Body";
+    let parser = MessageParser::with_decoded_headers(io::sink());
+    let message = write_lines(parser, msg).unwrap();
+    let header = &message.top().unwrap().header;
+    assert_eq!(
+        header.from,
+        field(br#""boss@corp.example," <attacker@evil.example>"#)
+    );
+    // The decoded display name does not add a sender
+    let from = header.from_addresses();
+    assert_eq!(from.len(), 1);
+    assert_eq!(from[0].display_name.as_deref(), Some("boss@corp.example,"));
+    assert_eq!(from[0].local, "attacker");
+    assert_eq!(from[0].domain, "evil.example");
+}
+
+#[test]
+fn duplicate_from() {
+    let msg = b"From: ceo@example.com
+From: attacker@example.net
+Subject: Invoice
+
+Body";
+    let expected_warnings = [ParseWarning::DuplicateHeader("From")];
+    // The last value is kept by default
+    let message = parse_message(msg).unwrap();
+    let top = message.top().unwrap();
+    assert_eq!(top.header.from, field(b"attacker@example.net"));
+    assert!(top.duplicate_headers().is_empty());
+    assert_eq!(message.warnings(), expected_warnings);
+
+    let parser = MessageParser::new(io::sink()).with_duplicate_headers(DuplicateHeaders::KeepFirst);
+    let message = write_lines(parser, msg).unwrap();
+    assert_eq!(
+        message.top().unwrap().header.from,
+        field(b"ceo@example.com")
+    );
+    assert_eq!(message.warnings(), expected_warnings);
+
+    let parser = MessageParser::new(io::sink()).with_duplicate_headers(DuplicateHeaders::RecordAll);
+    let message = write_lines(parser, msg).unwrap();
+    let top = message.top().unwrap();
+    assert_eq!(top.header.from, field(b"ceo@example.com"));
+    assert_eq!(
+        top.duplicate_headers(),
+        [("From", b"attacker@example.net".to_vec())]
+    );
+    assert_eq!(message.warnings(), expected_warnings);
+}
+
+#[test]
+fn attachment_filenames() {
+    let msg = br#"Content-Type: multipart/mixed; boundary=frontier
+
+--frontier
+Content-Type: text/plain
+
+See the attachments
+--frontier
+Content-Type: image/jpeg
+Content-Disposition: attachment; filename="genome.jpeg"
+Content-Transfer-Encoding: base64
+
+R0VOT01F
+--frontier
+Content-Type: application/pdf; name=report.pdf
+Content-Disposition: attachment
+
+UkVQT1JU
+--frontier--"#;
+    let message = parse_message(&msg[..]).unwrap();
+    let attachments: Vec<_> = message.attachments().collect();
+    assert_eq!(attachments.len(), 2);
+    assert_eq!(attachments[0].filename(), Some(b"genome.jpeg".to_vec()));
+    assert_eq!(attachments[0].mime_type_str(), Some(&b"image/jpeg"[..]));
+    // Falls back to the name parameter of the content type
+    assert_eq!(attachments[1].filename(), Some(b"report.pdf".to_vec()));
+    let content_type = attachments[1].content_type.as_ref().unwrap();
+    assert_eq!(
+        content_type.parameters().get(&b"name"[..]),
+        Some(&b"report.pdf".to_vec())
+    );
+    let top = message.top().unwrap();
+    assert_eq!(top.filename(), None);
+    assert_eq!(top.mime_type_str(), Some(&b"text/plain"[..]));
+}
+
+#[test]
+fn multipart_related() {
+    let msg = br#"Content-Type: multipart/alternative; boundary=alt
+
+--alt
+Content-Type: text/plain
+
+See the logo
+--alt
+Content-Type: multipart/related; boundary=rel
+
+--rel
+Content-Type: text/html
+
+<p>See the logo <img src="cid:logo@example.com"></p>
+--rel
+Content-Type: image/png
+Content-ID: <logo@example.com>
+Content-Transfer-Encoding: base64
+
+iVBORw0KGgo=
+--rel--
+--alt--"#;
+    let message = parse_message(&msg[..]).unwrap();
+    // The html root is followed by the image that it references
+    let expected = "multipart/alternative
+  text/plain [top, text] body 88+15
+  multipart/related
+    text/html [html] body 192+55
+    image/png [inline] body 347+15
+";
+    assert_eq!(message.tree_string(), expected);
+    let images = message.inline_images();
+    assert_eq!(images.len(), 1);
+    let (content_id, part) = &images[0];
+    assert_eq!(content_id.as_deref(), Some(&b"logo@example.com"[..]));
+    assert_eq!(part.mime_type_str(), Some(&b"image/png"[..]));
+    assert_eq!(part.body(), (347, 15));
+}
+
+#[test]
+fn multipart_report() {
+    let msg = br#"Content-Type: multipart/report; report-type=delivery-status; boundary=rep
+
+--rep
+Content-Type: text/plain
+
+The message could not be delivered
+--rep
+Content-Type: message/delivery-status
+
+Reporting-MTA: dns; mail.example.com
+--rep--"#;
+    let message = parse_message(&msg[..]).unwrap();
+    let expected = "multipart/report
+  text/plain [top, other] body 112+37
+  message/delivery-status [other] body 196+39
+";
+    assert_eq!(message.tree_string(), expected);
+}
+
+#[test]
+fn buffered_header() {
+    let mut parser = MessageParser::new(io::sink());
+    parser.write_all(b"Subject: folded\r\n").unwrap();
+    assert_eq!(parser.buffered(), 17);
+    parser.write_all(b" header\r\n").unwrap();
+    assert_eq!(parser.buffered(), 24);
+    parser.write_all(b"\r\n").unwrap();
+    assert_eq!(parser.buffered(), 0);
+    parser.write_all(b"body\r\n").unwrap();
+    assert_eq!(parser.buffered(), 0);
+}
+
+#[test]
+fn round_trip() {
+    let with_preamble = b"Content-Type: multipart/mixed; boundary=outer
+
+This is a multi-part message in MIME format.
+--outer
+Content-Type: multipart/alternative; boundary=inner
+
+Inner preamble
+--inner
+Content-Type: text/plain
+
+Plain
+--inner--
+Inner epilogue
+--outer
+
+Attachment
+--outer--
+First epilogue line
+
+Last epilogue line";
+    let fixtures = [
+        &include_bytes!("multipart_alternative.msg")[..],
+        &include_bytes!("multipart_mixed.msg")[..],
+        &include_bytes!("swaks.msg")[..],
+        &with_preamble[..],
+    ];
+    for msg in fixtures {
+        let mut input = Vec::new();
+        let mut raw = Vec::new();
+        let mut parser = MessageParser::new(&mut raw);
+        for line in msg.split(|ch| *ch == b'\n') {
+            let mut buf = line.to_vec();
+            buf.extend_from_slice(b"\r\n");
+            parser.write_all(&buf).unwrap();
+            input.extend_from_slice(&buf);
+        }
+        let message = parser.end();
+        assert_eq!(message.reconstruct(&raw), input);
+    }
+}
+
+#[test]
+fn preamble_and_epilogue() {
+    let msg = b"Content-Type: multipart/mixed; boundary=b
+
+Preamble
+--b
+
+Hello
+--b--
+Epilogue";
+    let message = parse_message(&msg[..]).unwrap();
+    assert_eq!(message.preamble(), b"Preamble\r\n");
+    assert_eq!(message.epilogue(), b"Epilogue\r\n");
+    assert_eq!(message.part_count(), 1);
+}
+
 fn field(value: &[u8]) -> Option<Vec<u8>> {
     Some(value.to_vec())
 }
 
 fn parse_message(message: &[u8]) -> io::Result<Message> {
     let writer = io::sink();
-    let mut parser = MessageParser::new(writer);
+    write_lines(MessageParser::new(writer), message)
+}
+
+fn write_lines(mut parser: MessageParser<io::Sink>, message: &[u8]) -> io::Result<Message> {
     for line in message.split(|ch| *ch == b'\n') {
         let mut buf = line.to_vec();
         buf.extend_from_slice(b"\r\n");
@@ -1,23 +1,24 @@
-use dnsclientx::{reverse_ip, DNSClient};
-
 use crate::err::{Error, Result};
-use std::net::IpAddr;
+use crate::net::reverse_name;
+use crate::resolver::{Answer, Resolver, DEFAULT_TIMEOUT};
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr};
 
 // TODO: TTL, multiple NS
 pub struct BlockList {
-    resolver: DNSClient,
+    resolver: Resolver,
     postfix: String,
 }
 
 impl BlockList {
-    pub fn new(ns: DNSClient, blocklist: &str) -> Self {
+    pub fn new(ns: Resolver, blocklist: &str) -> Self {
         Self {
             resolver: ns,
             postfix: blocklist.to_string(),
         }
     }
 
-    pub async fn lookup_ns(blocklist: &str, resolver: &DNSClient) -> Result<DNSClient> {
+    pub async fn lookup_ns(blocklist: &str, resolver: &Resolver) -> Result<Resolver> {
         let nameservers = resolver
             .query_ns(blocklist)
             .await
@@ -33,20 +34,35 @@ impl BlockList {
             };
             if let Some(ip) = ips.first() {
                 let socket_addr = (*ip, 53).into();
-                return Ok(DNSClient::new(vec![socket_addr]));
+                return Ok(Resolver::new(vec![socket_addr], DEFAULT_TIMEOUT));
             }
         }
         Err(Error::BlockListNameserverIp(blocklist.to_string()))
     }
 
-    pub async fn is_blocked(&self, ip: IpAddr) -> Result<bool> {
-        let reversed = reverse_ip(&ip);
-        let query_string = format!("{}.{}", reversed, self.postfix);
-        let result = self
-            .resolver
-            .query_a(&query_string)
-            .await
-            .map_err(|e| Error::BlockListLookup(query_string, e))?;
-        Ok(!result.is_empty())
+    // Get the return codes, such as 127.0.0.2, that give the reasons for a listing,
+    // and the TTL of the answer. An address that is not listed has no return codes.
+    pub async fn lookup(&self, ip: IpAddr) -> Result<Answer<Ipv4Addr>> {
+        let query_string = format!("{}.{}", reverse_name(ip), self.postfix);
+        let result = self.resolver.query_a_with_ttl(&query_string).await;
+        match result {
+            Ok(answer) => Ok(Answer {
+                records: answer
+                    .records
+                    .into_iter()
+                    .filter_map(|ip| match ip {
+                        IpAddr::V4(v4) => Some(v4),
+                        IpAddr::V6(_) => None,
+                    })
+                    .collect(),
+                ttl: answer.ttl,
+            }),
+            // Also the answer from lists without an IPv6 zone
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Answer {
+                records: Vec::new(),
+                ttl: 0,
+            }),
+            Err(e) => Err(Error::BlockListLookup(query_string, e)),
+        }
     }
 }
@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+type Key = (String, IpAddr);
+
+struct Entry {
+    codes: Vec<Ipv4Addr>,
+    expires: Instant,
+    last_used: Instant,
+}
+
+// Blocklist return codes, each kept for the TTL of its DNS answer. The least
+// recently used entry is evicted when the cache is full.
+pub struct BlockListCache {
+    capacity: usize,
+    entries: Mutex<HashMap<Key, Entry>>,
+}
+
+impl BlockListCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(HashMap::with_capacity(capacity)),
+        }
+    }
+
+    pub fn get(&self, blocklist: &str, ip: IpAddr) -> Option<Vec<Ipv4Addr>> {
+        let mut entries = self.lock();
+        let key = (blocklist.to_string(), ip);
+        let now = Instant::now();
+        match entries.get_mut(&key) {
+            Some(entry) if entry.expires > now => {
+                entry.last_used = now;
+                Some(entry.codes.clone())
+            }
+            Some(_) => {
+                entries.remove(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&self, blocklist: &str, ip: IpAddr, codes: Vec<Ipv4Addr>, ttl: Duration) {
+        // Answers with a zero TTL must not be cached
+        if self.capacity == 0 || ttl.is_zero() {
+            return;
+        }
+        let mut entries = self.lock();
+        let key = (blocklist.to_string(), ip);
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        let now = Instant::now();
+        let entry = Entry {
+            codes,
+            expires: now + ttl,
+            last_used: now,
+        };
+        entries.insert(key, entry);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<Key, Entry>> {
+        // The entries are still valid if a thread panicked while holding the lock
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IP1: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const IP2: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+    const IP3: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 3));
+    const LISTED: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 2);
+    const TTL: Duration = Duration::from_secs(300);
+
+    #[test]
+    fn evict_least_recently_used() {
+        let cache = BlockListCache::new(2);
+        cache.insert("bl.example", IP1, vec![LISTED], TTL);
+        cache.insert("bl.example", IP2, vec![], TTL);
+        // Use the first entry so that the second is the oldest
+        assert_eq!(cache.get("bl.example", IP1), Some(vec![LISTED]));
+        cache.insert("bl.example", IP3, vec![LISTED], TTL);
+        assert_eq!(cache.get("bl.example", IP1), Some(vec![LISTED]));
+        assert_eq!(cache.get("bl.example", IP2), None);
+        assert_eq!(cache.get("bl.example", IP3), Some(vec![LISTED]));
+        assert_eq!(cache.get("other.example", IP1), None);
+    }
+
+    #[test]
+    fn expired() {
+        let cache = BlockListCache::new(2);
+        cache.insert("bl.example", IP1, vec![LISTED], Duration::from_millis(50));
+        cache.insert("bl.example", IP2, vec![], TTL);
+        std::thread::sleep(Duration::from_millis(100));
+        // The entry with the short TTL expires first
+        assert_eq!(cache.get("bl.example", IP1), None);
+        assert_eq!(cache.get("bl.example", IP2), Some(vec![]));
+        assert_eq!(cache.lock().len(), 1);
+        // Answers with a zero TTL are not cached
+        cache.insert("bl.example", IP3, vec![LISTED], Duration::ZERO);
+        assert_eq!(cache.get("bl.example", IP3), None);
+        assert_eq!(cache.lock().len(), 1);
+    }
+}
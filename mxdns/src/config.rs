@@ -0,0 +1,72 @@
+use std::net::Ipv4Addr;
+
+/// What a listing on a DNS list means
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ListKind {
+    /// Addresses on the list are blocked
+    Block,
+    /// Addresses on the list are trusted and bypass the block lists
+    Allow,
+}
+
+/// The configuration of a single DNS list
+#[derive(Clone, Debug)]
+pub struct BlockListConfig {
+    /// The domain of the list e.g "zen.spamhaus.org"
+    pub fqdn: String,
+    /// Whether the list blocks or allows addresses
+    pub kind: ListKind,
+    /// The weight added to, or for an allow list subtracted from, the score
+    /// of an address on the list
+    pub weight: i32,
+    /// The return codes, such as 127.0.0.2, that count as a listing.
+    /// If empty, any return code counts as a listing.
+    pub return_codes: Vec<Ipv4Addr>,
+}
+
+impl BlockListConfig {
+    /// Configure a block list with a weight of 1 that accepts any return code
+    pub fn new<S: Into<String>>(fqdn: S) -> Self {
+        Self {
+            fqdn: fqdn.into(),
+            kind: ListKind::Block,
+            weight: 1,
+            return_codes: Vec::new(),
+        }
+    }
+
+    /// Configure an allow list with a weight of 1 that accepts any return code
+    pub fn allow<S: Into<String>>(fqdn: S) -> Self {
+        Self {
+            kind: ListKind::Allow,
+            ..Self::new(fqdn)
+        }
+    }
+
+    // Do the codes returned by the list mean that an address is listed?
+    pub(crate) fn is_listed(&self, codes: &[Ipv4Addr]) -> bool {
+        if self.return_codes.is_empty() {
+            !codes.is_empty()
+        } else {
+            codes.iter().any(|c| self.return_codes.contains(c))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn return_codes() {
+        let any = BlockListConfig::new("bl.example");
+        assert!(any.is_listed(&[Ipv4Addr::new(127, 0, 0, 10)]));
+        assert!(!any.is_listed(&[]));
+        let config = BlockListConfig {
+            return_codes: vec![Ipv4Addr::new(127, 0, 0, 2)],
+            ..BlockListConfig::new("bl.example")
+        };
+        assert!(config.is_listed(&[Ipv4Addr::new(127, 0, 0, 2)]));
+        assert!(!config.is_listed(&[Ipv4Addr::new(127, 0, 0, 10)]));
+    }
+}
@@ -16,9 +16,6 @@ pub enum Error {
     /// There was an error parsing the local resolv.conf
     #[error("{0} - parse error")]
     ResolvConfParse(String, #[source] ParseError),
-    /// There are no nameservers configured.
-    #[error("{0} - no nameservers found")]
-    NoNameservers(String),
     /// We were not able to find the nameserver that serves the blocklists.
     #[error("{0} - blocklist nameserver lookup failure")]
     BlockListNameserver(String, #[source] io::Error),
@@ -28,6 +25,9 @@ pub enum Error {
     /// When querying the blocklist there was a lookup failure.
     #[error("{0} - blocklist lookup failure")]
     BlockListLookup(String, #[source] io::Error),
+    /// A whitelisted network is not an address or a network in CIDR notation.
+    #[error("{0} - invalid network")]
+    InvalidNetwork(String),
     /// The was a DNS error when performing a reverse lookup.
     #[error("{0} - reverse lookup failure")]
     Reverse(String, #[source] io::Error),
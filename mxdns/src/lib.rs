@@ -1,10 +1,14 @@
 //! DNS utilities for email servers.
 //!
 //! Currently, DNS based blocklists and reverse DNS lookups are supported.
-//! The crate also supports forward confirmed reverse dns checks.
+//! The crate also supports forward confirmed reverse dns checks and a minimal
+//! SPF evaluator.
 //!
-//! Because blocklists are IP4 based, these utilities only support IP4
-//! addresses. IP6 addresses are converted to IP4 when possible.
+//! The lookups block the calling thread. Inside an async runtime, use the `_async`
+//! versions, such as `fcrdns_async`, which return futures instead.
+//!
+//! Blocklists are queried for both IP4 and IP6 addresses. Blocklists without an
+//! IP6 zone do not list IP6 addresses.
 //!
 //! # Examples
 //! ```no_run
@@ -25,35 +29,63 @@
 //! if let Ok(FCrDNS::Confirmed(_domain)) = mxdns.fcrdns([193, 25, 101, 5]) {
 //!    // _domain is Confirmed
 //! }
+//!
+//! // Check that the ip is allowed to send mail for a domain
+//! let spf = mxdns.check_spf([193, 25, 101, 5], "mail.alienscience.org", "alienscience.org");
 //! ```
 
 #![forbid(unsafe_code)]
 #![forbid(missing_docs)]
 mod blocklist;
+mod cache;
+mod config;
 mod err;
 mod join_all;
+mod net;
+mod resolver;
+mod spf;
+#[cfg(test)]
+mod stub;
+mod whitelist;
 
+use crate::cache::BlockListCache;
+pub use crate::config::{BlockListConfig, ListKind};
 pub use crate::err::{Error, Result};
-use crate::{blocklist::BlockList, join_all::join_all};
-use dnsclientx::DNSClient;
+use crate::resolver::{Resolver, DEFAULT_TIMEOUT};
+pub use crate::spf::SpfResult;
+use crate::whitelist::Whitelist;
+use crate::{blocklist::BlockList, join_all::join_all, spf::Spf};
 use log::Level::Debug;
 use log::{debug, log_enabled};
 use smol::future::FutureExt;
+use std::collections::HashMap;
 use std::io::ErrorKind;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
 use std::{fs::File, io::Read, matches, net::IpAddr};
 
 const RESOLV_CONF: &str = "/etc/resolv.conf";
 
+/// The nameserver that is used when resolv.conf is missing or lists no nameservers,
+/// e.g in minimal containers. As with the C library resolver, this is a nameserver
+/// on the local machine.
+pub const FALLBACK_DNS: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
 /// Utilities for looking up IP addresses on blocklists and doing reverse DNS
 #[derive(Clone)]
 pub struct MxDns {
-    bootstrap: DNSClient,
-    blocklists: Vec<String>,
+    bootstrap: Resolver,
+    lists: Vec<BlockListConfig>,
+    cache: Option<Arc<BlockListCache>>,
+    whitelist: Whitelist,
 }
 
 /// The result of a FCrDNS lookup
 #[derive(Debug)]
 pub enum FCrDNS {
+    /// The address is whitelisted so no lookup was done
+    Whitelisted,
     /// Reverse lookup failed
     NoReverse,
     /// Reverse lookup was successful but could not be forward confirmed
@@ -69,27 +101,88 @@ impl FCrDNS {
     }
 }
 
+/// Builds a MxDns with a configuration for each DNS list
+/// # Example
+/// ```no_run
+/// use mxdns::{BlockListConfig, MxDns};
+///
+/// let strict = BlockListConfig {
+///     weight: 5,
+///     ..BlockListConfig::new("zen.spamhaus.org")
+/// };
+/// let mxdns = MxDns::builder()
+///     .add_list(strict)
+///     .add_list(BlockListConfig::new("dnsbl-1.uceprotect.net"))
+///     .add_list(BlockListConfig::allow("list.dnswl.org"))
+///     .build()
+///     .unwrap();
+/// let score = mxdns.score([127, 0, 0, 2]).unwrap();
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct MxDnsBuilder {
+    dns: Option<IpAddr>,
+    fallback_dns: Option<IpAddr>,
+    lists: Vec<BlockListConfig>,
+}
+
+impl MxDnsBuilder {
+    /// Use the given DNS server for standard queries instead of the system
+    /// provided nameserver
+    pub fn dns<I: Into<IpAddr>>(&mut self, dns: I) -> &mut Self {
+        self.dns = Some(dns.into());
+        self
+    }
+
+    /// Use the given DNS server if the system does not provide a nameserver,
+    /// instead of `FALLBACK_DNS`
+    pub fn fallback_dns<I: Into<IpAddr>>(&mut self, dns: I) -> &mut Self {
+        self.fallback_dns = Some(dns.into());
+        self
+    }
+
+    /// Add a block list or an allow list
+    pub fn add_list(&mut self, config: BlockListConfig) -> &mut Self {
+        self.lists.push(config);
+        self
+    }
+
+    /// Create the MxDns
+    pub fn build(&self) -> Result<MxDns> {
+        let bootstrap = match self.dns {
+            Some(ip) => nameserver_resolver(ip),
+            None => system_resolver(self.fallback_dns.unwrap_or(FALLBACK_DNS))?.resolver(),
+        };
+        Ok(MxDns::with_lists(bootstrap, self.lists.clone()))
+    }
+}
+
 impl MxDns {
-    /// Create a MxDns using the system provided nameserver config
+    /// Create a MxDns using the system provided nameserver config. Every nameserver
+    /// in resolv.conf is used, with its `timeout` and `attempts` options.
+    /// `FALLBACK_DNS` is used if resolv.conf is missing or lists no nameservers.
     pub fn new<S>(blocklists_fqdn: S) -> Result<Self>
     where
         S: IntoIterator,
         S::Item: Into<String>,
     {
-        let mut buf = Vec::with_capacity(256);
-        let mut file = File::open(RESOLV_CONF)
-            .map_err(|e| Error::ResolvConfRead(RESOLV_CONF.to_string(), e))?;
-        file.read_to_end(&mut buf)
-            .map_err(|e| Error::ResolvConfRead(RESOLV_CONF.to_string(), e))?;
-        let conf = resolv_conf::Config::parse(&buf)
-            .map_err(|e| Error::ResolvConfParse(RESOLV_CONF.to_string(), e))?;
-        let nameservers = conf.get_nameservers_or_local();
-        if let Some(ip) = nameservers.first() {
-            let ip_addr: IpAddr = ip.into();
-            Ok(Self::with_dns(ip_addr, blocklists_fqdn))
-        } else {
-            Err(Error::NoNameservers(RESOLV_CONF.to_string()))
-        }
+        Self::with_fallback_dns(FALLBACK_DNS, blocklists_fqdn)
+    }
+
+    /// Create a MxDns using the system provided nameserver config, or the given
+    /// DNS server if resolv.conf is missing or lists no nameservers
+    pub fn with_fallback_dns<I, S>(fallback_dns: I, blocklists_fqdn: S) -> Result<Self>
+    where
+        I: Into<IpAddr>,
+        S: IntoIterator,
+        S::Item: Into<String>,
+    {
+        let bootstrap = system_resolver(fallback_dns.into())?.resolver();
+        Ok(Self::with_lists(bootstrap, list_configs(blocklists_fqdn)))
+    }
+
+    /// Start building a MxDns with a configuration for each DNS list
+    pub fn builder() -> MxDnsBuilder {
+        MxDnsBuilder::default()
     }
 
     /// Create a MxDns that uses the given DNS server for standard queries.
@@ -99,47 +192,157 @@ impl MxDns {
         S: IntoIterator,
         S::Item: Into<String>,
     {
-        let ip = bootstrap_dns.into();
-        let socket_addr = (ip, 53).into();
-        let bootstrap = DNSClient::new(vec![socket_addr]);
-        let blocklists: Vec<String> = blocklists_fqdn.into_iter().map(|i| i.into()).collect();
+        let bootstrap = nameserver_resolver(bootstrap_dns.into());
+        Self::with_lists(bootstrap, list_configs(blocklists_fqdn))
+    }
+
+    fn with_lists(bootstrap: Resolver, lists: Vec<BlockListConfig>) -> Self {
         Self {
             bootstrap,
-            blocklists,
+            lists,
+            cache: None,
+            whitelist: Whitelist::default(),
+        }
+    }
+
+    // The lists that block addresses
+    fn blocklists(&self) -> impl Iterator<Item = &BlockListConfig> {
+        self.lists.iter().filter(|l| l.kind == ListKind::Block)
+    }
+
+    /// Cache up to `capacity` blocklist results, so that repeated connections from
+    /// the same address do not query the blocklists again. Results are kept for
+    /// the TTL of the blocklist answer and the least recently used result is
+    /// dropped when the cache is full. Clones of the MxDns share the cache.
+    pub fn with_cache_capacity(mut self, capacity: usize) -> Self {
+        self.cache = Some(Arc::new(BlockListCache::new(capacity)));
+        self
+    }
+
+    /// Trust the given addresses, such as internal relays. Whitelisted addresses
+    /// are never blocked and are not looked up on blocklists or in reverse DNS.
+    pub fn with_whitelist<I>(mut self, addrs: I) -> Self
+    where
+        I: IntoIterator<Item = IpAddr>,
+    {
+        for ip in addrs {
+            self.whitelist.add_address(ip);
+        }
+        self
+    }
+
+    /// Trust the given networks, in CIDR notation e.g "192.0.2.0/24", in the same
+    /// way as `with_whitelist`. Returns an error if a network is not valid.
+    pub fn with_whitelist_networks<I, S>(mut self, networks: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        for network in networks {
+            self.whitelist.add_network(network.as_ref())?;
         }
+        Ok(self)
     }
 
     /// Queries blocklists for the given address
-    /// Returns a vector where each entry indicates if the address is on the blocklist
+    /// Returns a vector where each entry indicates if the address is on the blocklist.
+    /// The vector is empty if the address is whitelisted or on an allow list.
     pub fn on_blocklists<A>(&self, addr: A) -> Vec<Result<bool>>
     where
         A: Into<IpAddr>,
     {
-        if self.blocklists.is_empty() {
+        smol::block_on(self.on_blocklists_async(addr))
+    }
+
+    /// Async version of `on_blocklists` for use inside an async runtime
+    pub async fn on_blocklists_async<A>(&self, addr: A) -> Vec<Result<bool>>
+    where
+        A: Into<IpAddr>,
+    {
+        let ip: IpAddr = addr.into();
+        if self.blocklists().next().is_none() || self.whitelist.contains(ip) {
             return vec![];
         }
-        let ip: IpAddr = addr.into();
+        let res = self.check_lists(ip).await;
+        let is_allowed = self
+            .lists
+            .iter()
+            .zip(&res)
+            .any(|(list, r)| list.kind == ListKind::Allow && matches!(r, Ok(true)));
+        if is_allowed {
+            debug!("{} is on an allow list", ip);
+            return vec![];
+        }
+        self.lists
+            .iter()
+            .zip(res)
+            .filter(|(list, _)| list.kind == ListKind::Block)
+            .map(|(_, r)| r)
+            .collect()
+    }
 
-        let ret = smol::block_on({
-            let mut all_checks = Vec::new();
-            for blocklist in &self.blocklists {
-                let one_check = self.check_blocklist(blocklist, ip);
-                all_checks.push(one_check.boxed());
-            }
-            join_all(all_checks)
-        });
+    // Query all the lists, block and allow, for the given address
+    async fn check_lists(&self, ip: IpAddr) -> Vec<Result<bool>> {
+        let mut all_checks = Vec::new();
+        for list in &self.lists {
+            let one_check = self.check_list(list, ip);
+            all_checks.push(one_check.boxed());
+        }
+        let ret = join_all(all_checks).await;
         if log_enabled!(Debug) {
-            for i in ret.iter().enumerate() {
-                debug!("{} is blocked by {} = {:?}", ip, self.blocklists[i.0], i.1);
+            for (list, r) in self.lists.iter().zip(&ret) {
+                debug!("{} is listed by {} = {:?}", ip, list.fqdn, r);
             }
         }
         ret
     }
 
-    async fn check_blocklist(&self, blocklist: &str, ip: IpAddr) -> Result<bool> {
+    /// Queries blocklists for the return codes of the given address, such as
+    /// 127.0.0.2, which blocklists use to give the reason for a listing.
+    /// Returns a vector with the codes from each blocklist, an address that is not
+    /// on a blocklist has no codes.
+    pub fn blocklist_codes<A>(&self, addr: A) -> Vec<Result<Vec<Ipv4Addr>>>
+    where
+        A: Into<IpAddr>,
+    {
+        smol::block_on(self.blocklist_codes_async(addr))
+    }
+
+    /// Async version of `blocklist_codes` for use inside an async runtime
+    pub async fn blocklist_codes_async<A>(&self, addr: A) -> Vec<Result<Vec<Ipv4Addr>>>
+    where
+        A: Into<IpAddr>,
+    {
+        let ip: IpAddr = addr.into();
+        if self.whitelist.contains(ip) {
+            return vec![];
+        }
+        let mut all_lookups = Vec::new();
+        for blocklist in self.blocklists() {
+            let one_lookup = self.blocklist_lookup(&blocklist.fqdn, ip);
+            all_lookups.push(one_lookup.boxed());
+        }
+        join_all(all_lookups).await
+    }
+
+    // Get the return codes of the address from a list, the codes are cached
+    // rather than whether the address is listed as that depends on the list config
+    async fn blocklist_lookup(&self, blocklist: &str, ip: IpAddr) -> Result<Vec<Ipv4Addr>> {
+        if let Some(codes) = self.cache.as_ref().and_then(|c| c.get(blocklist, ip)) {
+            return Ok(codes);
+        }
         let resolver = BlockList::lookup_ns(blocklist, &self.bootstrap).await?;
-        let blocklist_lookup = BlockList::new(resolver, blocklist);
-        blocklist_lookup.is_blocked(ip).await
+        let answer = BlockList::new(resolver, blocklist).lookup(ip).await?;
+        if let Some(cache) = &self.cache {
+            let ttl = Duration::from_secs(answer.ttl.into());
+            cache.insert(blocklist, ip, answer.records.clone(), ttl);
+        }
+        Ok(answer.records)
+    }
+
+    async fn check_list(&self, list: &BlockListConfig, ip: IpAddr) -> Result<bool> {
+        let codes = self.blocklist_lookup(&list.fqdn, ip).await?;
+        Ok(list.is_listed(&codes))
     }
 
     /// Returns true if the address is on any of the blocklists
@@ -147,7 +350,15 @@ impl MxDns {
     where
         A: Into<IpAddr>,
     {
-        let mut res = self.on_blocklists(addr);
+        smol::block_on(self.is_blocked_async(addr))
+    }
+
+    /// Async version of `is_blocked` for use inside an async runtime
+    pub async fn is_blocked_async<A>(&self, addr: A) -> Result<bool>
+    where
+        A: Into<IpAddr>,
+    {
+        let mut res = self.on_blocklists_async(addr).await;
         if res.is_empty() {
             Ok(false)
         } else if res.iter().all(|r| r.is_err()) {
@@ -158,13 +369,59 @@ impl MxDns {
         }
     }
 
+    /// Returns the summed weight of the blocklists that contain the address.
+    /// Weights are looked up by the blocklist name, as given when creating the
+    /// MxDns, and blocklists without a weight use their configured weight, which
+    /// is 1 unless set with `MxDns::builder`. A caller can then block
+    /// the address when the score crosses a threshold, rather than relying on a
+    /// single aggressive blocklist.
+    pub fn weighted_score<A>(&self, addr: A, weights: &HashMap<String, i32>) -> Result<i32>
+    where
+        A: Into<IpAddr>,
+    {
+        let res = self.on_blocklists(addr);
+        score_lists(self.blocklists(), res, weights)
+    }
+
+    /// Returns the score of the address using the weights in the list
+    /// configurations: the summed weight of the block lists that contain the
+    /// address minus the summed weight of the allow lists that contain it.
+    /// Whitelisted addresses score 0.
+    pub fn score<A>(&self, addr: A) -> Result<i32>
+    where
+        A: Into<IpAddr>,
+    {
+        smol::block_on(self.score_async(addr))
+    }
+
+    /// Async version of `score` for use inside an async runtime
+    pub async fn score_async<A>(&self, addr: A) -> Result<i32>
+    where
+        A: Into<IpAddr>,
+    {
+        let ip: IpAddr = addr.into();
+        if self.lists.is_empty() || self.whitelist.contains(ip) {
+            return Ok(0);
+        }
+        let res = self.check_lists(ip).await;
+        score_lists(&self.lists, res, &HashMap::new())
+    }
+
     /// Does a reverse DNS lookup on the given ip address
     /// Returns Ok(None) if no reverse DNS entry exists.
     pub fn reverse_dns<A>(&self, ip: A) -> Result<Option<String>>
     where
         A: Into<IpAddr>,
     {
-        let res = smol::block_on(self.bootstrap.query_ptr(ip.into()));
+        smol::block_on(self.reverse_dns_async(ip))
+    }
+
+    /// Async version of `reverse_dns` for use inside an async runtime
+    pub async fn reverse_dns_async<A>(&self, ip: A) -> Result<Option<String>>
+    where
+        A: Into<IpAddr>,
+    {
+        let res = self.bootstrap.query_ptr(ip.into()).await;
         match res {
             Ok(fqdn) => Ok(Some(fqdn)),
             Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
@@ -172,21 +429,67 @@ impl MxDns {
         }
     }
 
+    /// Looks up the mail exchangers of a domain.
+    /// Returns an empty vector if the domain has no MX records.
+    pub fn mx_hosts(&self, domain: &str) -> Result<Vec<String>> {
+        smol::block_on(self.mx_hosts_async(domain))
+    }
+
+    /// Async version of `mx_hosts` for use inside an async runtime
+    pub async fn mx_hosts_async(&self, domain: &str) -> Result<Vec<String>> {
+        match self.bootstrap.query_mx(domain).await {
+            Ok(hosts) => Ok(hosts),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(Error::DnsQuery("mx_hosts".to_string(), e)),
+        }
+    }
+
+    /// Looks up the TXT records of a name, such as `_dmarc.example.com` for DMARC or
+    /// `selector._domainkey.example.com` for DKIM. Each record is returned as a
+    /// whole, the character strings within it are joined without separators.
+    /// Returns an empty vector if the name has no TXT records.
+    pub fn txt(&self, name: &str) -> Result<Vec<Vec<u8>>> {
+        smol::block_on(self.txt_async(name))
+    }
+
+    /// Async version of `txt` for use inside an async runtime
+    pub async fn txt_async(&self, name: &str) -> Result<Vec<Vec<u8>>> {
+        match self.bootstrap.query_txt(name).await {
+            Ok(records) => Ok(records),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(Error::DnsQuery("txt".to_string(), e)),
+        }
+    }
+
     /// Does a Forward Confirmed Reverse DNS check on the given ip address
     /// This checks that the reverse lookup on the ip address gives a domain
     /// name that will resolve to the original ip address.
     /// Returns the confirmed reverse DNS domain name.
     pub fn fcrdns<A>(&self, ip: A) -> Result<FCrDNS>
+    where
+        A: Into<IpAddr>,
+    {
+        smol::block_on(self.fcrdns_async(ip))
+    }
+
+    /// Async version of `fcrdns` for use inside an async runtime
+    pub async fn fcrdns_async<A>(&self, ip: A) -> Result<FCrDNS>
     where
         A: Into<IpAddr>,
     {
         let ipaddr = ip.into();
-        let fqdn = match self.reverse_dns(ipaddr)? {
+        if self.whitelist.contains(ipaddr) {
+            return Ok(FCrDNS::Whitelisted);
+        }
+        let fqdn = match self.reverse_dns_async(ipaddr).await? {
             None => return Ok(FCrDNS::NoReverse),
             Some(s) => s,
         };
         debug!("reverse lookup for {} = {}", ipaddr, fqdn);
-        let forward = smol::block_on(self.bootstrap.query_a(&fqdn))
+        let forward = self
+            .bootstrap
+            .query_a(&fqdn)
+            .await
             .map_err(|e| Error::DnsQuery("fcrdns".to_string(), e))?;
         let is_confirmed = forward.contains(&ipaddr);
         if is_confirmed {
@@ -195,13 +498,143 @@ impl MxDns {
             Ok(FCrDNS::UnConfirmed(fqdn))
         }
     }
+
+    /// Checks the SPF record of the MAIL FROM domain to see if the given ip address
+    /// is allowed to send mail for the domain. The HELO domain is checked when the
+    /// MAIL FROM domain is empty e.g for bounce messages.
+    ///
+    /// The ip4, ip6, a, mx, include, exists and all mechanisms are supported along
+    /// with the redirect modifier. Macros are not supported and give a PermError.
+    /// At most 10 DNS lookups are done, as required by RFC 7208.
+    pub fn check_spf<A>(&self, ip: A, helo: &str, mail_from_domain: &str) -> SpfResult
+    where
+        A: Into<IpAddr>,
+    {
+        let ipaddr = ip.into();
+        let domain = if mail_from_domain.is_empty() {
+            helo
+        } else {
+            mail_from_domain
+        };
+        let spf = Spf::new(&self.bootstrap, ipaddr);
+        let res = smol::block_on(spf.check_host(domain.to_string()));
+        debug!("spf for {} from {} = {:?}", domain, ipaddr, res);
+        res
+    }
+}
+
+// The nameservers and options from the system provided nameserver config
+fn system_resolver(fallback: IpAddr) -> Result<SystemResolver> {
+    read_resolv_conf(RESOLV_CONF, fallback)
+}
+
+// Configure blocklists with the default weight
+fn list_configs<S>(blocklists_fqdn: S) -> Vec<BlockListConfig>
+where
+    S: IntoIterator,
+    S::Item: Into<String>,
+{
+    blocklists_fqdn
+        .into_iter()
+        .map(BlockListConfig::new)
+        .collect()
+}
+
+// A resolver that queries a single nameserver
+fn nameserver_resolver(ip: IpAddr) -> Resolver {
+    Resolver::new(vec![SocketAddr::new(ip, 53)], DEFAULT_TIMEOUT)
+}
+
+// The nameservers and query options of the system resolver
+#[derive(Debug, PartialEq)]
+struct SystemResolver {
+    nameservers: Vec<SocketAddr>,
+    // How long to wait for an answer from a nameserver
+    timeout: Duration,
+    // How many times the nameservers are tried
+    attempts: u32,
+}
+
+impl SystemResolver {
+    // Create a resolver that tries each nameserver in turn until one answers
+    fn resolver(&self) -> Resolver {
+        let servers = self.nameservers.iter().copied();
+        let tries = self.nameservers.len() * self.attempts.max(1) as usize;
+        Resolver::new(servers.cycle().take(tries).collect(), self.timeout)
+    }
+}
+
+// Read the nameservers and options in a resolv.conf file. The fallback is used if
+// the file is missing or has no nameservers, a file that cannot be parsed is an error.
+fn read_resolv_conf(path: &str, fallback: IpAddr) -> Result<SystemResolver> {
+    let mut buf = Vec::with_capacity(256);
+    let read = File::open(path).and_then(|mut file| file.read_to_end(&mut buf));
+    let conf = match read {
+        Ok(_) => resolv_conf::Config::parse(&buf)
+            .map_err(|e| Error::ResolvConfParse(path.to_string(), e))?,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            debug!("{} not found, using the default options", path);
+            resolv_conf::Config::new()
+        }
+        Err(e) => return Err(Error::ResolvConfRead(path.to_string(), e)),
+    };
+    let mut nameservers: Vec<SocketAddr> = conf
+        .nameservers
+        .iter()
+        .map(|ip| SocketAddr::new(ip.into(), 53))
+        .collect();
+    if nameservers.is_empty() {
+        debug!("No nameservers in {}, using {}", path, fallback);
+        nameservers.push(SocketAddr::new(fallback, 53));
+    }
+    Ok(SystemResolver {
+        nameservers,
+        timeout: Duration::from_secs(conf.timeout.into()),
+        attempts: conf.attempts,
+    })
+}
+
+// Sum the weights of the lists that matched, allow lists subtract their weight.
+// Weights are looked up by list name in `weights`, lists without a weight there
+// use their configured weight. Lookup failures count as not matching unless
+// every lookup failed.
+fn score_lists<'a, L>(
+    lists: L,
+    mut res: Vec<Result<bool>>,
+    weights: &HashMap<String, i32>,
+) -> Result<i32>
+where
+    L: IntoIterator<Item = &'a BlockListConfig>,
+{
+    if res.iter().all(|r| r.is_err()) {
+        if let Some(Err(e)) = res.pop() {
+            return Err(e);
+        }
+    }
+    let score = lists
+        .into_iter()
+        .zip(res)
+        .filter(|(_, r)| matches!(r, Ok(true)))
+        .map(|(list, _)| {
+            let weight = weights.get(&list.fqdn).copied().unwrap_or(list.weight);
+            match list.kind {
+                ListKind::Block => weight,
+                ListKind::Allow => -weight,
+            }
+        })
+        .sum();
+    Ok(score)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::stub::{StubNameserver, Zone};
+    use dnssector::constants::Type;
     use std::io;
-    use std::net::Ipv4Addr;
+    use std::net::Ipv6Addr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Instant;
 
     const BOOTSTRAP_DNS: IpAddr = IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8));
 
@@ -217,13 +650,12 @@ mod tests {
     }
 
     fn lookup_host(host: &str) -> Result<IpAddr> {
-        let socket_addr = (BOOTSTRAP_DNS, 53).into();
-        let dns = DNSClient::new(vec![socket_addr]);
+        let dns = nameserver_resolver(BOOTSTRAP_DNS);
         smol::block_on(dns.query_a(host))
             .and_then(|res| {
                 res.first()
                     .cloned()
-                    .ok_or_else(|| io::Error::new(ErrorKind::Other, "no dns entries"))
+                    .ok_or_else(|| io::Error::other("no dns entries"))
             })
             .map_err(|e| Error::DnsQuery(host.to_string(), e))
     }
@@ -244,6 +676,159 @@ mod tests {
         assert!(!blocked);
     }
 
+    // Read resolv.conf contents from a temporary file
+    fn read_temp_resolv_conf(contents: &str, fallback: IpAddr) -> SystemResolver {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let count = COUNT.fetch_add(1, Ordering::SeqCst);
+        let name = format!("mxdns-resolv-{}-{}", std::process::id(), count);
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, contents).unwrap();
+        let resolver = read_resolv_conf(path.to_str().unwrap(), fallback);
+        std::fs::remove_file(&path).ok();
+        resolver.unwrap()
+    }
+
+    #[test]
+    fn missing_resolv_conf() {
+        let fallback = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 53));
+        let resolver = read_resolv_conf("/nonexistent/resolv.conf", fallback).unwrap();
+        assert_eq!(resolver.nameservers, vec![(fallback, 53).into()]);
+        let resolver = read_temp_resolv_conf("# no nameservers\n", fallback);
+        assert_eq!(resolver.nameservers, vec![(fallback, 53).into()]);
+        let resolver = read_temp_resolv_conf("nameserver 198.51.100.1\n", fallback);
+        assert_eq!(
+            resolver.nameservers,
+            vec!["198.51.100.1:53".parse().unwrap()]
+        );
+    }
+
+    #[test]
+    fn resolv_conf_options() {
+        let fallback = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 53));
+        let conf = "nameserver 198.51.100.1\nnameserver 2001:db8::53\n\
+                    options timeout:1 attempts:3\n";
+        let resolver = read_temp_resolv_conf(conf, fallback);
+        assert_eq!(
+            resolver,
+            SystemResolver {
+                nameservers: vec![
+                    "198.51.100.1:53".parse().unwrap(),
+                    "[2001:db8::53]:53".parse().unwrap(),
+                ],
+                timeout: Duration::from_secs(1),
+                attempts: 3,
+            }
+        );
+        // Without options, the resolv.conf defaults are used
+        let resolver = read_temp_resolv_conf("nameserver 198.51.100.1\n", fallback);
+        assert_eq!(resolver.timeout, Duration::from_secs(5));
+        assert_eq!(resolver.attempts, 2);
+    }
+
+    #[test]
+    fn resolver_timeout() {
+        // A nameserver that never answers
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let resolver = SystemResolver {
+            nameservers: vec![silent.local_addr().unwrap()],
+            timeout: Duration::from_millis(100),
+            attempts: 3,
+        }
+        .resolver();
+        // Each attempt waits for the timeout before the query fails
+        let start = Instant::now();
+        let res = smol::block_on(resolver.query_a("example.com"));
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::TimedOut);
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        assert!(start.elapsed() < Duration::from_secs(2));
+        let start = Instant::now();
+        let res = smol::block_on(resolver.query_txt("example.com"));
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::TimedOut);
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn cached_blocklist_result() {
+        let mxdns = MxDns::with_dns(BOOTSTRAP_DNS, vec!["bl.invalid"]).with_cache_capacity(10);
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let code = Ipv4Addr::new(127, 0, 0, 10);
+        mxdns.cache.as_ref().unwrap().insert(
+            "bl.invalid",
+            ip,
+            vec![code],
+            Duration::from_secs(300),
+        );
+        // The cached result is used without querying the blocklist, which does not exist
+        assert!(mxdns.is_blocked(ip).unwrap());
+        assert!(mxdns.clone().is_blocked(ip).unwrap());
+        assert_eq!(
+            mxdns.blocklist_codes(ip).pop().unwrap().unwrap(),
+            vec![code]
+        );
+        // The return codes are cached, so the list config decides if they are a listing
+        let strict = BlockListConfig {
+            return_codes: vec![Ipv4Addr::new(127, 0, 0, 2)],
+            ..BlockListConfig::new("bl.invalid")
+        };
+        let mxdns = MxDns::builder()
+            .dns(BOOTSTRAP_DNS)
+            .add_list(strict)
+            .build()
+            .unwrap()
+            .with_cache_capacity(10);
+        mxdns.cache.as_ref().unwrap().insert(
+            "bl.invalid",
+            ip,
+            vec![code],
+            Duration::from_secs(300),
+        );
+        assert!(!mxdns.is_blocked(ip).unwrap());
+    }
+
+    #[test]
+    fn blocklist_answer_ttl() {
+        // A listing with a short TTL, unlisted addresses are cached for longer
+        let mut zone = Zone::new();
+        zone.add("1.2.0.192.bl.example", Type::A, 1, vec![127, 0, 0, 2])
+            .soa(3600, 3600);
+        let nameserver = StubNameserver::start(zone);
+        let mxdns = MxDns::with_lists(nameserver.resolver(), list_configs(["bl.example"]))
+            .with_cache_capacity(10);
+        let listed = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let unlisted = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        assert!(mxdns.is_blocked(listed).unwrap());
+        assert!(!mxdns.is_blocked(unlisted).unwrap());
+        // Lookups within the TTL do not query the nameserver
+        let queries = nameserver.queries();
+        assert!(mxdns.is_blocked(listed).unwrap());
+        assert!(!mxdns.is_blocked(unlisted).unwrap());
+        assert_eq!(nameserver.queries(), queries);
+        // The listing expires first
+        std::thread::sleep(Duration::from_millis(1100));
+        assert!(mxdns.is_blocked(listed).unwrap());
+        assert!(nameserver.queries() > queries);
+        let queries = nameserver.queries();
+        assert!(!mxdns.is_blocked(unlisted).unwrap());
+        assert_eq!(nameserver.queries(), queries);
+    }
+
+    #[test]
+    fn whitelisted_network() {
+        let mxdns = MxDns::with_dns(BOOTSTRAP_DNS, vec!["bl.invalid"])
+            .with_whitelist_networks(["192.0.2.0/24"])
+            .unwrap()
+            .with_whitelist([IpAddr::V4(Ipv4Addr::new(198, 51, 100, 7))]);
+        // Whitelisted addresses are not looked up, the blocklist does not exist
+        assert!(!mxdns.is_blocked([192, 0, 2, 1]).unwrap());
+        assert!(!mxdns.is_blocked([198, 51, 100, 7]).unwrap());
+        assert!(matches!(
+            mxdns.fcrdns([192, 0, 2, 254]),
+            Ok(FCrDNS::Whitelisted)
+        ));
+        assert!(mxdns.on_blocklists([192, 0, 2, 1]).is_empty());
+    }
+
     #[test]
     fn blocklist_addrs() {
         let mxdns = build_mx_dns();
@@ -251,7 +836,7 @@ mod tests {
         for b in blocklists {
             let ns = smol::block_on(mxdns.bootstrap.query_ns(b.0));
             if b.1 {
-                assert!(matches!(ns, Ok(_)), "no NS for {}", b.0);
+                assert!(ns.is_ok(), "no NS for {}", b.0);
             } else {
                 assert!(
                     matches!(&ns, Ok(v) if v.is_empty()),
@@ -263,6 +848,63 @@ mod tests {
         }
     }
 
+    #[test]
+    fn weighted_threshold() {
+        let blocklists: Vec<BlockListConfig> =
+            ["aggressive.example", "strict.example", "other.example"]
+                .into_iter()
+                .map(BlockListConfig::new)
+                .collect();
+        let weights = HashMap::from([
+            ("aggressive.example".to_string(), 1),
+            ("strict.example".to_string(), 5),
+        ]);
+        let threshold = 5;
+        // A single aggressive list does not cross the threshold
+        let score = score_lists(&blocklists, vec![Ok(true), Ok(false), Ok(false)], &weights);
+        assert!(score.unwrap() <= threshold);
+        // Lists without a weight count as 1
+        let score = score_lists(&blocklists, vec![Ok(true), Ok(false), Ok(true)], &weights);
+        assert_eq!(score.unwrap(), 2);
+        let score = score_lists(&blocklists, vec![Ok(true), Ok(true), Ok(false)], &weights);
+        assert!(score.unwrap() > threshold);
+        // Failed lookups are ignored unless they all fail
+        let failure = || Err(Error::BlockListNameserverIp("strict.example".to_string()));
+        let score = score_lists(&blocklists, vec![Ok(true), failure(), Ok(false)], &weights);
+        assert_eq!(score.unwrap(), 1);
+        let score = score_lists(&blocklists, vec![failure(), failure(), failure()], &weights);
+        assert!(score.is_err());
+    }
+
+    #[test]
+    fn block_and_allow_lists() {
+        let block = BlockListConfig {
+            weight: 5,
+            return_codes: vec![Ipv4Addr::new(127, 0, 0, 2)],
+            ..BlockListConfig::new("bl.example")
+        };
+        let allow = BlockListConfig {
+            weight: 2,
+            ..BlockListConfig::allow("wl.example")
+        };
+        let mxdns = MxDns::builder()
+            .dns(BOOTSTRAP_DNS)
+            .add_list(block)
+            .add_list(allow)
+            .build()
+            .unwrap();
+        let blocklists: Vec<&str> = mxdns.blocklists().map(|l| l.fqdn.as_str()).collect();
+        assert_eq!(blocklists, vec!["bl.example"]);
+        let lists = &mxdns.lists;
+        let score = |res| score_lists(lists, res, &HashMap::new());
+        assert_eq!(score(vec![Ok(true), Ok(false)]).unwrap(), 5);
+        assert_eq!(score(vec![Ok(true), Ok(true)]).unwrap(), 3);
+        assert_eq!(score(vec![Ok(false), Ok(true)]).unwrap(), -2);
+        let failure = || Err(Error::BlockListNameserverIp("wl.example".to_string()));
+        assert_eq!(score(vec![Ok(true), failure()]).unwrap(), 5);
+        assert!(score(vec![failure(), failure()]).is_err());
+    }
+
     #[test]
     fn not_blocked() {
         let mxdns = build_mx_dns();
@@ -277,6 +919,30 @@ mod tests {
         assert!(blocked);
     }
 
+    #[test]
+    #[ignore = "queries public DNS servers"]
+    fn blocked_ipv6() {
+        // The RFC 5782 test entry
+        let mxdns = MxDns::with_dns(BOOTSTRAP_DNS, vec!["zen.spamhaus.org"]);
+        let ip: Ipv6Addr = "::ffff:7f00:2".parse().unwrap();
+        assert!(mxdns.is_blocked(ip).unwrap());
+        let ip: Ipv6Addr = "::ffff:7f00:1".parse().unwrap();
+        assert!(!mxdns.is_blocked(ip).unwrap());
+    }
+
+    #[test]
+    #[ignore = "queries public DNS servers"]
+    fn blocklist_return_codes() {
+        let mxdns = MxDns::with_dns(BOOTSTRAP_DNS, vec!["zen.spamhaus.org"]);
+        let codes = mxdns
+            .blocklist_codes([127, 0, 0, 2])
+            .pop()
+            .unwrap()
+            .unwrap();
+        assert!(!codes.is_empty());
+        assert!(codes.iter().all(|c| c.octets()[0] == 127), "{:?}", codes);
+    }
+
     #[test]
     fn reverse_lookup() {
         let alienscience_ip =
@@ -299,6 +965,51 @@ mod tests {
         );
     }
 
+    #[test]
+    #[ignore = "queries public DNS servers"]
+    fn fcrdns_async_ok() {
+        let mxdns = build_mx_dns();
+        let res = smol::block_on(mxdns.fcrdns_async([209, 85, 167, 66]));
+        assert!(
+            matches!(res, Ok(FCrDNS::Confirmed(_))),
+            "Valid google server failed async fcrdns: {:?}",
+            res
+        );
+    }
+
+    #[test]
+    fn async_lookups_are_send() {
+        fn assert_send<T: Send>(_: &T) {}
+        let mxdns = build_mx_dns();
+        let ip = Ipv4Addr::new(127, 0, 0, 2);
+        assert_send(&mxdns.is_blocked_async(ip));
+        assert_send(&mxdns.reverse_dns_async(ip));
+        assert_send(&mxdns.fcrdns_async(ip));
+    }
+
+    #[test]
+    #[ignore = "queries public DNS servers"]
+    fn google_mx_hosts() {
+        let mxdns = build_mx_dns();
+        let hosts = mxdns.mx_hosts("google.com").unwrap();
+        assert!(
+            hosts.iter().any(|h| h.ends_with("google.com")),
+            "Unexpected google.com MX hosts: {:?}",
+            hosts
+        );
+    }
+
+    #[test]
+    #[ignore = "queries public DNS servers"]
+    fn google_spf_txt() {
+        let mxdns = build_mx_dns();
+        let records = mxdns.txt("google.com").unwrap();
+        assert!(
+            records.iter().any(|r| r.starts_with(b"v=spf1 ")),
+            "No SPF record for google.com"
+        );
+    }
+
     #[test]
     fn fcrdns_google_ok() {
         let mxdns = build_mx_dns();
@@ -310,6 +1021,32 @@ mod tests {
         );
     }
 
+    #[test]
+    #[ignore = "queries public DNS servers"]
+    fn spf_include_pass() {
+        // google.com includes _spf.google.com
+        let mxdns = build_mx_dns();
+        let res = mxdns.check_spf([209, 85, 167, 66], "mail-lf1-f66.google.com", "google.com");
+        assert_eq!(res, SpfResult::Pass);
+    }
+
+    #[test]
+    #[ignore = "queries public DNS servers"]
+    fn spf_fail() {
+        // microsoft.com ends with -all
+        let mxdns = build_mx_dns();
+        let res = mxdns.check_spf([127, 0, 0, 2], "localhost", "microsoft.com");
+        assert_eq!(res, SpfResult::Fail);
+    }
+
+    #[test]
+    #[ignore = "queries public DNS servers"]
+    fn spf_helo() {
+        let mxdns = build_mx_dns();
+        let res = mxdns.check_spf([127, 0, 0, 2], "microsoft.com", "");
+        assert_eq!(res, SpfResult::Fail);
+    }
+
     #[test]
     fn fcrdns_fail() {
         let mxdns = build_mx_dns();
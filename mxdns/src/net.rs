@@ -0,0 +1,74 @@
+use std::net::IpAddr;
+
+// Use IPv4 addresses where possible
+pub fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        v4 => v4,
+    }
+}
+
+// The name of an address in the reverse DNS zones, and on blocklists, without
+// the zone. IPv6 addresses are reversed nibble by nibble as described in RFC 5782.
+pub fn reverse_name(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, c, d] = v4.octets();
+            format!("{}.{}.{}.{}", d, c, b, a)
+        }
+        IpAddr::V6(v6) => {
+            let nibbles: Vec<String> = v6
+                .octets()
+                .iter()
+                .rev()
+                .flat_map(|b| [b & 0x0f, b >> 4])
+                .map(|n| format!("{:x}", n))
+                .collect();
+            nibbles.join(".")
+        }
+    }
+}
+
+// Is the address in the network with the given prefix length?
+pub fn in_network(ip: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn networks() {
+        let ip: IpAddr = "192.0.2.10".parse().unwrap();
+        assert!(in_network(ip, "192.0.2.0".parse().unwrap(), 24));
+        assert!(!in_network(ip, "192.0.3.0".parse().unwrap(), 24));
+        assert!(in_network(ip, "10.0.0.0".parse().unwrap(), 0));
+        let ip6: IpAddr = "2001:db8::1".parse().unwrap();
+        assert!(in_network(ip6, "2001:db8::".parse().unwrap(), 32));
+        assert!(!in_network(ip6, ip, 0));
+        let mapped: IpAddr = "::ffff:192.0.2.10".parse().unwrap();
+        assert_eq!(canonical_ip(mapped), ip);
+    }
+
+    #[test]
+    fn reverse_names() {
+        let ip = IpAddr::V4("192.0.2.1".parse().unwrap());
+        assert_eq!(reverse_name(ip), "1.2.0.192");
+        let ip = IpAddr::V6("2001:db8:1:2:3:4:567:89ab".parse().unwrap());
+        assert_eq!(
+            reverse_name(ip),
+            "b.a.9.8.7.6.5.0.4.0.0.0.3.0.0.0.2.0.0.0.1.0.0.0.8.b.d.0.1.0.0.2"
+        );
+    }
+}
@@ -0,0 +1,501 @@
+use crate::net::reverse_name;
+use dnssector::{
+    constants::{Class, Rcode, Type},
+    gen, Compress, DNSIterable, DNSSector, ParsedPacket, RdataIterable, TypedIterable, DNS_FLAG_TC,
+    DNS_MAX_COMPRESSED_SIZE, DNS_RR_HEADER_SIZE,
+};
+use smol::future::FutureExt;
+use smol::io::{AsyncReadExt, AsyncWriteExt};
+use smol::net::{TcpStream, UdpSocket};
+use smol::Timer;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+// The default time to wait for an answer from a nameserver, as in resolv.conf
+pub(crate) const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The records of an answer and the number of seconds that they can be cached
+// for. This is the lowest TTL in the answer or, for an answer without records,
+// the negative caching TTL from the SOA record of the zone (RFC 2308).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Answer<T> {
+    pub records: Vec<T>,
+    pub ttl: u32,
+}
+
+impl<T> Answer<T> {
+    fn try_map<U, F>(self, f: F) -> io::Result<Answer<U>>
+    where
+        F: Fn(T) -> io::Result<U>,
+    {
+        let records = self.records.into_iter().map(f).collect::<io::Result<_>>()?;
+        Ok(Answer {
+            records,
+            ttl: self.ttl,
+        })
+    }
+}
+
+// Queries a list of nameservers, each in turn until one answers. Names that do
+// not exist give answers without records.
+#[derive(Clone, Debug)]
+pub(crate) struct Resolver {
+    nameservers: Vec<SocketAddr>,
+    timeout: Duration,
+}
+
+impl Resolver {
+    pub fn new(nameservers: Vec<SocketAddr>, timeout: Duration) -> Self {
+        Self {
+            nameservers,
+            timeout,
+        }
+    }
+
+    // Get the IPv4 addresses of a name
+    pub async fn query_a(&self, name: &str) -> io::Result<Vec<IpAddr>> {
+        self.query_a_with_ttl(name).await.map(|a| a.records)
+    }
+
+    pub async fn query_a_with_ttl(&self, name: &str) -> io::Result<Answer<IpAddr>> {
+        self.query(name, Type::A).await?.try_map(|rdata| {
+            let octets = <[u8; 4]>::try_from(rdata).map_err(|_| invalid_data("Bad A record"))?;
+            Ok(Ipv4Addr::from(octets).into())
+        })
+    }
+
+    // Get the IPv6 addresses of a name
+    pub async fn query_aaaa(&self, name: &str) -> io::Result<Vec<IpAddr>> {
+        self.query_aaaa_with_ttl(name).await.map(|a| a.records)
+    }
+
+    pub async fn query_aaaa_with_ttl(&self, name: &str) -> io::Result<Answer<IpAddr>> {
+        self.query(name, Type::AAAA).await?.try_map(|rdata| {
+            let octets =
+                <[u8; 16]>::try_from(rdata).map_err(|_| invalid_data("Bad AAAA record"))?;
+            Ok(Ipv6Addr::from(octets).into())
+        })
+    }
+
+    // Get the name of an ip address, NotFound if the address has no name
+    pub async fn query_ptr(&self, ip: IpAddr) -> io::Result<String> {
+        self.query_ptr_with_ttl(ip).await.map(|(name, _)| name)
+    }
+
+    pub async fn query_ptr_with_ttl(&self, ip: IpAddr) -> io::Result<(String, u32)> {
+        let zone = if ip.is_ipv4() {
+            "in-addr.arpa"
+        } else {
+            "ip6.arpa"
+        };
+        let name = format!("{}.{}", reverse_name(ip), zone);
+        let answer = self.query(&name, Type::PTR).await?;
+        let ttl = answer.ttl;
+        let name = answer
+            .records
+            .into_iter()
+            .next()
+            .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "No PTR record"))?;
+        Ok((decode_name(&name)?, ttl))
+    }
+
+    // Get the nameservers of a domain
+    pub async fn query_ns(&self, domain: &str) -> io::Result<Vec<String>> {
+        self.query_ns_with_ttl(domain).await.map(|a| a.records)
+    }
+
+    pub async fn query_ns_with_ttl(&self, domain: &str) -> io::Result<Answer<String>> {
+        self.query(domain, Type::NS)
+            .await?
+            .try_map(|rdata| decode_name(&rdata))
+    }
+
+    // Get the TXT records of a name, the character strings within each record
+    // are joined without separators
+    pub async fn query_txt(&self, name: &str) -> io::Result<Vec<Vec<u8>>> {
+        let answer = self.query(name, Type::TXT).await?;
+        let records = answer
+            .records
+            .iter()
+            .map(|rdata| character_strings(rdata))
+            .collect();
+        Ok(records)
+    }
+
+    // Get the mail exchangers of a domain, most preferred first
+    pub async fn query_mx(&self, domain: &str) -> io::Result<Vec<String>> {
+        let answer = self.query(domain, Type::MX).await?;
+        let mut exchanges = Vec::new();
+        for rdata in answer.records {
+            if rdata.len() < 2 {
+                return Err(invalid_data("MX record too short"));
+            }
+            let preference = u16::from_be_bytes([rdata[0], rdata[1]]);
+            exchanges.push((preference, decode_name(&rdata[2..])?));
+        }
+        if exchanges.is_empty() {
+            return Err(ErrorKind::NotFound.into());
+        }
+        exchanges.sort_by_key(|(preference, _)| *preference);
+        Ok(exchanges.into_iter().map(|(_, name)| name).collect())
+    }
+
+    // The rdata of the records with the given type
+    async fn query(&self, name: &str, rr_type: Type) -> io::Result<Answer<Vec<u8>>> {
+        let query = gen::query(name.as_bytes(), rr_type, Class::IN)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+        let tid = query.tid();
+        let packet = query.into_packet();
+        let mut res = Err(io::Error::new(ErrorKind::NotFound, "No nameservers"));
+        for nameserver in &self.nameservers {
+            res = self.query_nameserver(nameserver, &packet, tid).await;
+            if res.is_ok() {
+                break;
+            }
+        }
+        let mut response = res?;
+        match response.rcode() {
+            rcode if rcode == Rcode::NOERROR as u8 => Ok(answer(&mut response, rr_type)),
+            rcode if rcode == Rcode::NXDOMAIN as u8 => Ok(Answer {
+                records: Vec::new(),
+                ttl: negative_ttl(&mut response),
+            }),
+            rcode => Err(io::Error::other(format!(
+                "DNS query failed with rcode {}",
+                rcode
+            ))),
+        }
+    }
+
+    async fn query_nameserver(
+        &self,
+        nameserver: &SocketAddr,
+        packet: &[u8],
+        tid: u16,
+    ) -> io::Result<ParsedPacket> {
+        let raw = udp_query(nameserver, packet)
+            .or(timeout(self.timeout))
+            .await?;
+        let mut response = parse_response(raw)?;
+        // Answers that do not fit in a UDP message are truncated, ask again over TCP
+        if response.flags() & DNS_FLAG_TC == DNS_FLAG_TC {
+            let raw = tcp_query(nameserver, packet)
+                .or(timeout(self.timeout))
+                .await?;
+            response = parse_response(raw)?;
+        }
+        if response.tid() != tid {
+            return Err(invalid_data("DNS response does not match the query"));
+        }
+        Ok(response)
+    }
+}
+
+// Each query is sent from a new socket, bound to port 0, so that the OS picks
+// a random ephemeral source port for it. Together with the random query id
+// this makes it hard for an off-path attacker to guess the query that a
+// spoofed answer has to match (RFC 5452). The connected socket only receives
+// answers from the nameserver.
+async fn udp_query(nameserver: &SocketAddr, packet: &[u8]) -> io::Result<Vec<u8>> {
+    let local: SocketAddr = match nameserver {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(nameserver).await?;
+    socket.send(packet).await?;
+    let mut response = vec![0; DNS_MAX_COMPRESSED_SIZE];
+    let len = socket.recv(&mut response).await?;
+    response.truncate(len);
+    Ok(response)
+}
+
+// DNS over TCP prefixes messages with their length
+async fn tcp_query(nameserver: &SocketAddr, packet: &[u8]) -> io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(nameserver).await?;
+    let len = u16::try_from(packet.len()).map_err(|_| invalid_data("DNS query too long"))?;
+    stream.write_all(&len.to_be_bytes()).await?;
+    stream.write_all(packet).await?;
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len).await?;
+    let mut response = vec![0; u16::from_be_bytes(len).into()];
+    stream.read_exact(&mut response).await?;
+    Ok(response)
+}
+
+async fn timeout(duration: Duration) -> io::Result<Vec<u8>> {
+    Timer::after(duration).await;
+    Err(ErrorKind::TimedOut.into())
+}
+
+// Names in the response are uncompressed so that they can be read from the rdata
+fn parse_response(raw: Vec<u8>) -> io::Result<ParsedPacket> {
+    let raw = Compress::uncompress(&raw).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+    DNSSector::new(raw)
+        .and_then(|sector| sector.parse())
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+}
+
+// The rdata of the answers with the given type, CNAME answers are skipped but
+// their TTL still limits how long the answer can be cached
+fn answer(response: &mut ParsedPacket, rr_type: Type) -> Answer<Vec<u8>> {
+    let mut records = Vec::new();
+    let mut ttl = u32::MAX;
+    let mut answer = response.into_iter_answer();
+    while let Some(rr) = answer {
+        if rr.rr_type() == rr_type as u16 {
+            records.push(rdata(&rr).to_vec());
+        }
+        ttl = ttl.min(rr.rr_ttl());
+        answer = rr.next();
+    }
+    if records.is_empty() {
+        ttl = negative_ttl(response);
+    }
+    Answer { records, ttl }
+}
+
+// The lower of the TTL and the minimum field of the SOA record in the authority
+// section, which is 0 without a SOA record
+fn negative_ttl(response: &mut ParsedPacket) -> u32 {
+    let mut authority = response.into_iter_nameservers();
+    while let Some(rr) = authority {
+        if rr.rr_type() == Type::SOA as u16 {
+            let soa = rdata(&rr);
+            let minimum = soa
+                .len()
+                .checked_sub(4)
+                .map(|start| {
+                    u32::from_be_bytes([soa[start], soa[start + 1], soa[start + 2], soa[start + 3]])
+                })
+                .unwrap_or(0);
+            return rr.rr_ttl().min(minimum);
+        }
+        authority = rr.next();
+    }
+    0
+}
+
+// The rdata of a record, which follows the record header
+fn rdata<R>(rr: &R) -> &[u8]
+where
+    R: DNSIterable + TypedIterable + RdataIterable,
+{
+    let start = DNS_RR_HEADER_SIZE;
+    let end = start + rr.rr_rdlen();
+    rr.rdata_slice().get(start..end).unwrap_or_default()
+}
+
+// Join the length prefixed character strings of a TXT record
+fn character_strings(rdata: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(rdata.len());
+    let mut rest = rdata;
+    while let Some((&len, tail)) = rest.split_first() {
+        let len = (len as usize).min(tail.len());
+        ret.extend_from_slice(&tail[..len]);
+        rest = &tail[len..];
+    }
+    ret
+}
+
+// Convert an uncompressed name, a sequence of length prefixed labels, to a string
+fn decode_name(raw: &[u8]) -> io::Result<String> {
+    let mut labels = Vec::new();
+    let mut rest = raw;
+    loop {
+        let (&len, tail) = rest
+            .split_first()
+            .ok_or_else(|| invalid_data("Truncated name"))?;
+        if len == 0 {
+            break;
+        }
+        let label = tail
+            .get(..len as usize)
+            .ok_or_else(|| invalid_data("Truncated name"))?;
+        labels.push(String::from_utf8_lossy(label));
+        rest = &tail[len as usize..];
+    }
+    Ok(labels.join("."))
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub::{StubNameserver, Zone};
+    use std::thread;
+
+    // Answer one query on a local socket with the given answer records, which
+    // use a compression pointer to the question for their name
+    fn local_nameserver(answers: Vec<(Type, Vec<u8>)>) -> SocketAddr {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            let (len, client) = socket.recv_from(&mut buf).unwrap();
+            let mut response = buf[..len].to_vec();
+            // QR and RA flags, one answer per record, no additional records
+            response[2] |= 0x80;
+            response[3] = 0x80;
+            response[6..8].copy_from_slice(&(answers.len() as u16).to_be_bytes());
+            response[10..12].copy_from_slice(&[0, 0]);
+            for (rr_type, rdata) in answers {
+                response.extend_from_slice(&[0xc0, 12]);
+                response.extend_from_slice(&(rr_type as u16).to_be_bytes());
+                response.extend_from_slice(&[0, 1, 0, 0, 1, 0]);
+                response.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+                response.extend_from_slice(&rdata);
+            }
+            socket.send_to(&response, client).unwrap();
+        });
+        addr
+    }
+
+    #[test]
+    fn txt_records() {
+        let answers = vec![
+            (Type::TXT, b"\x07v=spf1 \x04-all".to_vec()),
+            (Type::TXT, b"\x03abc".to_vec()),
+        ];
+        let resolver = Resolver::new(vec![local_nameserver(answers)], DEFAULT_TIMEOUT);
+        let records = smol::block_on(resolver.query_txt("example.com")).unwrap();
+        assert_eq!(records, vec![b"v=spf1 -all".to_vec(), b"abc".to_vec()]);
+    }
+
+    #[test]
+    fn mx_records() {
+        let answers = vec![
+            (Type::MX, b"\x00\x14\x04alt1\xc0\x0c".to_vec()),
+            (Type::MX, b"\x00\x0a\x02mx\x07example\x03com\x00".to_vec()),
+        ];
+        let resolver = Resolver::new(vec![local_nameserver(answers)], DEFAULT_TIMEOUT);
+        let hosts = smol::block_on(resolver.query_mx("example.com")).unwrap();
+        assert_eq!(hosts, vec!["mx.example.com", "alt1.example.com"]);
+    }
+
+    #[test]
+    fn answer_ttl() {
+        let mut zone = Zone::new();
+        zone.add("example.com", Type::A, 300, vec![192, 0, 2, 1])
+            .add("example.com", Type::A, 60, vec![192, 0, 2, 2])
+            .a("example.com", "2001:db8::1".parse::<IpAddr>().unwrap())
+            .ns("example.com", "ns.example.com")
+            .ptr("1.2.0.192.in-addr.arpa", "mail.example.com");
+        let nameserver = StubNameserver::start(zone);
+        let resolver = nameserver.resolver();
+        let answer = smol::block_on(resolver.query_a_with_ttl("example.com")).unwrap();
+        let ips: Vec<IpAddr> = vec![[192, 0, 2, 1].into(), [192, 0, 2, 2].into()];
+        assert_eq!(answer.records, ips);
+        // The lowest TTL of the records
+        assert_eq!(answer.ttl, 60);
+        assert_eq!(
+            smol::block_on(resolver.query_a("example.com")).unwrap(),
+            ips
+        );
+        let answer = smol::block_on(resolver.query_aaaa_with_ttl("example.com")).unwrap();
+        assert_eq!(
+            answer.records,
+            vec!["2001:db8::1".parse::<IpAddr>().unwrap()]
+        );
+        assert_eq!(answer.ttl, 300);
+        let answer = smol::block_on(resolver.query_ns_with_ttl("example.com")).unwrap();
+        assert_eq!(answer.records, vec!["ns.example.com"]);
+        assert_eq!(answer.ttl, 300);
+        let ptr = smol::block_on(resolver.query_ptr_with_ttl(ips[0])).unwrap();
+        assert_eq!(ptr, ("mail.example.com".to_string(), 300));
+    }
+
+    #[test]
+    fn ptr_errors() {
+        let mut zone = Zone::new();
+        // A name without a PTR record and a PTR record with a truncated name
+        zone.txt("1.2.0.192.in-addr.arpa", "no name").add(
+            "2.2.0.192.in-addr.arpa",
+            Type::PTR,
+            300,
+            b"\x04mail\x07exa".to_vec(),
+        );
+        let nameserver = StubNameserver::start(zone);
+        let resolver = nameserver.resolver();
+        let query_ptr = |ip: [u8; 4]| smol::block_on(resolver.query_ptr(ip.into()));
+        assert_eq!(
+            query_ptr([192, 0, 2, 1]).unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+        assert_eq!(
+            query_ptr([192, 0, 2, 2]).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+        assert_eq!(
+            query_ptr([192, 0, 2, 3]).unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn negative_answer_ttl() {
+        let mut zone = Zone::new();
+        zone.a("example.com", [192, 0, 2, 1]).soa(900, 120);
+        let nameserver = StubNameserver::start(zone);
+        let resolver = nameserver.resolver();
+        // A name that does not exist
+        let answer = smol::block_on(resolver.query_a_with_ttl("missing.example.com")).unwrap();
+        assert!(answer.records.is_empty());
+        assert_eq!(answer.ttl, 120);
+        // A name without records of the type
+        let answer = smol::block_on(resolver.query_aaaa_with_ttl("example.com")).unwrap();
+        assert!(answer.records.is_empty());
+        assert_eq!(answer.ttl, 120);
+        // Without a SOA record the answer cannot be cached
+        let nameserver = StubNameserver::start(Zone::new());
+        let answer = smol::block_on(nameserver.resolver().query_a_with_ttl("example.com")).unwrap();
+        assert_eq!(answer.ttl, 0);
+    }
+
+    #[test]
+    fn truncated_answer() {
+        // Too many records to fit in a UDP message
+        let mut zone = Zone::new();
+        let texts: Vec<String> = (0..40).map(|i| format!("record number {:02}", i)).collect();
+        for text in &texts {
+            zone.txt("many.example.com", text);
+        }
+        let nameserver = StubNameserver::start(zone);
+        let records = smol::block_on(nameserver.resolver().query_txt("many.example.com")).unwrap();
+        let expected: Vec<Vec<u8>> = texts.into_iter().map(String::into_bytes).collect();
+        assert_eq!(records, expected);
+    }
+
+    #[test]
+    fn fresh_source_port() {
+        let mut zone = Zone::new();
+        zone.txt("example.com", "v=spf1 -all");
+        let nameserver = StubNameserver::start(zone);
+        let resolver = nameserver.resolver();
+        for _ in 0..2 {
+            smol::block_on(resolver.query_txt("example.com")).unwrap();
+        }
+        let ports = nameserver.source_ports();
+        assert_eq!(ports.len(), 2);
+        assert_ne!(ports[0], ports[1]);
+    }
+
+    #[test]
+    fn txt_strings() {
+        assert_eq!(character_strings(b"\x07v=spf1 \x04-all"), b"v=spf1 -all");
+        assert_eq!(character_strings(b""), b"");
+        // A truncated string is kept as far as it goes
+        assert_eq!(character_strings(b"\x09abc"), b"abc");
+    }
+
+    #[test]
+    fn mx_names() {
+        let name = decode_name(b"\x04alt1\x05gmail\x03com\x00").unwrap();
+        assert_eq!(name, "alt1.gmail.com");
+        assert!(decode_name(b"\x04alt1\x05gma").is_err());
+    }
+}
@@ -0,0 +1,517 @@
+use crate::net::{canonical_ip, in_network};
+use crate::resolver::Resolver;
+use std::cell::Cell;
+use std::future::Future;
+use std::io::{self, ErrorKind};
+use std::net::IpAddr;
+use std::pin::Pin;
+
+// RFC 7208 section 4.6.4
+const MAX_LOOKUPS: usize = 10;
+const MAX_MX_NAMES: usize = 10;
+
+/// The result of a SPF check, as described in RFC 7208 section 2.6
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpfResult {
+    /// The domain does not publish a SPF record
+    None,
+    /// The domain makes no assertion about the ip address
+    Neutral,
+    /// The ip address is authorized to send mail for the domain
+    Pass,
+    /// The ip address is not authorized to send mail for the domain
+    Fail,
+    /// The ip address is probably not authorized to send mail for the domain
+    SoftFail,
+    /// A transient DNS error occurred
+    TempError,
+    /// The SPF record is malformed or too many DNS lookups were needed
+    PermError,
+}
+
+#[derive(Debug, PartialEq)]
+struct Record {
+    directives: Vec<Directive>,
+    redirect: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Directive {
+    result: SpfResult,
+    mechanism: Mechanism,
+}
+
+#[derive(Debug, PartialEq)]
+enum Mechanism {
+    All,
+    Include(String),
+    A(Option<String>, Cidr),
+    Mx(Option<String>, Cidr),
+    Ip(IpAddr, u8),
+    Exists(String),
+    Ptr,
+}
+
+#[derive(Debug, PartialEq)]
+struct Cidr {
+    v4: u8,
+    v6: u8,
+}
+
+type Evaluation<'a> = Pin<Box<dyn Future<Output = SpfResult> + 'a>>;
+
+// Evaluates SPF records for one client ip address
+pub(crate) struct Spf<'a> {
+    resolver: &'a Resolver,
+    ip: IpAddr,
+    lookups: Cell<usize>,
+}
+
+impl<'a> Spf<'a> {
+    pub fn new(resolver: &'a Resolver, ip: IpAddr) -> Self {
+        Self {
+            resolver,
+            ip: canonical_ip(ip),
+            lookups: Cell::new(0),
+        }
+    }
+
+    // The check_host() function from RFC 7208 section 4
+    pub fn check_host(&'a self, domain: String) -> Evaluation<'a> {
+        Box::pin(async move {
+            let records = match self.resolver.query_txt(&domain).await {
+                Ok(r) => r,
+                Err(e) if e.kind() == ErrorKind::NotFound => return SpfResult::None,
+                Err(_) => return SpfResult::TempError,
+            };
+            let mut spf_records = records
+                .iter()
+                .map(|r| String::from_utf8_lossy(r))
+                .filter(|r| is_spf(r));
+            let record = match (spf_records.next(), spf_records.next()) {
+                (None, _) => return SpfResult::None,
+                (Some(r), None) => r,
+                (Some(_), Some(_)) => return SpfResult::PermError,
+            };
+            let record = match parse_record(&record) {
+                Some(r) => r,
+                None => return SpfResult::PermError,
+            };
+            self.evaluate(&record, &domain).await
+        })
+    }
+
+    async fn evaluate(&'a self, record: &Record, domain: &str) -> SpfResult {
+        for directive in &record.directives {
+            match self.matches(&directive.mechanism, domain).await {
+                Ok(true) => return directive.result,
+                Ok(false) => (),
+                Err(res) => return res,
+            }
+        }
+        match &record.redirect {
+            Some(target) => {
+                if !self.count_lookup() {
+                    return SpfResult::PermError;
+                }
+                match self.check_host(target.clone()).await {
+                    SpfResult::None => SpfResult::PermError,
+                    res => res,
+                }
+            }
+            None => SpfResult::Neutral,
+        }
+    }
+
+    // Returns Err with the final result if evaluation has to stop
+    async fn matches(&'a self, mechanism: &Mechanism, domain: &str) -> Result<bool, SpfResult> {
+        if !matches!(mechanism, Mechanism::All | Mechanism::Ip(_, _)) && !self.count_lookup() {
+            return Err(SpfResult::PermError);
+        }
+        match mechanism {
+            Mechanism::All => Ok(true),
+            Mechanism::Ip(network, prefix) => Ok(in_network(self.ip, *network, *prefix)),
+            Mechanism::Include(target) => match self.check_host(target.clone()).await {
+                SpfResult::Pass => Ok(true),
+                SpfResult::Fail | SpfResult::SoftFail | SpfResult::Neutral => Ok(false),
+                SpfResult::TempError => Err(SpfResult::TempError),
+                SpfResult::None | SpfResult::PermError => Err(SpfResult::PermError),
+            },
+            Mechanism::A(target, cidr) => {
+                let host = target.as_deref().unwrap_or(domain);
+                self.host_matches(host, cidr).await
+            }
+            Mechanism::Mx(target, cidr) => {
+                let host = target.as_deref().unwrap_or(domain);
+                let exchanges = lookup(self.resolver.query_mx(host).await)?;
+                if exchanges.len() > MAX_MX_NAMES {
+                    return Err(SpfResult::PermError);
+                }
+                for exchange in exchanges {
+                    if self.host_matches(&exchange, cidr).await? {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            Mechanism::Exists(target) => {
+                let ips = lookup(self.resolver.query_a(target).await)?;
+                Ok(ips.iter().any(|ip| ip.is_ipv4()))
+            }
+            // The ptr mechanism is deprecated and is never matched
+            Mechanism::Ptr => Ok(false),
+        }
+    }
+
+    async fn host_matches(&self, host: &str, cidr: &Cidr) -> Result<bool, SpfResult> {
+        let ips = match self.ip {
+            IpAddr::V4(_) => lookup(self.resolver.query_a(host).await)?,
+            IpAddr::V6(_) => lookup(self.resolver.query_aaaa(host).await)?,
+        };
+        let matched = ips.into_iter().any(|ip| match ip {
+            IpAddr::V4(_) => in_network(self.ip, ip, cidr.v4),
+            IpAddr::V6(_) => in_network(self.ip, ip, cidr.v6),
+        });
+        Ok(matched)
+    }
+
+    // Returns false if the DNS lookup limit has been reached
+    fn count_lookup(&self) -> bool {
+        let count = self.lookups.get() + 1;
+        self.lookups.set(count);
+        count <= MAX_LOOKUPS
+    }
+}
+
+// A lookup that does not find the domain gives no results
+fn lookup<T>(res: io::Result<Vec<T>>) -> Result<Vec<T>, SpfResult> {
+    match res {
+        Ok(v) => Ok(v),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(_) => Err(SpfResult::TempError),
+    }
+}
+
+fn is_spf(record: &str) -> bool {
+    let version = record.split(' ').next().unwrap_or_default();
+    version.eq_ignore_ascii_case("v=spf1")
+}
+
+// Parse a SPF record, returns None if the record is malformed.
+// Macros are not supported and are treated as malformed.
+fn parse_record(record: &str) -> Option<Record> {
+    let mut directives = Vec::new();
+    let mut redirect = None;
+    for term in record.split_ascii_whitespace().skip(1) {
+        if let Some((name, value)) = term.split_once('=') {
+            if name.eq_ignore_ascii_case("redirect") {
+                if redirect.is_some() {
+                    return None;
+                }
+                redirect = Some(domain_spec(value)?);
+            }
+            // Other modifiers, such as exp, are ignored
+            continue;
+        }
+        directives.push(parse_directive(term)?);
+    }
+    Some(Record {
+        directives,
+        redirect,
+    })
+}
+
+fn parse_directive(term: &str) -> Option<Directive> {
+    let (result, term) = match term.as_bytes()[0] {
+        b'+' => (SpfResult::Pass, &term[1..]),
+        b'-' => (SpfResult::Fail, &term[1..]),
+        b'~' => (SpfResult::SoftFail, &term[1..]),
+        b'?' => (SpfResult::Neutral, &term[1..]),
+        _ => (SpfResult::Pass, term),
+    };
+    let end = term.find([':', '/']).unwrap_or(term.len());
+    let (name, args) = term.split_at(end);
+    let value = args.strip_prefix(':');
+    let mechanism = match name.to_ascii_lowercase().as_str() {
+        "all" if args.is_empty() => Mechanism::All,
+        "include" => Mechanism::Include(domain_spec(value?)?),
+        "exists" => Mechanism::Exists(domain_spec(value?)?),
+        "a" => {
+            let (target, cidr) = target_and_cidr(args)?;
+            Mechanism::A(target, cidr)
+        }
+        "mx" => {
+            let (target, cidr) = target_and_cidr(args)?;
+            Mechanism::Mx(target, cidr)
+        }
+        "ip4" | "ip6" => {
+            let (addr, prefix) = match value?.split_once('/') {
+                Some((addr, prefix)) => (addr, Some(prefix.parse::<u8>().ok()?)),
+                None => (value?, None),
+            };
+            let ip: IpAddr = addr.parse().ok()?;
+            let max = if ip.is_ipv4() { 32 } else { 128 };
+            if (name.eq_ignore_ascii_case("ip4") != ip.is_ipv4()) || prefix.unwrap_or(0) > max {
+                return None;
+            }
+            Mechanism::Ip(ip, prefix.unwrap_or(max))
+        }
+        "ptr" => Mechanism::Ptr,
+        _ => return None,
+    };
+    Some(Directive { result, mechanism })
+}
+
+// Parse [:domain][/cidr4][//cidr6] as used by the a and mx mechanisms
+fn target_and_cidr(args: &str) -> Option<(Option<String>, Cidr)> {
+    let (target, cidrs) = match args.strip_prefix(':') {
+        Some(rest) => {
+            let end = rest.find('/').unwrap_or(rest.len());
+            (Some(domain_spec(&rest[..end])?), &rest[end..])
+        }
+        None => (None, args),
+    };
+    let (v4, v6) = match cidrs.split_once("//") {
+        Some((v4, v6)) => (v4, Some(v6)),
+        None => (cidrs, None),
+    };
+    let v4 = match v4.strip_prefix('/') {
+        Some(p) => p.parse().ok().filter(|p| *p <= 32)?,
+        None if v4.is_empty() => 32,
+        None => return None,
+    };
+    let v6 = match v6 {
+        Some(p) => p.parse().ok().filter(|p| *p <= 128)?,
+        None => 128,
+    };
+    Some((target, Cidr { v4, v6 }))
+}
+
+fn domain_spec(value: &str) -> Option<String> {
+    if value.is_empty() || value.contains('%') {
+        None
+    } else {
+        Some(value.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub::{StubNameserver, Zone};
+    use std::net::Ipv4Addr;
+
+    const CLIENT: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+    const OTHER: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 1);
+
+    // A zone with the given TXT records
+    fn txt_zone(records: &[(&str, &str)]) -> Zone {
+        let mut zone = Zone::new();
+        for (name, text) in records {
+            zone.txt(name, text);
+        }
+        zone
+    }
+
+    // Run check_host against a nameserver that serves the zone
+    fn check(zone: Zone, ip: Ipv4Addr, domain: &str) -> SpfResult {
+        let nameserver = StubNameserver::start(zone);
+        let resolver = nameserver.resolver();
+        let spf = Spf::new(&resolver, ip.into());
+        smol::block_on(spf.check_host(domain.to_string()))
+    }
+
+    // Each domain includes the next one and the last one passes everything
+    fn include_chain(includes: usize) -> Zone {
+        let mut zone = Zone::new();
+        for i in 0..includes {
+            let record = format!("v=spf1 include:l{}.example.com -all", i + 1);
+            zone.txt(&format!("l{}.example.com", i), &record);
+        }
+        zone.txt(&format!("l{}.example.com", includes), "v=spf1 +all");
+        zone
+    }
+
+    #[test]
+    fn check_all() {
+        let records = [
+            ("example.com", "v=spf1 ip4:192.0.2.0/24 -all"),
+            ("example.net", "v=spf1 ~all"),
+            ("example.org", "google-site-verification=abc"),
+        ];
+        assert_eq!(
+            check(txt_zone(&records), CLIENT, "example.com"),
+            SpfResult::Pass
+        );
+        assert_eq!(
+            check(txt_zone(&records), OTHER, "example.com"),
+            SpfResult::Fail
+        );
+        assert_eq!(
+            check(txt_zone(&records), OTHER, "example.net"),
+            SpfResult::SoftFail
+        );
+        assert_eq!(
+            check(txt_zone(&records), OTHER, "example.org"),
+            SpfResult::None
+        );
+        assert_eq!(
+            check(txt_zone(&records), OTHER, "nonexistent.example"),
+            SpfResult::None
+        );
+    }
+
+    #[test]
+    fn check_include() {
+        let records = [
+            ("example.com", "v=spf1 include:_spf.example.net -all"),
+            ("_spf.example.net", "v=spf1 ip4:192.0.2.0/24 ~all"),
+        ];
+        assert_eq!(
+            check(txt_zone(&records), CLIENT, "example.com"),
+            SpfResult::Pass
+        );
+        // A SoftFail from the included domain does not match
+        assert_eq!(
+            check(txt_zone(&records), OTHER, "example.com"),
+            SpfResult::Fail
+        );
+    }
+
+    #[test]
+    fn check_redirect() {
+        let records = [
+            ("example.com", "v=spf1 redirect=_spf.example.net"),
+            (
+                "example.org",
+                "v=spf1 ip4:198.51.100.0/24 redirect=_spf.example.net",
+            ),
+            ("example.info", "v=spf1 redirect=nospf.example.net"),
+            ("_spf.example.net", "v=spf1 ip4:192.0.2.0/24 -all"),
+        ];
+        assert_eq!(
+            check(txt_zone(&records), CLIENT, "example.com"),
+            SpfResult::Pass
+        );
+        assert_eq!(
+            check(txt_zone(&records), OTHER, "example.com"),
+            SpfResult::Fail
+        );
+        // The redirect is only followed when no mechanism matches
+        assert_eq!(
+            check(txt_zone(&records), OTHER, "example.org"),
+            SpfResult::Pass
+        );
+        // Redirecting to a domain without a SPF record is an error
+        assert_eq!(
+            check(txt_zone(&records), CLIENT, "example.info"),
+            SpfResult::PermError
+        );
+    }
+
+    #[test]
+    fn check_mx() {
+        let mut zone = txt_zone(&[("example.com", "v=spf1 mx -all")]);
+        zone.mx("example.com", 10, "mx.example.com")
+            .a("mx.example.com", CLIENT);
+        assert_eq!(check(zone, CLIENT, "example.com"), SpfResult::Pass);
+    }
+
+    #[test]
+    fn lookup_limit() {
+        assert_eq!(
+            check(include_chain(10), CLIENT, "l0.example.com"),
+            SpfResult::Pass
+        );
+        assert_eq!(
+            check(include_chain(11), CLIENT, "l0.example.com"),
+            SpfResult::PermError
+        );
+        // Mechanisms that query A records also count
+        let hosts = |count: usize| {
+            let mechanisms: Vec<String> = (0..count)
+                .map(|i| format!("a:h{}.example.com", i))
+                .collect();
+            format!("v=spf1 {} -all", mechanisms.join(" "))
+        };
+        let records = [("example.com", hosts(10)), ("example.net", hosts(11))];
+        let records: Vec<(&str, &str)> = records.iter().map(|(n, r)| (*n, r.as_str())).collect();
+        assert_eq!(
+            check(txt_zone(&records), CLIENT, "example.com"),
+            SpfResult::Fail
+        );
+        assert_eq!(
+            check(txt_zone(&records), CLIENT, "example.net"),
+            SpfResult::PermError
+        );
+    }
+
+    #[test]
+    fn perm_errors() {
+        let records = [
+            ("malformed.example", "v=spf1 ip4:192.0.2.0/33 -all"),
+            ("macro.example", "v=spf1 exists:%{i}.example.com -all"),
+            ("twice.example", "v=spf1 -all"),
+            ("twice.example", "v=spf1 +all"),
+            ("include.example", "v=spf1 include:nospf.example -all"),
+            ("nospf.example", "google-site-verification=abc"),
+        ];
+        for domain in [
+            "malformed.example",
+            "macro.example",
+            "twice.example",
+            "include.example",
+        ] {
+            assert_eq!(
+                check(txt_zone(&records), CLIENT, domain),
+                SpfResult::PermError,
+                "{}",
+                domain
+            );
+        }
+    }
+
+    #[test]
+    fn parse_common_record() {
+        let record = parse_record(
+            "v=spf1 ip4:192.0.2.0/24 a mx:mail.example.com/28 include:_spf.example.net ~all",
+        )
+        .unwrap();
+        let results: Vec<SpfResult> = record.directives.iter().map(|d| d.result).collect();
+        assert_eq!(
+            results,
+            vec![
+                SpfResult::Pass,
+                SpfResult::Pass,
+                SpfResult::Pass,
+                SpfResult::Pass,
+                SpfResult::SoftFail
+            ]
+        );
+        assert_eq!(
+            record.directives[2].mechanism,
+            Mechanism::Mx(
+                Some("mail.example.com".to_string()),
+                Cidr { v4: 28, v6: 128 }
+            )
+        );
+        assert_eq!(record.redirect, None);
+    }
+
+    #[test]
+    fn parse_redirect() {
+        let record =
+            parse_record("v=spf1 exp=explain.example.com redirect=_spf.example.com").unwrap();
+        assert!(record.directives.is_empty());
+        assert_eq!(record.redirect.as_deref(), Some("_spf.example.com"));
+    }
+
+    #[test]
+    fn malformed_records() {
+        assert_eq!(parse_record("v=spf1 ip4:192.0.2.0/33"), None);
+        assert_eq!(parse_record("v=spf1 ip4:2001:db8::1"), None);
+        assert_eq!(parse_record("v=spf1 include"), None);
+        assert_eq!(parse_record("v=spf1 foo -all"), None);
+        assert_eq!(parse_record("v=spf1 redirect=a.com redirect=b.com"), None);
+    }
+}
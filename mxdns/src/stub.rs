@@ -0,0 +1,239 @@
+// A nameserver on localhost that answers from a fixed set of records, so that
+// lookups can be tested without depending on public DNS servers
+use crate::resolver::{Resolver, DEFAULT_TIMEOUT};
+use dnssector::constants::Type;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+const DEFAULT_TTL: u32 = 300;
+// The largest UDP message without EDNS, RFC 1035 section 4.2.1
+const MAX_UDP_SIZE: usize = 512;
+
+struct Record {
+    name: String,
+    rr_type: u16,
+    ttl: u32,
+    rdata: Vec<u8>,
+}
+
+// The records served by a stub nameserver. Names are compared without the
+// trailing dot and ignoring case.
+#[derive(Default)]
+pub struct Zone {
+    records: Vec<Record>,
+    // The TTL and minimum of a SOA record sent with answers without records
+    soa: Option<(u32, u32)>,
+}
+
+impl Zone {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, name: &str, rr_type: Type, ttl: u32, rdata: Vec<u8>) -> &mut Self {
+        self.records.push(Record {
+            name: normalize(name),
+            rr_type: rr_type as u16,
+            ttl,
+            rdata,
+        });
+        self
+    }
+
+    // A TXT record, split into character strings of at most 255 bytes
+    pub fn txt(&mut self, name: &str, text: &str) -> &mut Self {
+        let mut rdata = Vec::new();
+        for chunk in text.as_bytes().chunks(255) {
+            rdata.push(chunk.len() as u8);
+            rdata.extend_from_slice(chunk);
+        }
+        self.add(name, Type::TXT, DEFAULT_TTL, rdata)
+    }
+
+    // An A or AAAA record, depending on the address
+    pub fn a<A: Into<IpAddr>>(&mut self, name: &str, ip: A) -> &mut Self {
+        match ip.into() {
+            IpAddr::V4(v4) => self.add(name, Type::A, DEFAULT_TTL, v4.octets().to_vec()),
+            IpAddr::V6(v6) => self.add(name, Type::AAAA, DEFAULT_TTL, v6.octets().to_vec()),
+        }
+    }
+
+    pub fn mx(&mut self, name: &str, preference: u16, exchange: &str) -> &mut Self {
+        let mut rdata = preference.to_be_bytes().to_vec();
+        rdata.extend(encode_name(exchange));
+        self.add(name, Type::MX, DEFAULT_TTL, rdata)
+    }
+
+    pub fn ns(&mut self, name: &str, host: &str) -> &mut Self {
+        self.add(name, Type::NS, DEFAULT_TTL, encode_name(host))
+    }
+
+    pub fn ptr(&mut self, name: &str, host: &str) -> &mut Self {
+        self.add(name, Type::PTR, DEFAULT_TTL, encode_name(host))
+    }
+
+    // Send a SOA record, for negative caching, with answers that have no records
+    pub fn soa(&mut self, ttl: u32, minimum: u32) -> &mut Self {
+        self.soa = Some((ttl, minimum));
+        self
+    }
+
+    // The answer to a query. Unknown names give NXDOMAIN, known names without
+    // records of the queried type give an empty answer. Answers that do not fit
+    // in max_size are left out and the TC bit is set.
+    fn answer(&self, query: &[u8], max_size: usize) -> Option<Vec<u8>> {
+        let (name, end) = decode_name(query, 12)?;
+        let question = query.get(12..end + 4)?;
+        let rr_type = u16::from_be_bytes([query[end], query[end + 1]]);
+        let known = self.records.iter().any(|r| r.name == name);
+        let answers: Vec<&Record> = self
+            .records
+            .iter()
+            .filter(|r| r.name == name && r.rr_type == rr_type)
+            .collect();
+        let mut response = query[..2].to_vec();
+        // QR and RD from the query, RA and the rcode
+        response.push(0x80 | (query[2] & 0x01));
+        response.push(if known { 0x80 } else { 0x83 });
+        response.extend_from_slice(&[0, 1]);
+        response.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+        response.extend_from_slice(&[0, 0, 0, 0]);
+        response.extend_from_slice(question);
+        let mut records = Vec::new();
+        for record in &answers {
+            records.extend(encode_record(
+                &record.name,
+                record.rr_type,
+                record.ttl,
+                &record.rdata,
+            ));
+        }
+        if let (true, Some((ttl, minimum))) = (answers.is_empty(), self.soa) {
+            let mut rdata = encode_name("ns.invalid");
+            rdata.extend(encode_name("hostmaster.invalid"));
+            for field in [1, 3600, 600, 86400, minimum] {
+                rdata.extend_from_slice(&u32::to_be_bytes(field));
+            }
+            records.extend(encode_record(&name, Type::SOA as u16, ttl, &rdata));
+            response[9] = 1;
+        }
+        if response.len() + records.len() > max_size {
+            response[2] |= 0x02;
+            response[6..8].copy_from_slice(&[0, 0]);
+        } else {
+            response.extend(records);
+        }
+        Some(response)
+    }
+}
+
+pub struct StubNameserver {
+    addr: SocketAddr,
+    source_ports: Arc<Mutex<Vec<u16>>>,
+}
+
+impl StubNameserver {
+    // Answer queries over UDP and TCP, on the same port, until the tests finish
+    pub fn start(zone: Zone) -> Self {
+        let zone = Arc::new(zone);
+        let (socket, listener) = bind();
+        let addr = socket.local_addr().unwrap();
+        let udp_zone = zone.clone();
+        let source_ports = Arc::new(Mutex::new(Vec::new()));
+        let udp_ports = source_ports.clone();
+        thread::spawn(move || {
+            let mut buf = [0u8; MAX_UDP_SIZE];
+            while let Ok((len, client)) = socket.recv_from(&mut buf) {
+                udp_ports.lock().unwrap().push(client.port());
+                if let Some(response) = udp_zone.answer(&buf[..len], MAX_UDP_SIZE) {
+                    socket.send_to(&response, client).ok();
+                }
+            }
+        });
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                serve_tcp(stream, &zone).ok();
+            }
+        });
+        Self { addr, source_ports }
+    }
+
+    pub fn resolver(&self) -> Resolver {
+        Resolver::new(vec![self.addr], DEFAULT_TIMEOUT)
+    }
+
+    // The number of UDP queries received
+    pub fn queries(&self) -> usize {
+        self.source_ports.lock().unwrap().len()
+    }
+
+    // The source ports of the UDP queries, in the order they were received
+    pub fn source_ports(&self) -> Vec<u16> {
+        self.source_ports.lock().unwrap().clone()
+    }
+}
+
+// A UDP socket and a TCP listener on the same port
+fn bind() -> (UdpSocket, TcpListener) {
+    loop {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        if let Ok(listener) = TcpListener::bind(socket.local_addr().unwrap()) {
+            return (socket, listener);
+        }
+    }
+}
+
+// Messages over TCP have a two byte length prefix
+fn serve_tcp(mut stream: TcpStream, zone: &Zone) -> io::Result<()> {
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len)?;
+    let mut query = vec![0; u16::from_be_bytes(len).into()];
+    stream.read_exact(&mut query)?;
+    if let Some(response) = zone.answer(&query, u16::MAX.into()) {
+        stream.write_all(&(response.len() as u16).to_be_bytes())?;
+        stream.write_all(&response)?;
+    }
+    Ok(())
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn encode_record(name: &str, rr_type: u16, ttl: u32, rdata: &[u8]) -> Vec<u8> {
+    let mut ret = encode_name(name);
+    ret.extend_from_slice(&rr_type.to_be_bytes());
+    ret.extend_from_slice(&[0, 1]);
+    ret.extend_from_slice(&ttl.to_be_bytes());
+    ret.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    ret.extend_from_slice(rdata);
+    ret
+}
+
+fn encode_name(name: &str) -> Vec<u8> {
+    let mut ret = Vec::new();
+    for label in normalize(name).split('.') {
+        ret.push(label.len() as u8);
+        ret.extend_from_slice(label.as_bytes());
+    }
+    ret.push(0);
+    ret
+}
+
+// Read an uncompressed name, returns the name and the offset after it
+fn decode_name(packet: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    loop {
+        let len = *packet.get(offset)? as usize;
+        offset += 1;
+        if len == 0 {
+            break;
+        }
+        let label = packet.get(offset..offset + len)?;
+        labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+        offset += len;
+    }
+    Some((labels.join("."), offset))
+}
@@ -0,0 +1,80 @@
+use crate::err::{Error, Result};
+use crate::net::{canonical_ip, in_network};
+use std::net::IpAddr;
+
+// Networks, as address and prefix length, that are trusted
+#[derive(Clone, Debug, Default)]
+pub struct Whitelist {
+    networks: Vec<(IpAddr, u8)>,
+}
+
+impl Whitelist {
+    pub fn add_address(&mut self, ip: IpAddr) {
+        let ip = canonical_ip(ip);
+        let prefix = host_prefix(ip);
+        self.networks.push((ip, prefix));
+    }
+
+    // Add a network in CIDR notation e.g 192.0.2.0/24, or a single address
+    pub fn add_network(&mut self, network: &str) -> Result<()> {
+        let invalid = || Error::InvalidNetwork(network.to_string());
+        let (ip, prefix) = match network.split_once('/') {
+            Some((ip, prefix)) => (ip, Some(prefix)),
+            None => (network, None),
+        };
+        let ip = canonical_ip(ip.trim().parse().map_err(|_| invalid())?);
+        let max_prefix = host_prefix(ip);
+        let prefix = match prefix {
+            Some(p) => p.trim().parse::<u8>().map_err(|_| invalid())?,
+            None => max_prefix,
+        };
+        if prefix > max_prefix {
+            return Err(invalid());
+        }
+        self.networks.push((ip, prefix));
+        Ok(())
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = canonical_ip(ip);
+        self.networks
+            .iter()
+            .any(|(network, prefix)| in_network(ip, *network, *prefix))
+    }
+}
+
+// The prefix length of a single address
+fn host_prefix(ip: IpAddr) -> u8 {
+    match ip {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn networks() {
+        let mut whitelist = Whitelist::default();
+        whitelist.add_network("192.0.2.0/24").unwrap();
+        whitelist.add_network("2001:db8::1").unwrap();
+        whitelist.add_address("198.51.100.7".parse().unwrap());
+        assert!(whitelist.contains("192.0.2.200".parse().unwrap()));
+        assert!(whitelist.contains("::ffff:192.0.2.1".parse().unwrap()));
+        assert!(!whitelist.contains("192.0.3.1".parse().unwrap()));
+        assert!(whitelist.contains("2001:db8::1".parse().unwrap()));
+        assert!(!whitelist.contains("2001:db8::2".parse().unwrap()));
+        assert!(whitelist.contains("198.51.100.7".parse().unwrap()));
+        assert!(!whitelist.contains("198.51.100.8".parse().unwrap()));
+    }
+
+    #[test]
+    fn invalid_networks() {
+        let mut whitelist = Whitelist::default();
+        assert!(whitelist.add_network("192.0.2.0/33").is_err());
+        assert!(whitelist.add_network("192.0.2/24").is_err());
+        assert!(whitelist.add_network("mail.example.com").is_err());
+    }
+}